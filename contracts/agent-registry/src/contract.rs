@@ -1,12 +1,54 @@
 use soroban_sdk::{
-    contract, contracterror, contractimpl, panic_with_error, Address, Env, String, Vec,
+    contract, contracterror, contractimpl, panic_with_error, symbol_short, Address, BytesN, Env,
+    Executable, IntoVal, Map, String, Symbol, Val, Vec,
 };
 
 use crate::{
-    storage::{AgentRecord, DataKey},
-    types::{AgentDetails, RateCard, RateCardInput},
+    storage::{AgentIndex, AgentRecord, DataKey},
+    types::{
+        AgentDelistedLog, AgentDetails, AgentPauseLog, AgentPlan, AgentRegisteredLog,
+        AgentTransferredLog, AgentVerifiedLog, AttributeChangedLog, AuditAttachedLog, AuditRecord,
+        ManifestPublishedLog, MetadataUpdatedLog, PlanInput, PlanPublishedLog, PlanStatusLog,
+        RateCard, RateCardInput, RateCardPublishedLog, RunnerChangedLog, RunnerInfo,
+        SettlementInfo, SettlementRateCard,
+    },
 };
 
+/// Upper bound on the number of entries returned by paginated views.
+const MAX_PAGE_SIZE: u32 = 50;
+
+/// Upper bound on the number of discovery tags an agent may carry.
+const MAX_TAGS: u32 = 8;
+
+/// Upper bound on the number of runners an agent may list, keeping the record small
+/// enough to load on every `is_runner` check.
+const MAX_RUNNERS: u32 = 32;
+
+/// Upper bound on the number of payees in an agent's revenue split.
+const MAX_SPLIT_PAYEES: u32 = 8;
+
+/// Upper bound on the number of on-chain attributes an agent may carry.
+const MAX_ATTRIBUTES: u32 = 16;
+
+/// Upper bound on the byte length of an attribute value.
+const MAX_ATTRIBUTE_LEN: u32 = 128;
+
+/// Upper bound on the number of audits attached to a single rate card version.
+const MAX_AUDITS_PER_VERSION: u32 = 8;
+
+/// Upper bound on the number of published rate cards not yet in effect above the current
+/// one, which also bounds the walk in `effective_rate_version`.
+const MAX_SCHEDULED_RATE_CARDS: u32 = 4;
+
+const BPS_DENOMINATOR: u32 = 10_000;
+
+const DAY_IN_LEDGERS: u32 = 17_280;
+
+/// Agent records and rate cards are extended to `TTL_EXTEND_TO` ledgers whenever they
+/// are written or read on a hot path with less than `TTL_THRESHOLD` ledgers left.
+const TTL_THRESHOLD: u32 = 30 * DAY_IN_LEDGERS;
+const TTL_EXTEND_TO: u32 = 120 * DAY_IN_LEDGERS;
+
 #[contract]
 pub struct AgentRegistry;
 
@@ -20,14 +62,29 @@ pub enum AgentRegistryError {
     InvalidRunnerList = 4,
     InvalidRates = 5,
     RunnerNotFound = 6,
+    NoPendingTransfer = 7,
+    NotInitialized = 8,
+    InvalidRateVersion = 9,
+    InvalidToken = 10,
+    TooManyTags = 11,
+    ManifestNotFound = 12,
+    TooManyRunners = 13,
+    InvalidRevenueSplit = 14,
+    TooManyAudits = 15,
+    PlanNotFound = 16,
+    TooManyAttributes = 17,
+    AttributeTooLong = 18,
+    TooManyScheduledRateCards = 19,
+    InitialRateCardNotEffective = 20,
 }
 
 #[contractimpl]
 impl AgentRegistry {
-    pub fn init(e: Env) {
+    pub fn init(e: Env, admin: Address) {
         if e.storage().instance().has(&DataKey::NextAgentId) {
             panic_with_error!(&e, AgentRegistryError::AlreadyInitialized);
         }
+        e.storage().instance().set(&DataKey::Admin, &admin);
         e.storage().instance().set(&DataKey::NextAgentId, &1u32);
     }
 
@@ -42,36 +99,54 @@ impl AgentRegistry {
         if runners.len() == 0 {
             panic_with_error!(&e, AgentRegistryError::InvalidRunnerList);
         }
-        if !initial_rate_card.rates.validate_non_negative() {
+        if !initial_rate_card.is_valid() {
             panic_with_error!(&e, AgentRegistryError::InvalidRates);
         }
-
-        let mut normalized_runners = Vec::new(&e);
-        for runner in runners.iter() {
-            if !contains_address(&normalized_runners, &runner) {
-                normalized_runners.push_back(runner);
-            }
+        if !rate_card_is_effective(&initial_rate_card, e.ledger().timestamp()) {
+            panic_with_error!(&e, AgentRegistryError::InitialRateCardNotEffective);
         }
-
-        if normalized_runners.len() == 0 {
-            panic_with_error!(&e, AgentRegistryError::InvalidRunnerList);
+        if !is_token_contract(&initial_rate_card.token) {
+            panic_with_error!(&e, AgentRegistryError::InvalidToken);
         }
 
+        let normalized_runners = dedup_runners(&e, &runners);
+
         let agent_id = next_agent_id_and_increment(&e);
+        let runner_count = normalized_runners.len();
 
         let record = AgentRecord {
             developer: developer.clone(),
             metadata_uri,
             runners: normalized_runners,
             latest_rate_version: 1,
+            min_rate_version: 1,
+            latest_manifest_version: 1,
+            payout: None,
+            active: true,
+            paused: false,
+            delisted: false,
+            tags: Vec::new(&e),
+            verified: false,
         };
 
-        e.storage()
-            .instance()
-            .set(&DataKey::Agent(agent_id), &record);
+        write_agent(&e, agent_id, &record);
 
-        let rate_card: RateCard = RateCard::from(initial_rate_card);
+        let manifest_hash = initial_rate_card.manifest_hash.clone();
+        write_manifest(&e, agent_id, 1, &manifest_hash);
+        let rate_card = RateCard::from_input(initial_rate_card, 1);
         write_rate_card(&e, agent_id, 1, &rate_card);
+        index_agent(&e, AgentIndex::Developer(developer.clone()), agent_id);
+
+        e.events().publish(
+            (symbol_short!("agent"), symbol_short!("register")),
+            AgentRegisteredLog {
+                agent_id,
+                developer,
+                runner_count,
+                manifest_hash,
+                timestamp: e.ledger().timestamp(),
+            },
+        );
 
         agent_id
     }
@@ -79,132 +154,1206 @@ impl AgentRegistry {
     pub fn set_metadata_uri(e: Env, agent_id: u32, metadata_uri: Option<String>) {
         let mut record = read_agent_or_panic(&e, agent_id);
         record.developer.require_auth();
-        record.metadata_uri = metadata_uri;
+        record.metadata_uri = metadata_uri.clone();
+        write_agent(&e, agent_id, &record);
+
+        e.events().publish(
+            (symbol_short!("agent"), symbol_short!("metadata")),
+            MetadataUpdatedLog {
+                agent_id,
+                metadata_uri,
+                timestamp: e.ledger().timestamp(),
+            },
+        );
+    }
+
+    /// Routes future settlements for the agent to `payout` instead of the developer.
+    pub fn set_payout_address(e: Env, agent_id: u32, payout: Address) {
+        let mut record = read_agent_or_panic(&e, agent_id);
+        record.developer.require_auth();
+        record.payout = Some(payout);
+        write_agent(&e, agent_id, &record);
+    }
+
+    /// Retires (or revives) an agent. Inactive agents cannot be used to open new runs.
+    pub fn set_agent_active(e: Env, agent_id: u32, active: bool) {
+        let mut record = read_agent_or_panic(&e, agent_id);
+        record.developer.require_auth();
+        record.active = active;
+        write_agent(&e, agent_id, &record);
+    }
+
+    /// Emergency stop for a compromised agent: the vault refuses to open or settle runs
+    /// against a paused agent until it is unpaused.
+    pub fn pause_agent(e: Env, agent_id: u32) {
+        set_paused(&e, agent_id, true);
+    }
+
+    pub fn unpause_agent(e: Env, agent_id: u32) {
+        set_paused(&e, agent_id, false);
+    }
+
+    /// Offers the agent to `new_developer`, replacing any earlier offer. Ownership only
+    /// moves once the new developer calls `accept_transfer`.
+    pub fn propose_transfer(e: Env, agent_id: u32, new_developer: Address) {
+        let record = read_agent_or_panic(&e, agent_id);
+        record.developer.require_auth();
         e.storage()
             .instance()
-            .set(&DataKey::Agent(agent_id), &record);
+            .set(&DataKey::PendingTransfer(agent_id), &new_developer);
     }
 
-    pub fn add_runner(e: Env, agent_id: u32, runner: Address) {
-        let mut record = read_agent_or_panic(&e, agent_id);
+    /// Completes a proposed transfer. Rate cards and runners are kept, while a payout
+    /// address configured by the previous owner is cleared.
+    pub fn accept_transfer(e: Env, agent_id: u32) {
+        let record = read_agent_or_panic(&e, agent_id);
+        let new_developer = match read_pending_transfer(&e, agent_id) {
+            Some(pending) => pending,
+            None => panic_with_error!(&e, AgentRegistryError::NoPendingTransfer),
+        };
+        new_developer.require_auth();
+        e.storage()
+            .instance()
+            .remove(&DataKey::PendingTransfer(agent_id));
+        transfer_ownership(&e, agent_id, record, new_developer);
+    }
+
+    pub fn cancel_transfer(e: Env, agent_id: u32) {
+        let record = read_agent_or_panic(&e, agent_id);
         record.developer.require_auth();
+        if read_pending_transfer(&e, agent_id).is_none() {
+            panic_with_error!(&e, AgentRegistryError::NoPendingTransfer);
+        }
+        e.storage()
+            .instance()
+            .remove(&DataKey::PendingTransfer(agent_id));
+    }
 
-        if !contains_address(&record.runners, &runner) {
-            record.runners.push_back(runner.clone());
+    pub fn pending_transfer(e: Env, agent_id: u32) -> Option<Address> {
+        read_agent_or_panic(&e, agent_id);
+        read_pending_transfer(&e, agent_id)
+    }
+
+    /// Admin takedown for abusive agents. The agent stops accepting runs but its rate
+    /// card history stays readable.
+    pub fn delist_agent(e: Env, agent_id: u32, reason_hash: BytesN<32>) {
+        require_admin(&e);
+        let mut record = read_agent_or_panic(&e, agent_id);
+        if !record.delisted {
+            let delisted = e
+                .storage()
+                .instance()
+                .get::<_, u32>(&DataKey::DelistedCount)
+                .unwrap_or(0);
+            e.storage()
+                .instance()
+                .set(&DataKey::DelistedCount, &(delisted + 1));
         }
+        record.delisted = true;
+        write_agent(&e, agent_id, &record);
 
+        e.events().publish(
+            (symbol_short!("agent"), symbol_short!("delisted")),
+            AgentDelistedLog {
+                agent_id,
+                reason_hash,
+                timestamp: e.ledger().timestamp(),
+            },
+        );
+    }
+
+    /// Admin-granted verification badge. Events are only emitted on actual changes.
+    pub fn set_verified(e: Env, agent_id: u32, verified: bool) {
+        require_admin(&e);
+        let mut record = read_agent_or_panic(&e, agent_id);
+        if record.verified == verified {
+            return;
+        }
+        record.verified = verified;
+        write_agent(&e, agent_id, &record);
+
+        e.events().publish(
+            (symbol_short!("agent"), symbol_short!("verified")),
+            AgentVerifiedLog {
+                agent_id,
+                verified,
+                timestamp: e.ledger().timestamp(),
+            },
+        );
+    }
+
+    pub fn add_auditor(e: Env, auditor: Address) {
+        require_admin(&e);
         e.storage()
             .instance()
-            .set(&DataKey::Agent(agent_id), &record);
+            .set(&DataKey::Auditor(auditor), &true);
+    }
+
+    pub fn remove_auditor(e: Env, auditor: Address) {
+        require_admin(&e);
+        e.storage().instance().remove(&DataKey::Auditor(auditor));
+    }
+
+    pub fn is_auditor(e: Env, auditor: Address) -> bool {
+        is_auditor(&e, &auditor)
+    }
+
+    /// Anchors an audit report for rate card `rate_version`. `auditor` must be the
+    /// registry admin or an allowlisted auditor.
+    pub fn attach_audit(
+        e: Env,
+        agent_id: u32,
+        rate_version: u32,
+        auditor: Address,
+        report_hash: BytesN<32>,
+    ) {
+        auditor.require_auth();
+        if auditor != read_admin(&e) && !is_auditor(&e, &auditor) {
+            panic_with_error!(&e, AgentRegistryError::Unauthorized);
+        }
+        if read_rate_card(&e, agent_id, rate_version).is_none() {
+            panic_with_error!(&e, AgentRegistryError::InvalidRateVersion);
+        }
+        let mut audits = read_audits(&e, agent_id, rate_version);
+        if audits.len() >= MAX_AUDITS_PER_VERSION {
+            panic_with_error!(&e, AgentRegistryError::TooManyAudits);
+        }
+        let attached_at = e.ledger().timestamp();
+        audits.push_back(AuditRecord {
+            auditor: auditor.clone(),
+            report_hash: report_hash.clone(),
+            attached_at,
+        });
+        write_audits(&e, agent_id, rate_version, &audits);
+
+        e.events().publish(
+            (symbol_short!("audit"), symbol_short!("attached")),
+            AuditAttachedLog {
+                agent_id,
+                rate_version,
+                auditor,
+                report_hash,
+                attached_at,
+            },
+        );
+    }
+
+    pub fn add_runner(e: Env, agent_id: u32, runner: Address) {
+        let mut record = read_agent_or_panic(&e, agent_id);
+        record.developer.require_auth();
+
+        if contains_address(&record.runners, &runner) {
+            return;
+        }
+        if record.runners.len() >= MAX_RUNNERS {
+            panic_with_error!(&e, AgentRegistryError::TooManyRunners);
+        }
+        record.runners.push_back(runner.clone());
+
+        write_agent(&e, agent_id, &record);
+
+        e.events().publish(
+            (symbol_short!("runner"), symbol_short!("added")),
+            RunnerChangedLog {
+                agent_id,
+                runner,
+                timestamp: e.ledger().timestamp(),
+            },
+        );
     }
 
     pub fn remove_runner(e: Env, agent_id: u32, runner: Address) {
+        let record = read_agent_or_panic(&e, agent_id);
+        record.developer.require_auth();
+        drop_runner(&e, agent_id, record, runner);
+    }
+
+    /// Lets a runner withdraw from an agent on its own authority, e.g. when its
+    /// infrastructure is being decommissioned. The last runner cannot resign.
+    pub fn resign_runner(e: Env, agent_id: u32, runner: Address) {
+        runner.require_auth();
+        let record = read_agent_or_panic(&e, agent_id);
+        drop_runner(&e, agent_id, record, runner);
+    }
+
+    /// Replaces the agent's runners with `runners` in one step, so key rotations never
+    /// expose a half-updated list. Emits an added or removed event per changed runner.
+    pub fn set_runners(e: Env, agent_id: u32, runners: Vec<Address>) {
         let mut record = read_agent_or_panic(&e, agent_id);
         record.developer.require_auth();
+        let runners = dedup_runners(&e, &runners);
+
+        let previous = record.runners.clone();
+        record.runners = runners.clone();
+        write_agent(&e, agent_id, &record);
 
-        let mut filtered = Vec::new(&e);
-        for existing in record.runners.iter() {
-            if existing != runner {
-                filtered.push_back(existing);
+        let timestamp = e.ledger().timestamp();
+        for runner in previous.iter() {
+            if !contains_address(&runners, &runner) {
+                e.storage()
+                    .instance()
+                    .remove(&DataKey::RunnerMetadata(agent_id, runner.clone()));
+                e.events().publish(
+                    (symbol_short!("runner"), symbol_short!("removed")),
+                    RunnerChangedLog {
+                        agent_id,
+                        runner,
+                        timestamp,
+                    },
+                );
             }
         }
-
-        if filtered.len() == 0 {
-            panic_with_error!(&e, AgentRegistryError::InvalidRunnerList);
+        for runner in runners.iter() {
+            if !contains_address(&previous, &runner) {
+                e.events().publish(
+                    (symbol_short!("runner"), symbol_short!("added")),
+                    RunnerChangedLog {
+                        agent_id,
+                        runner,
+                        timestamp,
+                    },
+                );
+            }
         }
+    }
 
-        if filtered.len() == record.runners.len() {
+    /// Describes the operator behind `runner` so users can tell who they are granting.
+    /// Passing `None` clears the entry.
+    pub fn set_runner_metadata(e: Env, agent_id: u32, runner: Address, uri: Option<String>) {
+        let record = read_agent_or_panic(&e, agent_id);
+        record.developer.require_auth();
+        if !contains_address(&record.runners, &runner) {
             panic_with_error!(&e, AgentRegistryError::RunnerNotFound);
         }
 
-        record.runners = filtered;
-        e.storage()
-            .instance()
-            .set(&DataKey::Agent(agent_id), &record);
+        let key = DataKey::RunnerMetadata(agent_id, runner);
+        match uri {
+            Some(uri) => e.storage().instance().set(&key, &uri),
+            None => e.storage().instance().remove(&key),
+        }
     }
 
     pub fn publish_rate_card(e: Env, agent_id: u32, rate_card: RateCardInput) -> u32 {
-        if !rate_card.rates.validate_non_negative() {
+        if !rate_card.is_valid() {
             panic_with_error!(&e, AgentRegistryError::InvalidRates);
         }
+        if !is_token_contract(&rate_card.token) {
+            panic_with_error!(&e, AgentRegistryError::InvalidToken);
+        }
         let mut record = read_agent_or_panic(&e, agent_id);
         record.developer.require_auth();
 
         let next_version = record.latest_rate_version + 1;
-        let converted: RateCard = RateCard::from(rate_card);
+        if !rate_card_is_effective(&rate_card, e.ledger().timestamp())
+            && record.latest_rate_version - effective_rate_version(&e, agent_id, &record)
+                >= MAX_SCHEDULED_RATE_CARDS
+        {
+            panic_with_error!(&e, AgentRegistryError::TooManyScheduledRateCards);
+        }
+        let manifest_version = resolve_manifest_version(&e, agent_id, &mut record, &rate_card);
+        let manifest_hash = rate_card.manifest_hash.clone();
+        let converted = RateCard::from_input(rate_card, manifest_version);
         write_rate_card(&e, agent_id, next_version, &converted);
 
         record.latest_rate_version = next_version;
+        write_agent(&e, agent_id, &record);
+
+        e.events().publish(
+            (symbol_short!("rate"), symbol_short!("published")),
+            RateCardPublishedLog {
+                agent_id,
+                version: next_version,
+                manifest_hash,
+                timestamp: e.ledger().timestamp(),
+            },
+        );
+
+        next_version
+    }
+
+    /// Publishes a named flat-price plan and returns its id. Ids count up from 1 per
+    /// agent, like rate card versions.
+    pub fn publish_plan(e: Env, agent_id: u32, plan: PlanInput) -> u32 {
+        if plan.price < 0
+            || plan.period_secs == 0
+            || plan.included_units.values().iter().any(|units| units < 0)
+        {
+            panic_with_error!(&e, AgentRegistryError::InvalidRates);
+        }
+        if !is_token_contract(&plan.token) {
+            panic_with_error!(&e, AgentRegistryError::InvalidToken);
+        }
+        let record = read_agent_or_panic(&e, agent_id);
+        record.developer.require_auth();
+
+        let plan_id = read_latest_plan_id(&e, agent_id) + 1;
+        let stored = AgentPlan {
+            name: plan.name,
+            price: plan.price,
+            period_secs: plan.period_secs,
+            included_units: plan.included_units,
+            token: plan.token,
+            manifest_hash: plan.manifest_hash,
+            active: true,
+            published_at: e.ledger().timestamp(),
+        };
+        write_plan(&e, agent_id, plan_id, &stored);
         e.storage()
             .instance()
-            .set(&DataKey::Agent(agent_id), &record);
+            .set(&DataKey::LatestPlanId(agent_id), &plan_id);
 
-        next_version
+        e.events().publish(
+            (symbol_short!("plan"), symbol_short!("published")),
+            PlanPublishedLog {
+                agent_id,
+                plan_id,
+                name: stored.name,
+                price: stored.price,
+                timestamp: stored.published_at,
+            },
+        );
+
+        plan_id
+    }
+
+    /// Stops or resumes offering a plan. Its terms are kept for existing subscribers.
+    pub fn set_plan_active(e: Env, agent_id: u32, plan_id: u32, active: bool) {
+        let record = read_agent_or_panic(&e, agent_id);
+        record.developer.require_auth();
+        let mut plan = read_plan_or_panic(&e, agent_id, plan_id);
+        if plan.active == active {
+            return;
+        }
+        plan.active = active;
+        write_plan(&e, agent_id, plan_id, &plan);
+
+        e.events().publish(
+            (symbol_short!("plan"), symbol_short!("status")),
+            PlanStatusLog {
+                agent_id,
+                plan_id,
+                active,
+                timestamp: e.ledger().timestamp(),
+            },
+        );
+    }
+
+    pub fn get_plan(e: Env, agent_id: u32, plan_id: u32) -> AgentPlan {
+        read_plan_or_panic(&e, agent_id, plan_id)
+    }
+
+    pub fn latest_plan_id(e: Env, agent_id: u32) -> u32 {
+        read_latest_plan_id(&e, agent_id)
+    }
+
+    /// Publishes a new manifest hash without touching pricing. Rate cards published later
+    /// with the same hash reference this version.
+    pub fn publish_manifest(e: Env, agent_id: u32, manifest_hash: BytesN<32>) -> u32 {
+        let mut record = read_agent_or_panic(&e, agent_id);
+        record.developer.require_auth();
+
+        let version = append_manifest(&e, agent_id, &mut record, manifest_hash);
+        write_agent(&e, agent_id, &record);
+        version
+    }
+
+    /// Sets a small on-chain fact about the agent, such as its model name or region.
+    pub fn set_attribute(e: Env, agent_id: u32, key: Symbol, value: String) {
+        let record = read_agent_or_panic(&e, agent_id);
+        record.developer.require_auth();
+        if value.len() > MAX_ATTRIBUTE_LEN {
+            panic_with_error!(&e, AgentRegistryError::AttributeTooLong);
+        }
+        let mut attributes = read_attributes(&e, agent_id);
+        if !attributes.contains_key(key.clone()) && attributes.len() >= MAX_ATTRIBUTES {
+            panic_with_error!(&e, AgentRegistryError::TooManyAttributes);
+        }
+        attributes.set(key.clone(), value.clone());
+        write_attributes(&e, agent_id, &attributes);
+
+        e.events().publish(
+            (symbol_short!("attr"), symbol_short!("set")),
+            AttributeChangedLog {
+                agent_id,
+                key,
+                value: Some(value),
+                timestamp: e.ledger().timestamp(),
+            },
+        );
+    }
+
+    pub fn remove_attribute(e: Env, agent_id: u32, key: Symbol) {
+        let record = read_agent_or_panic(&e, agent_id);
+        record.developer.require_auth();
+        let mut attributes = read_attributes(&e, agent_id);
+        if attributes.remove(key.clone()).is_none() {
+            return;
+        }
+        write_attributes(&e, agent_id, &attributes);
+
+        e.events().publish(
+            (symbol_short!("attr"), symbol_short!("removed")),
+            AttributeChangedLog {
+                agent_id,
+                key,
+                value: None,
+                timestamp: e.ledger().timestamp(),
+            },
+        );
+    }
+
+    pub fn get_attributes(e: Env, agent_id: u32) -> Map<Symbol, String> {
+        read_agent_or_panic(&e, agent_id);
+        read_attributes(&e, agent_id)
+    }
+
+    /// Replaces the agent's discovery tags, keeping the `agents_with_tag` index in sync.
+    /// Duplicate tags are collapsed.
+    pub fn set_tags(e: Env, agent_id: u32, tags: Vec<Symbol>) {
+        let mut record = read_agent_or_panic(&e, agent_id);
+        record.developer.require_auth();
+
+        let mut normalized = Vec::new(&e);
+        for tag in tags.iter() {
+            if !normalized.contains(&tag) {
+                normalized.push_back(tag);
+            }
+        }
+        if normalized.len() > MAX_TAGS {
+            panic_with_error!(&e, AgentRegistryError::TooManyTags);
+        }
+
+        for tag in record.tags.iter() {
+            if !normalized.contains(&tag) {
+                unindex_agent(&e, AgentIndex::Tag(tag), agent_id);
+            }
+        }
+        for tag in normalized.iter() {
+            if !record.tags.contains(&tag) {
+                index_agent(&e, AgentIndex::Tag(tag), agent_id);
+            }
+        }
+
+        record.tags = normalized;
+        write_agent(&e, agent_id, &record);
+    }
+
+    /// Splits settlement proceeds among `splits` payees by basis points, which must sum
+    /// to 10_000. The first payee also receives rounding dust. An empty list clears the
+    /// split so proceeds go to the payout address again.
+    pub fn set_revenue_split(e: Env, agent_id: u32, splits: Vec<(Address, u32)>) {
+        let record = read_agent_or_panic(&e, agent_id);
+        record.developer.require_auth();
+
+        let key = DataKey::RevenueSplit(agent_id);
+        if splits.is_empty() {
+            e.storage().instance().remove(&key);
+            return;
+        }
+        if splits.len() > MAX_SPLIT_PAYEES {
+            panic_with_error!(&e, AgentRegistryError::InvalidRevenueSplit);
+        }
+        let mut payees = Vec::new(&e);
+        let mut total: u32 = 0;
+        for (payee, bps) in splits.iter() {
+            if bps == 0 || contains_address(&payees, &payee) {
+                panic_with_error!(&e, AgentRegistryError::InvalidRevenueSplit);
+            }
+            payees.push_back(payee);
+            total = total.saturating_add(bps);
+        }
+        if total != BPS_DENOMINATOR {
+            panic_with_error!(&e, AgentRegistryError::InvalidRevenueSplit);
+        }
+        e.storage().instance().set(&key, &splits);
+    }
+
+    /// Retires rate card versions below `version`: new runs must use `version` or later,
+    /// while runs already opened against an older version still settle at that version.
+    pub fn set_min_rate_version(e: Env, agent_id: u32, version: u32) {
+        let mut record = read_agent_or_panic(&e, agent_id);
+        record.developer.require_auth();
+        if version == 0 || version > effective_rate_version(&e, agent_id, &record) {
+            panic_with_error!(&e, AgentRegistryError::InvalidRateVersion);
+        }
+        record.min_rate_version = version;
+        write_agent(&e, agent_id, &record);
     }
 
     pub fn get_agent(e: Env, agent_id: u32) -> AgentDetails {
         let record = read_agent_or_panic(&e, agent_id);
-        AgentDetails {
-            agent_id,
-            developer: record.developer,
-            metadata_uri: record.metadata_uri,
-            runners: record.runners,
-            latest_rate_version: record.latest_rate_version,
+        to_details(agent_id, record)
+    }
+
+    pub fn runner_metadata(e: Env, agent_id: u32, runner: Address) -> Option<String> {
+        read_runner_metadata(&e, agent_id, &runner)
+    }
+
+    /// The agent's runners paired with their metadata URIs.
+    pub fn get_agent_runners(e: Env, agent_id: u32) -> Vec<RunnerInfo> {
+        let record = read_agent_or_panic(&e, agent_id);
+        let mut runners = Vec::new(&e);
+        for runner in record.runners.iter() {
+            let metadata_uri = read_runner_metadata(&e, agent_id, &runner);
+            runners.push_back(RunnerInfo {
+                runner,
+                metadata_uri,
+            });
         }
+        runners
     }
 
-    pub fn get_rate_card(e: Env, agent_id: u32, version: u32) -> RateCard {
-        match e
+    /// Number of registered agents that have not been delisted.
+    pub fn total_agents(e: Env) -> u32 {
+        let registered = read_next_agent_id(&e) - 1;
+        let delisted = e
             .storage()
             .instance()
-            .get::<_, RateCard>(&DataKey::RateCard(agent_id, version))
-        {
-            Some(card) => card,
+            .get::<_, u32>(&DataKey::DelistedCount)
+            .unwrap_or(0);
+        registered.saturating_sub(delisted)
+    }
+
+    /// Lists listed agents with ids greater than `offset`, returning at most `limit`
+    /// entries. Callers page by passing the last returned `agent_id` as the next offset.
+    pub fn list_agents(e: Env, offset: u32, limit: u32) -> Vec<AgentDetails> {
+        let limit = limit.min(MAX_PAGE_SIZE);
+        let next_id = read_next_agent_id(&e);
+        let mut agents = Vec::new(&e);
+        let mut agent_id = offset.saturating_add(1);
+        while agent_id < next_id && agents.len() < limit {
+            if let Some(record) = read_agent(&e, agent_id) {
+                if !record.delisted {
+                    agents.push_back(to_details(agent_id, record));
+                }
+            }
+            agent_id += 1;
+        }
+        agents
+    }
+
+    pub fn get_rate_card(e: Env, agent_id: u32, version: u32) -> RateCard {
+        match read_rate_card(&e, agent_id, version) {
+            Some(card) => {
+                extend_persistent_ttl(&e, &DataKey::RateCard(agent_id, version));
+                extend_persistent_ttl(&e, &DataKey::Agent(agent_id));
+                card
+            }
             None => panic_with_error!(&e, AgentRegistryError::AgentNotFound),
         }
     }
 
+    /// The agent's developer, payout address and pause flag together with rate card
+    /// `version`, so settling a run takes one registry call. A `version` of 0 leaves the
+    /// card out for callers that keep their own copy.
+    pub fn get_settlement_info(e: Env, agent_id: u32, version: u32) -> SettlementInfo {
+        let record = read_agent_or_panic(&e, agent_id);
+        let rate_card = if version == 0 {
+            SettlementRateCard::NotRequested
+        } else {
+            SettlementRateCard::Card(Self::get_rate_card(e.clone(), agent_id, version))
+        };
+        SettlementInfo {
+            payout: record.payout.unwrap_or(record.developer.clone()),
+            developer: record.developer,
+            paused: record.paused,
+            rate_card,
+        }
+    }
+
+    /// Keeps the agent record, its usable rate cards, the manifests from the oldest usable
+    /// card's onwards, and its developer and tag listing entries from being archived.
+    /// Anyone may call it.
+    pub fn bump_agent_ttl(e: Env, agent_id: u32) {
+        let record = read_agent_or_panic(&e, agent_id);
+        extend_persistent_ttl(&e, &DataKey::Agent(agent_id));
+        extend_indexed_agent_ttl(
+            &e,
+            AgentIndex::Developer(record.developer.clone()),
+            agent_id,
+        );
+        for tag in record.tags.iter() {
+            extend_indexed_agent_ttl(&e, AgentIndex::Tag(tag), agent_id);
+        }
+        for version in record.min_rate_version..=record.latest_rate_version {
+            extend_persistent_ttl(&e, &DataKey::RateCard(agent_id, version));
+            extend_persistent_ttl(&e, &DataKey::Audits(agent_id, version));
+        }
+        let oldest_manifest = read_rate_card(&e, agent_id, record.min_rate_version)
+            .map_or(1, |card| card.manifest_version);
+        for version in oldest_manifest..=record.latest_manifest_version {
+            extend_persistent_ttl(&e, &DataKey::Manifest(agent_id, version));
+        }
+        e.storage()
+            .instance()
+            .extend_ttl(TTL_THRESHOLD, TTL_EXTEND_TO);
+    }
+
+    pub fn get_manifest(e: Env, agent_id: u32, version: u32) -> BytesN<32> {
+        match read_manifest(&e, agent_id, version) {
+            Some(hash) => {
+                extend_persistent_ttl(&e, &DataKey::Manifest(agent_id, version));
+                hash
+            }
+            None => panic_with_error!(&e, AgentRegistryError::ManifestNotFound),
+        }
+    }
+
+    /// Hash of the manifest rate card `version` was published against. Rate cards only
+    /// carry the manifest version; this resolves it for readers of the old card field.
+    pub fn rate_card_manifest_hash(e: Env, agent_id: u32, version: u32) -> BytesN<32> {
+        let card = Self::get_rate_card(e.clone(), agent_id, version);
+        Self::get_manifest(e, agent_id, card.manifest_version)
+    }
+
+    /// Audits attached to rate card `version`, oldest first.
+    pub fn audits_of(e: Env, agent_id: u32, version: u32) -> Vec<AuditRecord> {
+        read_audits(&e, agent_id, version)
+    }
+
+    pub fn latest_manifest_version(e: Env, agent_id: u32) -> u32 {
+        let record = read_agent_or_panic(&e, agent_id);
+        record.latest_manifest_version
+    }
+
+    /// Whether `hash` matches the manifest of rate card `version`. Unknown agents or
+    /// versions yield `false`, so runners and clients can call this speculatively.
+    pub fn verify_manifest(e: Env, agent_id: u32, version: u32, hash: BytesN<32>) -> bool {
+        match read_rate_card(&e, agent_id, version) {
+            Some(card) => read_manifest(&e, agent_id, card.manifest_version) == Some(hash),
+            None => false,
+        }
+    }
+
+    /// Asset the prices of rate card `version` are denominated in.
+    pub fn settlement_token(e: Env, agent_id: u32, version: u32) -> Address {
+        Self::get_rate_card(e, agent_id, version).token
+    }
+
+    /// Whether new runs may be opened against `version`: it exists, is not below the
+    /// minimum accepted version, is already effective and has not expired.
+    pub fn is_rate_card_usable(e: Env, agent_id: u32, version: u32) -> bool {
+        let record = match read_agent(&e, agent_id) {
+            Some(record) => record,
+            None => return false,
+        };
+        if version < record.min_rate_version {
+            return false;
+        }
+        let now = e.ledger().timestamp();
+        match read_rate_card(&e, agent_id, version) {
+            Some(card) => card.is_effective(now) && !card.is_expired(now),
+            None => false,
+        }
+    }
+
+    /// Latest rate card version already in effect. Cards scheduled for the future are
+    /// skipped until their `effective_from` time passes.
     pub fn latest_rate_version(e: Env, agent_id: u32) -> u32 {
         let record = read_agent_or_panic(&e, agent_id);
-        record.latest_rate_version
+        effective_rate_version(&e, agent_id, &record)
+    }
+
+    pub fn min_rate_version(e: Env, agent_id: u32) -> u32 {
+        let record = read_agent_or_panic(&e, agent_id);
+        record.min_rate_version
     }
 
     pub fn is_runner(e: Env, agent_id: u32, runner: Address) -> bool {
         let record = read_agent_or_panic(&e, agent_id);
-        contains_address(&record.runners, &runner)
+        !record.delisted && contains_address(&record.runners, &runner)
+    }
+
+    /// Batch form of `is_runner`, answering in input order. Unknown agents yield `false`.
+    pub fn is_runner_for(e: Env, runner: Address, agent_ids: Vec<u32>) -> Vec<bool> {
+        let mut results = Vec::new(&e);
+        for agent_id in agent_ids.iter() {
+            let listed = match read_agent(&e, agent_id) {
+                Some(record) => !record.delisted && contains_address(&record.runners, &runner),
+                None => false,
+            };
+            results.push_back(listed);
+        }
+        results
+    }
+
+    pub fn max_runners(_e: Env) -> u32 {
+        MAX_RUNNERS
+    }
+
+    pub fn is_delisted(e: Env, agent_id: u32) -> bool {
+        let record = read_agent_or_panic(&e, agent_id);
+        record.delisted
+    }
+
+    pub fn admin(e: Env) -> Address {
+        read_admin(&e)
+    }
+
+    /// Whether the agent accepts new runs: set active by its developer and not delisted.
+    pub fn is_active(e: Env, agent_id: u32) -> bool {
+        let record = read_agent_or_panic(&e, agent_id);
+        record.active && !record.delisted
+    }
+
+    pub fn is_verified(e: Env, agent_id: u32) -> bool {
+        read_agent_or_panic(&e, agent_id).verified
+    }
+
+    pub fn is_paused(e: Env, agent_id: u32) -> bool {
+        let record = read_agent_or_panic(&e, agent_id);
+        record.paused
     }
 
     pub fn developer_of(e: Env, agent_id: u32) -> Address {
         let record = read_agent_or_panic(&e, agent_id);
         record.developer
     }
+
+    pub fn agents_of(e: Env, developer: Address, offset: u32, limit: u32) -> Vec<u32> {
+        read_indexed_agents(&e, AgentIndex::Developer(developer), offset, limit)
+    }
+
+    pub fn agent_count_of(e: Env, developer: Address) -> u32 {
+        read_indexed_agent_count(&e, &AgentIndex::Developer(developer))
+    }
+
+    pub fn agents_with_tag(e: Env, tag: Symbol, offset: u32, limit: u32) -> Vec<u32> {
+        read_indexed_agents(&e, AgentIndex::Tag(tag), offset, limit)
+    }
+
+    /// The agent's revenue split, or an empty list when proceeds go to the payout address.
+    pub fn revenue_split_of(e: Env, agent_id: u32) -> Vec<(Address, u32)> {
+        read_agent_or_panic(&e, agent_id);
+        e.storage()
+            .instance()
+            .get(&DataKey::RevenueSplit(agent_id))
+            .unwrap_or_else(|| Vec::new(&e))
+    }
+
+    pub fn payout_address_of(e: Env, agent_id: u32) -> Address {
+        let record = read_agent_or_panic(&e, agent_id);
+        match record.payout {
+            Some(payout) => payout,
+            None => record.developer,
+        }
+    }
+}
+
+fn read_admin(e: &Env) -> Address {
+    match e.storage().instance().get::<_, Address>(&DataKey::Admin) {
+        Some(admin) => admin,
+        None => panic_with_error!(e, AgentRegistryError::NotInitialized),
+    }
+}
+
+fn require_admin(e: &Env) -> Address {
+    let admin = read_admin(e);
+    admin.require_auth();
+    admin
+}
+
+fn read_next_agent_id(e: &Env) -> u32 {
+    e.storage()
+        .instance()
+        .get::<_, u32>(&DataKey::NextAgentId)
+        .unwrap_or(1)
 }
 
 fn next_agent_id_and_increment(e: &Env) -> u32 {
-    let current = match e.storage().instance().get::<_, u32>(&DataKey::NextAgentId) {
-        Some(id) => id,
-        None => 1,
-    };
+    let current = read_next_agent_id(e);
     let next = current.checked_add(1).unwrap();
     e.storage().instance().set(&DataKey::NextAgentId, &next);
     current
 }
 
-fn read_agent_or_panic(e: &Env, agent_id: u32) -> AgentRecord {
-    match e
-        .storage()
-        .instance()
+fn read_agent(e: &Env, agent_id: u32) -> Option<AgentRecord> {
+    e.storage()
+        .persistent()
         .get::<_, AgentRecord>(&DataKey::Agent(agent_id))
-    {
+}
+
+fn write_agent(e: &Env, agent_id: u32, record: &AgentRecord) {
+    let key = DataKey::Agent(agent_id);
+    e.storage().persistent().set(&key, record);
+    e.storage()
+        .persistent()
+        .extend_ttl(&key, TTL_THRESHOLD, TTL_EXTEND_TO);
+}
+
+fn read_agent_or_panic(e: &Env, agent_id: u32) -> AgentRecord {
+    match read_agent(e, agent_id) {
         Some(record) => record,
         None => panic_with_error!(e, AgentRegistryError::AgentNotFound),
     }
 }
 
+fn to_details(agent_id: u32, record: AgentRecord) -> AgentDetails {
+    AgentDetails {
+        agent_id,
+        developer: record.developer,
+        metadata_uri: record.metadata_uri,
+        runners: record.runners,
+        latest_rate_version: record.latest_rate_version,
+        min_rate_version: record.min_rate_version,
+        latest_manifest_version: record.latest_manifest_version,
+        active: record.active,
+        paused: record.paused,
+        delisted: record.delisted,
+        tags: record.tags,
+        verified: record.verified,
+    }
+}
+
+fn set_paused(e: &Env, agent_id: u32, paused: bool) {
+    let mut record = read_agent_or_panic(e, agent_id);
+    record.developer.require_auth();
+    record.paused = paused;
+    write_agent(e, agent_id, &record);
+
+    let action = if paused {
+        symbol_short!("paused")
+    } else {
+        symbol_short!("unpaused")
+    };
+    e.events().publish(
+        (symbol_short!("agent"), action),
+        AgentPauseLog {
+            agent_id,
+            developer: record.developer,
+            timestamp: e.ledger().timestamp(),
+        },
+    );
+}
+
+fn drop_runner(e: &Env, agent_id: u32, mut record: AgentRecord, runner: Address) {
+    let mut filtered = Vec::new(e);
+    for existing in record.runners.iter() {
+        if existing != runner {
+            filtered.push_back(existing);
+        }
+    }
+
+    if filtered.len() == 0 {
+        panic_with_error!(e, AgentRegistryError::InvalidRunnerList);
+    }
+
+    if filtered.len() == record.runners.len() {
+        panic_with_error!(e, AgentRegistryError::RunnerNotFound);
+    }
+
+    record.runners = filtered;
+    write_agent(e, agent_id, &record);
+    e.storage()
+        .instance()
+        .remove(&DataKey::RunnerMetadata(agent_id, runner.clone()));
+
+    e.events().publish(
+        (symbol_short!("runner"), symbol_short!("removed")),
+        RunnerChangedLog {
+            agent_id,
+            runner,
+            timestamp: e.ledger().timestamp(),
+        },
+    );
+}
+
+fn read_pending_transfer(e: &Env, agent_id: u32) -> Option<Address> {
+    e.storage()
+        .instance()
+        .get::<_, Address>(&DataKey::PendingTransfer(agent_id))
+}
+
+fn transfer_ownership(e: &Env, agent_id: u32, mut record: AgentRecord, new_developer: Address) {
+    let old_developer = record.developer.clone();
+    record.developer = new_developer.clone();
+    record.payout = None;
+    write_agent(e, agent_id, &record);
+    e.storage()
+        .instance()
+        .remove(&DataKey::RevenueSplit(agent_id));
+
+    unindex_agent(e, AgentIndex::Developer(old_developer.clone()), agent_id);
+    index_agent(e, AgentIndex::Developer(new_developer.clone()), agent_id);
+
+    e.events().publish(
+        (symbol_short!("agent"), symbol_short!("transfer")),
+        AgentTransferredLog {
+            agent_id,
+            old_developer,
+            new_developer,
+            timestamp: e.ledger().timestamp(),
+        },
+    );
+}
+
+fn read_indexed_agent_count(e: &Env, index: &AgentIndex) -> u32 {
+    e.storage()
+        .persistent()
+        .get::<_, u32>(&DataKey::IndexedAgentCount(index.clone()))
+        .unwrap_or(0)
+}
+
+fn write_persistent<V: IntoVal<Env, Val>>(e: &Env, key: &DataKey, value: &V) {
+    e.storage().persistent().set(key, value);
+    e.storage()
+        .persistent()
+        .extend_ttl(key, TTL_THRESHOLD, TTL_EXTEND_TO);
+}
+
+fn read_indexed_agents(e: &Env, index: AgentIndex, offset: u32, limit: u32) -> Vec<u32> {
+    let end =
+        read_indexed_agent_count(e, &index).min(offset.saturating_add(limit.min(MAX_PAGE_SIZE)));
+    let mut ids = Vec::new(e);
+    for position in offset..end {
+        let key = DataKey::IndexedAgent(index.clone(), position);
+        if let Some(agent_id) = e.storage().persistent().get::<_, u32>(&key) {
+            ids.push_back(agent_id);
+        }
+    }
+    ids
+}
+
+fn index_agent(e: &Env, index: AgentIndex, agent_id: u32) {
+    let position = read_indexed_agent_count(e, &index);
+    write_persistent(
+        e,
+        &DataKey::IndexedAgent(index.clone(), position),
+        &agent_id,
+    );
+    write_persistent(
+        e,
+        &DataKey::IndexedAgentPosition(index.clone(), agent_id),
+        &position,
+    );
+    write_persistent(e, &DataKey::IndexedAgentCount(index), &(position + 1));
+}
+
+/// Removes `agent_id` from the listing by moving the last entry into its place.
+fn unindex_agent(e: &Env, index: AgentIndex, agent_id: u32) {
+    let storage = e.storage().persistent();
+    let position_key = DataKey::IndexedAgentPosition(index.clone(), agent_id);
+    let Some(position) = storage.get::<_, u32>(&position_key) else {
+        return;
+    };
+    storage.remove(&position_key);
+    let last = read_indexed_agent_count(e, &index) - 1;
+    let last_key = DataKey::IndexedAgent(index.clone(), last);
+    if position != last {
+        let moved: u32 = storage.get(&last_key).unwrap();
+        write_persistent(e, &DataKey::IndexedAgent(index.clone(), position), &moved);
+        write_persistent(
+            e,
+            &DataKey::IndexedAgentPosition(index.clone(), moved),
+            &position,
+        );
+    }
+    storage.remove(&last_key);
+    let count_key = DataKey::IndexedAgentCount(index);
+    if last == 0 {
+        storage.remove(&count_key);
+    } else {
+        write_persistent(e, &count_key, &last);
+    }
+}
+
+/// Keeps the agent's entry in a listing, and the listing's count, from being archived.
+fn extend_indexed_agent_ttl(e: &Env, index: AgentIndex, agent_id: u32) {
+    let position_key = DataKey::IndexedAgentPosition(index.clone(), agent_id);
+    if let Some(position) = e.storage().persistent().get::<_, u32>(&position_key) {
+        extend_persistent_ttl(e, &position_key);
+        extend_persistent_ttl(e, &DataKey::IndexedAgent(index.clone(), position));
+        extend_persistent_ttl(e, &DataKey::IndexedAgentCount(index));
+    }
+}
+
+fn rate_card_is_effective(rate_card: &RateCardInput, now: u64) -> bool {
+    rate_card
+        .effective_from
+        .is_none_or(|effective_from| effective_from <= now)
+}
+
+/// Newest rate card version in effect. `publish_rate_card` keeps at most
+/// `MAX_SCHEDULED_RATE_CARDS` pending cards above it, so the walk stops there; the
+/// initial card must be effective at registration, so the floor always is.
+fn effective_rate_version(e: &Env, agent_id: u32, record: &AgentRecord) -> u32 {
+    let now = e.ledger().timestamp();
+    let floor = record
+        .latest_rate_version
+        .saturating_sub(MAX_SCHEDULED_RATE_CARDS)
+        .max(1);
+    let mut version = record.latest_rate_version;
+    while version > floor {
+        if let Some(card) = read_rate_card(e, agent_id, version) {
+            if card.is_effective(now) {
+                return version;
+            }
+        }
+        version -= 1;
+    }
+    floor
+}
+
+fn read_audits(e: &Env, agent_id: u32, version: u32) -> Vec<AuditRecord> {
+    e.storage()
+        .persistent()
+        .get::<_, Vec<AuditRecord>>(&DataKey::Audits(agent_id, version))
+        .unwrap_or_else(|| Vec::new(e))
+}
+
+fn write_audits(e: &Env, agent_id: u32, version: u32, audits: &Vec<AuditRecord>) {
+    let key = DataKey::Audits(agent_id, version);
+    e.storage().persistent().set(&key, audits);
+    e.storage()
+        .persistent()
+        .extend_ttl(&key, TTL_THRESHOLD, TTL_EXTEND_TO);
+}
+
+fn is_auditor(e: &Env, auditor: &Address) -> bool {
+    e.storage()
+        .instance()
+        .get::<_, bool>(&DataKey::Auditor(auditor.clone()))
+        .unwrap_or(false)
+}
+
+fn read_manifest(e: &Env, agent_id: u32, version: u32) -> Option<BytesN<32>> {
+    e.storage()
+        .persistent()
+        .get::<_, BytesN<32>>(&DataKey::Manifest(agent_id, version))
+}
+
+fn write_manifest(e: &Env, agent_id: u32, version: u32, manifest_hash: &BytesN<32>) {
+    write_persistent(e, &DataKey::Manifest(agent_id, version), manifest_hash);
+}
+
+/// Stores `manifest_hash` as the agent's next manifest version. The caller persists
+/// `record`.
+fn append_manifest(
+    e: &Env,
+    agent_id: u32,
+    record: &mut AgentRecord,
+    manifest_hash: BytesN<32>,
+) -> u32 {
+    let version = record.latest_manifest_version + 1;
+    write_manifest(e, agent_id, version, &manifest_hash);
+    record.latest_manifest_version = version;
+
+    e.events().publish(
+        (symbol_short!("manifest"), symbol_short!("published")),
+        ManifestPublishedLog {
+            agent_id,
+            version,
+            manifest_hash,
+            timestamp: e.ledger().timestamp(),
+        },
+    );
+    version
+}
+
+/// Manifest version a new rate card refers to: the latest one when its hash matches,
+/// otherwise a freshly published version.
+fn resolve_manifest_version(
+    e: &Env,
+    agent_id: u32,
+    record: &mut AgentRecord,
+    rate_card: &RateCardInput,
+) -> u32 {
+    let latest = record.latest_manifest_version;
+    if read_manifest(e, agent_id, latest).as_ref() == Some(&rate_card.manifest_hash) {
+        return latest;
+    }
+    append_manifest(e, agent_id, record, rate_card.manifest_hash.clone())
+}
+
+fn read_rate_card(e: &Env, agent_id: u32, version: u32) -> Option<RateCard> {
+    e.storage()
+        .persistent()
+        .get::<_, RateCard>(&DataKey::RateCard(agent_id, version))
+}
+
 fn write_rate_card(e: &Env, agent_id: u32, version: u32, rate_card: &RateCard) {
+    let key = DataKey::RateCard(agent_id, version);
+    e.storage().persistent().set(&key, rate_card);
+    e.storage()
+        .persistent()
+        .extend_ttl(&key, TTL_THRESHOLD, TTL_EXTEND_TO);
+}
+
+fn read_attributes(e: &Env, agent_id: u32) -> Map<Symbol, String> {
+    e.storage()
+        .persistent()
+        .get::<_, Map<Symbol, String>>(&DataKey::Attributes(agent_id))
+        .unwrap_or_else(|| Map::new(e))
+}
+
+fn write_attributes(e: &Env, agent_id: u32, attributes: &Map<Symbol, String>) {
+    let key = DataKey::Attributes(agent_id);
+    if attributes.is_empty() {
+        e.storage().persistent().remove(&key);
+        return;
+    }
+    e.storage().persistent().set(&key, attributes);
+    e.storage()
+        .persistent()
+        .extend_ttl(&key, TTL_THRESHOLD, TTL_EXTEND_TO);
+}
+
+fn read_latest_plan_id(e: &Env, agent_id: u32) -> u32 {
     e.storage()
         .instance()
-        .set(&DataKey::RateCard(agent_id, version), rate_card);
+        .get::<_, u32>(&DataKey::LatestPlanId(agent_id))
+        .unwrap_or(0)
+}
+
+fn read_plan_or_panic(e: &Env, agent_id: u32, plan_id: u32) -> AgentPlan {
+    match e
+        .storage()
+        .persistent()
+        .get::<_, AgentPlan>(&DataKey::Plan(agent_id, plan_id))
+    {
+        Some(plan) => plan,
+        None => panic_with_error!(e, AgentRegistryError::PlanNotFound),
+    }
+}
+
+fn write_plan(e: &Env, agent_id: u32, plan_id: u32, plan: &AgentPlan) {
+    let key = DataKey::Plan(agent_id, plan_id);
+    e.storage().persistent().set(&key, plan);
+    e.storage()
+        .persistent()
+        .extend_ttl(&key, TTL_THRESHOLD, TTL_EXTEND_TO);
+}
+
+fn extend_persistent_ttl(e: &Env, key: &DataKey) {
+    if e.storage().persistent().has(key) {
+        e.storage()
+            .persistent()
+            .extend_ttl(key, TTL_THRESHOLD, TTL_EXTEND_TO);
+    }
+}
+
+fn read_runner_metadata(e: &Env, agent_id: u32, runner: &Address) -> Option<String> {
+    e.storage()
+        .instance()
+        .get::<_, String>(&DataKey::RunnerMetadata(agent_id, runner.clone()))
+}
+
+/// Drops duplicate runners, keeping first occurrences in order. Panics if none remain
+/// or if more than `MAX_RUNNERS` do.
+fn dedup_runners(e: &Env, runners: &Vec<Address>) -> Vec<Address> {
+    let mut normalized = Vec::new(e);
+    for runner in runners.iter() {
+        if !contains_address(&normalized, &runner) {
+            normalized.push_back(runner);
+        }
+    }
+    if normalized.is_empty() {
+        panic_with_error!(e, AgentRegistryError::InvalidRunnerList);
+    }
+    if normalized.len() > MAX_RUNNERS {
+        panic_with_error!(e, AgentRegistryError::TooManyRunners);
+    }
+    normalized
 }
 
 fn contains_address(vec: &Vec<Address>, addr: &Address) -> bool {
@@ -216,3 +1365,12 @@ fn contains_address(vec: &Vec<Address>, addr: &Address) -> bool {
     }
     false
 }
+
+/// Whether `token` is a deployed contract that can hold balances: a Stellar asset
+/// contract or a Wasm token.
+fn is_token_contract(token: &Address) -> bool {
+    matches!(
+        token.executable(),
+        Some(Executable::StellarAsset) | Some(Executable::Wasm(_))
+    )
+}
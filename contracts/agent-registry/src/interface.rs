@@ -1,12 +1,15 @@
-use soroban_sdk::{contractclient, Address, Env, String, Vec};
+use soroban_sdk::{contractclient, Address, BytesN, Env, Map, String, Symbol, Vec};
 
-use crate::types::{AgentDetails, RateCard, RateCardInput};
+use crate::types::{
+    AgentDetails, AgentPlan, AuditRecord, PlanInput, RateCard, RateCardInput, RunnerInfo,
+    SettlementInfo,
+};
 
 /// Client-only interface for invoking the AgentRegistry contract.
 #[allow(dead_code)]
 #[contractclient(name = "AgentRegistryClient")]
 pub trait AgentRegistryInterface {
-    fn init(env: Env);
+    fn init(env: Env, admin: Address);
 
     fn register_agent(
         env: Env,
@@ -18,19 +21,130 @@ pub trait AgentRegistryInterface {
 
     fn set_metadata_uri(env: Env, agent_id: u32, metadata_uri: Option<String>);
 
+    fn set_payout_address(env: Env, agent_id: u32, payout: Address);
+
+    fn set_agent_active(env: Env, agent_id: u32, active: bool);
+
+    fn pause_agent(env: Env, agent_id: u32);
+
+    fn unpause_agent(env: Env, agent_id: u32);
+
+    fn propose_transfer(env: Env, agent_id: u32, new_developer: Address);
+
+    fn accept_transfer(env: Env, agent_id: u32);
+
+    fn cancel_transfer(env: Env, agent_id: u32);
+
+    fn pending_transfer(env: Env, agent_id: u32) -> Option<Address>;
+
+    fn delist_agent(env: Env, agent_id: u32, reason_hash: BytesN<32>);
+    fn set_verified(env: Env, agent_id: u32, verified: bool);
+
+    fn add_auditor(env: Env, auditor: Address);
+
+    fn remove_auditor(env: Env, auditor: Address);
+
+    fn is_auditor(env: Env, auditor: Address) -> bool;
+
+    fn attach_audit(
+        env: Env,
+        agent_id: u32,
+        rate_version: u32,
+        auditor: Address,
+        report_hash: BytesN<32>,
+    );
+
     fn add_runner(env: Env, agent_id: u32, runner: Address);
 
     fn remove_runner(env: Env, agent_id: u32, runner: Address);
 
+    fn resign_runner(env: Env, agent_id: u32, runner: Address);
+
+    fn set_runners(env: Env, agent_id: u32, runners: Vec<Address>);
+
+    fn set_runner_metadata(env: Env, agent_id: u32, runner: Address, uri: Option<String>);
+
     fn publish_rate_card(env: Env, agent_id: u32, rate_card: RateCardInput) -> u32;
 
+    fn publish_plan(env: Env, agent_id: u32, plan: PlanInput) -> u32;
+
+    fn set_plan_active(env: Env, agent_id: u32, plan_id: u32, active: bool);
+
+    fn get_plan(env: Env, agent_id: u32, plan_id: u32) -> AgentPlan;
+
+    fn latest_plan_id(env: Env, agent_id: u32) -> u32;
+
+    fn publish_manifest(env: Env, agent_id: u32, manifest_hash: BytesN<32>) -> u32;
+
+    fn set_attribute(env: Env, agent_id: u32, key: Symbol, value: String);
+
+    fn remove_attribute(env: Env, agent_id: u32, key: Symbol);
+
+    fn get_attributes(env: Env, agent_id: u32) -> Map<Symbol, String>;
+
+    fn set_tags(env: Env, agent_id: u32, tags: Vec<Symbol>);
+
+    fn set_revenue_split(env: Env, agent_id: u32, splits: Vec<(Address, u32)>);
+
+    fn set_min_rate_version(env: Env, agent_id: u32, version: u32);
+
     fn get_agent(env: Env, agent_id: u32) -> AgentDetails;
 
+    fn runner_metadata(env: Env, agent_id: u32, runner: Address) -> Option<String>;
+
+    fn get_agent_runners(env: Env, agent_id: u32) -> Vec<RunnerInfo>;
+
+    fn total_agents(env: Env) -> u32;
+
+    fn list_agents(env: Env, offset: u32, limit: u32) -> Vec<AgentDetails>;
+
     fn get_rate_card(env: Env, agent_id: u32, version: u32) -> RateCard;
 
+    fn get_settlement_info(env: Env, agent_id: u32, version: u32) -> SettlementInfo;
+
+    fn bump_agent_ttl(env: Env, agent_id: u32);
+    fn get_manifest(env: Env, agent_id: u32, version: u32) -> BytesN<32>;
+
+    fn rate_card_manifest_hash(env: Env, agent_id: u32, version: u32) -> BytesN<32>;
+
+    fn audits_of(env: Env, agent_id: u32, version: u32) -> Vec<AuditRecord>;
+
+    fn latest_manifest_version(env: Env, agent_id: u32) -> u32;
+
+    fn verify_manifest(env: Env, agent_id: u32, version: u32, hash: BytesN<32>) -> bool;
+
+    fn settlement_token(env: Env, agent_id: u32, version: u32) -> Address;
+
+    fn is_rate_card_usable(env: Env, agent_id: u32, version: u32) -> bool;
+
     fn latest_rate_version(env: Env, agent_id: u32) -> u32;
 
+    fn min_rate_version(env: Env, agent_id: u32) -> u32;
+
     fn is_runner(env: Env, agent_id: u32, runner: Address) -> bool;
 
+    fn is_runner_for(env: Env, runner: Address, agent_ids: Vec<u32>) -> Vec<bool>;
+
+    fn max_runners(env: Env) -> u32;
+
+    fn is_delisted(env: Env, agent_id: u32) -> bool;
+
+    fn admin(env: Env) -> Address;
+
+    fn is_active(env: Env, agent_id: u32) -> bool;
+
+    fn is_verified(env: Env, agent_id: u32) -> bool;
+    fn is_paused(env: Env, agent_id: u32) -> bool;
+
     fn developer_of(env: Env, agent_id: u32) -> Address;
+
+    fn agents_of(env: Env, developer: Address, offset: u32, limit: u32) -> Vec<u32>;
+
+    fn agent_count_of(env: Env, developer: Address) -> u32;
+
+    fn agents_with_tag(env: Env, tag: Symbol, offset: u32, limit: u32) -> Vec<u32>;
+
+    fn revenue_split_of(env: Env, agent_id: u32) -> Vec<(Address, u32)>;
+
+    fn payout_address_of(env: Env, agent_id: u32) -> Address;
 }
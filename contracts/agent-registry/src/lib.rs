@@ -20,7 +20,13 @@ pub use contract::AgentRegistryClient;
 #[cfg(feature = "interface")]
 pub use interface::AgentRegistryClient;
 
-pub use types::{AgentDetails, RateCard, RateCardInput, UsageMeterRates};
+pub use types::{
+    AgentDelistedLog, AgentDetails, AgentPauseLog, AgentPlan, AgentRegisteredLog,
+    AgentTransferredLog, AgentVerifiedLog, AttributeChangedLog, AuditAttachedLog, AuditRecord,
+    ManifestPublishedLog, MetadataUpdatedLog, PlanInput, PlanPublishedLog, PlanStatusLog, RateCard,
+    RateCardInput, RateCardPublishedLog, RunnerChangedLog, RunnerInfo, SettlementInfo,
+    SettlementRateCard, SubscriptionOffer, SubscriptionPlan, UsageMeterRates,
+};
 
 #[cfg(test)]
 mod test;
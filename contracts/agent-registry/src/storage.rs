@@ -1,11 +1,36 @@
-use soroban_sdk::{contracttype, Address, String, Vec};
+use soroban_sdk::{contracttype, Address, String, Symbol, Vec};
 
 #[derive(Clone)]
 #[contracttype]
 pub enum DataKey {
+    Admin,
     NextAgentId,
     Agent(u32),
     RateCard(u32, u32),
+    PendingTransfer(u32),
+    DelistedCount,
+    RunnerMetadata(u32, Address),
+    Manifest(u32, u32),
+    RevenueSplit(u32),
+    Auditor(Address),
+    Audits(u32, u32),
+    Plan(u32, u32),
+    LatestPlanId(u32),
+    Attributes(u32),
+    IndexedAgent(AgentIndex, u32),
+    IndexedAgentCount(AgentIndex),
+    IndexedAgentPosition(AgentIndex, u32),
+}
+
+/// An agent listing kept in persistent storage, one entry per agent. Removing an agent
+/// moves the listing's last entry into its place.
+#[derive(Clone)]
+#[contracttype]
+pub enum AgentIndex {
+    /// Agents owned by the developer, for `agents_of`.
+    Developer(Address),
+    /// Agents carrying the tag, for `agents_with_tag`.
+    Tag(Symbol),
 }
 
 #[derive(Clone)]
@@ -15,4 +40,13 @@ pub struct AgentRecord {
     pub metadata_uri: Option<String>,
     pub runners: Vec<Address>,
     pub latest_rate_version: u32,
+    pub min_rate_version: u32,
+    pub latest_manifest_version: u32,
+    pub payout: Option<Address>,
+    pub active: bool,
+    pub paused: bool,
+    pub delisted: bool,
+    pub tags: Vec<Symbol>,
+    /// Admin-granted badge for audited, first-party agents.
+    pub verified: bool,
 }
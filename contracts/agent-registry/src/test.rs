@@ -1,9 +1,20 @@
 extern crate std;
 
-use soroban_sdk::{testutils::Address as _, Address, BytesN, Env, String, Vec};
+use soroban_sdk::{
+    symbol_short,
+    testutils::{storage::Persistent as _, Address as _, Events, Ledger},
+    vec, Address, BytesN, Env, IntoVal, Map, String, Symbol, Vec,
+};
 
 use crate::{
-    types::{RateCardInput, UsageMeterRates},
+    contract::AgentRegistryError,
+    storage::{AgentIndex, DataKey},
+    types::{
+        AgentDelistedLog, AgentPauseLog, AgentRegisteredLog, AgentTransferredLog, AgentVerifiedLog,
+        AttributeChangedLog, AuditAttachedLog, ManifestPublishedLog, MetadataUpdatedLog, PlanInput,
+        PlanStatusLog, RateCardInput, RateCardPublishedLog, RunnerChangedLog, SettlementRateCard,
+        SubscriptionOffer, UsageMeterRates,
+    },
     AgentRegistry, AgentRegistryClient,
 };
 
@@ -16,12 +27,30 @@ fn hash(e: &Env, byte: u8) -> BytesN<32> {
     BytesN::from_array(e, &[byte; 32])
 }
 
-fn sample_rates() -> UsageMeterRates {
+fn sample_rates(e: &Env) -> UsageMeterRates {
     UsageMeterRates {
         llm_in: 10_000_000,
         llm_out: 20_000_000,
         http_calls: 1_000_000,
         runtime_ms: 1000,
+        extra: Map::new(e),
+    }
+}
+
+fn sample_rate_card(e: &Env) -> RateCardInput {
+    RateCardInput {
+        rates: sample_rates(e),
+        unit_scale: 1,
+        manifest_hash: hash(e, 1),
+        subscription: SubscriptionOffer::NotOffered,
+        effective_from: None,
+        expires_at: None,
+        free_daily_units: Map::new(e),
+        min_charge: 0,
+        base_fee: 0,
+        token: e
+            .register_stellar_asset_contract_v2(Address::generate(e))
+            .address(),
     }
 }
 
@@ -36,10 +65,7 @@ fn register_agent_sets_initial_state() {
     let client = register_contract(&e);
 
     e.mock_all_auths();
-    let rate_card = RateCardInput {
-        rates: sample_rates(),
-        manifest_hash: hash(&e, 1),
-    };
+    let rate_card = sample_rate_card(&e);
 
     let agent_id = client.register_agent(&developer, &metadata, &runners, &rate_card);
     assert_eq!(agent_id, 1);
@@ -49,10 +75,14 @@ fn register_agent_sets_initial_state() {
     assert_eq!(details.metadata_uri, metadata);
     assert_eq!(details.runners.len(), 1);
     assert_eq!(details.latest_rate_version, 1);
+    assert!(details.active);
 
     let stored_rate = client.get_rate_card(&agent_id, &1);
     assert_eq!(stored_rate.rates.llm_in, rate_card.rates.llm_in);
-    assert_eq!(stored_rate.manifest_hash, rate_card.manifest_hash);
+    assert_eq!(
+        client.rate_card_manifest_hash(&agent_id, &1),
+        rate_card.manifest_hash
+    );
     assert!(client.is_runner(&agent_id, &runner));
     assert_eq!(client.developer_of(&agent_id), developer);
 }
@@ -67,18 +97,16 @@ fn publish_rate_card_increments_version() {
     let client = register_contract(&e);
     e.mock_all_auths();
 
-    let base_rate = RateCardInput {
-        rates: sample_rates(),
-        manifest_hash: hash(&e, 1),
-    };
+    let base_rate = sample_rate_card(&e);
     let agent_id = client.register_agent(&developer, &None, &runners, &base_rate);
 
     let new_rate = RateCardInput {
         rates: UsageMeterRates {
             llm_in: 15_000_000,
-            ..sample_rates()
+            ..sample_rates(&e)
         },
         manifest_hash: hash(&e, 2),
+        ..sample_rate_card(&e)
     };
     let version = client.publish_rate_card(&agent_id, &new_rate);
     assert_eq!(version, 2);
@@ -100,11 +128,1290 @@ fn cannot_remove_last_runner() {
 
     let client = register_contract(&e);
     e.mock_all_auths();
+    let rate_card = sample_rate_card(&e);
+    let agent_id = client.register_agent(&developer, &None, &runners, &rate_card);
+
+    client.remove_runner(&agent_id, &runner);
+}
+
+#[test]
+fn payout_address_defaults_to_developer() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let payout = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let rate_card = sample_rate_card(&e);
+    let agent_id = client.register_agent(&developer, &None, &runners, &rate_card);
+    assert_eq!(client.payout_address_of(&agent_id), developer);
+
+    client.set_payout_address(&agent_id, &payout);
+    assert_eq!(client.payout_address_of(&agent_id), payout);
+    assert_eq!(client.developer_of(&agent_id), developer);
+}
+
+#[test]
+fn settlement_info_combines_payout_pause_and_rate_card() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let payout = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let agent_id = client.register_agent(&developer, &None, &runners, &sample_rate_card(&e));
+    client.set_payout_address(&agent_id, &payout);
+    client.pause_agent(&agent_id);
+
+    let info = client.get_settlement_info(&agent_id, &1);
+    assert_eq!(info.developer, developer);
+    assert_eq!(info.payout, payout);
+    assert!(info.paused);
+    match info.rate_card {
+        SettlementRateCard::Card(card) => assert_eq!(card.manifest_version, 1),
+        SettlementRateCard::NotRequested => panic!("card should be included"),
+    }
+    assert!(matches!(
+        client.get_settlement_info(&agent_id, &0).rate_card,
+        SettlementRateCard::NotRequested
+    ));
+    assert_eq!(
+        client.try_get_settlement_info(&agent_id, &2).err(),
+        Some(Ok(AgentRegistryError::AgentNotFound.into()))
+    );
+}
+
+#[test]
+fn pause_agent_toggles_flag_and_emits_events() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let rate_card = sample_rate_card(&e);
+    let agent_id = client.register_agent(&developer, &None, &runners, &rate_card);
+    assert!(!client.is_paused(&agent_id));
+
+    client.pause_agent(&agent_id);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                client.address.clone(),
+                (symbol_short!("agent"), symbol_short!("paused")).into_val(&e),
+                AgentPauseLog {
+                    agent_id,
+                    developer: developer.clone(),
+                    timestamp: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    assert!(client.is_paused(&agent_id));
+    assert!(client.get_agent(&agent_id).paused);
+
+    client.unpause_agent(&agent_id);
+    assert!(!client.is_paused(&agent_id));
+}
+
+#[test]
+fn accepted_transfer_moves_ownership_and_keeps_history() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let new_developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(runner.clone());
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let rate_card = sample_rate_card(&e);
+    let agent_id = client.register_agent(&developer, &None, &runners, &rate_card);
+    client.set_payout_address(&agent_id, &Address::generate(&e));
+
+    client.propose_transfer(&agent_id, &Address::generate(&e));
+    client.propose_transfer(&agent_id, &new_developer);
+    assert_eq!(
+        client.pending_transfer(&agent_id),
+        Some(new_developer.clone())
+    );
+    assert_eq!(client.developer_of(&agent_id), developer);
+
+    client.accept_transfer(&agent_id);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                client.address.clone(),
+                (symbol_short!("agent"), symbol_short!("transfer")).into_val(&e),
+                AgentTransferredLog {
+                    agent_id,
+                    old_developer: developer.clone(),
+                    new_developer: new_developer.clone(),
+                    timestamp: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+
+    assert_eq!(client.developer_of(&agent_id), new_developer);
+    assert_eq!(client.pending_transfer(&agent_id), None);
+    assert_eq!(client.payout_address_of(&agent_id), new_developer);
+    assert!(client.is_runner(&agent_id, &runner));
+    assert_eq!(client.rate_card_manifest_hash(&agent_id, &1), hash(&e, 1));
+}
+
+#[test]
+fn cancelled_transfer_cannot_be_accepted() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let new_developer = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let rate_card = sample_rate_card(&e);
+    let agent_id = client.register_agent(&developer, &None, &runners, &rate_card);
+
+    client.propose_transfer(&agent_id, &new_developer);
+    client.cancel_transfer(&agent_id);
+    assert_eq!(client.pending_transfer(&agent_id), None);
+    assert_eq!(
+        client.try_accept_transfer(&agent_id).err(),
+        Some(Ok(AgentRegistryError::NoPendingTransfer.into()))
+    );
+    assert_eq!(client.developer_of(&agent_id), developer);
+}
+
+#[test]
+fn admin_sets_the_verified_badge() {
+    let e = Env::default();
+    let admin = Address::generate(&e);
+    let developer = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+    client.init(&admin);
+
+    let agent_id = client.register_agent(&developer, &None, &runners, &sample_rate_card(&e));
+    assert!(!client.is_verified(&agent_id));
+    assert!(!client.get_agent(&agent_id).verified);
+
+    client.set_verified(&agent_id, &true);
+    assert_eq!(e.auths()[0].0, admin);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                client.address.clone(),
+                (symbol_short!("agent"), symbol_short!("verified")).into_val(&e),
+                AgentVerifiedLog {
+                    agent_id,
+                    verified: true,
+                    timestamp: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    assert!(client.get_agent(&agent_id).verified);
+
+    client.set_verified(&agent_id, &true);
+    assert_eq!(e.events().all(), vec![&e]);
+    client.set_verified(&agent_id, &false);
+    assert!(!client.is_verified(&agent_id));
+}
+
+#[test]
+fn admin_delists_agent_and_keeps_rate_history() {
+    let e = Env::default();
+    let admin = Address::generate(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(runner.clone());
+    let client = register_contract(&e);
+    e.mock_all_auths();
+    client.init(&admin);
+
+    let rate_card = sample_rate_card(&e);
+    let agent_id = client.register_agent(&developer, &None, &runners, &rate_card);
+    assert!(client.is_runner(&agent_id, &runner));
+
+    client.delist_agent(&agent_id, &hash(&e, 7));
+    assert_eq!(
+        e.auths()[0].0,
+        admin,
+        "delisting must be authorized by the registry admin"
+    );
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                client.address.clone(),
+                (symbol_short!("agent"), symbol_short!("delisted")).into_val(&e),
+                AgentDelistedLog {
+                    agent_id,
+                    reason_hash: hash(&e, 7),
+                    timestamp: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+
+    assert!(client.is_delisted(&agent_id));
+    assert!(!client.is_active(&agent_id));
+    assert!(!client.is_runner(&agent_id, &runner));
+    assert_eq!(client.rate_card_manifest_hash(&agent_id, &1), hash(&e, 1));
+}
+
+#[test]
+fn developer_agent_index_follows_transfers() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let new_developer = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let rate_card = sample_rate_card(&e);
+    let first = client.register_agent(&developer, &None, &runners, &rate_card);
+    let second = client.register_agent(&developer, &None, &runners, &rate_card);
+    let third = client.register_agent(&developer, &None, &runners, &rate_card);
+
+    assert_eq!(client.agent_count_of(&developer), 3);
+    assert_eq!(
+        client.agents_of(&developer, &0, &10),
+        vec![&e, first, second, third]
+    );
+    assert_eq!(client.agents_of(&developer, &1, &1), vec![&e, second]);
+    assert_eq!(client.agents_of(&developer, &5, &10), Vec::new(&e));
+
+    client.propose_transfer(&second, &new_developer);
+    client.accept_transfer(&second);
+
+    assert_eq!(
+        client.agents_of(&developer, &0, &10),
+        vec![&e, first, third]
+    );
+    assert_eq!(client.agents_of(&new_developer, &0, &10), vec![&e, second]);
+    assert_eq!(client.agent_count_of(&new_developer), 1);
+
+    client.propose_transfer(&first, &new_developer);
+    client.accept_transfer(&first);
+    assert_eq!(client.agents_of(&developer, &0, &10), vec![&e, third]);
+    assert_eq!(client.agent_count_of(&developer), 1);
+    assert_eq!(
+        client.agents_of(&new_developer, &0, &10),
+        vec![&e, second, first]
+    );
+}
+
+#[test]
+fn list_agents_pages_and_skips_delisted() {
+    let e = Env::default();
+    let admin = Address::generate(&e);
+    let developer = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+    client.init(&admin);
+
+    let rate_card = sample_rate_card(&e);
+    for _ in 0..4 {
+        client.register_agent(&developer, &None, &runners, &rate_card);
+    }
+    assert_eq!(client.total_agents(), 4);
+
+    client.delist_agent(&2, &hash(&e, 9));
+    client.delist_agent(&2, &hash(&e, 9));
+    assert_eq!(client.total_agents(), 3);
+
+    let first_page = client.list_agents(&0, &2);
+    assert_eq!(first_page.len(), 2);
+    assert_eq!(first_page.get(0).unwrap().agent_id, 1);
+    assert_eq!(first_page.get(1).unwrap().agent_id, 3);
+
+    let last_id = first_page.get(1).unwrap().agent_id;
+    let second_page = client.list_agents(&last_id, &2);
+    assert_eq!(second_page.len(), 1);
+    assert_eq!(second_page.get(0).unwrap().agent_id, 4);
+
+    assert_eq!(client.list_agents(&10, &5).len(), 0);
+}
+
+#[test]
+fn min_rate_version_cannot_exceed_latest() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let rate_card = sample_rate_card(&e);
+    let agent_id = client.register_agent(&developer, &None, &runners, &rate_card);
+    assert_eq!(client.min_rate_version(&agent_id), 1);
+    assert_eq!(
+        client.try_set_min_rate_version(&agent_id, &2).err(),
+        Some(Ok(AgentRegistryError::InvalidRateVersion.into()))
+    );
+
+    client.publish_rate_card(&agent_id, &rate_card);
+    client.set_min_rate_version(&agent_id, &2);
+    assert_eq!(client.min_rate_version(&agent_id), 2);
+    assert_eq!(client.get_agent(&agent_id).min_rate_version, 2);
+}
+
+#[test]
+fn scheduled_rate_card_activates_at_effective_time() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+    e.ledger().with_mut(|li| li.timestamp = 1_000);
+
+    assert_eq!(
+        client
+            .try_register_agent(&developer, &None, &runners, &scheduled_at(&e, 5_000))
+            .err(),
+        Some(Ok(AgentRegistryError::InitialRateCardNotEffective.into()))
+    );
+
+    let rate_card = sample_rate_card(&e);
+    let agent_id = client.register_agent(&developer, &None, &runners, &rate_card);
+    assert_eq!(agent_id, 1);
+
+    let scheduled = RateCardInput {
+        effective_from: Some(5_000),
+        expires_at: None,
+        manifest_hash: hash(&e, 2),
+        ..rate_card.clone()
+    };
+    assert_eq!(client.publish_rate_card(&agent_id, &scheduled), 2);
+    assert_eq!(client.latest_rate_version(&agent_id), 1);
+    assert_eq!(
+        client.get_rate_card(&agent_id, &2).effective_from,
+        Some(5_000)
+    );
+
+    let backdated = RateCardInput {
+        effective_from: Some(500),
+        expires_at: None,
+        ..rate_card
+    };
+    assert_eq!(client.publish_rate_card(&agent_id, &backdated), 3);
+    assert_eq!(client.latest_rate_version(&agent_id), 3);
+
+    e.ledger().with_mut(|li| li.timestamp = 5_000);
+    assert_eq!(client.latest_rate_version(&agent_id), 3);
+
+    for version in 4..8 {
+        assert_eq!(
+            client.publish_rate_card(&agent_id, &scheduled_at(&e, 9_000)),
+            version
+        );
+    }
+    assert_eq!(
+        client
+            .try_publish_rate_card(&agent_id, &scheduled_at(&e, 9_000))
+            .err(),
+        Some(Ok(AgentRegistryError::TooManyScheduledRateCards.into()))
+    );
+    assert_eq!(client.latest_rate_version(&agent_id), 3);
+
+    e.ledger().with_mut(|li| li.timestamp = 9_000);
+    assert_eq!(client.latest_rate_version(&agent_id), 7);
+    assert_eq!(
+        client.publish_rate_card(&agent_id, &scheduled_at(&e, 10_000)),
+        8
+    );
+}
+
+fn scheduled_at(e: &Env, effective_from: u64) -> RateCardInput {
+    RateCardInput {
+        effective_from: Some(effective_from),
+        ..sample_rate_card(e)
+    }
+}
+
+#[test]
+fn rate_card_usability_combines_min_version_schedule_and_expiry() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+    e.ledger().with_mut(|li| li.timestamp = 1_000);
+
     let rate_card = RateCardInput {
-        rates: sample_rates(),
-        manifest_hash: hash(&e, 1),
+        expires_at: Some(3_000),
+        ..sample_rate_card(&e)
     };
     let agent_id = client.register_agent(&developer, &None, &runners, &rate_card);
+    let scheduled = RateCardInput {
+        effective_from: Some(2_000),
+        expires_at: None,
+        ..rate_card
+    };
+    client.publish_rate_card(&agent_id, &scheduled);
+
+    assert!(client.is_rate_card_usable(&agent_id, &1));
+    assert!(!client.is_rate_card_usable(&agent_id, &2));
+    assert!(!client.is_rate_card_usable(&agent_id, &3));
+    assert!(!client.is_rate_card_usable(&99, &1));
+
+    e.ledger().with_mut(|li| li.timestamp = 2_000);
+    assert!(client.is_rate_card_usable(&agent_id, &2));
+    client.set_min_rate_version(&agent_id, &2);
+    assert!(!client.is_rate_card_usable(&agent_id, &1));
+
+    e.ledger().with_mut(|li| li.timestamp = 3_000);
+    assert!(client.is_rate_card_usable(&agent_id, &2));
+}
+
+#[test]
+fn rate_card_token_must_be_a_deployed_contract() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let rate_card = sample_rate_card(&e);
+    let agent_id = client.register_agent(&developer, &None, &runners, &rate_card);
+    assert_eq!(client.settlement_token(&agent_id, &1), rate_card.token);
+
+    let unbacked = RateCardInput {
+        token: Address::generate(&e),
+        ..rate_card
+    };
+    assert_eq!(
+        client.try_publish_rate_card(&agent_id, &unbacked).err(),
+        Some(Ok(AgentRegistryError::InvalidToken.into()))
+    );
+}
+
+#[test]
+fn registry_mutations_emit_events() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(runner.clone());
+    runners.push_back(runner.clone());
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let rate_card = sample_rate_card(&e);
+    let agent_id = client.register_agent(&developer, &None, &runners, &rate_card);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                client.address.clone(),
+                (symbol_short!("agent"), symbol_short!("register")).into_val(&e),
+                AgentRegisteredLog {
+                    agent_id,
+                    developer: developer.clone(),
+                    runner_count: 1,
+                    manifest_hash: hash(&e, 1),
+                    timestamp: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+
+    let second_runner = Address::generate(&e);
+    client.add_runner(&agent_id, &second_runner);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                client.address.clone(),
+                (symbol_short!("runner"), symbol_short!("added")).into_val(&e),
+                RunnerChangedLog {
+                    agent_id,
+                    runner: second_runner.clone(),
+                    timestamp: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
 
     client.remove_runner(&agent_id, &runner);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                client.address.clone(),
+                (symbol_short!("runner"), symbol_short!("removed")).into_val(&e),
+                RunnerChangedLog {
+                    agent_id,
+                    runner: runner.clone(),
+                    timestamp: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+
+    let metadata = Some(String::from_str(&e, "ipfs://agent"));
+    client.set_metadata_uri(&agent_id, &metadata);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                client.address.clone(),
+                (symbol_short!("agent"), symbol_short!("metadata")).into_val(&e),
+                MetadataUpdatedLog {
+                    agent_id,
+                    metadata_uri: metadata.clone(),
+                    timestamp: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+
+    let new_rate = RateCardInput {
+        manifest_hash: hash(&e, 2),
+        ..rate_card
+    };
+    let version = client.publish_rate_card(&agent_id, &new_rate);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                client.address.clone(),
+                (symbol_short!("manifest"), symbol_short!("published")).into_val(&e),
+                ManifestPublishedLog {
+                    agent_id,
+                    version: 2,
+                    manifest_hash: hash(&e, 2),
+                    timestamp: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+            (
+                client.address.clone(),
+                (symbol_short!("rate"), symbol_short!("published")).into_val(&e),
+                RateCardPublishedLog {
+                    agent_id,
+                    version,
+                    manifest_hash: hash(&e, 2),
+                    timestamp: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+}
+
+#[test]
+fn is_runner_for_answers_in_input_order() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(runner.clone());
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let served = client.register_agent(&developer, &None, &runners, &sample_rate_card(&e));
+    let mut other_runners = Vec::new(&e);
+    other_runners.push_back(Address::generate(&e));
+    let unserved = client.register_agent(&developer, &None, &other_runners, &sample_rate_card(&e));
+
+    assert_eq!(
+        client.is_runner_for(&runner, &vec![&e, unserved, 99, served]),
+        vec![&e, false, false, true]
+    );
+}
+
+#[test]
+fn runner_metadata_is_set_listed_and_cleared_on_removal() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let backup = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(runner.clone());
+    runners.push_back(backup.clone());
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let agent_id = client.register_agent(&developer, &None, &runners, &sample_rate_card(&e));
+    let uri = Some(String::from_str(&e, "https://runner.example/operator.json"));
+    client.set_runner_metadata(&agent_id, &runner, &uri);
+    assert_eq!(client.runner_metadata(&agent_id, &runner), uri);
+
+    let listed = client.get_agent_runners(&agent_id);
+    assert_eq!(listed.len(), 2);
+    assert_eq!(listed.get(0).unwrap().metadata_uri, uri);
+    assert_eq!(listed.get(1).unwrap().metadata_uri, None);
+
+    assert_eq!(
+        client
+            .try_set_runner_metadata(&agent_id, &Address::generate(&e), &uri)
+            .err(),
+        Some(Ok(AgentRegistryError::RunnerNotFound.into()))
+    );
+
+    client.remove_runner(&agent_id, &runner);
+    client.add_runner(&agent_id, &runner);
+    assert_eq!(client.runner_metadata(&agent_id, &runner), None);
+}
+
+#[test]
+fn runner_can_resign_unless_it_is_the_last_one() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let backup = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(runner.clone());
+    runners.push_back(backup.clone());
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let agent_id = client.register_agent(&developer, &None, &runners, &sample_rate_card(&e));
+    client.resign_runner(&agent_id, &runner);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                client.address.clone(),
+                (symbol_short!("runner"), symbol_short!("removed")).into_val(&e),
+                RunnerChangedLog {
+                    agent_id,
+                    runner: runner.clone(),
+                    timestamp: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    assert!(!client.is_runner(&agent_id, &runner));
+
+    assert_eq!(
+        client.try_resign_runner(&agent_id, &backup).err(),
+        Some(Ok(AgentRegistryError::InvalidRunnerList.into()))
+    );
+}
+
+#[test]
+fn set_runners_replaces_the_list_and_reports_the_diff() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let kept = Address::generate(&e);
+    let retired = Address::generate(&e);
+    let fresh = Address::generate(&e);
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let agent_id = client.register_agent(
+        &developer,
+        &None,
+        &vec![&e, kept.clone(), retired.clone()],
+        &sample_rate_card(&e),
+    );
+    client.set_runner_metadata(
+        &agent_id,
+        &retired,
+        &Some(String::from_str(&e, "ipfs://old")),
+    );
+
+    client.set_runners(
+        &agent_id,
+        &vec![&e, kept.clone(), fresh.clone(), fresh.clone()],
+    );
+    let timestamp = e.ledger().timestamp();
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                client.address.clone(),
+                (symbol_short!("runner"), symbol_short!("removed")).into_val(&e),
+                RunnerChangedLog {
+                    agent_id,
+                    runner: retired.clone(),
+                    timestamp,
+                }
+                .into_val(&e),
+            ),
+            (
+                client.address.clone(),
+                (symbol_short!("runner"), symbol_short!("added")).into_val(&e),
+                RunnerChangedLog {
+                    agent_id,
+                    runner: fresh.clone(),
+                    timestamp,
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    assert_eq!(client.get_agent(&agent_id).runners, vec![&e, kept, fresh]);
+    assert!(!client.is_runner(&agent_id, &retired));
+    assert_eq!(client.runner_metadata(&agent_id, &retired), None);
+
+    assert_eq!(
+        client.try_set_runners(&agent_id, &Vec::new(&e)).err(),
+        Some(Ok(AgentRegistryError::InvalidRunnerList.into()))
+    );
+}
+
+#[test]
+fn runner_list_is_capped() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let max = client.max_runners();
+    let mut runners = Vec::new(&e);
+    for _ in 0..max + 1 {
+        runners.push_back(Address::generate(&e));
+    }
+    assert_eq!(
+        client
+            .try_register_agent(&developer, &None, &runners, &sample_rate_card(&e))
+            .err(),
+        Some(Ok(AgentRegistryError::TooManyRunners.into()))
+    );
+
+    let extra = runners.pop_back().unwrap();
+    let last = runners.pop_back().unwrap();
+    let agent_id = client.register_agent(&developer, &None, &runners, &sample_rate_card(&e));
+    client.add_runner(&agent_id, &last);
+    assert_eq!(client.get_agent(&agent_id).runners.len(), max);
+    assert_eq!(
+        client.try_add_runner(&agent_id, &extra).err(),
+        Some(Ok(AgentRegistryError::TooManyRunners.into()))
+    );
+
+    runners.push_back(last);
+    runners.push_back(extra);
+    assert_eq!(
+        client.try_set_runners(&agent_id, &runners).err(),
+        Some(Ok(AgentRegistryError::TooManyRunners.into()))
+    );
+}
+
+#[test]
+fn revenue_split_must_cover_exactly_the_whole() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let partner = Address::generate(&e);
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let agent_id = client.register_agent(
+        &developer,
+        &None,
+        &vec![&e, runner.clone()],
+        &sample_rate_card(&e),
+    );
+    assert_eq!(client.revenue_split_of(&agent_id).len(), 0);
+
+    for splits in [
+        vec![
+            &e,
+            (developer.clone(), 6_000u32),
+            (partner.clone(), 3_000u32),
+        ],
+        vec![
+            &e,
+            (developer.clone(), 5_000u32),
+            (developer.clone(), 5_000u32),
+        ],
+        vec![&e, (developer.clone(), 10_000u32), (partner.clone(), 0u32)],
+    ] {
+        assert_eq!(
+            client.try_set_revenue_split(&agent_id, &splits).err(),
+            Some(Ok(AgentRegistryError::InvalidRevenueSplit.into()))
+        );
+    }
+
+    let splits = vec![
+        &e,
+        (developer.clone(), 7_000u32),
+        (partner.clone(), 3_000u32),
+    ];
+    client.set_revenue_split(&agent_id, &splits);
+    assert_eq!(client.revenue_split_of(&agent_id), splits);
+
+    client.propose_transfer(&agent_id, &partner);
+    client.accept_transfer(&agent_id);
+    assert_eq!(client.revenue_split_of(&agent_id).len(), 0);
+}
+
+#[test]
+fn set_tags_maintains_reverse_index() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let coder = client.register_agent(&developer, &None, &runners, &sample_rate_card(&e));
+    let analyst = client.register_agent(&developer, &None, &runners, &sample_rate_card(&e));
+    let code = symbol_short!("code");
+    let research = symbol_short!("research");
+
+    client.set_tags(
+        &coder,
+        &vec![&e, code.clone(), research.clone(), code.clone()],
+    );
+    client.set_tags(&analyst, &vec![&e, research.clone()]);
+    assert_eq!(
+        client.get_agent(&coder).tags,
+        vec![&e, code.clone(), research.clone()]
+    );
+    assert_eq!(
+        client.agents_with_tag(&research, &0, &10),
+        vec![&e, coder, analyst]
+    );
+
+    client.set_tags(&coder, &vec![&e, code.clone()]);
+    assert_eq!(
+        client.agents_with_tag(&research, &0, &10),
+        vec![&e, analyst]
+    );
+    assert_eq!(client.agents_with_tag(&code, &0, &10), vec![&e, coder]);
+
+    // Tag listings live in persistent storage and are kept alive with the agent.
+    let ttl_of =
+        |key: DataKey| e.as_contract(&client.address, || e.storage().persistent().get_ttl(&key));
+    let tagged = DataKey::IndexedAgent(AgentIndex::Tag(code.clone()), 0);
+    let full_ttl = ttl_of(tagged.clone());
+    e.ledger()
+        .with_mut(|li| li.sequence_number += full_ttl - 1_000);
+    client.bump_agent_ttl(&coder);
+    assert_eq!(ttl_of(tagged), full_ttl);
+
+    let mut too_many = Vec::new(&e);
+    for tag in ["a", "b", "c", "d", "e", "f", "g", "h", "i"] {
+        too_many.push_back(soroban_sdk::Symbol::new(&e, tag));
+    }
+    assert_eq!(
+        client.try_set_tags(&coder, &too_many).err(),
+        Some(Ok(AgentRegistryError::TooManyTags.into()))
+    );
+}
+
+#[test]
+fn verify_manifest_matches_hash_per_version() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let agent_id = client.register_agent(&developer, &None, &runners, &sample_rate_card(&e));
+    assert!(client.verify_manifest(&agent_id, &1, &hash(&e, 1)));
+    assert!(!client.verify_manifest(&agent_id, &1, &hash(&e, 2)));
+    assert!(!client.verify_manifest(&agent_id, &2, &hash(&e, 1)));
+    assert!(!client.verify_manifest(&99, &1, &hash(&e, 1)));
+}
+
+#[test]
+fn manifests_version_independently_of_rate_cards() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let rate_card = sample_rate_card(&e);
+    let agent_id = client.register_agent(&developer, &None, &runners, &rate_card);
+    assert_eq!(client.latest_manifest_version(&agent_id), 1);
+    assert_eq!(client.get_manifest(&agent_id, &1), hash(&e, 1));
+
+    assert_eq!(client.publish_manifest(&agent_id, &hash(&e, 2)), 2);
+    assert_eq!(client.latest_rate_version(&agent_id), 1);
+    assert_eq!(client.get_manifest(&agent_id, &2), hash(&e, 2));
+
+    let repriced = RateCardInput {
+        manifest_hash: hash(&e, 2),
+        min_charge: 1,
+        ..rate_card
+    };
+    client.publish_rate_card(&agent_id, &repriced);
+    let card = client.get_rate_card(&agent_id, &2);
+    assert_eq!(card.manifest_version, 2);
+    assert_eq!(client.rate_card_manifest_hash(&agent_id, &2), hash(&e, 2));
+    assert_eq!(client.latest_manifest_version(&agent_id), 2);
+
+    assert_eq!(
+        client.try_get_manifest(&agent_id, &3).err(),
+        Some(Ok(AgentRegistryError::ManifestNotFound.into()))
+    );
+}
+
+#[test]
+fn agent_entries_stay_live_through_reads_and_bumps() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let agent_id = client.register_agent(&developer, &None, &runners, &sample_rate_card(&e));
+    let ttl_of =
+        |key: DataKey| e.as_contract(&client.address, || e.storage().persistent().get_ttl(&key));
+    let full_ttl = ttl_of(DataKey::Agent(agent_id));
+    assert_eq!(ttl_of(DataKey::RateCard(agent_id, 1)), full_ttl);
+    assert_eq!(ttl_of(DataKey::Manifest(agent_id, 1)), full_ttl);
+
+    e.ledger()
+        .with_mut(|li| li.sequence_number += full_ttl - 1_000);
+    client.get_rate_card(&agent_id, &1);
+    assert_eq!(ttl_of(DataKey::Agent(agent_id)), full_ttl);
+    assert_eq!(ttl_of(DataKey::RateCard(agent_id, 1)), full_ttl);
+
+    e.ledger()
+        .with_mut(|li| li.sequence_number += full_ttl - 1_000);
+    client.bump_agent_ttl(&agent_id);
+    assert_eq!(ttl_of(DataKey::Agent(agent_id)), full_ttl);
+    assert_eq!(ttl_of(DataKey::RateCard(agent_id, 1)), full_ttl);
+    assert_eq!(ttl_of(DataKey::Manifest(agent_id, 1)), full_ttl);
+    let listing = AgentIndex::Developer(developer.clone());
+    assert_eq!(ttl_of(DataKey::IndexedAgent(listing.clone(), 0)), full_ttl);
+    assert_eq!(ttl_of(DataKey::IndexedAgentCount(listing)), full_ttl);
+    assert_eq!(client.get_agent(&agent_id).developer, developer);
+
+    assert_eq!(
+        client.try_bump_agent_ttl(&(agent_id + 1)).err(),
+        Some(Ok(AgentRegistryError::AgentNotFound.into()))
+    );
+}
+
+#[test]
+fn rate_cards_must_price_a_non_zero_unit_scale() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let rate_card = sample_rate_card(&e);
+    let agent_id = client.register_agent(&developer, &None, &runners, &rate_card);
+    let unscaled = RateCardInput {
+        unit_scale: 0,
+        ..rate_card.clone()
+    };
+    assert_eq!(
+        client.try_publish_rate_card(&agent_id, &unscaled).err(),
+        Some(Ok(AgentRegistryError::InvalidRates.into()))
+    );
+
+    let per_thousand = RateCardInput {
+        unit_scale: 1_000,
+        ..rate_card
+    };
+    assert_eq!(client.publish_rate_card(&agent_id, &per_thousand), 2);
+    assert_eq!(client.get_rate_card(&agent_id, &2).unit_scale, 1_000);
+}
+
+#[test]
+fn allowlisted_auditors_attach_capped_audits_per_version() {
+    let e = Env::default();
+    let admin = Address::generate(&e);
+    let auditor = Address::generate(&e);
+    let developer = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+    client.init(&admin);
+    e.ledger().with_mut(|li| li.timestamp = 1_000);
+
+    let agent_id = client.register_agent(&developer, &None, &runners, &sample_rate_card(&e));
+    assert_eq!(
+        client
+            .try_attach_audit(&agent_id, &1, &auditor, &hash(&e, 9))
+            .err(),
+        Some(Ok(AgentRegistryError::Unauthorized.into()))
+    );
+
+    client.add_auditor(&auditor);
+    assert!(client.is_auditor(&auditor));
+    client.attach_audit(&agent_id, &1, &auditor, &hash(&e, 9));
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                client.address.clone(),
+                (symbol_short!("audit"), symbol_short!("attached")).into_val(&e),
+                AuditAttachedLog {
+                    agent_id,
+                    rate_version: 1,
+                    auditor: auditor.clone(),
+                    report_hash: hash(&e, 9),
+                    attached_at: 1_000,
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    client.attach_audit(&agent_id, &1, &admin, &hash(&e, 10));
+
+    let audits = client.audits_of(&agent_id, &1);
+    assert_eq!(audits.len(), 2);
+    assert_eq!(audits.get(0).unwrap().auditor, auditor);
+    assert_eq!(audits.get(1).unwrap().report_hash, hash(&e, 10));
+    assert_eq!(audits.get(1).unwrap().attached_at, 1_000);
+    assert!(client.audits_of(&agent_id, &2).is_empty());
+    assert_eq!(
+        client
+            .try_attach_audit(&agent_id, &2, &auditor, &hash(&e, 9))
+            .err(),
+        Some(Ok(AgentRegistryError::InvalidRateVersion.into()))
+    );
+
+    for byte in 0..6 {
+        client.attach_audit(&agent_id, &1, &auditor, &hash(&e, byte));
+    }
+    assert_eq!(
+        client
+            .try_attach_audit(&agent_id, &1, &auditor, &hash(&e, 20))
+            .err(),
+        Some(Ok(AgentRegistryError::TooManyAudits.into()))
+    );
+
+    client.remove_auditor(&auditor);
+    assert!(!client.is_auditor(&auditor));
+
+    let audits_ttl = || {
+        e.as_contract(&client.address, || {
+            e.storage()
+                .persistent()
+                .get_ttl(&DataKey::Audits(agent_id, 1))
+        })
+    };
+    let full_ttl = audits_ttl();
+    e.ledger()
+        .with_mut(|li| li.sequence_number += full_ttl - 1_000);
+    client.bump_agent_ttl(&agent_id);
+    assert_eq!(audits_ttl(), full_ttl);
+}
+
+#[test]
+fn developers_publish_and_deactivate_named_plans() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let rate_card = sample_rate_card(&e);
+    let agent_id = client.register_agent(&developer, &None, &runners, &rate_card);
+    assert_eq!(client.latest_plan_id(&agent_id), 0);
+    assert_eq!(
+        client.try_get_plan(&agent_id, &1).err(),
+        Some(Ok(AgentRegistryError::PlanNotFound.into()))
+    );
+
+    let mut included_units = Map::new(&e);
+    included_units.set(symbol_short!("llm_in"), 1_000_000);
+    let starter = PlanInput {
+        name: String::from_str(&e, "Starter"),
+        price: 50_000_000,
+        period_secs: 30 * 86_400,
+        included_units,
+        token: rate_card.token.clone(),
+        manifest_hash: None,
+    };
+    let mut negative_quota = starter.clone();
+    negative_quota
+        .included_units
+        .set(symbol_short!("llm_out"), -1);
+    for invalid in [
+        PlanInput {
+            price: -1,
+            ..starter.clone()
+        },
+        PlanInput {
+            period_secs: 0,
+            ..starter.clone()
+        },
+        negative_quota,
+    ] {
+        assert_eq!(
+            client.try_publish_plan(&agent_id, &invalid).err(),
+            Some(Ok(AgentRegistryError::InvalidRates.into()))
+        );
+    }
+
+    assert_eq!(client.publish_plan(&agent_id, &starter), 1);
+    let pro = PlanInput {
+        name: String::from_str(&e, "Pro"),
+        price: 200_000_000,
+        manifest_hash: Some(hash(&e, 1)),
+        ..starter.clone()
+    };
+    assert_eq!(client.publish_plan(&agent_id, &pro), 2);
+    assert_eq!(client.latest_plan_id(&agent_id), 2);
+
+    let plan = client.get_plan(&agent_id, &2);
+    assert_eq!(plan.name, pro.name);
+    assert_eq!(plan.price, 200_000_000);
+    assert_eq!(plan.manifest_hash, Some(hash(&e, 1)));
+    assert!(plan.active);
+
+    client.set_plan_active(&agent_id, &1, &false);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                client.address.clone(),
+                (symbol_short!("plan"), symbol_short!("status")).into_val(&e),
+                PlanStatusLog {
+                    agent_id,
+                    plan_id: 1,
+                    active: false,
+                    timestamp: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    assert!(!client.get_plan(&agent_id, &1).active);
+    assert_eq!(client.get_plan(&agent_id, &1).price, 50_000_000);
+    assert_eq!(
+        client.try_set_plan_active(&agent_id, &3, &false).err(),
+        Some(Ok(AgentRegistryError::PlanNotFound.into()))
+    );
+}
+
+#[test]
+fn developers_manage_capped_agent_attributes() {
+    let e = Env::default();
+    let developer = Address::generate(&e);
+    let mut runners = Vec::new(&e);
+    runners.push_back(Address::generate(&e));
+    let client = register_contract(&e);
+    e.mock_all_auths();
+
+    let agent_id = client.register_agent(&developer, &None, &runners, &sample_rate_card(&e));
+    assert!(client.get_attributes(&agent_id).is_empty());
+
+    let model = String::from_str(&e, "llama-3-70b");
+    client.set_attribute(&agent_id, &symbol_short!("model"), &model);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                client.address.clone(),
+                (symbol_short!("attr"), symbol_short!("set")).into_val(&e),
+                AttributeChangedLog {
+                    agent_id,
+                    key: symbol_short!("model"),
+                    value: Some(model.clone()),
+                    timestamp: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    client.set_attribute(
+        &agent_id,
+        &symbol_short!("region"),
+        &String::from_str(&e, "eu-west"),
+    );
+    let attributes = client.get_attributes(&agent_id);
+    assert_eq!(attributes.len(), 2);
+    assert_eq!(attributes.get(symbol_short!("model")), Some(model));
+
+    let too_long = String::from_bytes(&e, &[b'x'; 129]);
+    assert_eq!(
+        client
+            .try_set_attribute(&agent_id, &symbol_short!("notes"), &too_long)
+            .err(),
+        Some(Ok(AgentRegistryError::AttributeTooLong.into()))
+    );
+    for i in 0..14u32 {
+        let key = Symbol::new(&e, &std::format!("k{i}"));
+        client.set_attribute(&agent_id, &key, &String::from_str(&e, "v"));
+    }
+    assert_eq!(
+        client
+            .try_set_attribute(
+                &agent_id,
+                &symbol_short!("extra"),
+                &String::from_str(&e, "v")
+            )
+            .err(),
+        Some(Ok(AgentRegistryError::TooManyAttributes.into()))
+    );
+    client.set_attribute(
+        &agent_id,
+        &symbol_short!("region"),
+        &String::from_str(&e, "us-east"),
+    );
+
+    client.remove_attribute(&agent_id, &symbol_short!("model"));
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                client.address.clone(),
+                (symbol_short!("attr"), symbol_short!("removed")).into_val(&e),
+                AttributeChangedLog {
+                    agent_id,
+                    key: symbol_short!("model"),
+                    value: None,
+                    timestamp: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    client.remove_attribute(&agent_id, &symbol_short!("model"));
+    assert_eq!(e.events().all(), vec![&e]);
+    assert_eq!(client.get_attributes(&agent_id).len(), 15);
+    assert!(client.get_agent(&agent_id).metadata_uri.is_none());
 }
@@ -1,49 +1,204 @@
-use soroban_sdk::{contracttype, Address, BytesN, String, Vec};
+use soroban_sdk::{contracttype, Address, BytesN, Map, String, Symbol, Vec};
+
+pub use lumio_types::{
+    RateCard, RateCardInput, SubscriptionOffer, SubscriptionPlan, UsageMeterRates,
+};
 
 #[derive(Clone)]
 #[contracttype]
-pub struct UsageMeterRates {
-    pub llm_in: i128,
-    pub llm_out: i128,
-    pub http_calls: i128,
-    pub runtime_ms: i128,
+pub struct AgentDetails {
+    pub agent_id: u32,
+    pub developer: Address,
+    pub metadata_uri: Option<String>,
+    pub runners: Vec<Address>,
+    pub latest_rate_version: u32,
+    pub min_rate_version: u32,
+    pub latest_manifest_version: u32,
+    pub active: bool,
+    pub paused: bool,
+    pub delisted: bool,
+    pub tags: Vec<Symbol>,
+    pub verified: bool,
 }
 
-impl UsageMeterRates {
-    pub fn validate_non_negative(&self) -> bool {
-        self.llm_in >= 0 && self.llm_out >= 0 && self.http_calls >= 0 && self.runtime_ms >= 0
-    }
+/// The rate card part of `get_settlement_info`.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+#[contracttype]
+pub enum SettlementRateCard {
+    /// Version 0 was asked for; the caller prices from its own copy of the card.
+    NotRequested,
+    Card(RateCard),
 }
 
+/// What a vault reads from the registry to settle a run, in one call.
 #[derive(Clone)]
 #[contracttype]
-pub struct RateCard {
-    pub rates: UsageMeterRates,
-    pub manifest_hash: BytesN<32>,
+pub struct SettlementInfo {
+    pub developer: Address,
+    /// Where the charge is paid: the payout address, or the developer if none is set.
+    pub payout: Address,
+    pub paused: bool,
+    pub rate_card: SettlementRateCard,
 }
 
+/// Terms of a named plan as submitted by the developer.
 #[derive(Clone)]
 #[contracttype]
-pub struct RateCardInput {
-    pub rates: UsageMeterRates,
-    pub manifest_hash: BytesN<32>,
+pub struct PlanInput {
+    pub name: String,
+    /// Flat price charged once per period, in `token`.
+    pub price: i128,
+    pub period_secs: u64,
+    /// Units per meter included each period, keyed by meter name.
+    pub included_units: Map<Symbol, i128>,
+    pub token: Address,
+    pub manifest_hash: Option<BytesN<32>>,
 }
 
-impl From<RateCardInput> for RateCard {
-    fn from(value: RateCardInput) -> Self {
-        RateCard {
-            rates: value.rates,
-            manifest_hash: value.manifest_hash,
-        }
-    }
+/// A named flat-price plan with included usage, published alongside the agent's
+/// pay-as-you-go rate cards. Published terms never change; a plan can only be
+/// deactivated.
+#[derive(Clone)]
+#[contracttype]
+pub struct AgentPlan {
+    pub name: String,
+    pub price: i128,
+    pub period_secs: u64,
+    pub included_units: Map<Symbol, i128>,
+    pub token: Address,
+    pub manifest_hash: Option<BytesN<32>>,
+    pub active: bool,
+    pub published_at: u64,
 }
 
 #[derive(Clone)]
 #[contracttype]
-pub struct AgentDetails {
+pub struct RunnerInfo {
+    pub runner: Address,
+    pub metadata_uri: Option<String>,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct AgentPauseLog {
+    pub agent_id: u32,
+    pub developer: Address,
+    pub timestamp: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct AgentTransferredLog {
+    pub agent_id: u32,
+    pub old_developer: Address,
+    pub new_developer: Address,
+    pub timestamp: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct AuditRecord {
+    pub auditor: Address,
+    pub report_hash: BytesN<32>,
+    pub attached_at: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct AuditAttachedLog {
+    pub agent_id: u32,
+    pub rate_version: u32,
+    pub auditor: Address,
+    pub report_hash: BytesN<32>,
+    pub attached_at: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct AgentVerifiedLog {
+    pub agent_id: u32,
+    pub verified: bool,
+    pub timestamp: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct AgentDelistedLog {
+    pub agent_id: u32,
+    pub reason_hash: BytesN<32>,
+    pub timestamp: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct AgentRegisteredLog {
     pub agent_id: u32,
     pub developer: Address,
+    pub runner_count: u32,
+    pub manifest_hash: BytesN<32>,
+    pub timestamp: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct RunnerChangedLog {
+    pub agent_id: u32,
+    pub runner: Address,
+    pub timestamp: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct MetadataUpdatedLog {
+    pub agent_id: u32,
     pub metadata_uri: Option<String>,
-    pub runners: Vec<Address>,
-    pub latest_rate_version: u32,
+    pub timestamp: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct RateCardPublishedLog {
+    pub agent_id: u32,
+    pub version: u32,
+    pub manifest_hash: BytesN<32>,
+    pub timestamp: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct PlanPublishedLog {
+    pub agent_id: u32,
+    pub plan_id: u32,
+    pub name: String,
+    pub price: i128,
+    pub timestamp: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct PlanStatusLog {
+    pub agent_id: u32,
+    pub plan_id: u32,
+    pub active: bool,
+    pub timestamp: u64,
+}
+
+/// Published when an attribute is set or removed; `value` is `None` on removal.
+#[derive(Clone)]
+#[contracttype]
+pub struct AttributeChangedLog {
+    pub agent_id: u32,
+    pub key: Symbol,
+    pub value: Option<String>,
+    pub timestamp: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct ManifestPublishedLog {
+    pub agent_id: u32,
+    pub version: u32,
+    pub manifest_hash: BytesN<32>,
+    pub timestamp: u64,
 }
@@ -0,0 +1,197 @@
+use soroban_sdk::{contracttype, Env, Map, Symbol};
+
+use crate::usage::{UsageBreakdown, UsageMeterRates};
+
+/// Why a charge could not be computed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChargeError {
+    /// The charge does not fit in an `i128`.
+    Overflow,
+    /// A rate, the base fee, or a usage figure is negative.
+    NegativeInput,
+    /// Usage reports a custom meter the rates do not price.
+    UnpricedMeter,
+    /// The rate card prices meters per zero units.
+    ZeroUnitScale,
+}
+
+/// Prices `usage` against `rates`, each quoted per `unit_scale` units, plus the flat
+/// `base_fee`. The metered total is divided by the scale once and rounded up, so the
+/// charge never decreases as usage grows and budgets priced the same way bound it.
+pub fn compute_charge(
+    rates: &UsageMeterRates,
+    base_fee: i128,
+    usage: &UsageBreakdown,
+    unit_scale: u32,
+) -> Result<i128, ChargeError> {
+    if base_fee < 0 || !rates.validate_non_negative() || !validate_non_negative_usage(usage) {
+        return Err(ChargeError::NegativeInput);
+    }
+    if unit_scale == 0 {
+        return Err(ChargeError::ZeroUnitScale);
+    }
+    let mut metered: i128 = 0;
+    metered = add_metered(metered, rates.llm_in, usage.llm_in)?;
+    metered = add_metered(metered, rates.llm_out, usage.llm_out)?;
+    metered = add_metered(metered, rates.http_calls, usage.http_calls)?;
+    metered = add_metered(metered, rates.runtime_ms, usage.runtime_ms)?;
+    for (meter, units) in usage.extra.iter() {
+        let rate = rates.extra.get(meter).ok_or(ChargeError::UnpricedMeter)?;
+        metered = add_metered(metered, rate, units)?;
+    }
+    let scale = i128::from(unit_scale);
+    let scaled = metered / scale + i128::from(metered % scale != 0);
+    base_fee.checked_add(scaled).ok_or(ChargeError::Overflow)
+}
+
+/// What each meter contributed to a charge, in the charge's units. The parts always
+/// sum to the charge exactly, with `discount` taken off.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[contracttype]
+pub struct ChargeBreakdown {
+    pub base_fee: i128,
+    pub llm_in: i128,
+    pub llm_out: i128,
+    pub http_calls: i128,
+    pub runtime_ms: i128,
+    /// All custom meters together.
+    pub extra: i128,
+    /// Rounding the metered total up to whole units, which no single meter accounts
+    /// for. Never more than one unit per priced meter.
+    pub adjustment: i128,
+    /// Added to bring the charge up to the rate card's minimum.
+    pub minimum: i128,
+    /// Taken off the charge, as for a failed run.
+    pub discount: i128,
+}
+
+impl ChargeBreakdown {
+    pub fn total(&self) -> i128 {
+        self.base_fee
+            + self.llm_in
+            + self.llm_out
+            + self.http_calls
+            + self.runtime_ms
+            + self.extra
+            + self.adjustment
+            + self.minimum
+            - self.discount
+    }
+}
+
+/// `compute_charge`, split by meter. Each meter's share is its cost divided by
+/// `unit_scale` and rounded down; the rounding `compute_charge` does on the total lands
+/// in `adjustment`, so the breakdown totals exactly what `compute_charge` returns.
+pub fn compute_charge_breakdown(
+    rates: &UsageMeterRates,
+    base_fee: i128,
+    usage: &UsageBreakdown,
+    unit_scale: u32,
+) -> Result<ChargeBreakdown, ChargeError> {
+    let charge = compute_charge(rates, base_fee, usage, unit_scale)?;
+    // The metered sum fit in an i128, so none of the non-negative products overflow.
+    let scale = i128::from(unit_scale);
+    let mut extra = 0;
+    for (meter, units) in usage.extra.iter() {
+        extra += rates.extra.get_unchecked(meter) * units / scale;
+    }
+    let mut breakdown = ChargeBreakdown {
+        base_fee,
+        llm_in: rates.llm_in * usage.llm_in / scale,
+        llm_out: rates.llm_out * usage.llm_out / scale,
+        http_calls: rates.http_calls * usage.http_calls / scale,
+        runtime_ms: rates.runtime_ms * usage.runtime_ms / scale,
+        extra,
+        ..ChargeBreakdown::default()
+    };
+    breakdown.adjustment = charge - breakdown.total();
+    Ok(breakdown)
+}
+
+fn add_metered(total: i128, rate: i128, units: i128) -> Result<i128, ChargeError> {
+    rate.checked_mul(units)
+        .and_then(|cost| total.checked_add(cost))
+        .ok_or(ChargeError::Overflow)
+}
+
+pub fn has_unpriced_meter(rates: &UsageMeterRates, usage: &UsageBreakdown) -> bool {
+    usage
+        .extra
+        .keys()
+        .iter()
+        .any(|meter| !rates.extra.contains_key(meter))
+}
+
+pub fn validate_non_negative_usage(usage: &UsageBreakdown) -> bool {
+    usage.llm_in >= 0
+        && usage.llm_out >= 0
+        && usage.http_calls >= 0
+        && usage.runtime_ms >= 0
+        && usage.extra.values().iter().all(|units| units >= 0)
+}
+
+/// Whether any meter in `usage`, built-in or custom, exceeds its budget. Custom meters
+/// missing from `budgets` have a budget of zero.
+pub fn exceeds_budget(usage: &UsageBreakdown, budgets: &UsageBreakdown) -> bool {
+    if usage.llm_in > budgets.llm_in
+        || usage.llm_out > budgets.llm_out
+        || usage.http_calls > budgets.http_calls
+        || usage.runtime_ms > budgets.runtime_ms
+    {
+        return true;
+    }
+    usage
+        .extra
+        .iter()
+        .any(|(meter, units)| units > budgets.extra.get(meter).unwrap_or(0))
+}
+
+/// Units reported for `meter`, addressing the built-in meters by their field names.
+pub fn meter_units(e: &Env, usage: &UsageBreakdown, meter: &Symbol) -> i128 {
+    if *meter == Symbol::new(e, "llm_in") {
+        usage.llm_in
+    } else if *meter == Symbol::new(e, "llm_out") {
+        usage.llm_out
+    } else if *meter == Symbol::new(e, "http_calls") {
+        usage.http_calls
+    } else if *meter == Symbol::new(e, "runtime_ms") {
+        usage.runtime_ms
+    } else {
+        usage.extra.get(meter.clone()).unwrap_or(0)
+    }
+}
+
+fn set_meter_units(e: &Env, usage: &mut UsageBreakdown, meter: &Symbol, units: i128) {
+    if *meter == Symbol::new(e, "llm_in") {
+        usage.llm_in = units;
+    } else if *meter == Symbol::new(e, "llm_out") {
+        usage.llm_out = units;
+    } else if *meter == Symbol::new(e, "http_calls") {
+        usage.http_calls = units;
+    } else if *meter == Symbol::new(e, "runtime_ms") {
+        usage.runtime_ms = units;
+    } else if usage.extra.contains_key(meter.clone()) {
+        usage.extra.set(meter.clone(), units);
+    }
+}
+
+/// Covers as much of `usage` as the remaining free tier allows, recording the covered
+/// units in `consumed`. Returns the usage left to bill.
+pub fn deduct_free_units(
+    e: &Env,
+    usage: &UsageBreakdown,
+    free_daily_units: &Map<Symbol, i128>,
+    consumed: &mut Map<Symbol, i128>,
+) -> UsageBreakdown {
+    let mut billable = usage.clone();
+    for (meter, allowance) in free_daily_units.iter() {
+        let used = consumed.get(meter.clone()).unwrap_or(0);
+        let units = meter_units(e, usage, &meter);
+        let covered = units.min(allowance - used).max(0);
+        if covered > 0 {
+            set_meter_units(e, &mut billable, &meter, units - covered);
+            consumed.set(meter, used + covered);
+        }
+    }
+    billable
+}
@@ -0,0 +1,15 @@
+#![no_std]
+
+mod charge;
+mod rate_card;
+mod usage;
+
+pub use charge::{
+    compute_charge, compute_charge_breakdown, deduct_free_units, exceeds_budget,
+    has_unpriced_meter, meter_units, validate_non_negative_usage, ChargeBreakdown, ChargeError,
+};
+pub use rate_card::{RateCard, RateCardInput, SubscriptionOffer, SubscriptionPlan};
+pub use usage::{UsageBreakdown, UsageMeterRates};
+
+#[cfg(test)]
+mod test;
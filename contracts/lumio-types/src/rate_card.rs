@@ -0,0 +1,119 @@
+use soroban_sdk::{contracttype, Address, BytesN, Map, Symbol};
+
+use crate::usage::UsageMeterRates;
+
+/// Flat-rate access to an agent for a fixed period, sold instead of metered usage.
+#[derive(Clone)]
+#[contracttype]
+pub struct SubscriptionPlan {
+    pub price: i128,
+    pub period_secs: u64,
+}
+
+impl SubscriptionPlan {
+    pub fn is_valid(&self) -> bool {
+        self.price >= 0 && self.period_secs > 0
+    }
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub enum SubscriptionOffer {
+    NotOffered,
+    Offered(SubscriptionPlan),
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct RateCard {
+    pub rates: UsageMeterRates,
+    /// Number of units each rate is quoted for, e.g. 1_000 to price tokens per thousand.
+    pub unit_scale: u32,
+    /// Manifest version this card was published against. The registry's
+    /// `rate_card_manifest_hash` resolves it to the hash.
+    pub manifest_version: u32,
+    pub subscription: SubscriptionOffer,
+    pub effective_from: Option<u64>,
+    pub expires_at: Option<u64>,
+    /// Units per meter each user may consume free of charge per day, keyed by meter name
+    /// (`llm_in`, `llm_out`, `http_calls`, `runtime_ms`, or a custom meter).
+    pub free_daily_units: Map<Symbol, i128>,
+    /// Floor applied to the charge of every finalized run.
+    pub min_charge: i128,
+    /// Flat fee charged on every finalized run on top of metered usage.
+    pub base_fee: i128,
+    /// Asset every amount on this card is denominated in.
+    pub token: Address,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct RateCardInput {
+    pub rates: UsageMeterRates,
+    /// Number of units each rate is quoted for, e.g. 1_000 to price tokens per thousand.
+    pub unit_scale: u32,
+    pub manifest_hash: BytesN<32>,
+    pub subscription: SubscriptionOffer,
+    pub effective_from: Option<u64>,
+    pub expires_at: Option<u64>,
+    /// Units per meter each user may consume free of charge per day, keyed by meter name
+    /// (`llm_in`, `llm_out`, `http_calls`, `runtime_ms`, or a custom meter).
+    pub free_daily_units: Map<Symbol, i128>,
+    /// Floor applied to the charge of every finalized run.
+    pub min_charge: i128,
+    /// Flat fee charged on every finalized run on top of metered usage.
+    pub base_fee: i128,
+    /// Asset every amount on this card is denominated in.
+    pub token: Address,
+}
+
+impl RateCardInput {
+    pub fn is_valid(&self) -> bool {
+        if !self.rates.validate_non_negative() || self.unit_scale == 0 {
+            return false;
+        }
+        if self.min_charge < 0
+            || self.base_fee < 0
+            || self.free_daily_units.values().iter().any(|units| units < 0)
+        {
+            return false;
+        }
+        match &self.subscription {
+            SubscriptionOffer::Offered(plan) => plan.is_valid(),
+            SubscriptionOffer::NotOffered => true,
+        }
+    }
+}
+
+impl RateCard {
+    pub fn is_effective(&self, now: u64) -> bool {
+        match self.effective_from {
+            Some(effective_from) => effective_from <= now,
+            None => true,
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= now,
+            None => false,
+        }
+    }
+}
+
+impl RateCard {
+    pub fn from_input(value: RateCardInput, manifest_version: u32) -> Self {
+        RateCard {
+            rates: value.rates,
+            unit_scale: value.unit_scale,
+            manifest_version,
+            subscription: value.subscription,
+            effective_from: value.effective_from,
+            expires_at: value.expires_at,
+            free_daily_units: value.free_daily_units,
+            min_charge: value.min_charge,
+            base_fee: value.base_fee,
+            token: value.token,
+        }
+    }
+}
@@ -0,0 +1,219 @@
+#![cfg(test)]
+
+use soroban_sdk::{map, Env, Map, Symbol};
+
+use crate::{
+    compute_charge, compute_charge_breakdown, deduct_free_units, exceeds_budget,
+    has_unpriced_meter, meter_units, ChargeBreakdown, ChargeError, UsageBreakdown, UsageMeterRates,
+};
+
+fn sample_rates(e: &Env) -> UsageMeterRates {
+    UsageMeterRates {
+        llm_in: 10,
+        llm_out: 20,
+        http_calls: 1_000,
+        runtime_ms: 1,
+        extra: map![e, (Symbol::new(e, "gpu_secs"), 500)],
+    }
+}
+
+fn usage(e: &Env, llm_in: i128, gpu_secs: i128) -> UsageBreakdown {
+    UsageBreakdown {
+        llm_in,
+        llm_out: 2,
+        http_calls: 3,
+        runtime_ms: 4,
+        extra: map![e, (Symbol::new(e, "gpu_secs"), gpu_secs)],
+    }
+}
+
+#[test]
+fn compute_charge_prices_every_meter_plus_base_fee() {
+    let e = Env::default();
+    let charge = compute_charge(&sample_rates(&e), 7, &usage(&e, 1, 2), 1);
+    assert_eq!(charge, Ok(7 + 10 + 40 + 3_000 + 4 + 1_000));
+}
+
+#[test]
+fn compute_charge_reports_overflow_near_i128_max() {
+    let e = Env::default();
+    let rates = sample_rates(&e);
+    assert_eq!(
+        compute_charge(&rates, 0, &usage(&e, i128::MAX, 0), 1),
+        Err(ChargeError::Overflow)
+    );
+    assert_eq!(
+        compute_charge(&rates, i128::MAX - 10, &usage(&e, 1, 0), 1),
+        Err(ChargeError::Overflow)
+    );
+
+    let huge = UsageMeterRates {
+        llm_in: i128::MAX / 2,
+        ..sample_rates(&e)
+    };
+    let near_max = UsageBreakdown {
+        llm_in: 2,
+        llm_out: 0,
+        http_calls: 0,
+        runtime_ms: 0,
+        extra: Map::new(&e),
+    };
+    assert_eq!(compute_charge(&huge, 1, &near_max, 1), Ok(i128::MAX));
+    assert_eq!(
+        compute_charge(&huge, 2, &near_max, 1),
+        Err(ChargeError::Overflow)
+    );
+}
+
+#[test]
+fn compute_charge_rounds_scaled_rates_up() {
+    let e = Env::default();
+    let per_thousand = UsageMeterRates {
+        llm_in: 400,
+        llm_out: 0,
+        http_calls: 0,
+        runtime_ms: 0,
+        extra: Map::new(&e),
+    };
+    let tokens = |llm_in: i128| UsageBreakdown {
+        llm_in,
+        ..UsageBreakdown::zero(&e)
+    };
+    assert_eq!(compute_charge(&per_thousand, 0, &tokens(0), 1_000), Ok(0));
+    assert_eq!(compute_charge(&per_thousand, 0, &tokens(1), 1_000), Ok(1));
+    assert_eq!(
+        compute_charge(&per_thousand, 0, &tokens(1_000), 1_000),
+        Ok(400)
+    );
+    assert_eq!(
+        compute_charge(&per_thousand, 5, &tokens(1_001), 1_000),
+        Ok(406)
+    );
+    assert_eq!(
+        compute_charge(&per_thousand, 0, &tokens(1), 0),
+        Err(ChargeError::ZeroUnitScale)
+    );
+}
+
+#[test]
+fn charge_breakdown_splits_the_charge_by_meter() {
+    let e = Env::default();
+    let rates = sample_rates(&e);
+    assert_eq!(
+        compute_charge_breakdown(&rates, 7, &usage(&e, 1, 2), 1),
+        Ok(ChargeBreakdown {
+            base_fee: 7,
+            llm_in: 10,
+            llm_out: 40,
+            http_calls: 3_000,
+            runtime_ms: 4,
+            extra: 1_000,
+            ..ChargeBreakdown::default()
+        })
+    );
+
+    // Each meter rounds down on its own; rounding the total up lands in the adjustment.
+    for llm_in in [0, 1, 99, 1_001, 123_457] {
+        let used = usage(&e, llm_in, 3);
+        let charge = compute_charge(&rates, 5, &used, 1_000).unwrap();
+        let breakdown = compute_charge_breakdown(&rates, 5, &used, 1_000).unwrap();
+        assert_eq!(breakdown.total(), charge);
+        // Five priced meters: the four built-in ones and one custom one.
+        assert!((0..=5).contains(&breakdown.adjustment));
+    }
+    assert_eq!(
+        compute_charge_breakdown(&rates, 0, &usage(&e, i128::MAX, 0), 1),
+        Err(ChargeError::Overflow)
+    );
+}
+
+#[test]
+fn scaled_charge_never_exceeds_the_budget_charge() {
+    let e = Env::default();
+    let rates = sample_rates(&e);
+    let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut next = |bound: u64| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        i128::from(seed % bound)
+    };
+    for _ in 0..500 {
+        let scale = next(10_000) as u32 + 1;
+        let budget = usage(&e, next(1_000_000), next(1_000));
+        let mut used = budget.clone();
+        used.llm_in = next(budget.llm_in as u64 + 1);
+        used.extra.set(Symbol::new(&e, "gpu_secs"), next(1_000));
+        if exceeds_budget(&used, &budget) {
+            continue;
+        }
+        let max_charge = compute_charge(&rates, 3, &budget, scale).unwrap();
+        let actual = compute_charge(&rates, 3, &used, scale).unwrap();
+        assert!(actual <= max_charge);
+        let exact = compute_charge(&rates, 0, &used, 1).unwrap();
+        let scale = i128::from(scale);
+        assert!(actual - 3 >= exact / scale && actual - 3 <= exact / scale + 1);
+        assert!((actual - 3) * scale >= exact);
+    }
+}
+
+#[test]
+fn compute_charge_rejects_negative_input_and_unpriced_meters() {
+    let e = Env::default();
+    let rates = sample_rates(&e);
+    assert_eq!(
+        compute_charge(&rates, -1, &usage(&e, 1, 0), 1),
+        Err(ChargeError::NegativeInput)
+    );
+    assert_eq!(
+        compute_charge(&rates, 0, &usage(&e, -1, 0), 1),
+        Err(ChargeError::NegativeInput)
+    );
+    let negative_rate = UsageMeterRates {
+        llm_out: -1,
+        ..sample_rates(&e)
+    };
+    assert_eq!(
+        compute_charge(&negative_rate, 0, &usage(&e, 1, 0), 1),
+        Err(ChargeError::NegativeInput)
+    );
+
+    let mut unpriced = usage(&e, 1, 0);
+    unpriced.extra.set(Symbol::new(&e, "vector_reads"), 1);
+    assert!(has_unpriced_meter(&rates, &unpriced));
+    assert_eq!(
+        compute_charge(&rates, 0, &unpriced, 1),
+        Err(ChargeError::UnpricedMeter)
+    );
+}
+
+#[test]
+fn exceeds_budget_treats_missing_custom_meters_as_zero() {
+    let e = Env::default();
+    let budgets = usage(&e, 10, 5);
+    assert!(!exceeds_budget(&usage(&e, 10, 5), &budgets));
+    assert!(exceeds_budget(&usage(&e, 11, 5), &budgets));
+
+    let mut extra = usage(&e, 1, 1);
+    extra.extra.set(Symbol::new(&e, "vector_reads"), 1);
+    assert!(exceeds_budget(&extra, &budgets));
+}
+
+#[test]
+fn free_units_cover_usage_until_the_allowance_runs_out() {
+    let e = Env::default();
+    let llm_in = Symbol::new(&e, "llm_in");
+    let gpu = Symbol::new(&e, "gpu_secs");
+    let free = map![&e, (llm_in.clone(), 15), (gpu.clone(), 1)];
+    let mut consumed: Map<Symbol, i128> = Map::new(&e);
+
+    let billable = deduct_free_units(&e, &usage(&e, 10, 3), &free, &mut consumed);
+    assert_eq!(meter_units(&e, &billable, &llm_in), 0);
+    assert_eq!(meter_units(&e, &billable, &gpu), 2);
+
+    let billable = deduct_free_units(&e, &usage(&e, 10, 3), &free, &mut consumed);
+    assert_eq!(billable.llm_in, 5);
+    assert_eq!(billable.extra.get(gpu.clone()), Some(3));
+    assert_eq!(consumed.get(llm_in), Some(15));
+    assert_eq!(consumed.get(gpu), Some(1));
+}
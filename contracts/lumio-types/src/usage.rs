@@ -0,0 +1,51 @@
+use soroban_sdk::{contracttype, Env, Map, Symbol};
+
+#[derive(Clone)]
+#[contracttype]
+pub struct UsageMeterRates {
+    pub llm_in: i128,
+    pub llm_out: i128,
+    pub http_calls: i128,
+    pub runtime_ms: i128,
+    /// Rates for agent-specific meters (e.g. GPU seconds) beyond the four built-in ones.
+    pub extra: Map<Symbol, i128>,
+}
+
+/// Units reported or budgeted per meter, shaped like the rates they are priced against.
+pub type UsageBreakdown = UsageMeterRates;
+
+impl UsageMeterRates {
+    pub fn zero(e: &Env) -> Self {
+        Self {
+            llm_in: 0,
+            llm_out: 0,
+            http_calls: 0,
+            runtime_ms: 0,
+            extra: Map::new(e),
+        }
+    }
+
+    /// Meter-by-meter sum, or `None` on overflow.
+    pub fn checked_add(&self, other: &Self) -> Option<Self> {
+        let mut extra = self.extra.clone();
+        for (meter, units) in other.extra.iter() {
+            let sum = extra.get(meter.clone()).unwrap_or(0).checked_add(units)?;
+            extra.set(meter, sum);
+        }
+        Some(Self {
+            llm_in: self.llm_in.checked_add(other.llm_in)?,
+            llm_out: self.llm_out.checked_add(other.llm_out)?,
+            http_calls: self.http_calls.checked_add(other.http_calls)?,
+            runtime_ms: self.runtime_ms.checked_add(other.runtime_ms)?,
+            extra,
+        })
+    }
+
+    pub fn validate_non_negative(&self) -> bool {
+        self.llm_in >= 0
+            && self.llm_out >= 0
+            && self.http_calls >= 0
+            && self.runtime_ms >= 0
+            && self.extra.values().iter().all(|rate| rate >= 0)
+    }
+}
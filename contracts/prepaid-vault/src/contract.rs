@@ -1,34 +1,367 @@
-use agent_registry::AgentRegistryClient;
+use agent_registry::{
+    AgentRegistryClient, RateCard, SettlementInfo, SettlementRateCard, SubscriptionOffer,
+};
 use soroban_sdk::{
-    contract, contractimpl, panic_with_error, symbol_short, Address, BytesN, Env, Vec,
+    contract, contractimpl, panic_with_error, symbol_short, token, Address, Bytes, BytesN, Env,
+    IntoVal, Map, String, Symbol, TryFromVal, Val, Vec,
 };
 
 use crate::{
-    storage::{DataKey, RunRecord},
+    oracle::PriceOracleClient,
+    storage::{
+        DataKey, FreeUsage, RollingSpend, RunIndex, RunRecordV1, UserActivity, UserPolicyV1,
+        WithdrawalDelay,
+    },
     types::{
-        PolicyInput, RunFinalizedLog, RunLifecycle, RunOpenedLog, RunReceipt, RunSettlement,
-        RunnerGrant, RunnerGrantLog, RunnerRevokeLog, UsageBreakdown, UserPolicy, VaultError,
+        AgentAllowance, AgentStats, AllowanceSetLog, CircuitBreaker, ConfigUpdate,
+        DepositApprovalLog, DepositForLog, DeveloperClaimLog, DeveloperGrant, DeveloperGrantLog,
+        DeveloperRevokeLog, DisputeError, FailedSettlement, GoodwillRefundLog, GuardianPauseLog,
+        InitConfig, LifecycleKind, MigrationLog, OpenRunResult, PauseLevel, PendingWithdrawal,
+        PolicyError, PolicyInput, PriceOracleConfig, PriceOracleError, ProposedSettlement,
+        ProtocolFeeLog, QuoteResult, RateSnapshot, ReferralCreditLog, RevenueShareLog,
+        RunChargedLog, RunClosedLog, RunDisputedLog, RunFinalizedLog, RunLifecycle, RunOpenedLog,
+        RunPage, RunPricing, RunReceipt, RunRecord, RunSettlement, RunTipLog, RunnerCap,
+        RunnerClaimLog, RunnerGrant, RunnerGrantLog, RunnerRevokeLog, SchemaError, SessionKey,
+        SessionLog, SettlementProposedLog, SponsorError, Subscription, SubscriptionOpenedLog,
+        SubscriptionReleasedLog, UsageBreakdown, UsageMeterRates, UserPolicy, UserStats,
+        VaultConfig, VaultError, VaultPauseLog, VersionedKey, Voucher, VoucherCreatedLog,
+        VoucherError, VoucherReclaimedLog, VoucherRedeemedLog, WithdrawLog, WithdrawalError,
+        MIN_FORCE_CLOSE_SECS,
+    },
+    utils::{
+        compute_charge, compute_charge_breakdown, current_day, deduct_free_units, exceeds_budget,
+        has_unpriced_meter, validate_non_negative_usage, ChargeError,
     },
-    utils::{compute_charge, current_day, validate_non_negative_usage},
 };
 
+/// Upper bound on the number of budget presets a user may store.
+const MAX_BUDGET_PRESETS: u32 = 16;
+
+const BPS_DENOMINATOR: u32 = 10_000;
+
+/// Upper bound on the number of entries returned by paginated views.
+const MAX_PAGE_SIZE: u32 = 50;
+
+/// Most entries a run listing examines per call. A filtered listing reads the run
+/// behind each entry too, and both count against the 100-entry transaction footprint.
+const MAX_RUN_SCAN: u32 = 40;
+
+/// Longest withdrawal delay a user may opt into.
+const MAX_WITHDRAWAL_DELAY_SECS: u64 = 30 * 86_400;
+
+/// Longest run memo accepted by `open_run`, in bytes.
+const MAX_MEMO_LEN: u32 = 64;
+
+/// How long a user has to accept or reject a proposed settlement.
+const SETTLEMENT_RESPONSE_SECS: u64 = 3 * 86_400;
+
+/// Layout of the vault config written by `init_with_config` and `update_config`.
+const CONFIG_VERSION: u32 = 1;
+
+/// Layout of `RunRecord` and `UserPolicy` entries written by this code. Entries in the
+/// previous layout are still read and are rewritten in this one on their next write.
+const SCHEMA_VERSION: u32 = 2;
+
+/// Decimals of USD amounts in USD-denominated policy caps.
+const USD_DECIMALS: u32 = 7;
+
+const DAY_IN_LEDGERS: u32 = 17_280;
+
+/// Persistent entries and the instance are extended to `TTL_EXTEND_TO` ledgers whenever
+/// they are written or bumped with less than `TTL_THRESHOLD` ledgers left.
+const TTL_THRESHOLD: u32 = 30 * DAY_IN_LEDGERS;
+const TTL_EXTEND_TO: u32 = 120 * DAY_IN_LEDGERS;
+
 #[contract]
 pub struct PrepaidVault;
 
 #[contractimpl]
 impl PrepaidVault {
-    pub fn init(e: Env, registry: Address) {
-        if e.storage().instance().has(&DataKey::AgentRegistry) {
+    /// Shorthand for `init_with_config` with no vault token, fee or treasury.
+    pub fn init(e: Env, admin: Address, registry: Address) {
+        Self::init_with_config(
+            e,
+            InitConfig {
+                admin,
+                registry,
+                token: None,
+                fee_bps: 0,
+                treasury: None,
+            },
+        );
+    }
+
+    /// Initializes the vault and records the config layout version. Fails if the vault
+    /// was already initialized under this or any earlier version.
+    pub fn init_with_config(e: Env, config: InitConfig) {
+        if e.storage().instance().has(&DataKey::AgentRegistry)
+            || read_config_version(&e) >= CONFIG_VERSION
+        {
             panic_with_error!(&e, VaultError::AlreadyInitialized);
         }
+        if config.fee_bps > BPS_DENOMINATOR {
+            panic_with_error!(&e, VaultError::InvalidAmount);
+        }
+        let storage = e.storage().instance();
+        storage.set(&DataKey::Admin, &config.admin);
+        storage.set(&DataKey::AgentRegistry, &config.registry);
+        storage.set(&DataKey::FeeBps, &config.fee_bps);
+        if let Some(token) = config.token {
+            storage.set(&DataKey::VaultToken, &token);
+        }
+        if let Some(treasury) = config.treasury {
+            storage.set(&DataKey::Treasury, &treasury);
+        }
+        storage.set(&DataKey::NextRunId, &1u64);
+        storage.set(&DataKey::ConfigVersion, &CONFIG_VERSION);
+        storage.set(&DataKey::SchemaVersion, &SCHEMA_VERSION);
+    }
+
+    /// Layout version of newly written runs and policies. Vaults deployed before the
+    /// schema was versioned report 1.
+    pub fn schema_version(e: Env) -> u32 {
+        read_schema_version(&e)
+    }
+
+    /// Eagerly rewrites the entries of `batch` still in layout `from_version` into the
+    /// current one and returns how many were rewritten. Call it once per batch, then
+    /// `finish_migration`; entries never listed are upgraded lazily.
+    pub fn migrate(e: Env, from_version: u32, batch: Vec<VersionedKey>) -> u32 {
+        read_admin(&e).require_auth();
+        ensure_migrating_from(&e, from_version);
+        let instance = e.storage().instance();
+        let mut migrated = 0;
+        for key in batch.iter() {
+            match key {
+                VersionedKey::Run(run_id) => {
+                    let key = DataKey::Run(run_id);
+                    if instance.has(&key) {
+                        if !e.storage().persistent().has(&key) {
+                            write_run(&e, run_id, &read_run_or_panic(&e, run_id));
+                        }
+                        instance.remove(&key);
+                        migrated += 1;
+                    }
+                }
+                VersionedKey::Policy(user) => {
+                    let key = DataKey::UserPolicy(user.clone());
+                    if instance.has(&key) {
+                        if !e.storage().persistent().has(&key) {
+                            write_policy(&e, &user, &read_policy(&e, &user));
+                        }
+                        instance.remove(&key);
+                        migrated += 1;
+                    }
+                }
+                VersionedKey::Balance(user) => {
+                    let key = DataKey::UserBalance(user.clone());
+                    if instance.has(&key) {
+                        if !e.storage().persistent().has(&key) {
+                            write_balance(&e, &user, read_balance(&e, &user));
+                        }
+                        instance.remove(&key);
+                        migrated += 1;
+                    }
+                }
+                VersionedKey::DeveloperBalance(developer) => {
+                    let key = DataKey::DeveloperBalance(developer.clone());
+                    if instance.has(&key) {
+                        if !e.storage().persistent().has(&key) {
+                            write_developer_balance(
+                                &e,
+                                &developer,
+                                read_developer_balance(&e, &developer),
+                            );
+                        }
+                        instance.remove(&key);
+                        migrated += 1;
+                    }
+                }
+            }
+        }
+        let total = read_migrated_entries(&e) + migrated;
+        instance.set(&DataKey::MigratedEntries, &total);
+        migrated
+    }
+
+    /// Records that every entry the operator meant to migrate from `from_version` has
+    /// been, moving the vault to the current schema version. Returns how many entries
+    /// `migrate` rewrote across all batches.
+    pub fn finish_migration(e: Env, from_version: u32) -> u32 {
+        read_admin(&e).require_auth();
+        ensure_migrating_from(&e, from_version);
+        let total = read_migrated_entries(&e);
+        let instance = e.storage().instance();
+        instance.set(&DataKey::SchemaVersion, &SCHEMA_VERSION);
+        instance.remove(&DataKey::MigratedEntries);
+        total
+    }
+
+    /// Entries rewritten by `migrate` since the last `finish_migration`.
+    pub fn migrated_entries(e: Env) -> u32 {
+        read_migrated_entries(&e)
+    }
+
+    /// Applies every field set in `update`, leaving the others unchanged.
+    pub fn update_config(e: Env, update: ConfigUpdate) {
+        read_admin(&e).require_auth();
+        let storage = e.storage().instance();
+        if let Some(fee_bps) = update.fee_bps {
+            if fee_bps > BPS_DENOMINATOR {
+                panic_with_error!(&e, VaultError::InvalidAmount);
+            }
+            storage.set(&DataKey::FeeBps, &fee_bps);
+        }
+        if let Some(admin) = update.admin {
+            storage.set(&DataKey::Admin, &admin);
+        }
+        if let Some(registry) = update.registry {
+            storage.set(&DataKey::AgentRegistry, &registry);
+        }
+        if let Some(token) = update.token {
+            storage.set(&DataKey::VaultToken, &token);
+        }
+        if let Some(treasury) = update.treasury {
+            storage.set(&DataKey::Treasury, &treasury);
+        }
+        if let Some(agent_event_topics) = update.agent_event_topics {
+            storage.set(&DataKey::AgentEventTopics, &agent_event_topics);
+        }
+        if let Some(failure_discount_bps) = update.failure_discount_bps {
+            if failure_discount_bps > BPS_DENOMINATOR {
+                panic_with_error!(&e, VaultError::InvalidAmount);
+            }
+            storage.set(&DataKey::FailureDiscountBps, &failure_discount_bps);
+        }
+        storage.set(&DataKey::ConfigVersion, &CONFIG_VERSION);
+    }
+
+    pub fn get_config(e: Env) -> VaultConfig {
+        let storage = e.storage().instance();
+        VaultConfig {
+            admin: read_admin(&e),
+            registry: require_registry(&e),
+            token: storage.get(&DataKey::VaultToken),
+            fee_bps: storage.get(&DataKey::FeeBps).unwrap_or(0),
+            treasury: storage.get(&DataKey::Treasury),
+            config_version: read_config_version(&e),
+            agent_event_topics: read_agent_event_topics(&e),
+            failure_discount_bps: read_failure_discount_bps(&e),
+        }
+    }
+
+    /// Stops new activity (deposits, runs, subscriptions, vouchers and claims) while
+    /// leaving withdrawals open, so users can always exit. Open runs can still be
+    /// finalized or cancelled.
+    pub fn pause_new_activity(e: Env) {
+        set_pause_level(&e, PauseLevel::NewActivity);
+    }
+
+    /// Emergency stop: `pause_new_activity` plus withdrawals. Open runs can still be
+    /// finalized or cancelled.
+    pub fn pause_all(e: Env) {
+        set_pause_level(&e, PauseLevel::All);
+    }
+
+    /// Same as `pause_all`.
+    pub fn pause_vault(e: Env) {
+        set_pause_level(&e, PauseLevel::All);
+    }
+
+    pub fn unpause_vault(e: Env) {
+        set_pause_level(&e, PauseLevel::Unpaused);
+    }
+
+    /// Whether any pause level is in effect.
+    pub fn is_vault_paused(e: Env) -> bool {
+        read_pause_level(&e) != PauseLevel::Unpaused
+    }
+
+    pub fn pause_level(e: Env) -> PauseLevel {
+        read_pause_level(&e)
+    }
+
+    /// Caps the total charge settled across all runs per day. Zero disables the breaker.
+    pub fn set_circuit_breaker_ceiling(e: Env, ceiling: i128) {
+        read_admin(&e).require_auth();
+        if ceiling < 0 {
+            panic_with_error!(&e, VaultError::InvalidAmount);
+        }
+        let mut breaker = read_circuit_breaker(&e);
+        breaker.ceiling = ceiling;
+        write_circuit_breaker(&e, &breaker);
+    }
+
+    /// Lets settlements through for the rest of the day after the breaker has tripped.
+    pub fn override_circuit_breaker(e: Env) {
+        read_admin(&e).require_auth();
+        let mut breaker = read_circuit_breaker(&e);
+        breaker.overridden = true;
+        write_circuit_breaker(&e, &breaker);
+    }
+
+    pub fn circuit_breaker(e: Env) -> CircuitBreaker {
+        read_circuit_breaker(&e)
+    }
+
+    /// Oracle used by users whose policy caps are denominated in USD.
+    pub fn set_price_oracle(e: Env, config: Option<PriceOracleConfig>) {
+        read_admin(&e).require_auth();
+        match config {
+            Some(config) => {
+                if config.max_age_secs == 0 {
+                    panic_with_error!(&e, VaultError::InvalidAmount);
+                }
+                e.storage().instance().set(&DataKey::PriceOracle, &config);
+            }
+            None => e.storage().instance().remove(&DataKey::PriceOracle),
+        }
+    }
+
+    pub fn price_oracle(e: Env) -> Option<PriceOracleConfig> {
+        e.storage().instance().get(&DataKey::PriceOracle)
+    }
+
+    /// Share of every settlement, in basis points, paid to the referrer of the user.
+    pub fn set_referral_bps(e: Env, bps: u32) {
+        read_admin(&e).require_auth();
+        if bps > BPS_DENOMINATOR {
+            panic_with_error!(&e, VaultError::InvalidAmount);
+        }
+        e.storage().instance().set(&DataKey::ReferralBps, &bps);
+    }
+
+    pub fn referral_bps(e: Env) -> u32 {
+        read_referral_bps(&e)
+    }
+
+    /// Waives the protocol fee on settlements credited to `developer`, who then receives
+    /// the full `actual_charge`.
+    pub fn add_fee_exempt(e: Env, developer: Address) {
+        read_admin(&e).require_auth();
+        e.storage()
+            .instance()
+            .set(&DataKey::FeeExempt(developer), &true);
+    }
+
+    pub fn remove_fee_exempt(e: Env, developer: Address) {
+        read_admin(&e).require_auth();
         e.storage()
             .instance()
-            .set(&DataKey::AgentRegistry, &registry);
-        e.storage().instance().set(&DataKey::NextRunId, &1u64);
+            .remove(&DataKey::FeeExempt(developer));
+    }
+
+    pub fn is_fee_exempt(e: Env, developer: Address) -> bool {
+        is_fee_exempt(&e, &developer)
+    }
+
+    pub fn admin(e: Env) -> Address {
+        read_admin(&e)
     }
 
     pub fn deposit(e: Env, user: Address, amount: i128) {
         user.require_auth();
+        ensure_not_paused(&e);
         if amount <= 0 {
             panic_with_error!(&e, VaultError::InvalidAmount);
         }
@@ -37,497 +370,4075 @@ impl PrepaidVault {
         write_balance(&e, &user, new_balance);
     }
 
-    pub fn withdraw(e: Env, user: Address, amount: i128) {
-        user.require_auth();
+    /// Credits `beneficiary`'s balance on behalf of `payer`. Only the payer authorizes.
+    pub fn deposit_for(e: Env, payer: Address, beneficiary: Address, amount: i128) {
+        payer.require_auth();
+        ensure_not_paused(&e);
         if amount <= 0 {
             panic_with_error!(&e, VaultError::InvalidAmount);
         }
-        let balance = read_balance(&e, &user);
-        if balance < amount {
-            panic_with_error!(&e, VaultError::InsufficientBalance);
-        }
-        write_balance(&e, &user, balance - amount);
-    }
+        let balance = read_balance(&e, &beneficiary);
+        let new_balance = balance.checked_add(amount).unwrap();
+        write_balance(&e, &beneficiary, new_balance);
 
-    pub fn set_policy(e: Env, user: Address, policy: PolicyInput) {
-        user.require_auth();
-        if policy.per_run_cap < 0 || policy.daily_cap < 0 {
-            panic_with_error!(&e, VaultError::InvalidAmount);
-        }
-        let mut stored = read_policy(&e, &user);
-        stored.per_run_cap = policy.per_run_cap;
-        stored.daily_cap = policy.daily_cap;
-        stored.paused = policy.paused;
-        write_policy(&e, &user, &stored);
+        e.events().publish(
+            (symbol_short!("deposit"), symbol_short!("for")),
+            DepositForLog {
+                payer,
+                beneficiary,
+                amount,
+            },
+        );
     }
 
-    pub fn grant_runner(
-        e: Env,
-        user: Address,
-        runner: Address,
-        agent_id: u32,
-        expires_at: Option<u64>,
-    ) {
+    /// Lets `spender`, an app acting for the user, credit up to `amount` to the user's
+    /// balance through `deposit_from_allowance` with only its own signature. Replaces any
+    /// earlier approval; zero revokes it, so the spender again needs the user to co-sign.
+    pub fn approve_deposits(e: Env, user: Address, spender: Address, amount: i128) {
         user.require_auth();
-        if runner == user {
+        if amount < 0 {
             panic_with_error!(&e, VaultError::InvalidAmount);
         }
-
-        let registry_addr = require_registry(&e);
-        let registry = AgentRegistryClient::new(&e, &registry_addr);
-        if !registry.is_runner(&agent_id, &runner) {
-            panic_with_error!(&e, VaultError::UnauthorizedRunner);
-        }
-
-        let grants = read_runner_grants(&e, &user);
-        let mut grants = prune_expired_grants(&e, grants);
-        for grant in grants.iter() {
-            if grant.runner == runner && grant.agent_id == agent_id {
-                panic_with_error!(&e, VaultError::RunnerGrantExists);
-            }
+        let key = DataKey::DepositAllowance(user.clone(), spender.clone());
+        if amount == 0 {
+            e.storage().persistent().remove(&key);
+        } else {
+            write_deposit_allowance(&e, &user, &spender, amount);
         }
 
-        let grant = RunnerGrant {
-            runner: runner.clone(),
-            agent_id,
-            issued_at: e.ledger().timestamp(),
-            expires_at,
-        };
-
-        grants.push_back(grant.clone());
-        write_runner_grants(&e, &user, &grants);
-
         e.events().publish(
-            (symbol_short!("runner"), symbol_short!("granted")),
-            RunnerGrantLog {
+            (symbol_short!("deposit"), symbol_short!("approved")),
+            DepositApprovalLog {
                 user,
-                runner,
-                agent_id,
-                issued_at: grant.issued_at,
-                expires_at: grant.expires_at,
+                spender,
+                amount,
             },
         );
     }
 
-    pub fn revoke_runner(e: Env, user: Address, runner: Address, agent_id: u32) {
-        user.require_auth();
+    pub fn deposit_allowance(e: Env, user: Address, spender: Address) -> i128 {
+        read_deposit_allowance(&e, &user, &spender).unwrap_or(0)
+    }
 
-        let grants = read_runner_grants(&e, &user);
-        let grants = prune_expired_grants(&e, grants);
-        let (filtered, removed) = remove_runner_grant(&e, grants, &runner, agent_id);
-        if !removed {
-            panic_with_error!(&e, VaultError::RunnerGrantNotFound);
+    /// Credits `amount` to the user's balance on a deposit triggered by `spender`. A
+    /// spender the user approved through `approve_deposits` signs alone and draws the
+    /// approval down, failing with `InsufficientAllowance` once it runs out; any other
+    /// spender needs the user's signature as well. Like
+    /// `deposit`, this only moves the vault's internal balances.
+    pub fn deposit_from_allowance(e: Env, spender: Address, user: Address, amount: i128) {
+        spender.require_auth();
+        ensure_not_paused(&e);
+        if amount <= 0 {
+            panic_with_error!(&e, VaultError::InvalidAmount);
         }
-        write_runner_grants(&e, &user, &filtered);
+        match read_deposit_allowance(&e, &user, &spender) {
+            Some(allowance) if allowance >= amount => {
+                write_deposit_allowance(&e, &user, &spender, allowance - amount);
+            }
+            Some(_) => panic_with_error!(&e, VaultError::InsufficientAllowance),
+            None => user.require_auth(),
+        }
+        let balance = read_balance(&e, &user);
+        let new_balance = balance
+            .checked_add(amount)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::InvalidAmount));
+        write_balance(&e, &user, new_balance);
 
         e.events().publish(
-            (symbol_short!("runner"), symbol_short!("revoked")),
-            RunnerRevokeLog {
-                user,
-                runner,
-                agent_id,
-                revoked_at: e.ledger().timestamp(),
+            (symbol_short!("deposit"), symbol_short!("allowance")),
+            DepositForLog {
+                payer: spender,
+                beneficiary: user,
+                amount,
             },
         );
     }
 
-    pub fn list_runner_grants(e: Env, user: Address) -> Vec<RunnerGrant> {
-        let grants = read_runner_grants(&e, &user);
-        let grants = prune_expired_grants(&e, grants);
-        write_runner_grants(&e, &user, &grants);
-        grants
+    pub fn withdraw(e: Env, user: Address, amount: i128) {
+        user.require_auth();
+        ensure_withdrawals_open(&e);
+        ensure_no_withdrawal_delay(&e, &user);
+        if amount <= 0 {
+            panic_with_error!(&e, VaultError::InvalidAmount);
+        }
+        let balance = read_balance(&e, &user);
+        if balance < amount {
+            panic_with_error!(&e, VaultError::InsufficientBalance);
+        }
+        write_balance(&e, &user, balance - amount);
+        publish_withdraw(&e, user, amount);
     }
 
-    pub fn is_runner_authorized(e: Env, user: Address, runner: Address, agent_id: u32) -> bool {
-        ensure_runner_authorized(&e, &user, &runner, agent_id)
+    /// Withdraws the user's entire balance and returns the amount. Withdrawing an empty
+    /// balance is a no-op returning 0.
+    pub fn withdraw_all(e: Env, user: Address) -> i128 {
+        user.require_auth();
+        ensure_withdrawals_open(&e);
+        ensure_no_withdrawal_delay(&e, &user);
+        let amount = read_balance(&e, &user);
+        if amount == 0 {
+            return 0;
+        }
+        write_balance(&e, &user, 0);
+        publish_withdraw(&e, user, amount);
+        amount
     }
 
-    pub fn open_run(
-        e: Env,
-        user: Address,
-        caller: Address,
-        agent_id: u32,
-        rate_version: u32,
-        budgets: UsageBreakdown,
-    ) -> u64 {
-        caller.require_auth();
-        if caller != user {
-            if !ensure_runner_authorized(&e, &user, &caller, agent_id) {
-                panic_with_error!(&e, VaultError::UnauthorizedRunner);
-            }
+    /// Opts into a withdrawal timelock: with a non-zero delay, `withdraw` is replaced by
+    /// `request_withdrawal` and `execute_withdrawal`. Raising the delay applies at once;
+    /// lowering it applies only once the current delay has passed. Delays are capped at
+    /// 30 days.
+    pub fn set_withdrawal_delay(e: Env, user: Address, delay_secs: u64) {
+        user.require_auth();
+        if delay_secs > MAX_WITHDRAWAL_DELAY_SECS {
+            panic_with_error!(&e, WithdrawalError::DelayTooLong);
         }
+        let now = e.ledger().timestamp();
+        let current = read_withdrawal_delay(&e, &user);
+        let delay = if delay_secs >= current.delay_secs {
+            WithdrawalDelay {
+                delay_secs,
+                next_delay_secs: None,
+                next_effective_at: 0,
+            }
+        } else {
+            WithdrawalDelay {
+                delay_secs: current.delay_secs,
+                next_delay_secs: Some(delay_secs),
+                next_effective_at: now
+                    .checked_add(current.delay_secs)
+                    .unwrap_or_else(|| panic_with_error!(&e, WithdrawalError::DelayTooLong)),
+            }
+        };
+        e.storage()
+            .instance()
+            .set(&DataKey::WithdrawalDelay(user), &delay);
+    }
 
-        if !validate_non_negative_usage(&budgets) {
+    /// The withdrawal delay in force now, in seconds.
+    pub fn withdrawal_delay_of(e: Env, user: Address) -> u64 {
+        read_withdrawal_delay(&e, &user).delay_secs
+    }
+
+    /// Sets `amount` aside for withdrawal once the user's delay has passed. Further
+    /// requests add to the pending amount and restart the delay.
+    pub fn request_withdrawal(e: Env, user: Address, amount: i128) -> PendingWithdrawal {
+        user.require_auth();
+        ensure_withdrawals_open(&e);
+        if amount <= 0 {
             panic_with_error!(&e, VaultError::InvalidAmount);
         }
+        debit_balance(&e, &user, amount);
 
-        let registry_addr = require_registry(&e);
-        let registry = AgentRegistryClient::new(&e, &registry_addr);
+        let now = e.ledger().timestamp();
+        let pending_amount = read_pending_withdrawal(&e, &user)
+            .map_or(0, |pending| pending.amount)
+            .checked_add(amount)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::InvalidAmount));
+        let pending = PendingWithdrawal {
+            amount: pending_amount,
+            requested_at: now,
+            available_at: now
+                .checked_add(read_withdrawal_delay(&e, &user).delay_secs)
+                .unwrap_or_else(|| panic_with_error!(&e, WithdrawalError::DelayTooLong)),
+        };
+        e.storage()
+            .instance()
+            .set(&DataKey::PendingWithdrawal(user), &pending);
+        pending
+    }
 
-        let rate_card = registry.get_rate_card(&agent_id, &rate_version);
-        let max_charge = compute_charge(&rate_card.rates, &budgets)
+    /// Pays out the pending withdrawal once it is available, returning the amount paid
+    /// or 0 if nothing is pending.
+    pub fn execute_withdrawal(e: Env, user: Address) -> i128 {
+        user.require_auth();
+        ensure_withdrawals_open(&e);
+        let Some(pending) = read_pending_withdrawal(&e, &user) else {
+            return 0;
+        };
+        if e.ledger().timestamp() < pending.available_at {
+            panic_with_error!(&e, VaultError::WithdrawalLocked);
+        }
+        e.storage()
+            .instance()
+            .remove(&DataKey::PendingWithdrawal(user.clone()));
+        publish_withdraw(&e, user, pending.amount);
+        pending.amount
+    }
+
+    /// Returns a pending withdrawal to the user's balance.
+    pub fn cancel_withdrawal(e: Env, user: Address) {
+        user.require_auth();
+        let Some(pending) = read_pending_withdrawal(&e, &user) else {
+            return;
+        };
+        e.storage()
+            .instance()
+            .remove(&DataKey::PendingWithdrawal(user.clone()));
+        let balance = read_balance(&e, &user)
+            .checked_add(pending.amount)
             .unwrap_or_else(|| panic_with_error!(&e, VaultError::InvalidAmount));
+        write_balance(&e, &user, balance);
+    }
 
-        let mut policy = read_policy(&e, &user);
-        let today = current_day(&e);
-        policy.ensure_day(today);
+    pub fn pending_withdrawal(e: Env, user: Address) -> Option<PendingWithdrawal> {
+        read_pending_withdrawal(&e, &user)
+    }
 
-        if policy.paused {
-            panic_with_error!(&e, VaultError::PolicyPaused);
+    /// The vault deployment users may move their balances to with `migrate_balance`.
+    pub fn set_migration_target(e: Env, target: Option<Address>) {
+        read_admin(&e).require_auth();
+        match target {
+            Some(target) => e
+                .storage()
+                .instance()
+                .set(&DataKey::MigrationTarget, &target),
+            None => e.storage().instance().remove(&DataKey::MigrationTarget),
         }
+    }
+
+    pub fn migration_target(e: Env) -> Option<Address> {
+        e.storage().instance().get(&DataKey::MigrationTarget)
+    }
 
-        if policy.per_run_cap > 0 && max_charge > policy.per_run_cap {
-            panic_with_error!(&e, VaultError::PerRunCapExceeded);
+    /// The previous vault deployment allowed to call `receive_migration` on this one.
+    pub fn set_migration_source(e: Env, source: Option<Address>) {
+        read_admin(&e).require_auth();
+        match source {
+            Some(source) => e
+                .storage()
+                .instance()
+                .set(&DataKey::MigrationSource, &source),
+            None => e.storage().instance().remove(&DataKey::MigrationSource),
         }
+    }
 
-        if policy.daily_cap > 0 {
-            let new_reserved = policy
-                .reserved_today
-                .checked_add(max_charge)
-                .unwrap_or_else(|| panic_with_error!(&e, VaultError::DailyCapExceeded));
-            if new_reserved > policy.daily_cap {
-                panic_with_error!(&e, VaultError::DailyCapExceeded);
-            }
-            policy.reserved_today = new_reserved;
+    pub fn migration_source(e: Env) -> Option<Address> {
+        e.storage().instance().get(&DataKey::MigrationSource)
+    }
+
+    /// Moves the user's whole balance to the migration target and returns the amount.
+    /// Blocked while the user still has runs holding escrow, while the account is
+    /// paused, and under the same withdrawal timelock as `withdraw`.
+    pub fn migrate_balance(e: Env, user: Address) -> i128 {
+        user.require_auth();
+        ensure_not_paused(&e);
+        ensure_no_withdrawal_delay(&e, &user);
+        if read_pending_withdrawal(&e, &user).is_some() {
+            panic_with_error!(&e, VaultError::WithdrawalLocked);
+        }
+        if read_policy(&e, &user).paused {
+            panic_with_error!(&e, VaultError::PolicyPaused);
+        }
+        let target: Address = e
+            .storage()
+            .instance()
+            .get(&DataKey::MigrationTarget)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::NotInitialized));
+        if read_user_activity(&e, &user).open_runs > 0 {
+            panic_with_error!(&e, VaultError::RunNotFinalized);
+        }
+        let amount = read_balance(&e, &user);
+        if amount == 0 {
+            return 0;
         }
+        write_balance(&e, &user, 0);
 
-        write_policy(&e, &user, &policy);
+        e.events().publish(
+            (symbol_short!("migrate"), symbol_short!("out")),
+            MigrationLog {
+                user: user.clone(),
+                counterparty: target.clone(),
+                amount,
+            },
+        );
+        PrepaidVaultClient::new(&e, &target).receive_migration(&user, &amount);
+        amount
+    }
 
-        let balance = read_balance(&e, &user);
-        if balance < max_charge {
-            panic_with_error!(&e, VaultError::InsufficientBalance);
+    /// Credits a balance moved from the migration source. Only that vault may call it.
+    pub fn receive_migration(e: Env, user: Address, amount: i128) {
+        let source: Address = e
+            .storage()
+            .instance()
+            .get(&DataKey::MigrationSource)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::Unauthorized));
+        source.require_auth();
+        if amount <= 0 {
+            panic_with_error!(&e, VaultError::InvalidAmount);
         }
-        write_balance(&e, &user, balance - max_charge);
+        let balance = read_balance(&e, &user);
+        let balance = balance
+            .checked_add(amount)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::InvalidAmount));
+        write_balance(&e, &user, balance);
 
-        let run_id = next_run_id(&e);
-        let record = RunRecord {
-            user: user.clone(),
-            opened_by: caller.clone(),
+        e.events().publish(
+            (symbol_short!("migrate"), symbol_short!("in")),
+            MigrationLog {
+                user,
+                counterparty: source,
+                amount,
+            },
+        );
+    }
+
+    /// Lets `operator` manage the user's policy, agent allowances and grant revocations
+    /// by passing itself as `caller`. Operators can never move funds or grant runners.
+    /// `None` removes the operator.
+    pub fn set_policy_operator(e: Env, user: Address, operator: Option<Address>) {
+        user.require_auth();
+        let key = DataKey::PolicyOperator(user);
+        match operator {
+            Some(operator) => e.storage().instance().set(&key, &operator),
+            None => e.storage().instance().remove(&key),
+        }
+    }
+
+    pub fn policy_operator_of(e: Env, user: Address) -> Option<Address> {
+        read_policy_operator(&e, &user)
+    }
+
+    /// Sets a key that may only emergency-pause the account via `guardian_pause`.
+    pub fn set_guardian(e: Env, user: Address, guardian: Option<Address>) {
+        user.require_auth();
+        let key = DataKey::Guardian(user);
+        match guardian {
+            Some(guardian) => e.storage().instance().set(&key, &guardian),
+            None => e.storage().instance().remove(&key),
+        }
+    }
+
+    pub fn guardian_of(e: Env, user: Address) -> Option<Address> {
+        e.storage().instance().get(&DataKey::Guardian(user))
+    }
+
+    /// Pauses the user's policy and revokes every runner and developer grant at once.
+    pub fn guardian_pause(e: Env, user: Address) {
+        let guardian: Address = e
+            .storage()
+            .instance()
+            .get(&DataKey::Guardian(user.clone()))
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::Unauthorized));
+        guardian.require_auth();
+
+        let mut policy = read_policy(&e, &user);
+        policy.paused = true;
+        write_policy(&e, &user, &policy);
+        revoke_all_grants(&e, &user);
+
+        e.events().publish(
+            (symbol_short!("guardian"), symbol_short!("paused")),
+            GuardianPauseLog {
+                user,
+                guardian,
+                paused_at: e.ledger().timestamp(),
+            },
+        );
+    }
+
+    /// Lifting a pause always needs the user's own key, so neither an operator nor a
+    /// guardian can undo an emergency pause.
+    pub fn set_policy(e: Env, user: Address, caller: Address, policy: PolicyInput) {
+        require_user_or_operator(&e, &user, &caller);
+        if policy.per_run_cap < 0
+            || policy.daily_cap < 0
+            || policy.rolling_cap < 0
+            || policy.force_close_after_secs < MIN_FORCE_CLOSE_SECS
+            || policy.cap_window_secs == 0
+            || policy.cap_window_offset_secs >= policy.cap_window_secs
+        {
+            panic_with_error!(&e, VaultError::InvalidAmount);
+        }
+        let mut stored = read_policy(&e, &user);
+        if stored.paused && !policy.paused && caller != user {
+            panic_with_error!(&e, VaultError::Unauthorized);
+        }
+        if stored.caps_in_usd != policy.caps_in_usd {
+            convert_cap_units(&e, &user, &mut stored, policy.caps_in_usd);
+        }
+        if stored.cap_window_secs != policy.cap_window_secs
+            || stored.cap_window_offset_secs != policy.cap_window_offset_secs
+        {
+            // The old reservation has no meaning in the new windows; start over in the
+            // window `now` falls in.
+            stored.cap_window_secs = policy.cap_window_secs;
+            stored.cap_window_offset_secs = policy.cap_window_offset_secs;
+            stored.reserved_today = 0;
+            stored.reserved_day = stored.cap_window(e.ledger().timestamp());
+        }
+        stored.caps_in_usd = policy.caps_in_usd;
+        stored.only_verified_agents = policy.only_verified_agents;
+        stored.per_run_cap = policy.per_run_cap;
+        stored.daily_cap = policy.daily_cap;
+        stored.rolling_cap = policy.rolling_cap;
+        stored.force_close_after_secs = policy.force_close_after_secs;
+        stored.paused = policy.paused;
+        stored.require_user_ack = policy.require_user_ack;
+        stored.default_run_ttl_secs = policy.default_run_ttl_secs;
+        stored.max_runs_per_hour = policy.max_runs_per_hour;
+        write_policy(&e, &user, &stored);
+    }
+
+    /// The user's policy, or the default for users who never set one. The reservation
+    /// and hourly run fields reflect the current period even if nothing happened since
+    /// the rollover.
+    pub fn get_policy(e: Env, user: Address) -> UserPolicy {
+        let mut policy = read_policy(&e, &user);
+        policy.ensure_window(e.ledger().timestamp());
+        policy.ensure_hour(e.ledger().timestamp() / 3600);
+        policy
+    }
+
+    /// What the user spent against their caps over the trailing `ROLLING_WINDOW_DAYS`
+    /// days, the figure `rolling_cap` is checked against. Spend is only tracked while a
+    /// rolling cap is set; refunds come off the day their run was opened.
+    pub fn rolling_spend(e: Env, user: Address) -> i128 {
+        let mut rolling = read_rolling_spend(&e, &user);
+        rolling.advance(current_day(&e));
+        rolling.total()
+    }
+
+    /// Exempts runs against `agent_id` from the user's per-run and daily caps. The pause
+    /// flag and balance checks still apply.
+    pub fn trust_agent(e: Env, user: Address, agent_id: u32) {
+        user.require_auth();
+        let mut trusted = read_trusted_agents(&e, &user);
+        if !trusted.contains(agent_id) {
+            trusted.push_back(agent_id);
+            write_trusted_agents(&e, &user, &trusted);
+        }
+    }
+
+    pub fn untrust_agent(e: Env, user: Address, agent_id: u32) {
+        user.require_auth();
+        let mut trusted = read_trusted_agents(&e, &user);
+        if let Some(index) = trusted.first_index_of(agent_id) {
+            trusted.remove(index);
+            write_trusted_agents(&e, &user, &trusted);
+        }
+    }
+
+    pub fn is_trusted(e: Env, user: Address, agent_id: u32) -> bool {
+        read_trusted_agents(&e, &user).contains(agent_id)
+    }
+
+    pub fn list_trusted(e: Env, user: Address) -> Vec<u32> {
+        read_trusted_agents(&e, &user)
+    }
+
+    /// How much more the user's daily cap allows reserving today, or `i128::MAX` when
+    /// no daily cap is set. Day rollover is applied without being persisted.
+    pub fn daily_headroom(e: Env, user: Address) -> i128 {
+        let mut policy = read_policy(&e, &user);
+        if policy.daily_cap == 0 {
+            return i128::MAX;
+        }
+        policy.ensure_window(e.ledger().timestamp());
+        (policy.daily_cap - policy.reserved_today).max(0)
+    }
+
+    /// Balance, caps, today's reservation and spend, and escrow held by open runs.
+    pub fn get_user_stats(e: Env, user: Address) -> UserStats {
+        let mut policy = read_policy(&e, &user);
+        policy.ensure_window(e.ledger().timestamp());
+        let activity = read_user_activity(&e, &user);
+        UserStats {
+            balance: read_balance(&e, &user),
+            per_run_cap: policy.per_run_cap,
+            daily_cap: policy.daily_cap,
+            paused: policy.paused,
+            reserved_today: policy.reserved_today,
+            spent_today: activity.spent_today,
+            open_runs: activity.open_runs,
+            escrowed: activity.escrowed,
+        }
+    }
+
+    /// The user's per-run cap; zero means uncapped.
+    pub fn per_run_cap_of(e: Env, user: Address) -> i128 {
+        read_policy(&e, &user).per_run_cap
+    }
+
+    /// Declares which asset the user's balance is held in. Runs and subscriptions can then
+    /// only be opened against rate cards denominated in that token. The balance is counted
+    /// in it, so it cannot change while the user holds a balance.
+    pub fn set_balance_token(e: Env, user: Address, token: Address) {
+        user.require_auth();
+        if read_balance_token(&e, &user).is_some_and(|current| current != token)
+            && (read_balance(&e, &user) != 0 || read_pending_withdrawal(&e, &user).is_some())
+        {
+            panic_with_error!(&e, VaultError::TokenMismatch);
+        }
+        write_persistent(&e, &DataKey::BalanceToken(user), &token);
+    }
+
+    pub fn balance_token_of(e: Env, user: Address) -> Option<Address> {
+        read_balance_token(&e, &user)
+    }
+
+    pub fn grant_runner(
+        e: Env,
+        user: Address,
+        runner: Address,
+        agent_id: u32,
+        expires_at: Option<u64>,
+    ) {
+        user.require_auth();
+        if runner == user {
+            panic_with_error!(&e, VaultError::SelfGrantNotAllowed);
+        }
+        if let Some(expires_at) = expires_at {
+            if expires_at <= e.ledger().timestamp() {
+                panic_with_error!(&e, VaultError::GrantAlreadyExpired);
+            }
+        }
+
+        let registry_addr = require_registry(&e);
+        let registry = AgentRegistryClient::new(&e, &registry_addr);
+        let is_runner = match registry.try_is_runner(&agent_id, &runner) {
+            Ok(Ok(is_runner)) => is_runner,
+            _ => panic_with_error!(&e, VaultError::AgentNotFound),
+        };
+        if !is_runner {
+            panic_with_error!(&e, VaultError::UnauthorizedRunner);
+        }
+
+        let grants = read_runner_grants(&e, &user);
+        let mut grants = prune_expired_grants(&e, grants);
+        for grant in grants.iter() {
+            if grant.runner == runner && grant.agent_id == agent_id {
+                panic_with_error!(&e, VaultError::RunnerGrantExists);
+            }
+        }
+
+        let grant = RunnerGrant {
+            runner: runner.clone(),
             agent_id,
-            rate_version,
-            budgets,
-            max_charge,
-            escrowed: max_charge,
-            opened_at: e.ledger().timestamp(),
-            lifecycle: RunLifecycle::Open,
+            issued_at: e.ledger().timestamp(),
+            expires_at,
+        };
+
+        grants.push_back(grant.clone());
+        write_runner_grants(&e, &user, &grants);
+
+        e.events().publish(
+            (symbol_short!("runner"), symbol_short!("granted")),
+            RunnerGrantLog {
+                user,
+                runner,
+                agent_id,
+                issued_at: grant.issued_at,
+                expires_at: grant.expires_at,
+            },
+        );
+    }
+
+    pub fn revoke_runner(e: Env, user: Address, caller: Address, runner: Address, agent_id: u32) {
+        require_user_or_operator(&e, &user, &caller);
+
+        let grants = read_runner_grants(&e, &user);
+        let grants = prune_expired_grants(&e, grants);
+        let (filtered, removed) = remove_runner_grant(&e, grants, &runner, agent_id);
+        if !removed {
+            panic_with_error!(&e, VaultError::RunnerGrantNotFound);
+        }
+        write_runner_grants(&e, &user, &filtered);
+
+        e.events().publish(
+            (symbol_short!("runner"), symbol_short!("revoked")),
+            RunnerRevokeLog {
+                user,
+                runner,
+                agent_id,
+                revoked_at: e.ledger().timestamp(),
+            },
+        );
+    }
+
+    /// Authorizes `runner` for every agent `developer` publishes. A live per-agent grant
+    /// for the same runner is checked first and governs that agent's expiry.
+    pub fn grant_runner_for_developer(
+        e: Env,
+        user: Address,
+        runner: Address,
+        developer: Address,
+        expires_at: Option<u64>,
+    ) {
+        user.require_auth();
+        if runner == user {
+            panic_with_error!(&e, VaultError::SelfGrantNotAllowed);
+        }
+        if let Some(expires_at) = expires_at {
+            if expires_at <= e.ledger().timestamp() {
+                panic_with_error!(&e, VaultError::GrantAlreadyExpired);
+            }
+        }
+
+        let grants = read_developer_grants(&e, &user);
+        let mut grants = prune_expired_developer_grants(&e, grants);
+        if grants
+            .iter()
+            .any(|grant| grant.runner == runner && grant.developer == developer)
+        {
+            panic_with_error!(&e, VaultError::RunnerGrantExists);
+        }
+
+        let grant = DeveloperGrant {
+            runner: runner.clone(),
+            developer: developer.clone(),
+            issued_at: e.ledger().timestamp(),
+            expires_at,
         };
+        grants.push_back(grant.clone());
+        write_developer_grants(&e, &user, &grants);
+
+        e.events().publish(
+            (symbol_short!("devgrant"), symbol_short!("granted")),
+            DeveloperGrantLog {
+                user,
+                runner,
+                developer,
+                issued_at: grant.issued_at,
+                expires_at: grant.expires_at,
+            },
+        );
+    }
+
+    pub fn revoke_runner_for_developer(
+        e: Env,
+        user: Address,
+        caller: Address,
+        runner: Address,
+        developer: Address,
+    ) {
+        require_user_or_operator(&e, &user, &caller);
+
+        let grants = read_developer_grants(&e, &user);
+        let grants = prune_expired_developer_grants(&e, grants);
+        let mut filtered = Vec::new(&e);
+        for grant in grants.iter() {
+            if grant.runner != runner || grant.developer != developer {
+                filtered.push_back(grant);
+            }
+        }
+        if filtered.len() == grants.len() {
+            panic_with_error!(&e, VaultError::RunnerGrantNotFound);
+        }
+        write_developer_grants(&e, &user, &filtered);
+
+        e.events().publish(
+            (symbol_short!("devgrant"), symbol_short!("revoked")),
+            DeveloperRevokeLog {
+                user,
+                runner,
+                developer,
+                revoked_at: e.ledger().timestamp(),
+            },
+        );
+    }
+
+    pub fn list_developer_grants(e: Env, user: Address) -> Vec<DeveloperGrant> {
+        let grants = read_developer_grants(&e, &user);
+        let grants = prune_expired_developer_grants(&e, grants);
+        write_developer_grants(&e, &user, &grants);
+        grants
+    }
+
+    /// Revokes every runner grant `user` has issued, per-agent and per-developer. Succeeds
+    /// when there are none.
+    pub fn revoke_all_runners(e: Env, user: Address, caller: Address) {
+        require_user_or_operator(&e, &user, &caller);
+        revoke_all_grants(&e, &user);
+    }
+
+    /// Moves the expiry of a live grant without revoking it, keeping its `issued_at`.
+    pub fn extend_runner_grant(
+        e: Env,
+        user: Address,
+        runner: Address,
+        agent_id: u32,
+        new_expires_at: Option<u64>,
+    ) {
+        user.require_auth();
+        if let Some(expires_at) = new_expires_at {
+            if expires_at <= e.ledger().timestamp() {
+                panic_with_error!(&e, VaultError::GrantAlreadyExpired);
+            }
+        }
+
+        let grants = read_runner_grants(&e, &user);
+        let mut grants = prune_expired_grants(&e, grants);
+        let index = grants
+            .iter()
+            .position(|grant| grant.runner == runner && grant.agent_id == agent_id)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::RunnerGrantNotFound));
+        let mut grant = grants.get_unchecked(index as u32);
+        grant.expires_at = new_expires_at;
+        grants.set(index as u32, grant.clone());
+        write_runner_grants(&e, &user, &grants);
+
+        e.events().publish(
+            (symbol_short!("runner"), symbol_short!("extended")),
+            RunnerGrantLog {
+                user,
+                runner,
+                agent_id,
+                issued_at: grant.issued_at,
+                expires_at: grant.expires_at,
+            },
+        );
+    }
+
+    /// The user's unexpired grants whose runner the registry still lists for the agent.
+    /// Read-only; stale entries stay in storage until `sweep_grants` or
+    /// `sweep_invalid_grants` removes them.
+    pub fn list_runner_grants(e: Env, user: Address) -> Vec<RunnerGrant> {
+        let grants = prune_expired_grants(&e, read_runner_grants(&e, &user));
+        if grants.is_empty() {
+            return grants;
+        }
+        let registry = AgentRegistryClient::new(&e, &require_registry(&e));
+        let mut listed = Vec::new(&e);
+        for grant in grants.iter() {
+            if registry.is_runner(&grant.agent_id, &grant.runner) {
+                listed.push_back(grant);
+            }
+        }
+        listed
+    }
+
+    /// Removes the user's expired grants, emitting a revoke event for each, and returns
+    /// how many were removed. Callable by anyone.
+    pub fn sweep_grants(e: Env, user: Address) -> u32 {
+        let grants = read_runner_grants(&e, &user);
+        let now = e.ledger().timestamp();
+        let mut kept = Vec::new(&e);
+        let mut removed = 0u32;
+        for grant in grants.iter() {
+            match grant.expires_at {
+                Some(expiry) if expiry <= now => {
+                    removed += 1;
+                    e.events().publish(
+                        (symbol_short!("runner"), symbol_short!("revoked")),
+                        RunnerRevokeLog {
+                            user: user.clone(),
+                            runner: grant.runner,
+                            agent_id: grant.agent_id,
+                            revoked_at: now,
+                        },
+                    );
+                }
+                _ => kept.push_back(grant),
+            }
+        }
+        if removed > 0 {
+            write_runner_grants(&e, &user, &kept);
+        }
+        removed
+    }
+
+    /// Drops the user's grants for runners the registry has removed. Callable by anyone.
+    pub fn sweep_invalid_grants(e: Env, user: Address) {
+        sweep_runner_grants(&e, &user);
+    }
+
+    /// Limits the escrow `runner` may open against the user's balance per day, across
+    /// all agents. Escrow counts when the run opens and is not given back on settlement.
+    pub fn set_runner_cap(e: Env, user: Address, runner: Address, cap: i128) {
+        user.require_auth();
+        if cap <= 0 {
+            panic_with_error!(&e, VaultError::InvalidAmount);
+        }
+        let mut caps = read_runner_caps(&e, &user);
+        let mut runner_cap = caps.get(runner.clone()).unwrap_or(RunnerCap {
+            cap,
+            used_today: 0,
+            day: current_day(&e),
+        });
+        runner_cap.cap = cap;
+        caps.set(runner, runner_cap);
+        write_runner_caps(&e, &user, &caps);
+    }
+
+    pub fn clear_runner_cap(e: Env, user: Address, runner: Address) {
+        user.require_auth();
+        let mut caps = read_runner_caps(&e, &user);
+        caps.remove(runner);
+        write_runner_caps(&e, &user, &caps);
+    }
+
+    pub fn list_runner_caps(e: Env, user: Address) -> Map<Address, RunnerCap> {
+        let today = current_day(&e);
+        let mut caps = read_runner_caps(&e, &user);
+        for (runner, mut runner_cap) in caps.clone().iter() {
+            runner_cap.ensure_day(today);
+            caps.set(runner, runner_cap);
+        }
+        caps
+    }
+
+    /// Lets `session_key` open runs on the user's behalf until `expires_at`, escrowing at
+    /// most `max_total_spend` in total. Session keys can do nothing else; an expired or
+    /// exhausted session fails with `AllowanceExpired` or `AllowanceExceeded`.
+    /// Re-authorizing a key resets what it has spent.
+    pub fn authorize_session(
+        e: Env,
+        user: Address,
+        session_key: Address,
+        expires_at: u64,
+        max_total_spend: i128,
+    ) {
+        user.require_auth();
+        if session_key == user {
+            panic_with_error!(&e, VaultError::SelfGrantNotAllowed);
+        }
+        if expires_at <= e.ledger().timestamp() {
+            panic_with_error!(&e, VaultError::GrantAlreadyExpired);
+        }
+        if max_total_spend <= 0 {
+            panic_with_error!(&e, VaultError::InvalidAmount);
+        }
+
+        e.storage().instance().set(
+            &DataKey::Session(user.clone(), session_key.clone()),
+            &SessionKey {
+                expires_at,
+                max_total_spend,
+                spent: 0,
+            },
+        );
+        e.events().publish(
+            (symbol_short!("session"), symbol_short!("granted")),
+            SessionLog {
+                user,
+                session_key,
+                expires_at,
+                max_total_spend,
+            },
+        );
+    }
+
+    pub fn revoke_session(e: Env, user: Address, session_key: Address) {
+        user.require_auth();
+        let session = read_session(&e, &user, &session_key)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::RunnerGrantNotFound));
+        e.storage()
+            .instance()
+            .remove(&DataKey::Session(user.clone(), session_key.clone()));
+        e.events().publish(
+            (symbol_short!("session"), symbol_short!("revoked")),
+            SessionLog {
+                user,
+                session_key,
+                expires_at: session.expires_at,
+                max_total_spend: session.max_total_spend,
+            },
+        );
+    }
+
+    pub fn get_session(e: Env, user: Address, session_key: Address) -> Option<SessionKey> {
+        read_session(&e, &user, &session_key)
+    }
+
+    /// Pre-approves a spending envelope that granted runners draw from when they open
+    /// runs for `agent_id` on the user's behalf. Refunds are returned to the envelope.
+    pub fn set_agent_allowance(
+        e: Env,
+        user: Address,
+        caller: Address,
+        agent_id: u32,
+        amount: i128,
+        expires_at: Option<u64>,
+    ) {
+        require_user_or_operator(&e, &user, &caller);
+        if amount < 0 {
+            panic_with_error!(&e, VaultError::InvalidAmount);
+        }
+
+        write_allowance(&e, &user, agent_id, &AgentAllowance { amount, expires_at });
+
+        e.events().publish(
+            (symbol_short!("allow"), symbol_short!("set")),
+            AllowanceSetLog {
+                user,
+                agent_id,
+                amount,
+                expires_at,
+            },
+        );
+    }
+
+    pub fn get_agent_allowance(e: Env, user: Address, agent_id: u32) -> Option<AgentAllowance> {
+        read_allowance(&e, &user, agent_id)
+    }
+
+    /// Free units left today for each meter in the agent's current rate card.
+    pub fn free_units_remaining(e: Env, user: Address, agent_id: u32) -> Map<Symbol, i128> {
+        let registry_addr = require_registry(&e);
+        let registry = AgentRegistryClient::new(&e, &registry_addr);
+        let rate_version = registry.latest_rate_version(&agent_id);
+        let rate_card = registry.get_rate_card(&agent_id, &rate_version);
+        let free_usage = read_free_usage(&e, &user, agent_id);
+
+        let mut remaining = Map::new(&e);
+        for (meter, allowance) in rate_card.free_daily_units.iter() {
+            let used = free_usage.consumed.get(meter.clone()).unwrap_or(0);
+            remaining.set(meter, (allowance - used).max(0));
+        }
+        remaining
+    }
+
+    pub fn is_runner_authorized(e: Env, user: Address, runner: Address, agent_id: u32) -> bool {
+        ensure_runner_authorized(&e, &user, &runner, agent_id)
+    }
+
+    /// Stores `budgets` under `name` for `open_run_with_preset`, replacing any preset of
+    /// the same name.
+    pub fn save_budget_preset(e: Env, user: Address, name: Symbol, budgets: UsageBreakdown) {
+        user.require_auth();
+        if !validate_non_negative_usage(&budgets) {
+            panic_with_error!(&e, VaultError::InvalidAmount);
+        }
+        let mut presets = read_budget_presets(&e, &user);
+        if !presets.contains_key(name.clone()) && presets.len() >= MAX_BUDGET_PRESETS {
+            panic_with_error!(&e, VaultError::TooManyPresets);
+        }
+        presets.set(name, budgets);
+        write_budget_presets(&e, &user, &presets);
+    }
+
+    pub fn delete_budget_preset(e: Env, user: Address, name: Symbol) {
+        user.require_auth();
+        let mut presets = read_budget_presets(&e, &user);
+        if presets.remove(name).is_none() {
+            panic_with_error!(&e, VaultError::PresetNotFound);
+        }
+        write_budget_presets(&e, &user, &presets);
+    }
+
+    pub fn list_budget_presets(e: Env, user: Address) -> Map<Symbol, UsageBreakdown> {
+        read_budget_presets(&e, &user)
+    }
+
+    /// `open_run` with the budgets the user saved under `preset`.
+    pub fn open_run_with_preset(
+        e: Env,
+        user: Address,
+        caller: Address,
+        agent_id: u32,
+        rate_version: u32,
+        preset: Symbol,
+        input_hash: BytesN<32>,
+        memo: Option<String>,
+    ) -> OpenRunResult {
+        let budgets = read_budget_presets(&e, &user)
+            .get(preset)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::PresetNotFound));
+        Self::open_run(
+            e,
+            user,
+            caller,
+            agent_id,
+            rate_version,
+            budgets,
+            input_hash,
+            memo,
+        )
+    }
+
+    /// Escrows the most `budgets` can cost at `rate_version`, or at the agent's latest
+    /// effective version when `rate_version` is 0. The version used is returned and logged.
+    /// `input_hash` identifies the job so the run's receipt can be matched to its input;
+    /// `memo` is a label of at most 64 bytes kept on the run for statements.
+    pub fn open_run(
+        e: Env,
+        user: Address,
+        caller: Address,
+        agent_id: u32,
+        rate_version: u32,
+        budgets: UsageBreakdown,
+        input_hash: BytesN<32>,
+        memo: Option<String>,
+    ) -> OpenRunResult {
+        Self::open_run_with_ttl(
+            e,
+            user,
+            caller,
+            agent_id,
+            rate_version,
+            budgets,
+            input_hash,
+            None,
+            memo,
+        )
+    }
+
+    /// `open_run` with a lifetime of `ttl_secs`, which may shorten but never lengthen the
+    /// user's `default_run_ttl_secs`. Without one the policy TTL applies, if any.
+    pub fn open_run_with_ttl(
+        e: Env,
+        user: Address,
+        caller: Address,
+        agent_id: u32,
+        rate_version: u32,
+        budgets: UsageBreakdown,
+        input_hash: BytesN<32>,
+        ttl_secs: Option<u64>,
+        memo: Option<String>,
+    ) -> OpenRunResult {
+        open_user_run(
+            &e,
+            user,
+            caller,
+            agent_id,
+            rate_version,
+            budgets,
+            input_hash,
+            ttl_secs,
+            0,
+            memo,
+            None,
+        )
+    }
+
+    /// `open_run` that also pays `priority_fee` to whichever runner settles the run, on
+    /// top of the charge. The fee is escrowed with the run and counts against the same
+    /// caps, runner caps and allowances as `max_charge`; a cancelled or expired run
+    /// refunds it.
+    pub fn open_run_with_priority_fee(
+        e: Env,
+        user: Address,
+        caller: Address,
+        agent_id: u32,
+        rate_version: u32,
+        budgets: UsageBreakdown,
+        input_hash: BytesN<32>,
+        priority_fee: i128,
+        memo: Option<String>,
+    ) -> OpenRunResult {
+        if priority_fee < 0 {
+            panic_with_error!(&e, VaultError::InvalidAmount);
+        }
+        open_user_run(
+            &e,
+            user,
+            caller,
+            agent_id,
+            rate_version,
+            budgets,
+            input_hash,
+            None,
+            priority_fee,
+            memo,
+            None,
+        )
+    }
+
+    /// The charge `finalize_run` computes for `usage` at `rates`, with no base fee and a
+    /// unit scale of 1. Fails with `ChargeOverflow` when the charge does not fit an i128.
+    pub fn compute_charge_view(e: Env, rates: UsageMeterRates, usage: UsageBreakdown) -> i128 {
+        charge_or_panic(&e, compute_charge(&rates, 0, &usage, 1))
+    }
+
+    /// `compute_charge_view` at an agent's rate card, including its base fee and unit
+    /// scale but before free units and the minimum charge. A `rate_version` of 0 uses the
+    /// latest effective version.
+    pub fn compute_charge_for_agent(
+        e: Env,
+        agent_id: u32,
+        rate_version: u32,
+        usage: UsageBreakdown,
+    ) -> i128 {
+        let registry_addr = require_registry(&e);
+        let registry = AgentRegistryClient::new(&e, &registry_addr);
+        let rate_version = resolve_rate_version(&e, &registry, agent_id, rate_version);
+        let rate_card = fetch_rate_card(&e, &registry, agent_id, rate_version);
+        charge_or_panic(
+            &e,
+            compute_charge(
+                &rate_card.rates,
+                rate_card.base_fee,
+                &usage,
+                rate_card.unit_scale,
+            ),
+        )
+    }
+
+    /// Prices `budgets` against the rate card `open_run` would use, returning the amount
+    /// it would escrow. A `rate_version` of 0 quotes the latest effective version.
+    pub fn quote_run(e: Env, agent_id: u32, rate_version: u32, budgets: UsageBreakdown) -> i128 {
+        quote_max_charge(&e, agent_id, rate_version, &budgets).0
+    }
+
+    /// Like `quote_run`, and also reports whether the user's current caps and balance
+    /// would let the run open.
+    pub fn quote_run_for(
+        e: Env,
+        user: Address,
+        agent_id: u32,
+        rate_version: u32,
+        budgets: UsageBreakdown,
+    ) -> QuoteResult {
+        let (max_charge, token) = quote_max_charge(&e, agent_id, rate_version, &budgets);
+        let mut policy = read_policy(&e, &user);
+        policy.ensure_window(e.ledger().timestamp());
+        let cap_exempt = read_trusted_agents(&e, &user).contains(agent_id);
+        let capped = if cap_exempt {
+            max_charge
+        } else {
+            cap_amount(&e, &policy, &token, max_charge)
+        };
+
+        QuoteResult {
+            max_charge,
+            within_per_run_cap: cap_exempt
+                || policy.per_run_cap == 0
+                || capped <= policy.per_run_cap,
+            within_daily_cap: cap_exempt
+                || policy.daily_cap == 0
+                || policy
+                    .reserved_today
+                    .checked_add(capped)
+                    .is_some_and(|reserved| reserved <= policy.daily_cap),
+            sufficient_balance: read_balance(&e, &user) >= max_charge,
+        }
+    }
+
+    /// Runs settled with `output_hash`, oldest first, a page at a time. The all-zero hash
+    /// is never indexed.
+    pub fn runs_by_output_hash(
+        e: Env,
+        output_hash: BytesN<32>,
+        offset: u32,
+        limit: u32,
+    ) -> Vec<u64> {
+        let end = read_run_output_count(&e, &output_hash)
+            .min(offset.saturating_add(limit.min(MAX_PAGE_SIZE)));
+        let mut runs = Vec::new(&e);
+        for index in offset..end {
+            let key = DataKey::RunByOutput(output_hash.clone(), index);
+            if let Some(run_id) = e.storage().persistent().get::<_, u64>(&key) {
+                runs.push_back(run_id);
+            }
+        }
+        runs
+    }
+
+    /// Opens a run for `user` escrowed from `sponsor`'s balance. The run is checked
+    /// against the sponsor's policy and caps, and its refunds go back to the sponsor,
+    /// while `user` receives the service and appears as the run's user. `caller` is
+    /// `user` or a runner `user` has granted.
+    pub fn open_sponsored_run(
+        e: Env,
+        sponsor: Address,
+        user: Address,
+        caller: Address,
+        agent_id: u32,
+        rate_version: u32,
+        budgets: UsageBreakdown,
+        input_hash: BytesN<32>,
+        memo: Option<String>,
+    ) -> OpenRunResult {
+        sponsor.require_auth();
+        if sponsor == user {
+            panic_with_error!(&e, SponsorError::SponsorIsUser);
+        }
+        open_user_run(
+            &e,
+            user,
+            caller,
+            agent_id,
+            rate_version,
+            budgets,
+            input_hash,
+            None,
+            0,
+            memo,
+            Some(sponsor),
+        )
+    }
+
+    pub fn finalize_run(
+        e: Env,
+        run_id: u64,
+        runner: Address,
+        rate_version: u32,
+        usage: UsageBreakdown,
+        output_hash: BytesN<32>,
+    ) -> RunReceipt {
+        runner.require_auth();
+
+        let (record, info) = read_settleable_run(&e, run_id, &runner, &usage);
+        if rate_version != record.rate_version {
+            panic_with_error!(&e, VaultError::InvalidRateVersion);
+        }
+        if record.require_user_ack {
+            panic_with_error!(&e, VaultError::SettlementAckRequired);
+        }
+
+        extend_instance_ttl(&e);
+        settle_run(&e, run_id, record, info, runner, usage, output_hash, None)
+    }
+
+    /// Settles a run that failed partway for the usage it consumed before failing, less
+    /// the vault's `failure_discount_bps`. There is no output to record; `failure_code`
+    /// is the runner's reason and is kept on the settlement, receipt and event.
+    pub fn finalize_failed(
+        e: Env,
+        run_id: u64,
+        runner: Address,
+        usage: UsageBreakdown,
+        failure_code: u32,
+    ) -> RunReceipt {
+        runner.require_auth();
+
+        let (record, info) = read_settleable_run(&e, run_id, &runner, &usage);
+        if record.require_user_ack {
+            panic_with_error!(&e, VaultError::SettlementAckRequired);
+        }
+
+        extend_instance_ttl(&e);
+        let output_hash = BytesN::from_array(&e, &[0; 32]);
+        settle_run(
+            &e,
+            run_id,
+            record,
+            info,
+            runner,
+            usage,
+            output_hash,
+            Some(failure_code),
+        )
+    }
+
+    /// Pays the developer for `usage_delta` straight out of the run's escrow at the pinned
+    /// rate version. `finalize_run` then reports only the usage not streamed yet, and the
+    /// streamed and final charges together never exceed `max_charge`. Each call charges
+    /// the price of all usage streamed so far less what was already paid, so streaming in
+    /// chunks rounds no differently from reporting the usage at once.
+    pub fn charge_run(e: Env, run_id: u64, runner: Address, usage_delta: UsageBreakdown) -> i128 {
+        runner.require_auth();
+
+        let (mut record, info) = read_settleable_run(&e, run_id, &runner, &usage_delta);
+        if record.require_user_ack {
+            panic_with_error!(&e, VaultError::SettlementAckRequired);
+        }
+
+        let pricing = run_rate_snapshot(&e, &record, &info);
+        let developer = info.payout;
+
+        let mut free_usage = read_free_usage(&e, &record.user, record.agent_id);
+        let billable = deduct_free_units(
+            &e,
+            &usage_delta,
+            &pricing.free_daily_units,
+            &mut free_usage.consumed,
+        );
+        let streamed_billable = record
+            .streamed_billable
+            .checked_add(&billable)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::InvalidAmount));
+        let cumulative_charge = charge_or_panic(
+            &e,
+            compute_charge(&pricing.rates, 0, &streamed_billable, pricing.unit_scale),
+        );
+        let charge = cumulative_charge - record.streamed_charge;
+        if cumulative_charge > record.max_charge {
+            panic_with_error!(&e, VaultError::UsageExceedsBudget);
+        }
+
+        if !pricing.free_daily_units.is_empty() {
+            write_free_usage(&e, &record.user, record.agent_id, &free_usage);
+        }
+        record_settled_outflow(&e, charge);
+        credit_settlement(&e, run_id, &record, &developer, charge);
+
+        record.streamed_usage = streamed_total(&e, &record, &usage_delta);
+        record.streamed_charge = cumulative_charge;
+        record.streamed_billable = streamed_billable;
+        record.escrowed -= charge;
+        write_run(&e, run_id, &record);
+        track_user_activity(&e, &run_payer(&record), 0, -charge, charge);
+
+        e.events().publish(
+            (symbol_short!("run"), symbol_short!("charged")),
+            RunChargedLog {
+                run_id,
+                runner,
+                charge,
+                cumulative_charge,
+                cumulative_usage: record.streamed_usage,
+                charged_at: e.ledger().timestamp(),
+            },
+        );
+
+        charge
+    }
+
+    /// Records the runner's reported usage for a run whose user settles it with
+    /// `accept_settlement`. Nothing is charged until then, and a proposal the user leaves
+    /// unanswered for `SETTLEMENT_RESPONSE_SECS` can be refunded through `expire_run`.
+    pub fn propose_settlement(
+        e: Env,
+        run_id: u64,
+        runner: Address,
+        usage: UsageBreakdown,
+        output_hash: BytesN<32>,
+    ) {
+        runner.require_auth();
+
+        let (mut record, _) = read_settleable_run(&e, run_id, &runner, &usage);
+        let proposal = ProposedSettlement {
+            runner: runner.clone(),
+            usage: usage.clone(),
+            output_hash: output_hash.clone(),
+            proposed_at: e.ledger().timestamp(),
+            respond_by: e.ledger().timestamp() + SETTLEMENT_RESPONSE_SECS,
+        };
+        record.lifecycle = RunLifecycle::Proposed(proposal.clone());
+        write_run(&e, run_id, &record);
+
+        e.events().publish(
+            (symbol_short!("run"), symbol_short!("proposed")),
+            SettlementProposedLog {
+                run_id,
+                runner,
+                usage,
+                output_hash,
+                proposed_at: proposal.proposed_at,
+                respond_by: proposal.respond_by,
+            },
+        );
+    }
+
+    /// Settles a proposed run exactly as `finalize_run` would have.
+    pub fn accept_settlement(e: Env, run_id: u64, user: Address) -> RunReceipt {
+        user.require_auth();
+
+        let record = read_run_or_panic(&e, run_id);
+        if record.user != user {
+            panic_with_error!(&e, VaultError::Unauthorized);
+        }
+        let proposal = match record.lifecycle.clone() {
+            RunLifecycle::Proposed(proposal) => proposal,
+            _ => panic_with_error!(&e, VaultError::SettlementNotProposed),
+        };
+
+        let registry_addr = require_registry(&e);
+        let registry = AgentRegistryClient::new(&e, &registry_addr);
+        let info = fetch_settlement_info(&e, &registry, &record);
+        settle_run(
+            &e,
+            run_id,
+            record,
+            info,
+            proposal.runner,
+            proposal.usage,
+            proposal.output_hash,
+            None,
+        )
+    }
+
+    /// Refuses a proposed settlement, leaving the run disputed with its escrow held.
+    pub fn reject_settlement(e: Env, run_id: u64, user: Address) {
+        user.require_auth();
+
+        let mut record = read_run_or_panic(&e, run_id);
+        if record.user != user {
+            panic_with_error!(&e, VaultError::Unauthorized);
+        }
+        let proposal = match record.lifecycle.clone() {
+            RunLifecycle::Proposed(proposal) => proposal,
+            _ => panic_with_error!(&e, VaultError::SettlementNotProposed),
+        };
+        record.lifecycle = RunLifecycle::Disputed(proposal);
+        write_run(&e, run_id, &record);
+
+        e.events().publish(
+            (symbol_short!("run"), symbol_short!("disputed")),
+            RunDisputedLog {
+                run_id,
+                user,
+                disputed_at: e.ledger().timestamp(),
+            },
+        );
+    }
+
+    /// Ends a dispute by settling the run for the `usage` the admin found, within its
+    /// budgets, as the proposal's runner and output.
+    pub fn settle_dispute(e: Env, run_id: u64, usage: UsageBreakdown) -> RunReceipt {
+        read_admin(&e).require_auth();
+
+        let record = read_run_or_panic(&e, run_id);
+        let proposal = match record.lifecycle.clone() {
+            RunLifecycle::Disputed(proposal) => proposal,
+            _ => panic_with_error!(&e, DisputeError::RunNotDisputed),
+        };
+        if !validate_non_negative_usage(&usage) {
+            panic_with_error!(&e, VaultError::InvalidAmount);
+        }
+        if exceeds_budget(&streamed_total(&e, &record, &usage), &record.budgets) {
+            panic_with_error!(&e, VaultError::UsageExceedsBudget);
+        }
+
+        extend_instance_ttl(&e);
+        let registry_addr = require_registry(&e);
+        let registry = AgentRegistryClient::new(&e, &registry_addr);
+        let info = fetch_settlement_info(&e, &registry, &record);
+        settle_run(
+            &e,
+            run_id,
+            record,
+            info,
+            proposal.runner,
+            usage,
+            proposal.output_hash,
+            None,
+        )
+    }
+
+    /// Ends a dispute in the user's favour, returning the run's escrow as `cancel_run`
+    /// would.
+    pub fn void_dispute(e: Env, run_id: u64) {
+        read_admin(&e).require_auth();
+
+        let mut record = read_run_or_panic(&e, run_id);
+        match record.lifecycle {
+            RunLifecycle::Disputed(_) => {}
+            _ => panic_with_error!(&e, DisputeError::RunNotDisputed),
+        }
+
+        let refunded = record.escrowed - record.priority_fee;
+        refund_escrow(&e, &mut record);
+        record.lifecycle = RunLifecycle::Cancelled;
+
+        write_run(&e, run_id, &record);
+        record_run_cancelled(&e, record.agent_id);
+        publish_run_event(
+            &e,
+            symbol_short!("cancelled"),
+            record.agent_id,
+            RunClosedLog {
+                run_id,
+                agent_id: record.agent_id,
+                user: record.user,
+                sponsor: record.sponsor,
+                refunded,
+                priority_fee: record.priority_fee,
+                closed_at: e.ledger().timestamp(),
+            },
+        );
+    }
+
+    pub fn cancel_run(e: Env, user: Address, run_id: u64) {
+        user.require_auth();
+        let mut record = read_run_or_panic(&e, run_id);
+        if record.user != user && record.sponsor.as_ref() != Some(&user) {
+            panic_with_error!(&e, VaultError::Unauthorized);
+        }
+        match record.lifecycle {
+            RunLifecycle::Open => {}
+            _ => panic_with_error!(&e, VaultError::RunNotOpen),
+        }
+
+        let refunded = record.escrowed - record.priority_fee;
+        refund_escrow(&e, &mut record);
+        record.lifecycle = RunLifecycle::Cancelled;
+
+        write_run(&e, run_id, &record);
+        record_run_cancelled(&e, record.agent_id);
+        publish_run_event(
+            &e,
+            symbol_short!("cancelled"),
+            record.agent_id,
+            RunClosedLog {
+                run_id,
+                agent_id: record.agent_id,
+                user: record.user,
+                sponsor: record.sponsor,
+                refunded,
+                priority_fee: record.priority_fee,
+                closed_at: e.ledger().timestamp(),
+            },
+        );
+    }
+
+    /// Returns the escrow to the user of an open run past its `expires_at`, or of a
+    /// proposed run whose user let its `respond_by` pass. Callable by anyone once the run
+    /// has expired.
+    pub fn expire_run(e: Env, run_id: u64) {
+        let mut record = read_run_or_panic(&e, run_id);
+        let expired = match &record.lifecycle {
+            RunLifecycle::Open => is_run_expired(&e, &record),
+            RunLifecycle::Proposed(proposal) => proposal.respond_by < e.ledger().timestamp(),
+            _ => panic_with_error!(&e, VaultError::RunNotOpen),
+        };
+        if !expired {
+            panic_with_error!(&e, VaultError::RunNotExpired);
+        }
+
+        let refunded = record.escrowed - record.priority_fee;
+        refund_escrow(&e, &mut record);
+        record.lifecycle = RunLifecycle::Expired;
+
+        write_run(&e, run_id, &record);
+        record_run_cancelled(&e, record.agent_id);
+        publish_run_event(
+            &e,
+            symbol_short!("expired"),
+            record.agent_id,
+            RunClosedLog {
+                run_id,
+                agent_id: record.agent_id,
+                user: record.user,
+                sponsor: record.sponsor,
+                refunded,
+                priority_fee: record.priority_fee,
+                closed_at: e.ledger().timestamp(),
+            },
+        );
+    }
+
+    /// Escape hatch for a run its runner never settles: once the user's
+    /// `force_close_after_secs` has passed since opening, the user closes it and its
+    /// escrow is returned. The runner can no longer settle it afterwards.
+    pub fn force_close_run(e: Env, user: Address, run_id: u64) {
+        user.require_auth();
+        let mut record = read_run_or_panic(&e, run_id);
+        if record.user != user {
+            panic_with_error!(&e, VaultError::Unauthorized);
+        }
+        match record.lifecycle {
+            RunLifecycle::Open => {}
+            _ => panic_with_error!(&e, VaultError::RunNotOpen),
+        }
+        let wait = read_policy(&e, &user)
+            .force_close_after_secs
+            .max(MIN_FORCE_CLOSE_SECS);
+        if e.ledger().timestamp().saturating_sub(record.opened_at) <= wait {
+            panic_with_error!(&e, VaultError::RunNotExpired);
+        }
+
+        let refunded = record.escrowed - record.priority_fee;
+        refund_escrow(&e, &mut record);
+        record.lifecycle = RunLifecycle::ForceClosed;
+
+        write_run(&e, run_id, &record);
+        record_run_cancelled(&e, record.agent_id);
+        publish_run_event(
+            &e,
+            symbol_short!("forced"),
+            record.agent_id,
+            RunClosedLog {
+                run_id,
+                agent_id: record.agent_id,
+                user: record.user,
+                sponsor: record.sponsor,
+                refunded,
+                priority_fee: record.priority_fee,
+                closed_at: e.ledger().timestamp(),
+            },
+        );
+    }
+
+    pub fn open_subscription(
+        e: Env,
+        user: Address,
+        agent_id: u32,
+        rate_version: u32,
+    ) -> Subscription {
+        user.require_auth();
+        ensure_not_paused(&e);
+
+        let registry_addr = require_registry(&e);
+        let registry = AgentRegistryClient::new(&e, &registry_addr);
+        let rate_card = load_openable_rate_card(&e, &registry, agent_id, rate_version);
+        ensure_token_matches(&e, &user, &rate_card);
+        let plan = match rate_card.subscription {
+            SubscriptionOffer::Offered(plan) => plan,
+            SubscriptionOffer::NotOffered => {
+                panic_with_error!(&e, VaultError::SubscriptionNotOffered)
+            }
+        };
+
+        let now = e.ledger().timestamp();
+        if let Some(existing) = read_subscription(&e, &user, agent_id) {
+            if existing.expires_at > now {
+                panic_with_error!(&e, VaultError::SubscriptionActive);
+            }
+            if !existing.released {
+                release_subscription_to_developer(&e, &registry, &user, existing);
+            }
+        }
+
+        let reserved = reserve_and_debit(&e, &user, plan.price, &rate_card.token, false);
+
+        let expires_at = now
+            .checked_add(plan.period_secs)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::InvalidAmount));
+        let subscription = Subscription {
+            agent_id,
+            rate_version,
+            price: plan.price,
+            started_at: now,
+            expires_at,
+            released: false,
+            reserved,
+        };
+        write_subscription(&e, &user, &subscription);
+
+        e.events().publish(
+            (symbol_short!("sub"), symbol_short!("opened")),
+            SubscriptionOpenedLog {
+                user,
+                agent_id,
+                rate_version,
+                price: subscription.price,
+                started_at: subscription.started_at,
+                expires_at: subscription.expires_at,
+            },
+        );
+
+        subscription
+    }
+
+    /// Credits the developer with a subscription's price once its period has elapsed.
+    /// Callable by anyone so developers or keepers can settle lapsed subscriptions.
+    pub fn release_subscription(e: Env, user: Address, agent_id: u32) -> i128 {
+        let subscription = match read_subscription(&e, &user, agent_id) {
+            Some(subscription) if !subscription.released => subscription,
+            _ => panic_with_error!(&e, VaultError::SubscriptionNotFound),
+        };
+        if subscription.expires_at > e.ledger().timestamp() {
+            panic_with_error!(&e, VaultError::SubscriptionActive);
+        }
+
+        let registry_addr = require_registry(&e);
+        let registry = AgentRegistryClient::new(&e, &registry_addr);
+        release_subscription_to_developer(&e, &registry, &user, subscription)
+    }
+
+    pub fn has_active_subscription(e: Env, user: Address, agent_id: u32) -> bool {
+        match read_subscription(&e, &user, agent_id) {
+            Some(subscription) => subscription.expires_at > e.ledger().timestamp(),
+            None => false,
+        }
+    }
+
+    pub fn get_subscription(e: Env, user: Address, agent_id: u32) -> Option<Subscription> {
+        read_subscription(&e, &user, agent_id)
+    }
+
+    pub fn agent_stats(e: Env, agent_id: u32) -> AgentStats {
+        read_agent_stats(&e, agent_id)
+    }
+
+    /// Total the user has been charged for finalized runs against `agent_id`.
+    pub fn spend_on_agent(e: Env, user: Address, agent_id: u32) -> i128 {
+        read_user_agent_spend(&e, &user, agent_id)
+    }
+
+    /// Batch form of `spend_on_agent`, answering in input order.
+    pub fn list_agent_spend(e: Env, user: Address, agent_ids: Vec<u32>) -> Vec<i128> {
+        let mut spend = Vec::new(&e);
+        for agent_id in agent_ids.iter() {
+            spend.push_back(read_user_agent_spend(&e, &user, agent_id));
+        }
+        spend
+    }
+
+    pub fn balance_of(e: Env, user: Address) -> i128 {
+        read_balance(&e, &user)
+    }
+
+    pub fn developer_balance(e: Env, developer: Address) -> i128 {
+        read_developer_balance(&e, &developer)
+    }
+
+    /// Lifetime amount credited to `developer` for runs and tips on `agent_id`. Refunds
+    /// and claims do not reduce it.
+    pub fn earnings_for_agent(e: Env, developer: Address, agent_id: u32) -> i128 {
+        read_agent_earnings(&e, &developer, agent_id)
+    }
+
+    pub fn earnings_breakdown(e: Env, developer: Address, agent_ids: Vec<u32>) -> Vec<i128> {
+        let mut earnings = Vec::new(&e);
+        for agent_id in agent_ids.iter() {
+            earnings.push_back(read_agent_earnings(&e, &developer, agent_id));
+        }
+        earnings
+    }
+
+    pub fn claim_developer(e: Env, developer: Address, amount: i128) {
+        Self::claim_developer_to(e, developer.clone(), developer, amount);
+    }
+
+    /// Claims `amount` of the developer's earnings on behalf of `destination`, e.g. a
+    /// treasury multisig. The developer still authorizes the claim.
+    pub fn claim_developer_to(e: Env, developer: Address, destination: Address, amount: i128) {
+        developer.require_auth();
+        ensure_not_paused(&e);
+        if amount <= 0 {
+            panic_with_error!(&e, VaultError::InvalidAmount);
+        }
+        let balance = read_developer_balance(&e, &developer);
+        if balance < amount {
+            panic_with_error!(&e, VaultError::InsufficientBalance);
+        }
+        write_developer_balance(&e, &developer, balance - amount);
+        publish_developer_claim(&e, developer, destination, amount);
+    }
+
+    /// Claims the developer's entire balance and returns the amount claimed. Claiming an
+    /// empty balance is a no-op returning 0.
+    pub fn claim_developer_all(e: Env, developer: Address) -> i128 {
+        developer.require_auth();
+        ensure_not_paused(&e);
+        let amount = read_developer_balance(&e, &developer);
+        if amount == 0 {
+            return 0;
+        }
+        write_developer_balance(&e, &developer, 0);
+        publish_developer_claim(&e, developer.clone(), developer, amount);
+        amount
+    }
+
+    /// Pays the developer credited for a finalized run an extra `amount` from the user's
+    /// balance. Tips are not counted against the user's caps.
+    pub fn tip_run(e: Env, user: Address, run_id: u64, amount: i128) {
+        user.require_auth();
+        ensure_not_paused(&e);
+        if amount <= 0 {
+            panic_with_error!(&e, VaultError::InvalidAmount);
+        }
+
+        let mut record = read_run_or_panic(&e, run_id);
+        if record.user != user {
+            panic_with_error!(&e, VaultError::Unauthorized);
+        }
+        let Some(settlement) = record.lifecycle.settlement_mut() else {
+            panic_with_error!(&e, VaultError::RunNotFinalized);
+        };
+        let total_tipped = settlement
+            .tipped
+            .checked_add(amount)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::InvalidAmount));
+
+        debit_balance(&e, &user, amount);
+        credit_developer(&e, &settlement.developer, record.agent_id, amount);
+
+        settlement.tipped = total_tipped;
+        let developer = settlement.developer.clone();
+        write_run(&e, run_id, &record);
+
+        e.events().publish(
+            (symbol_short!("run"), symbol_short!("tipped")),
+            RunTipLog {
+                run_id,
+                user,
+                developer,
+                amount,
+                total_tipped,
+                tipped_at: e.ledger().timestamp(),
+            },
+        );
+    }
+
+    /// Returns up to the run's charge, less earlier refunds, to whoever funded the run.
+    /// Only the agent's developer may call it; the refund comes out of the balance the
+    /// charge was credited to.
+    pub fn refund_user(e: Env, developer: Address, run_id: u64, amount: i128) {
+        developer.require_auth();
+        if amount <= 0 {
+            panic_with_error!(&e, VaultError::InvalidAmount);
+        }
+
+        let mut record = read_run_or_panic(&e, run_id);
+        let payer = run_payer(&record);
+        let agent_developer =
+            AgentRegistryClient::new(&e, &require_registry(&e)).developer_of(&record.agent_id);
+        let Some(settlement) = record.lifecycle.settlement_mut() else {
+            panic_with_error!(&e, VaultError::RunNotFinalized);
+        };
+        if agent_developer != developer {
+            panic_with_error!(&e, VaultError::Unauthorized);
+        }
+        let payout = settlement.developer.clone();
+        let total_refunded = settlement
+            .goodwill_refunded
+            .checked_add(amount)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::RefundExceedsCharge));
+        if total_refunded > settlement.actual_charge {
+            panic_with_error!(&e, VaultError::RefundExceedsCharge);
+        }
+
+        let dev_balance = read_developer_balance(&e, &payout);
+        if dev_balance < amount {
+            panic_with_error!(&e, VaultError::InsufficientBalance);
+        }
+        write_developer_balance(&e, &payout, dev_balance - amount);
+        let payer_balance = read_balance(&e, &payer);
+        let new_payer_balance = payer_balance
+            .checked_add(amount)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::InvalidAmount));
+        write_balance(&e, &payer, new_payer_balance);
+
+        settlement.goodwill_refunded = total_refunded;
+        write_run(&e, run_id, &record);
+
+        e.events().publish(
+            (symbol_short!("run"), symbol_short!("refunded")),
+            GoodwillRefundLog {
+                run_id,
+                developer,
+                user: record.user,
+                amount,
+                total_refunded,
+                refunded_at: e.ledger().timestamp(),
+            },
+        );
+    }
+
+    /// Escrows `amount * max_redemptions` from the funder's balance behind `code_hash`,
+    /// the SHA-256 of a code handed out off-chain.
+    pub fn create_voucher(
+        e: Env,
+        funder: Address,
+        code_hash: BytesN<32>,
+        amount: i128,
+        max_redemptions: u32,
+        expires_at: u64,
+    ) {
+        funder.require_auth();
+        ensure_not_paused(&e);
+        if amount <= 0 || max_redemptions == 0 {
+            panic_with_error!(&e, VaultError::InvalidAmount);
+        }
+        if expires_at <= e.ledger().timestamp() {
+            panic_with_error!(&e, VoucherError::VoucherExpired);
+        }
+        let key = DataKey::Voucher(code_hash.clone());
+        if e.storage().persistent().has(&key) {
+            panic_with_error!(&e, VoucherError::VoucherExists);
+        }
+        let total = amount
+            .checked_mul(max_redemptions as i128)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::InvalidAmount));
+        debit_balance(&e, &funder, total);
+
+        let voucher = Voucher {
+            funder: funder.clone(),
+            token: read_balance_token(&e, &funder),
+            amount,
+            max_redemptions,
+            redeemed: 0,
+            expires_at,
+        };
+        write_voucher(&e, &code_hash, &voucher);
+
+        e.events().publish(
+            (symbol_short!("voucher"), symbol_short!("created")),
+            VoucherCreatedLog {
+                code_hash,
+                funder,
+                token: voucher.token,
+                amount,
+                max_redemptions,
+                expires_at,
+            },
+        );
+    }
+
+    /// Credits the voucher whose code hashes from `preimage` to the user's balance. Each
+    /// user can redeem a given voucher once, and only into a balance held in the token
+    /// the voucher was funded in.
+    pub fn redeem_voucher(e: Env, user: Address, preimage: Bytes) -> i128 {
+        user.require_auth();
+        ensure_not_paused(&e);
+        let code_hash: BytesN<32> = e.crypto().sha256(&preimage).into();
+        let mut voucher = read_voucher_or_panic(&e, &code_hash);
+        if read_balance_token(&e, &user) != voucher.token {
+            panic_with_error!(&e, VaultError::TokenMismatch);
+        }
+        if e.ledger().timestamp() >= voucher.expires_at {
+            panic_with_error!(&e, VoucherError::VoucherExpired);
+        }
+        if voucher.redeemed >= voucher.max_redemptions {
+            panic_with_error!(&e, VoucherError::VoucherExhausted);
+        }
+        let redeemed_key = DataKey::VoucherRedeemed(code_hash.clone(), user.clone());
+        if e.storage().persistent().has(&redeemed_key) {
+            panic_with_error!(&e, VoucherError::AlreadyRedeemed);
+        }
+
+        voucher.redeemed += 1;
+        write_voucher(&e, &code_hash, &voucher);
+        e.storage().persistent().set(&redeemed_key, &true);
+        e.storage()
+            .persistent()
+            .extend_ttl(&redeemed_key, TTL_THRESHOLD, TTL_EXTEND_TO);
+
+        let balance = read_balance(&e, &user);
+        let new_balance = balance
+            .checked_add(voucher.amount)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::InvalidAmount));
+        write_balance(&e, &user, new_balance);
+
+        e.events().publish(
+            (symbol_short!("voucher"), symbol_short!("redeemed")),
+            VoucherRedeemedLog {
+                code_hash,
+                user,
+                amount: voucher.amount,
+                redemptions_left: voucher.max_redemptions - voucher.redeemed,
+            },
+        );
+        voucher.amount
+    }
+
+    /// Returns an expired voucher's unredeemed escrow to its funder and deletes it.
+    /// Callable by anyone.
+    pub fn reclaim_voucher(e: Env, code_hash: BytesN<32>) -> i128 {
+        let voucher = read_voucher_or_panic(&e, &code_hash);
+        if e.ledger().timestamp() < voucher.expires_at {
+            panic_with_error!(&e, VoucherError::VoucherNotExpired);
+        }
+        let unredeemed = voucher.amount * (voucher.max_redemptions - voucher.redeemed) as i128;
+        e.storage()
+            .persistent()
+            .remove(&DataKey::Voucher(code_hash.clone()));
+
+        let balance = read_balance(&e, &voucher.funder);
+        let new_balance = balance
+            .checked_add(unredeemed)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::InvalidAmount));
+        write_balance(&e, &voucher.funder, new_balance);
+
+        e.events().publish(
+            (symbol_short!("voucher"), symbol_short!("reclaimed")),
+            VoucherReclaimedLog {
+                code_hash,
+                funder: voucher.funder,
+                amount: unredeemed,
+            },
+        );
+        unredeemed
+    }
+
+    pub fn get_voucher(e: Env, code_hash: BytesN<32>) -> Option<Voucher> {
+        e.storage()
+            .persistent()
+            .get::<_, Voucher>(&DataKey::Voucher(code_hash))
+    }
+
+    /// Records the integrator that brought `user` in. Can only be set once.
+    pub fn set_referrer(e: Env, user: Address, referrer: Address) {
+        user.require_auth();
+        if referrer == user {
+            panic_with_error!(&e, VaultError::InvalidReferrer);
+        }
+        let key = DataKey::Referrer(user.clone());
+        if e.storage().instance().has(&key) {
+            panic_with_error!(&e, VaultError::ReferrerAlreadySet);
+        }
+        e.storage().instance().set(&key, &referrer);
+    }
+
+    pub fn referrer_of(e: Env, user: Address) -> Option<Address> {
+        read_referrer(&e, &user)
+    }
+
+    pub fn referrer_balance(e: Env, referrer: Address) -> i128 {
+        read_referrer_balance(&e, &referrer)
+    }
+
+    pub fn claim_referrer(e: Env, referrer: Address, amount: i128) {
+        referrer.require_auth();
+        ensure_not_paused(&e);
+        if amount <= 0 {
+            panic_with_error!(&e, VaultError::InvalidAmount);
+        }
+        let balance = read_referrer_balance(&e, &referrer);
+        if balance < amount {
+            panic_with_error!(&e, VaultError::InsufficientBalance);
+        }
+        write_referrer_balance(&e, &referrer, balance - amount);
+    }
+
+    /// Priority fees the runner has earned and not yet claimed.
+    pub fn runner_balance(e: Env, runner: Address) -> i128 {
+        read_runner_balance(&e, &runner)
+    }
+
+    pub fn claim_runner(e: Env, runner: Address, amount: i128) {
+        runner.require_auth();
+        ensure_not_paused(&e);
+        if amount <= 0 {
+            panic_with_error!(&e, VaultError::InvalidAmount);
+        }
+        let balance = read_runner_balance(&e, &runner);
+        if balance < amount {
+            panic_with_error!(&e, VaultError::InsufficientBalance);
+        }
+        write_runner_balance(&e, &runner, balance - amount);
+        e.events().publish(
+            (symbol_short!("runner"), symbol_short!("claimed")),
+            RunnerClaimLog { runner, amount },
+        );
+    }
+
+    /// Ids of the user's runs, oldest first, optionally only those in `lifecycle_filter`.
+    /// `offset` counts matching runs, so pages stay consistent under a filter.
+    /// The user's runs, oldest first, starting at `cursor` (zero for the first page).
+    /// At most `MAX_RUN_SCAN` runs are examined per call; with a `lifecycle_filter`, only
+    /// the matching ones are returned, so a page may come back short or empty while
+    /// `next_cursor` is still set.
+    pub fn list_runs_for_user(
+        e: Env,
+        user: Address,
+        lifecycle_filter: Option<LifecycleKind>,
+        cursor: u32,
+        limit: u32,
+    ) -> RunPage {
+        list_indexed_runs(&e, RunIndex::User(user), lifecycle_filter, cursor, limit)
+    }
+
+    /// `list_runs_for_user` over the runs opened against the agent.
+    pub fn list_runs_for_agent(
+        e: Env,
+        agent_id: u32,
+        lifecycle_filter: Option<LifecycleKind>,
+        cursor: u32,
+        limit: u32,
+    ) -> RunPage {
+        list_indexed_runs(
+            &e,
+            RunIndex::Agent(agent_id),
+            lifecycle_filter,
+            cursor,
+            limit,
+        )
+    }
+
+    /// `list_runs_for_user` over the runs the runner settled.
+    pub fn list_runs_for_runner(
+        e: Env,
+        runner: Address,
+        lifecycle_filter: Option<LifecycleKind>,
+        cursor: u32,
+        limit: u32,
+    ) -> RunPage {
+        list_indexed_runs(
+            &e,
+            RunIndex::Runner(runner),
+            lifecycle_filter,
+            cursor,
+            limit,
+        )
+    }
+
+    /// Keeps the address's balances, as a user, developer, referrer and runner, and its
+    /// policy and balance token from being archived. Anyone may call it; entries never
+    /// stored are skipped. Per-agent developer earnings are extended whenever they are
+    /// credited. Grants, allowances, sessions and the other per-user settings still live
+    /// in instance storage, which every call keeps alive.
+    pub fn bump_user_ttl(e: Env, user: Address) {
+        for key in [
+            DataKey::UserBalance(user.clone()),
+            DataKey::UserPolicy(user.clone()),
+            DataKey::BalanceToken(user.clone()),
+            DataKey::DeveloperBalance(user.clone()),
+            DataKey::ReferrerBalance(user.clone()),
+            DataKey::RunnerBalance(user),
+        ] {
+            extend_persistent_ttl(&e, &key);
+        }
+        extend_instance_ttl(&e);
+    }
+
+    /// Keeps a run record from being archived. Anyone may call it.
+    pub fn bump_run_ttl(e: Env, run_id: u64) {
+        let key = DataKey::Run(run_id);
+        if !e.storage().persistent().has(&key) {
+            panic_with_error!(&e, VaultError::RunNotFound);
+        }
+        e.storage()
+            .persistent()
+            .extend_ttl(&key, TTL_THRESHOLD, TTL_EXTEND_TO);
+        extend_instance_ttl(&e);
+    }
+
+    pub fn get_run(e: Env, run_id: u64) -> RunRecord {
+        read_run_or_panic(&e, run_id)
+    }
+}
+
+fn ensure_runner_authorized(e: &Env, user: &Address, runner: &Address, agent_id: u32) -> bool {
+    let registry_addr = require_registry(e);
+    let registry = AgentRegistryClient::new(e, &registry_addr);
+    runner_holds_grant(e, user, runner, agent_id, || {
+        registry.developer_of(&agent_id)
+    }) && registry.is_runner(&agent_id, runner)
+}
+
+/// Whether the user has granted `runner` the agent, either directly or through a
+/// developer-scoped grant for `developer`, which is only looked up when needed. The
+/// caller still checks that the runner is registered for the agent. A per-agent grant
+/// decides on its own: once it expires the runner is denied for that agent even if a
+/// developer grant would cover it, so expired per-agent grants are left for
+/// `sweep_grants` rather than pruned here.
+fn runner_holds_grant(
+    e: &Env,
+    user: &Address,
+    runner: &Address,
+    agent_id: u32,
+    developer: impl FnOnce() -> Address,
+) -> bool {
+    if let Some(grant) = read_runner_grants(e, user)
+        .iter()
+        .find(|grant| grant.runner == runner.clone() && grant.agent_id == agent_id)
+    {
+        let now = e.ledger().timestamp();
+        return grant.expires_at.is_none_or(|expiry| expiry > now);
+    }
+
+    let grants = prune_expired_developer_grants(e, read_developer_grants(e, user));
+    write_developer_grants(e, user, &grants);
+    if grants.is_empty() {
+        return false;
+    }
+    let developer = developer();
+    grants
+        .iter()
+        .any(|grant| grant.runner == runner.clone() && grant.developer == developer)
+}
+
+/// Fetches the rate card a new run or subscription would be priced with, rejecting
+/// agents that are retired or paused and versions that are deprecated, scheduled for
+/// the future or expired.
+fn load_openable_rate_card(
+    e: &Env,
+    registry: &AgentRegistryClient,
+    agent_id: u32,
+    rate_version: u32,
+) -> RateCard {
+    let active = match registry.try_is_active(&agent_id) {
+        Ok(Ok(active)) => active,
+        _ => panic_with_error!(e, VaultError::AgentNotFound),
+    };
+    if !active {
+        panic_with_error!(e, VaultError::AgentInactive);
+    }
+    if registry.is_paused(&agent_id) {
+        panic_with_error!(e, VaultError::AgentPaused);
+    }
+    if rate_version < registry.min_rate_version(&agent_id) {
+        panic_with_error!(e, VaultError::InvalidRateVersion);
+    }
+
+    let rate_card = fetch_rate_card(e, registry, agent_id, rate_version);
+    let now = e.ledger().timestamp();
+    if !rate_card.is_effective(now) {
+        panic_with_error!(e, VaultError::InvalidRateVersion);
+    }
+    if rate_card.is_expired(now) {
+        panic_with_error!(e, VaultError::RateCardExpired);
+    }
+    rate_card
+}
+
+/// Debits `amount` from the user's balance after enforcing their policy, returning how
+/// much was reserved against the daily cap. `cap_exempt` skips the per-run and daily caps
+/// (but not the pause flag or the balance check) and reserves nothing. USD caps are
+/// checked against the USD value of `amount` of `token`.
+fn reserve_and_debit(
+    e: &Env,
+    user: &Address,
+    amount: i128,
+    token: &Address,
+    cap_exempt: bool,
+) -> i128 {
+    let mut account = Account::load(e, user);
+    let capped = account.reserve_and_debit(e, amount, token, cap_exempt);
+    account.persist(e);
+    capped
+}
+
+/// A user's policy and balance, read once and written back once by `persist`, so the
+/// reservation, debit and release of a run are applied to the same copy. Only the parts
+/// that changed are written.
+struct Account {
+    user: Address,
+    policy: UserPolicy,
+    balance: i128,
+    policy_changed: bool,
+    balance_changed: bool,
+}
+
+impl Account {
+    fn load(e: &Env, user: &Address) -> Self {
+        Account {
+            user: user.clone(),
+            policy: read_policy(e, user),
+            balance: read_balance(e, user),
+            policy_changed: false,
+            balance_changed: false,
+        }
+    }
+
+    /// Counts a run opening against the user's hourly limit. Nothing gives the slot back,
+    /// so opening and cancelling runs cannot be used to get around it.
+    fn count_hourly_run(&mut self, e: &Env) {
+        if self.policy.max_runs_per_hour == 0 {
+            return;
+        }
+        self.policy.ensure_hour(e.ledger().timestamp() / 3600);
+        if self.policy.runs_this_hour >= self.policy.max_runs_per_hour {
+            panic_with_error!(e, VaultError::RateLimited);
+        }
+        self.policy.runs_this_hour += 1;
+        self.policy_changed = true;
+    }
+
+    /// Reserves `amount` against the user's caps and debits it from the balance,
+    /// returning the amount reserved in cap units. Trusted agents skip the caps.
+    fn reserve_and_debit(
+        &mut self,
+        e: &Env,
+        amount: i128,
+        token: &Address,
+        cap_exempt: bool,
+    ) -> i128 {
+        if self.policy.paused {
+            panic_with_error!(e, VaultError::PolicyPaused);
+        }
+
+        if cap_exempt || !self.policy.has_caps() {
+            self.debit(e, amount);
+            return 0;
+        }
+
+        self.policy.ensure_window(e.ledger().timestamp());
+        let capped = cap_amount(e, &self.policy, token, amount);
+        if self.policy.per_run_cap > 0 && capped > self.policy.per_run_cap {
+            panic_with_error!(e, VaultError::PerRunCapExceeded);
+        }
+
+        if self.policy.daily_cap > 0 {
+            let new_reserved = self
+                .policy
+                .reserved_today
+                .checked_add(capped)
+                .unwrap_or_else(|| panic_with_error!(e, VaultError::DailyCapExceeded));
+            if new_reserved > self.policy.daily_cap {
+                panic_with_error!(e, VaultError::DailyCapExceeded);
+            }
+            self.policy.reserved_today = new_reserved;
+        }
+
+        if self.policy.rolling_cap > 0 {
+            let mut rolling = read_rolling_spend(e, &self.user);
+            rolling.advance(current_day(e));
+            let new_total = rolling
+                .total()
+                .checked_add(capped)
+                .unwrap_or_else(|| panic_with_error!(e, PolicyError::RollingCapExceeded));
+            if new_total > self.policy.rolling_cap {
+                panic_with_error!(e, PolicyError::RollingCapExceeded);
+            }
+            rolling.add(capped);
+            write_rolling_spend(e, &self.user, &rolling);
+        }
+
+        self.policy_changed = true;
+        self.debit(e, amount);
+        capped
+    }
+
+    fn debit(&mut self, e: &Env, amount: i128) {
+        if self.balance < amount {
+            panic_with_error!(e, VaultError::InsufficientBalance);
+        }
+        self.balance -= amount;
+        self.balance_changed = true;
+    }
+
+    fn credit(&mut self, e: &Env, amount: i128) {
+        self.balance = self
+            .balance
+            .checked_add(amount)
+            .unwrap_or_else(|| panic_with_error!(e, VaultError::InvalidAmount));
+        self.balance_changed = true;
+    }
+
+    /// Hands `amount` of the current window's reservation back, stopping at zero.
+    fn release_reserved(&mut self, e: &Env, amount: i128) {
+        self.policy.ensure_window(e.ledger().timestamp());
+        if self.policy.reserved_today >= amount {
+            self.policy.reserved_today -= amount;
+        } else {
+            self.policy.reserved_today = 0;
+        }
+        self.policy_changed = true;
+    }
+
+    fn persist(self, e: &Env) {
+        if self.policy.reserved_today < 0 || self.balance < 0 {
+            panic_with_error!(e, VaultError::InvalidAmount);
+        }
+        if self.policy_changed {
+            write_policy(e, &self.user, &self.policy);
+        }
+        if self.balance_changed {
+            write_balance(e, &self.user, self.balance);
+        }
+    }
+}
+
+/// `amount` of `token` in the units of the user's caps: unchanged for token caps, or its
+/// USD value rounded up for USD caps. Fails closed when no fresh price is available.
+fn cap_amount(e: &Env, policy: &UserPolicy, token: &Address, amount: i128) -> i128 {
+    if !policy.caps_in_usd || !policy.has_caps() {
+        return amount;
+    }
+    to_usd(e, token, amount)
+}
+
+/// `amount` of `token` in USD, rounded up.
+fn to_usd(e: &Env, token: &Address, amount: i128) -> i128 {
+    let (rate, divisor) = usd_rate(e, token);
+    let usd = amount
+        .checked_mul(rate)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::ChargeOverflow));
+    usd / divisor + i128::from(usd % divisor != 0)
+}
+
+/// A USD amount in units of `token`, rounded up.
+fn from_usd(e: &Env, token: &Address, usd: i128) -> i128 {
+    let (rate, divisor) = usd_rate(e, token);
+    let scaled = usd
+        .checked_mul(divisor)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::ChargeOverflow));
+    scaled / rate + i128::from(scaled % rate != 0)
+}
+
+/// The oracle's price of `token` as `(rate, divisor)`, with `amount * rate / divisor`
+/// in USD units. Fails closed when no fresh price is available.
+fn usd_rate(e: &Env, token: &Address) -> (i128, i128) {
+    let config: PriceOracleConfig = e
+        .storage()
+        .instance()
+        .get(&DataKey::PriceOracle)
+        .unwrap_or_else(|| panic_with_error!(e, PriceOracleError::OracleNotSet));
+    let (price, price_decimals, updated_at) =
+        match PriceOracleClient::new(e, &config.oracle).try_price(token) {
+            Ok(Ok(quote)) if quote.0 > 0 => quote,
+            _ => panic_with_error!(e, PriceOracleError::PriceUnavailable),
+        };
+    if e.ledger().timestamp().saturating_sub(updated_at) > config.max_age_secs {
+        panic_with_error!(e, PriceOracleError::PriceStale);
+    }
+    let token_decimals = token::Client::new(e, token).decimals();
+    let rate = 10i128
+        .checked_pow(USD_DECIMALS)
+        .and_then(|scale| price.checked_mul(scale))
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::ChargeOverflow));
+    let divisor = token_decimals
+        .checked_add(price_decimals)
+        .and_then(|decimals| 10i128.checked_pow(decimals))
+        .unwrap_or_else(|| panic_with_error!(e, PriceOracleError::PriceUnavailable));
+    (rate, divisor)
+}
+
+/// Restates the user's current reservation and rolling spend in the units `caps_in_usd`
+/// selects, at the oracle price of the user's balance token. Open runs hold
+/// reservations in the old units, so the switch waits until none are left.
+fn convert_cap_units(e: &Env, user: &Address, policy: &mut UserPolicy, caps_in_usd: bool) {
+    if read_user_activity(e, user).open_runs > 0 {
+        panic_with_error!(e, VaultError::RunNotFinalized);
+    }
+    policy.ensure_window(e.ledger().timestamp());
+    let mut rolling = read_rolling_spend(e, user);
+    rolling.advance(current_day(e));
+    if policy.reserved_today == 0 && rolling.total() == 0 {
+        return;
+    }
+
+    let token = read_balance_token(e, user)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::BalanceTokenNotSet));
+    let convert = |amount: i128| match (amount, caps_in_usd) {
+        (0, _) => 0,
+        (_, true) => to_usd(e, &token, amount),
+        (_, false) => from_usd(e, &token, amount),
+    };
+    policy.reserved_today = convert(policy.reserved_today);
+    for slot in 0..rolling.buckets.len() {
+        rolling
+            .buckets
+            .set(slot, convert(rolling.buckets.get_unchecked(slot)));
+    }
+    write_rolling_spend(e, user, &rolling);
+}
+
+fn debit_balance(e: &Env, user: &Address, amount: i128) {
+    let balance = read_balance(e, user);
+    if balance < amount {
+        panic_with_error!(e, VaultError::InsufficientBalance);
+    }
+    write_balance(e, user, balance - amount);
+}
+
+fn release_subscription_to_developer(
+    e: &Env,
+    registry: &AgentRegistryClient,
+    user: &Address,
+    mut subscription: Subscription,
+) -> i128 {
+    let developer = registry.payout_address_of(&subscription.agent_id);
+    let dev_balance = read_developer_balance(e, &developer);
+    let new_dev_balance = dev_balance
+        .checked_add(subscription.price)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::InvalidAmount));
+    write_developer_balance(e, &developer, new_dev_balance);
+
+    if subscription.reserved > 0 {
+        let mut account = Account::load(e, user);
+        account.release_reserved(e, subscription.reserved);
+        account.persist(e);
+    }
+    subscription.released = true;
+    write_subscription(e, user, &subscription);
+
+    e.events().publish(
+        (symbol_short!("sub"), symbol_short!("released")),
+        SubscriptionReleasedLog {
+            user: user.clone(),
+            agent_id: subscription.agent_id,
+            developer,
+            amount: subscription.price,
+            released_at: e.ledger().timestamp(),
+        },
+    );
+
+    subscription.price
+}
+
+fn read_agent_event_topics(e: &Env) -> bool {
+    e.storage()
+        .instance()
+        .get::<_, bool>(&DataKey::AgentEventTopics)
+        .unwrap_or(false)
+}
+
+/// Publishes a run lifecycle event, adding `agent_id` as a third topic when the vault
+/// is configured for agent-scoped topics.
+fn publish_run_event<D>(e: &Env, action: Symbol, agent_id: u32, data: D)
+where
+    D: IntoVal<Env, Val>,
+{
+    if read_agent_event_topics(e) {
+        e.events()
+            .publish((symbol_short!("run"), action, agent_id), data);
+    } else {
+        e.events().publish((symbol_short!("run"), action), data);
+    }
+}
+
+fn read_config_version(e: &Env) -> u32 {
+    e.storage()
+        .instance()
+        .get::<_, u32>(&DataKey::ConfigVersion)
+        .unwrap_or(0)
+}
+
+fn read_admin(e: &Env) -> Address {
+    match e.storage().instance().get::<_, Address>(&DataKey::Admin) {
+        Some(admin) => admin,
+        None => panic_with_error!(e, VaultError::NotInitialized),
+    }
+}
+
+/// The current pause level. Vaults paused before levels existed stored a flag under
+/// `DataKey::Paused`, which meant a full pause.
+fn read_pause_level(e: &Env) -> PauseLevel {
+    let storage = e.storage().instance();
+    match storage.get::<_, PauseLevel>(&DataKey::PauseLevel) {
+        Some(level) => level,
+        None if storage.get::<_, bool>(&DataKey::Paused).unwrap_or(false) => PauseLevel::All,
+        None => PauseLevel::Unpaused,
+    }
+}
+
+fn set_pause_level(e: &Env, level: PauseLevel) {
+    let admin = read_admin(e);
+    admin.require_auth();
+    let storage = e.storage().instance();
+    storage.set(&DataKey::PauseLevel, &level);
+    storage.remove(&DataKey::Paused);
+
+    let action = if level == PauseLevel::Unpaused {
+        symbol_short!("unpaused")
+    } else {
+        symbol_short!("paused")
+    };
+    e.events().publish(
+        (symbol_short!("vault"), action),
+        VaultPauseLog {
+            admin,
+            level,
+            timestamp: e.ledger().timestamp(),
+        },
+    );
+}
+
+/// Breaker state for the current day, rolled over without being persisted.
+fn read_circuit_breaker(e: &Env) -> CircuitBreaker {
+    let mut breaker = e
+        .storage()
+        .instance()
+        .get::<_, CircuitBreaker>(&DataKey::CircuitBreaker)
+        .unwrap_or_default();
+    breaker.ensure_day(current_day(e));
+    breaker
+}
+
+fn write_circuit_breaker(e: &Env, breaker: &CircuitBreaker) {
+    e.storage()
+        .instance()
+        .set(&DataKey::CircuitBreaker, breaker);
+}
+
+fn record_settled_outflow(e: &Env, amount: i128) {
+    let mut breaker = read_circuit_breaker(e);
+    let settled = breaker
+        .settled_today
+        .checked_add(amount)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::InvalidAmount));
+    if breaker.ceiling > 0 && !breaker.overridden && settled > breaker.ceiling {
+        panic_with_error!(e, VaultError::CircuitBreakerTripped);
+    }
+    breaker.settled_today = settled;
+    write_circuit_breaker(e, &breaker);
+}
+
+/// Refuses new activity under any pause level.
+fn ensure_not_paused(e: &Env) {
+    if read_pause_level(e) != PauseLevel::Unpaused {
+        panic_with_error!(e, VaultError::VaultPaused);
+    }
+}
+
+/// Refuses withdrawals, which only a full pause stops.
+fn ensure_withdrawals_open(e: &Env) {
+    if read_pause_level(e) == PauseLevel::All {
+        panic_with_error!(e, VaultError::VaultPaused);
+    }
+}
+
+fn require_registry(e: &Env) -> Address {
+    match e
+        .storage()
+        .instance()
+        .get::<_, Address>(&DataKey::AgentRegistry)
+    {
+        Some(addr) => addr,
+        None => panic_with_error!(e, VaultError::AgentRegistryNotSet),
+    }
+}
+
+/// Reads a rate card through a fallible call so an unknown agent or version fails with
+/// the vault's own `AgentNotFound` instead of the registry's trap.
+fn fetch_rate_card(
+    e: &Env,
+    registry: &AgentRegistryClient,
+    agent_id: u32,
+    rate_version: u32,
+) -> RateCard {
+    match registry.try_get_rate_card(&agent_id, &rate_version) {
+        Ok(Ok(rate_card)) => rate_card,
+        _ => panic_with_error!(e, VaultError::AgentNotFound),
+    }
+}
+
+fn snapshot_rate_card(rate_card: &RateCard) -> RateSnapshot {
+    RateSnapshot {
+        rates: rate_card.rates.clone(),
+        base_fee: rate_card.base_fee,
+        unit_scale: rate_card.unit_scale,
+        min_charge: rate_card.min_charge,
+        free_daily_units: rate_card.free_daily_units.clone(),
+        manifest_version: rate_card.manifest_version,
+    }
+}
+
+/// Everything settling `record` needs from the registry, in one call. The rate card is
+/// only requested for runs opened before snapshots were taken.
+fn fetch_settlement_info(
+    e: &Env,
+    registry: &AgentRegistryClient,
+    record: &RunRecord,
+) -> SettlementInfo {
+    let rate_version = match record.pricing {
+        RunPricing::Snapshot(_) => 0,
+        RunPricing::Registry => record.rate_version,
+    };
+    match registry.try_get_settlement_info(&record.agent_id, &rate_version) {
+        Ok(Ok(info)) => info,
+        _ => panic_with_error!(e, VaultError::AgentNotFound),
+    }
+}
+
+/// The pricing a run settles at: its snapshot, or the registry's card for runs opened
+/// before snapshots were taken.
+fn run_rate_snapshot(e: &Env, record: &RunRecord, info: &SettlementInfo) -> RateSnapshot {
+    match (&record.pricing, &info.rate_card) {
+        (RunPricing::Snapshot(snapshot), _) => snapshot.clone(),
+        (RunPricing::Registry, SettlementRateCard::Card(rate_card)) => {
+            snapshot_rate_card(rate_card)
+        }
+        (RunPricing::Registry, SettlementRateCard::NotRequested) => {
+            panic_with_error!(e, VaultError::AgentNotFound)
+        }
+    }
+}
+
+/// The user's balance. Balances not written since schema version 1 are still in
+/// instance storage.
+fn read_balance(e: &Env, user: &Address) -> i128 {
+    let key = DataKey::UserBalance(user.clone());
+    e.storage()
+        .persistent()
+        .get::<_, i128>(&key)
+        .or_else(|| e.storage().instance().get::<_, i128>(&key))
+        .unwrap_or(0)
+}
+
+fn write_balance(e: &Env, user: &Address, amount: i128) {
+    let key = DataKey::UserBalance(user.clone());
+    e.storage().persistent().set(&key, &amount);
+    e.storage()
+        .persistent()
+        .extend_ttl(&key, TTL_THRESHOLD, TTL_EXTEND_TO);
+}
+
+/// The user's standing approval for `spender`, if they ever gave one.
+fn read_deposit_allowance(e: &Env, user: &Address, spender: &Address) -> Option<i128> {
+    e.storage()
+        .persistent()
+        .get(&DataKey::DepositAllowance(user.clone(), spender.clone()))
+}
+
+fn write_deposit_allowance(e: &Env, user: &Address, spender: &Address, amount: i128) {
+    let key = DataKey::DepositAllowance(user.clone(), spender.clone());
+    let storage = e.storage().persistent();
+    storage.set(&key, &amount);
+    storage.extend_ttl(&key, TTL_THRESHOLD, TTL_EXTEND_TO);
+}
+
+fn write_persistent<V: IntoVal<Env, Val>>(e: &Env, key: &DataKey, value: &V) {
+    e.storage().persistent().set(key, value);
+    e.storage()
+        .persistent()
+        .extend_ttl(key, TTL_THRESHOLD, TTL_EXTEND_TO);
+}
+
+fn extend_persistent_ttl(e: &Env, key: &DataKey) {
+    if e.storage().persistent().has(key) {
+        e.storage()
+            .persistent()
+            .extend_ttl(key, TTL_THRESHOLD, TTL_EXTEND_TO);
+    }
+}
+
+fn write_run(e: &Env, run_id: u64, record: &RunRecord) {
+    let key = DataKey::Run(run_id);
+    e.storage().persistent().set(&key, record);
+    e.storage()
+        .persistent()
+        .extend_ttl(&key, TTL_THRESHOLD, TTL_EXTEND_TO);
+}
+
+fn extend_instance_ttl(e: &Env) {
+    e.storage()
+        .instance()
+        .extend_ttl(TTL_THRESHOLD, TTL_EXTEND_TO);
+}
+
+/// The user's timelock, with a scheduled decrease applied once it has taken effect.
+fn read_withdrawal_delay(e: &Env, user: &Address) -> WithdrawalDelay {
+    let mut delay = e
+        .storage()
+        .instance()
+        .get::<_, WithdrawalDelay>(&DataKey::WithdrawalDelay(user.clone()))
+        .unwrap_or(WithdrawalDelay {
+            delay_secs: 0,
+            next_delay_secs: None,
+            next_effective_at: 0,
+        });
+    if let Some(next) = delay.next_delay_secs {
+        if e.ledger().timestamp() >= delay.next_effective_at {
+            delay.delay_secs = next;
+            delay.next_delay_secs = None;
+        }
+    }
+    delay
+}
+
+fn ensure_no_withdrawal_delay(e: &Env, user: &Address) {
+    if read_withdrawal_delay(e, user).delay_secs > 0 {
+        panic_with_error!(e, VaultError::WithdrawalLocked);
+    }
+}
+
+fn read_pending_withdrawal(e: &Env, user: &Address) -> Option<PendingWithdrawal> {
+    e.storage()
+        .instance()
+        .get(&DataKey::PendingWithdrawal(user.clone()))
+}
+
+/// Developer balances from schema version 1 stay in instance storage until migrated.
+fn read_developer_balance(e: &Env, developer: &Address) -> i128 {
+    let key = DataKey::DeveloperBalance(developer.clone());
+    e.storage()
+        .persistent()
+        .get::<_, i128>(&key)
+        .or_else(|| e.storage().instance().get::<_, i128>(&key))
+        .unwrap_or(0)
+}
+
+fn write_developer_balance(e: &Env, developer: &Address, amount: i128) {
+    write_persistent(e, &DataKey::DeveloperBalance(developer.clone()), &amount);
+}
+
+fn read_agent_earnings(e: &Env, developer: &Address, agent_id: u32) -> i128 {
+    e.storage()
+        .persistent()
+        .get(&DataKey::DeveloperAgentEarnings(
+            developer.clone(),
+            agent_id,
+        ))
+        .unwrap_or(0)
+}
+
+/// The user's chosen balance token, falling back to the vault's configured token.
+fn read_balance_token(e: &Env, user: &Address) -> Option<Address> {
+    e.storage()
+        .persistent()
+        .get::<_, Address>(&DataKey::BalanceToken(user.clone()))
+        .or_else(|| {
+            e.storage()
+                .instance()
+                .get::<_, Address>(&DataKey::VaultToken)
+        })
+}
+
+/// Rejects rate cards priced in a different asset than the user's chosen balance token.
+fn ensure_token_matches(e: &Env, user: &Address, rate_card: &RateCard) {
+    if let Some(token) = read_balance_token(e, user) {
+        if token != rate_card.token {
+            panic_with_error!(e, VaultError::TokenMismatch);
+        }
+    }
+}
+
+/// The user's policy, upgrading one still in instance storage in the schema version 1
+/// layout.
+fn read_policy(e: &Env, user: &Address) -> UserPolicy {
+    let key = DataKey::UserPolicy(user.clone());
+    let stored = match e
+        .storage()
+        .persistent()
+        .get::<_, Val>(&key)
+        .or_else(|| e.storage().instance().get::<_, Val>(&key))
+    {
+        Some(stored) => stored,
+        None => return UserPolicy::default(),
+    };
+    if has_field(e, &stored, "force_close_after_secs") {
+        decode_stored::<UserPolicy>(e, &stored)
+    } else if has_layout(e, &stored, &UserPolicyV1::FIELDS) {
+        decode_stored::<UserPolicyV1>(e, &stored).upgrade()
+    } else {
+        panic_with_error!(e, SchemaError::UnknownLayout)
+    }
+}
+
+/// The fields of a stored struct.
+fn stored_fields(e: &Env, stored: &Val) -> Map<Symbol, Val> {
+    Map::<Symbol, Val>::try_from_val(e, stored)
+        .unwrap_or_else(|_| panic_with_error!(e, SchemaError::UnknownLayout))
+}
+
+/// Whether a stored struct has `field`, telling apart its schema layouts.
+fn has_field(e: &Env, stored: &Val, field: &str) -> bool {
+    stored_fields(e, stored).contains_key(Symbol::new(e, field))
+}
+
+/// Whether a stored struct has exactly `fields`, so decoding it as that layout cannot
+/// fail on a missing or extra field.
+fn has_layout(e: &Env, stored: &Val, fields: &[&str]) -> bool {
+    let stored = stored_fields(e, stored);
+    stored.len() as usize == fields.len()
+        && fields
+            .iter()
+            .all(|field| stored.contains_key(Symbol::new(e, field)))
+}
+
+fn decode_stored<T: TryFromVal<Env, Val>>(e: &Env, stored: &Val) -> T {
+    T::try_from_val(e, stored).unwrap_or_else(|_| panic_with_error!(e, SchemaError::UnknownLayout))
+}
+
+fn read_schema_version(e: &Env) -> u32 {
+    e.storage()
+        .instance()
+        .get::<_, u32>(&DataKey::SchemaVersion)
+        .unwrap_or(1)
+}
+
+fn ensure_migrating_from(e: &Env, from_version: u32) {
+    if from_version != read_schema_version(e) || from_version >= SCHEMA_VERSION {
+        panic_with_error!(e, SchemaError::VersionMismatch);
+    }
+}
+
+fn read_migrated_entries(e: &Env) -> u32 {
+    e.storage()
+        .instance()
+        .get::<_, u32>(&DataKey::MigratedEntries)
+        .unwrap_or(0)
+}
+
+fn write_policy(e: &Env, user: &Address, policy: &UserPolicy) {
+    write_persistent(e, &DataKey::UserPolicy(user.clone()), policy);
+}
+
+fn read_trusted_agents(e: &Env, user: &Address) -> Vec<u32> {
+    e.storage()
+        .instance()
+        .get::<_, Vec<u32>>(&DataKey::TrustedAgents(user.clone()))
+        .unwrap_or_else(|| Vec::new(e))
+}
+
+fn write_trusted_agents(e: &Env, user: &Address, trusted: &Vec<u32>) {
+    if trusted.is_empty() {
+        e.storage()
+            .instance()
+            .remove(&DataKey::TrustedAgents(user.clone()));
+    } else {
+        e.storage()
+            .instance()
+            .set(&DataKey::TrustedAgents(user.clone()), trusted);
+    }
+}
+
+fn read_runner_grants(e: &Env, user: &Address) -> Vec<RunnerGrant> {
+    e.storage()
+        .instance()
+        .get::<_, Vec<RunnerGrant>>(&DataKey::RunnerGrants(user.clone()))
+        .unwrap_or_else(|| Vec::new(e))
+}
+
+fn write_runner_grants(e: &Env, user: &Address, grants: &Vec<RunnerGrant>) {
+    if grants.len() == 0 {
+        e.storage()
+            .instance()
+            .remove(&DataKey::RunnerGrants(user.clone()));
+    } else {
+        e.storage()
+            .instance()
+            .set(&DataKey::RunnerGrants(user.clone()), grants);
+    }
+}
+
+fn is_fee_exempt(e: &Env, developer: &Address) -> bool {
+    e.storage()
+        .instance()
+        .has(&DataKey::FeeExempt(developer.clone()))
+}
+
+fn read_referral_bps(e: &Env) -> u32 {
+    e.storage()
+        .instance()
+        .get(&DataKey::ReferralBps)
+        .unwrap_or(0)
+}
+
+fn read_referrer(e: &Env, user: &Address) -> Option<Address> {
+    e.storage().instance().get(&DataKey::Referrer(user.clone()))
+}
+
+fn read_referrer_balance(e: &Env, referrer: &Address) -> i128 {
+    e.storage()
+        .persistent()
+        .get(&DataKey::ReferrerBalance(referrer.clone()))
+        .unwrap_or(0)
+}
+
+fn write_referrer_balance(e: &Env, referrer: &Address, amount: i128) {
+    write_persistent(e, &DataKey::ReferrerBalance(referrer.clone()), &amount);
+}
+
+fn read_failure_discount_bps(e: &Env) -> u32 {
+    e.storage()
+        .instance()
+        .get(&DataKey::FailureDiscountBps)
+        .unwrap_or(0)
+}
+
+fn read_runner_balance(e: &Env, runner: &Address) -> i128 {
+    e.storage()
+        .persistent()
+        .get(&DataKey::RunnerBalance(runner.clone()))
+        .unwrap_or(0)
+}
+
+fn write_runner_balance(e: &Env, runner: &Address, amount: i128) {
+    write_persistent(e, &DataKey::RunnerBalance(runner.clone()), &amount);
+}
+
+fn credit_runner(e: &Env, runner: &Address, amount: i128) {
+    let balance = read_runner_balance(e, runner)
+        .checked_add(amount)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::InvalidAmount));
+    write_runner_balance(e, runner, balance);
+}
+
+fn read_budget_presets(e: &Env, user: &Address) -> Map<Symbol, UsageBreakdown> {
+    e.storage()
+        .instance()
+        .get(&DataKey::BudgetPresets(user.clone()))
+        .unwrap_or_else(|| Map::new(e))
+}
+
+fn write_budget_presets(e: &Env, user: &Address, presets: &Map<Symbol, UsageBreakdown>) {
+    if presets.is_empty() {
+        e.storage()
+            .instance()
+            .remove(&DataKey::BudgetPresets(user.clone()));
+    } else {
+        e.storage()
+            .instance()
+            .set(&DataKey::BudgetPresets(user.clone()), presets);
+    }
+}
+
+fn read_developer_grants(e: &Env, user: &Address) -> Vec<DeveloperGrant> {
+    e.storage()
+        .instance()
+        .get::<_, Vec<DeveloperGrant>>(&DataKey::DeveloperGrants(user.clone()))
+        .unwrap_or_else(|| Vec::new(e))
+}
+
+fn write_developer_grants(e: &Env, user: &Address, grants: &Vec<DeveloperGrant>) {
+    if grants.is_empty() {
+        e.storage()
+            .instance()
+            .remove(&DataKey::DeveloperGrants(user.clone()));
+    } else {
+        e.storage()
+            .instance()
+            .set(&DataKey::DeveloperGrants(user.clone()), grants);
+    }
+}
+
+fn prune_expired_developer_grants(e: &Env, grants: Vec<DeveloperGrant>) -> Vec<DeveloperGrant> {
+    let now = e.ledger().timestamp();
+    let mut filtered = Vec::new(e);
+    for grant in grants.iter() {
+        match grant.expires_at {
+            Some(expiry) if expiry <= now => {}
+            _ => filtered.push_back(grant),
+        }
+    }
+    filtered
+}
+
+fn read_allowance(e: &Env, user: &Address, agent_id: u32) -> Option<AgentAllowance> {
+    e.storage()
+        .instance()
+        .get::<_, AgentAllowance>(&DataKey::AgentAllowance(user.clone(), agent_id))
+}
+
+fn write_allowance(e: &Env, user: &Address, agent_id: u32, allowance: &AgentAllowance) {
+    e.storage()
+        .instance()
+        .set(&DataKey::AgentAllowance(user.clone(), agent_id), allowance);
+}
+
+fn read_runner_caps(e: &Env, user: &Address) -> Map<Address, RunnerCap> {
+    e.storage()
+        .instance()
+        .get::<_, Map<Address, RunnerCap>>(&DataKey::RunnerCaps(user.clone()))
+        .unwrap_or_else(|| Map::new(e))
+}
+
+fn write_runner_caps(e: &Env, user: &Address, caps: &Map<Address, RunnerCap>) {
+    if caps.is_empty() {
+        e.storage()
+            .instance()
+            .remove(&DataKey::RunnerCaps(user.clone()));
+    } else {
+        e.storage()
+            .instance()
+            .set(&DataKey::RunnerCaps(user.clone()), caps);
+    }
+}
+
+/// Counts `amount` against the daily cap the user set for `runner`, if any.
+fn use_runner_cap(e: &Env, user: &Address, runner: &Address, amount: i128) {
+    let mut caps = read_runner_caps(e, user);
+    let Some(mut runner_cap) = caps.get(runner.clone()) else {
+        return;
+    };
+    runner_cap.ensure_day(current_day(e));
+    let used = runner_cap
+        .used_today
+        .checked_add(amount)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::RunnerCapExceeded));
+    if used > runner_cap.cap {
+        panic_with_error!(e, VaultError::RunnerCapExceeded);
+    }
+    runner_cap.used_today = used;
+    caps.set(runner.clone(), runner_cap);
+    write_runner_caps(e, user, &caps);
+}
+
+fn read_session(e: &Env, user: &Address, session_key: &Address) -> Option<SessionKey> {
+    e.storage()
+        .instance()
+        .get(&DataKey::Session(user.clone(), session_key.clone()))
+}
+
+/// Counts `amount` against the session's budget, rejecting expired or exhausted sessions.
+fn spend_session(
+    e: &Env,
+    user: &Address,
+    session_key: &Address,
+    mut session: SessionKey,
+    amount: i128,
+) {
+    if session.expires_at <= e.ledger().timestamp() {
+        panic_with_error!(e, VaultError::AllowanceExpired);
+    }
+    let spent = session
+        .spent
+        .checked_add(amount)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::InvalidAmount));
+    if spent > session.max_total_spend {
+        panic_with_error!(e, VaultError::AllowanceExceeded);
+    }
+    session.spent = spent;
+    e.storage().instance().set(
+        &DataKey::Session(user.clone(), session_key.clone()),
+        &session,
+    );
+}
+
+fn read_policy_operator(e: &Env, user: &Address) -> Option<Address> {
+    e.storage()
+        .instance()
+        .get(&DataKey::PolicyOperator(user.clone()))
+}
+
+/// Requires auth from `caller`, which must be the user or the user's policy operator.
+fn require_user_or_operator(e: &Env, user: &Address, caller: &Address) {
+    if caller != user && read_policy_operator(e, user).as_ref() != Some(caller) {
+        panic_with_error!(e, VaultError::Unauthorized);
+    }
+    caller.require_auth();
+}
+
+fn revoke_all_grants(e: &Env, user: &Address) {
+    let grants = read_runner_grants(e, user);
+    let grants = prune_expired_grants(e, grants);
+    write_runner_grants(e, user, &Vec::new(e));
+
+    let developer_grants = read_developer_grants(e, user);
+    let developer_grants = prune_expired_developer_grants(e, developer_grants);
+    write_developer_grants(e, user, &Vec::new(e));
+
+    let revoked_at = e.ledger().timestamp();
+    for grant in grants.iter() {
+        e.events().publish(
+            (symbol_short!("runner"), symbol_short!("revoked")),
+            RunnerRevokeLog {
+                user: user.clone(),
+                runner: grant.runner,
+                agent_id: grant.agent_id,
+                revoked_at,
+            },
+        );
+    }
+    for grant in developer_grants.iter() {
+        e.events().publish(
+            (symbol_short!("devgrant"), symbol_short!("revoked")),
+            DeveloperRevokeLog {
+                user: user.clone(),
+                runner: grant.runner,
+                developer: grant.developer,
+                revoked_at,
+            },
+        );
+    }
+}
+
+fn read_indexed_run_count(e: &Env, index: &RunIndex) -> u32 {
+    e.storage()
+        .persistent()
+        .get::<_, u32>(&DataKey::IndexedRunCount(index.clone()))
+        .unwrap_or(0)
+}
+
+/// Appends `run_id` to a run listing as its own persistent entry.
+fn index_run(e: &Env, index: RunIndex, run_id: u64) {
+    let position = read_indexed_run_count(e, &index);
+    let entry_key = DataKey::IndexedRun(index.clone(), position);
+    let count_key = DataKey::IndexedRunCount(index);
+    let storage = e.storage().persistent();
+    storage.set(&entry_key, &run_id);
+    storage.set(&count_key, &(position + 1));
+    storage.extend_ttl(&entry_key, TTL_THRESHOLD, TTL_EXTEND_TO);
+    storage.extend_ttl(&count_key, TTL_THRESHOLD, TTL_EXTEND_TO);
+}
+
+/// Examines at most `MAX_RUN_SCAN` entries from `cursor`, collecting up to `limit` runs
+/// that match the filter.
+fn list_indexed_runs(
+    e: &Env,
+    index: RunIndex,
+    lifecycle_filter: Option<LifecycleKind>,
+    cursor: u32,
+    limit: u32,
+) -> RunPage {
+    let count = read_indexed_run_count(e, &index);
+    let limit = limit.min(MAX_PAGE_SIZE);
+    let end = count.min(cursor.saturating_add(MAX_RUN_SCAN));
+    let mut runs = Vec::new(e);
+    let mut position = cursor;
+    while position < end && runs.len() < limit {
+        let key = DataKey::IndexedRun(index.clone(), position);
+        position += 1;
+        let Some(run_id) = e.storage().persistent().get::<_, u64>(&key) else {
+            continue;
+        };
+        if lifecycle_filter
+            .is_some_and(|kind| !read_run_or_panic(e, run_id).lifecycle.is_kind(kind))
+        {
+            continue;
+        }
+        runs.push_back(run_id);
+    }
+    RunPage {
+        runs,
+        next_cursor: (position < count).then_some(position),
+    }
+}
+
+fn read_user_activity(e: &Env, user: &Address) -> UserActivity {
+    let today = current_day(e);
+    let mut activity = e
+        .storage()
+        .instance()
+        .get::<_, UserActivity>(&DataKey::UserActivity(user.clone()))
+        .unwrap_or(UserActivity {
+            open_runs: 0,
+            escrowed: 0,
+            spent_day: today,
+            spent_today: 0,
+        });
+    if activity.spent_day != today {
+        activity.spent_day = today;
+        activity.spent_today = 0;
+    }
+    activity
+}
+
+/// Applies a change in open runs, escrow held and charges settled to the user's totals.
+fn track_user_activity(e: &Env, user: &Address, open_runs: i32, escrowed: i128, spent: i128) {
+    let mut activity = read_user_activity(e, user);
+    activity.open_runs = activity.open_runs.saturating_add_signed(open_runs);
+    activity.escrowed += escrowed;
+    activity.spent_today += spent;
+    e.storage()
+        .instance()
+        .set(&DataKey::UserActivity(user.clone()), &activity);
+}
+
+/// Reads the user's free-tier consumption for `agent_id`, starting afresh on a new day.
+fn read_free_usage(e: &Env, user: &Address, agent_id: u32) -> FreeUsage {
+    let today = current_day(e);
+    match e
+        .storage()
+        .instance()
+        .get::<_, FreeUsage>(&DataKey::FreeUsage(user.clone(), agent_id))
+    {
+        Some(free_usage) if free_usage.day == today => free_usage,
+        _ => FreeUsage {
+            day: today,
+            consumed: Map::new(e),
+        },
+    }
+}
+
+fn write_free_usage(e: &Env, user: &Address, agent_id: u32, free_usage: &FreeUsage) {
+    e.storage()
+        .instance()
+        .set(&DataKey::FreeUsage(user.clone(), agent_id), free_usage);
+}
+
+/// Draws `amount` from the user's allowance for `agent_id`, returning how much was
+/// drawn. Users without an allowance configured are unaffected.
+fn draw_allowance(e: &Env, user: &Address, agent_id: u32, amount: i128) -> i128 {
+    let mut allowance = match read_allowance(e, user, agent_id) {
+        Some(allowance) => allowance,
+        None => return 0,
+    };
+    if let Some(expiry) = allowance.expires_at {
+        if expiry <= e.ledger().timestamp() {
+            panic_with_error!(e, VaultError::AllowanceExpired);
+        }
+    }
+    if allowance.amount < amount {
+        panic_with_error!(e, VaultError::AllowanceExceeded);
+    }
+    allowance.amount -= amount;
+    write_allowance(e, user, agent_id, &allowance);
+    amount
+}
+
+/// Hands `amount` refunded from `record` back to the allowance it was drawn from, never
+/// more than the run drew.
+fn restore_allowance(e: &Env, record: &RunRecord, amount: i128) {
+    let amount = amount.min(record.allowance_drawn);
+    if amount <= 0 {
+        return;
+    }
+    if let Some(mut allowance) = read_allowance(e, &record.user, record.agent_id) {
+        allowance.amount = allowance
+            .amount
+            .checked_add(amount)
+            .unwrap_or_else(|| panic_with_error!(e, VaultError::InvalidAmount));
+        write_allowance(e, &record.user, record.agent_id, &allowance);
+    }
+}
+
+fn read_subscription(e: &Env, user: &Address, agent_id: u32) -> Option<Subscription> {
+    e.storage()
+        .instance()
+        .get::<_, Subscription>(&DataKey::Subscription(user.clone(), agent_id))
+}
+
+fn write_subscription(e: &Env, user: &Address, subscription: &Subscription) {
+    e.storage().instance().set(
+        &DataKey::Subscription(user.clone(), subscription.agent_id),
+        subscription,
+    );
+}
+
+fn prune_expired_grants(e: &Env, grants: Vec<RunnerGrant>) -> Vec<RunnerGrant> {
+    if grants.len() == 0 {
+        return grants;
+    }
+    let now = e.ledger().timestamp();
+    let mut filtered = Vec::new(e);
+    for grant in grants.iter() {
+        match grant.expires_at {
+            Some(expiry) if expiry <= now => {}
+            _ => filtered.push_back(grant),
+        }
+    }
+    filtered
+}
+
+/// Prunes expired grants and revokes those whose runner the registry no longer lists,
+/// so a runner re-added later does not inherit the old grant.
+fn sweep_runner_grants(e: &Env, user: &Address) -> Vec<RunnerGrant> {
+    let grants = read_runner_grants(e, user);
+    let grants = prune_expired_grants(e, grants);
+    if grants.is_empty() {
+        write_runner_grants(e, user, &grants);
+        return grants;
+    }
+
+    let registry_addr = require_registry(e);
+    let registry = AgentRegistryClient::new(e, &registry_addr);
+    let now = e.ledger().timestamp();
+    let mut kept = Vec::new(e);
+    for grant in grants.iter() {
+        if registry.is_runner(&grant.agent_id, &grant.runner) {
+            kept.push_back(grant);
+            continue;
+        }
+        e.events().publish(
+            (symbol_short!("runner"), symbol_short!("revoked")),
+            RunnerRevokeLog {
+                user: user.clone(),
+                runner: grant.runner,
+                agent_id: grant.agent_id,
+                revoked_at: now,
+            },
+        );
+    }
+    write_runner_grants(e, user, &kept);
+    kept
+}
+
+fn remove_runner_grant(
+    e: &Env,
+    grants: Vec<RunnerGrant>,
+    runner: &Address,
+    agent_id: u32,
+) -> (Vec<RunnerGrant>, bool) {
+    if grants.len() == 0 {
+        return (grants, false);
+    }
+    let mut filtered = Vec::new(e);
+    let mut removed = false;
+    for grant in grants.iter() {
+        if grant.runner == runner.clone() && grant.agent_id == agent_id {
+            removed = true;
+            continue;
+        }
+        filtered.push_back(grant);
+    }
+    (filtered, removed)
+}
+
+fn read_rolling_spend(e: &Env, user: &Address) -> RollingSpend {
+    e.storage()
+        .persistent()
+        .get(&DataKey::RollingSpend(user.clone()))
+        .unwrap_or_else(|| RollingSpend::new(e))
+}
+
+fn write_rolling_spend(e: &Env, user: &Address, rolling: &RollingSpend) {
+    let key = DataKey::RollingSpend(user.clone());
+    e.storage().persistent().set(&key, rolling);
+    e.storage()
+        .persistent()
+        .extend_ttl(&key, TTL_THRESHOLD, TTL_EXTEND_TO);
+}
+
+/// Takes the share of a run's reservation matching `refunded` back off the rolling
+/// spend of the day the run was opened.
+fn release_rolling_spend(e: &Env, payer: &Address, record: &RunRecord, refunded: i128) {
+    let reserved_for = record.max_charge + record.priority_fee;
+    if record.reserved <= 0 || refunded <= 0 || reserved_for <= 0 {
+        return;
+    }
+    let key = DataKey::RollingSpend(payer.clone());
+    let Some(mut rolling) = e.storage().persistent().get::<_, RollingSpend>(&key) else {
+        return;
+    };
+    let released = record
+        .reserved
+        .checked_mul(refunded)
+        .map_or(record.reserved, |value| value / reserved_for);
+    rolling.advance(current_day(e));
+    rolling.release(record.opened_at / 86_400, released);
+    write_rolling_spend(e, payer, &rolling);
+}
+
+fn read_agent_stats(e: &Env, agent_id: u32) -> AgentStats {
+    e.storage()
+        .instance()
+        .get::<_, AgentStats>(&DataKey::AgentStats(agent_id))
+        .unwrap_or_default()
+}
+
+fn write_agent_stats(e: &Env, agent_id: u32, stats: &AgentStats) {
+    e.storage()
+        .instance()
+        .set(&DataKey::AgentStats(agent_id), stats);
+}
+
+fn record_run_opened(e: &Env, agent_id: u32) {
+    let mut stats = read_agent_stats(e, agent_id);
+    stats.runs_opened = stats
+        .runs_opened
+        .checked_add(1)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::InvalidAmount));
+    write_agent_stats(e, agent_id, &stats);
+}
+
+fn record_run_finalized(e: &Env, agent_id: u32, actual_charge: i128) {
+    let mut stats = read_agent_stats(e, agent_id);
+    stats.runs_finalized = stats
+        .runs_finalized
+        .checked_add(1)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::InvalidAmount));
+    stats.total_settled_volume = stats
+        .total_settled_volume
+        .checked_add(actual_charge)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::InvalidAmount));
+    write_agent_stats(e, agent_id, &stats);
+}
+
+fn record_run_cancelled(e: &Env, agent_id: u32) {
+    let mut stats = read_agent_stats(e, agent_id);
+    stats.runs_cancelled = stats
+        .runs_cancelled
+        .checked_add(1)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::InvalidAmount));
+    write_agent_stats(e, agent_id, &stats);
+}
 
-        e.storage().instance().set(&DataKey::Run(run_id), &record);
+fn read_user_agent_spend(e: &Env, user: &Address, agent_id: u32) -> i128 {
+    e.storage()
+        .persistent()
+        .get::<_, i128>(&DataKey::UserAgentSpend(user.clone(), agent_id))
+        .unwrap_or(0)
+}
 
-        e.events().publish(
-            (symbol_short!("run"), symbol_short!("opened")),
-            RunOpenedLog {
-                run_id,
-                user,
-                opened_by: caller,
-                agent_id,
-                rate_version,
-                max_charge,
-                budgets: record.budgets.clone(),
-                opened_at: record.opened_at,
-            },
-        );
+fn add_user_agent_spend(e: &Env, user: &Address, agent_id: u32, amount: i128) {
+    let total = read_user_agent_spend(e, user, agent_id)
+        .checked_add(amount)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::InvalidAmount));
+    e.storage()
+        .persistent()
+        .set(&DataKey::UserAgentSpend(user.clone(), agent_id), &total);
+}
 
-        run_id
+fn read_voucher_or_panic(e: &Env, code_hash: &BytesN<32>) -> Voucher {
+    match e
+        .storage()
+        .persistent()
+        .get::<_, Voucher>(&DataKey::Voucher(code_hash.clone()))
+    {
+        Some(voucher) => voucher,
+        None => panic_with_error!(e, VoucherError::VoucherNotFound),
     }
+}
 
-    pub fn finalize_run(
-        e: Env,
-        run_id: u64,
-        runner: Address,
-        rate_version: u32,
-        usage: UsageBreakdown,
-        output_hash: BytesN<32>,
-    ) -> RunReceipt {
-        runner.require_auth();
+fn write_voucher(e: &Env, code_hash: &BytesN<32>, voucher: &Voucher) {
+    let key = DataKey::Voucher(code_hash.clone());
+    e.storage().persistent().set(&key, voucher);
+    e.storage()
+        .persistent()
+        .extend_ttl(&key, TTL_THRESHOLD, TTL_EXTEND_TO);
+}
 
-        if !validate_non_negative_usage(&usage) {
-            panic_with_error!(&e, VaultError::InvalidAmount);
-        }
+fn read_run_output_count(e: &Env, output_hash: &BytesN<32>) -> u32 {
+    e.storage()
+        .persistent()
+        .get::<_, u32>(&DataKey::RunByOutputCount(output_hash.clone()))
+        .unwrap_or(0)
+}
 
-        let mut record = read_run_or_panic(&e, run_id);
-        match record.lifecycle {
-            RunLifecycle::Open => {}
-            _ => panic_with_error!(&e, VaultError::RunNotOpen),
-        }
+/// Records `run_id` as the next entry under the output hash it settled with, one entry
+/// per run so a popular hash never grows a single value. The all-zero hash is what
+/// runners report when they have no output, so it is not indexed.
+fn index_run_output(e: &Env, output_hash: &BytesN<32>, run_id: u64) {
+    if output_hash.to_array() == [0u8; 32] {
+        return;
+    }
+    let index = read_run_output_count(e, output_hash);
+    let entry_key = DataKey::RunByOutput(output_hash.clone(), index);
+    let count_key = DataKey::RunByOutputCount(output_hash.clone());
+    let storage = e.storage().persistent();
+    storage.set(&entry_key, &run_id);
+    storage.set(&count_key, &(index + 1));
+    storage.extend_ttl(&entry_key, TTL_THRESHOLD, TTL_EXTEND_TO);
+    storage.extend_ttl(&count_key, TTL_THRESHOLD, TTL_EXTEND_TO);
+}
 
-        if rate_version != record.rate_version {
-            panic_with_error!(&e, VaultError::InvalidRateVersion);
+/// When a run opened now should expire, given the user's policy TTL and an optional
+/// explicit TTL that may only shorten it.
+fn run_expiry(e: &Env, policy: &UserPolicy, ttl_secs: Option<u64>) -> Option<u64> {
+    let policy_ttl = policy.default_run_ttl_secs;
+    let ttl = match ttl_secs {
+        Some(0) => panic_with_error!(e, VaultError::InvalidAmount),
+        Some(ttl) if policy_ttl > 0 && ttl > policy_ttl => {
+            panic_with_error!(e, VaultError::RunTtlExceedsPolicy)
         }
+        Some(ttl) => ttl,
+        None if policy_ttl > 0 => policy_ttl,
+        None => return None,
+    };
+    Some(e.ledger().timestamp().saturating_add(ttl))
+}
 
-        if usage.llm_in > record.budgets.llm_in
-            || usage.llm_out > record.budgets.llm_out
-            || usage.http_calls > record.budgets.http_calls
-            || usage.runtime_ms > record.budgets.runtime_ms
-        {
-            panic_with_error!(&e, VaultError::UsageExceedsBudget);
+fn is_run_expired(e: &Env, record: &RunRecord) -> bool {
+    match record.expires_at {
+        Some(expires_at) => expires_at <= e.ledger().timestamp(),
+        None => false,
+    }
+}
+
+/// Opens a run for `user`; the `open_run` variants differ only in what they pass. With
+/// a `sponsor`, the escrow, caps and policy checks apply to the sponsor's account
+/// instead, and session keys, runner caps and allowances, which spend `user`'s own
+/// balance, do not apply.
+fn open_user_run(
+    e: &Env,
+    user: Address,
+    caller: Address,
+    agent_id: u32,
+    rate_version: u32,
+    budgets: UsageBreakdown,
+    input_hash: BytesN<32>,
+    ttl_secs: Option<u64>,
+    priority_fee: i128,
+    memo: Option<String>,
+    sponsor: Option<Address>,
+) -> OpenRunResult {
+    caller.require_auth();
+    ensure_not_paused(e);
+    extend_instance_ttl(e);
+    if let Some(memo) = &memo {
+        if memo.len() > MAX_MEMO_LEN {
+            panic_with_error!(e, VaultError::MemoTooLong);
         }
+    }
+    let session = if caller != user && sponsor.is_none() {
+        read_session(e, &user, &caller)
+    } else {
+        None
+    };
+    if caller != user && session.is_none() && !ensure_runner_authorized(e, &user, &caller, agent_id)
+    {
+        panic_with_error!(e, VaultError::UnauthorizedRunner);
+    }
 
-        let registry_addr = require_registry(&e);
-        let registry = AgentRegistryClient::new(&e, &registry_addr);
+    if !validate_non_negative_usage(&budgets) {
+        panic_with_error!(e, VaultError::InvalidAmount);
+    }
 
-        if !registry.is_runner(&record.agent_id, &runner) {
-            panic_with_error!(&e, VaultError::UnauthorizedRunner);
-        }
+    let payer = sponsor.clone().unwrap_or_else(|| user.clone());
+    let mut account = Account::load(e, &payer);
+    account.count_hourly_run(e);
+    let expires_at = run_expiry(e, &account.policy, ttl_secs);
 
-        let rate_card = registry.get_rate_card(&record.agent_id, &record.rate_version);
-        let developer = registry.developer_of(&record.agent_id);
+    let registry_addr = require_registry(e);
+    let registry = AgentRegistryClient::new(e, &registry_addr);
+    let rate_version = resolve_rate_version(e, &registry, agent_id, rate_version);
+    let rate_card = load_openable_rate_card(e, &registry, agent_id, rate_version);
+    ensure_token_matches(e, &payer, &rate_card);
+    if account.policy.only_verified_agents && !registry.is_verified(&agent_id) {
+        panic_with_error!(e, PolicyError::UnverifiedAgent);
+    }
+    let max_charge = max_charge_for(e, &rate_card, &budgets);
+    let escrow = max_charge
+        .checked_add(priority_fee)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::ChargeOverflow));
 
-        if !ensure_runner_authorized(&e, &record.user, &runner, record.agent_id) {
-            panic_with_error!(&e, VaultError::UnauthorizedRunner);
-        }
+    let draws_on_user = caller != user && sponsor.is_none();
+    if draws_on_user {
+        use_runner_cap(e, &user, &caller, escrow);
+    }
+    let allowance_drawn = if let Some(session) = session {
+        spend_session(e, &user, &caller, session, escrow);
+        0
+    } else if draws_on_user {
+        draw_allowance(e, &user, agent_id, escrow)
+    } else {
+        0
+    };
 
-        let actual_charge = compute_charge(&rate_card.rates, &usage)
-            .unwrap_or_else(|| panic_with_error!(&e, VaultError::InvalidAmount));
+    let cap_exempt = read_trusted_agents(e, &payer).contains(agent_id);
+    let reserved = account.reserve_and_debit(e, escrow, &rate_card.token, cap_exempt);
+    account.persist(e);
 
-        if actual_charge > record.max_charge {
-            panic_with_error!(&e, VaultError::UsageExceedsBudget);
-        }
+    let run_id = next_run_id(e);
+    let record = RunRecord {
+        user: user.clone(),
+        opened_by: caller.clone(),
+        agent_id,
+        rate_version,
+        budgets,
+        max_charge,
+        escrowed: escrow,
+        reserved,
+        allowance_drawn,
+        opened_at: e.ledger().timestamp(),
+        lifecycle: RunLifecycle::Open,
+        streamed_usage: UsageBreakdown::zero(e),
+        streamed_charge: 0,
+        streamed_billable: UsageBreakdown::zero(e),
+        require_user_ack: read_policy(e, &user).require_user_ack,
+        expires_at,
+        input_hash,
+        memo,
+        sponsor: sponsor.clone(),
+        priority_fee,
+        pricing: RunPricing::Snapshot(snapshot_rate_card(&rate_card)),
+    };
 
-        let refund = record.max_charge - actual_charge;
+    write_run(e, run_id, &record);
+    record_run_opened(e, agent_id);
+    track_user_activity(e, &payer, 1, escrow, 0);
+    index_run(e, RunIndex::User(record.user.clone()), run_id);
+    index_run(e, RunIndex::Agent(agent_id), run_id);
 
-        // credit developer
-        let dev_balance = read_developer_balance(&e, &developer);
-        let new_dev_balance = dev_balance
-            .checked_add(actual_charge)
-            .unwrap_or_else(|| panic_with_error!(&e, VaultError::InvalidAmount));
-        write_developer_balance(&e, &developer, new_dev_balance);
+    publish_run_event(
+        e,
+        symbol_short!("opened"),
+        agent_id,
+        RunOpenedLog {
+            run_id,
+            user,
+            opened_by: caller,
+            agent_id,
+            rate_version,
+            max_charge,
+            budgets: record.budgets.clone(),
+            opened_at: record.opened_at,
+            input_hash: record.input_hash.clone(),
+            memo: record.memo.clone(),
+            sponsor,
+            priority_fee,
+        },
+    );
 
-        // refund user
-        let user_balance = read_balance(&e, &record.user);
-        let new_user_balance = user_balance
-            .checked_add(refund)
-            .unwrap_or_else(|| panic_with_error!(&e, VaultError::InvalidAmount));
-        write_balance(&e, &record.user, new_user_balance);
+    OpenRunResult {
+        run_id,
+        max_charge,
+        escrowed: record.escrowed,
+        rate_version,
+        opened_at: record.opened_at,
+    }
+}
 
-        // release reservation
-        release_reserved(&e, &record.user, record.max_charge);
+/// The account whose balance funded `record`'s escrow and receives its refunds.
+fn run_payer(record: &RunRecord) -> Address {
+    record
+        .sponsor
+        .clone()
+        .unwrap_or_else(|| record.user.clone())
+}
 
-        record.escrowed = 0;
-        let output_hash_clone = output_hash.clone();
-        record.lifecycle = RunLifecycle::Finalized(RunSettlement {
-            usage: usage.clone(),
-            actual_charge,
-            refund,
-            output_hash,
-        });
+/// Returns whatever is still escrowed for a run to its payer and releases its
+/// reservation.
+fn refund_escrow(e: &Env, record: &mut RunRecord) {
+    let payer = run_payer(record);
+    let mut account = Account::load(e, &payer);
+    account.credit(e, record.escrowed);
+    account.release_reserved(e, record.reserved);
+    account.persist(e);
+    release_rolling_spend(e, &payer, record, record.escrowed);
+    restore_allowance(e, record, record.escrowed);
+    track_user_activity(e, &payer, -1, -record.escrowed, 0);
+    record.escrowed = 0;
+}
+
+/// `usage` added to what the run has already streamed.
+fn streamed_total(e: &Env, record: &RunRecord, usage: &UsageBreakdown) -> UsageBreakdown {
+    record
+        .streamed_usage
+        .checked_add(usage)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::InvalidAmount))
+}
+
+/// Credits a settled `amount` to the developer, less the protocol fee owed to the
+/// treasury and the referral share owed to the user's referrer. Both round down, in the
+/// developer's favor.
+fn credit_settlement(e: &Env, run_id: u64, record: &RunRecord, developer: &Address, amount: i128) {
+    let amount = amount - take_protocol_fee(e, run_id, developer, amount);
+    let referral = match read_referrer(e, &record.user) {
+        Some(referrer) => {
+            let share = amount
+                .checked_mul(read_referral_bps(e) as i128)
+                .unwrap_or_else(|| panic_with_error!(e, VaultError::ChargeOverflow))
+                / BPS_DENOMINATOR as i128;
+            if share > 0 {
+                let balance = read_referrer_balance(e, &referrer);
+                let new_balance = balance
+                    .checked_add(share)
+                    .unwrap_or_else(|| panic_with_error!(e, VaultError::InvalidAmount));
+                write_referrer_balance(e, &referrer, new_balance);
+                e.events().publish(
+                    (symbol_short!("referral"), symbol_short!("credited")),
+                    ReferralCreditLog {
+                        run_id,
+                        user: record.user.clone(),
+                        referrer,
+                        amount: share,
+                    },
+                );
+            }
+            share
+        }
+        None => 0,
+    };
+    distribute_revenue(e, run_id, record.agent_id, developer, amount - referral);
+}
 
-        e.storage().instance().set(&DataKey::Run(run_id), &record);
+/// Credits the configured share of `amount` to the treasury's claimable balance and
+/// returns it. Exempt developers, a zero fee or no treasury take nothing.
+fn take_protocol_fee(e: &Env, run_id: u64, developer: &Address, amount: i128) -> i128 {
+    let storage = e.storage().instance();
+    let fee_bps = storage.get::<_, u32>(&DataKey::FeeBps).unwrap_or(0);
+    let treasury = match storage.get::<_, Address>(&DataKey::Treasury) {
+        Some(treasury) if fee_bps > 0 && !is_fee_exempt(e, developer) => treasury,
+        _ => return 0,
+    };
+    let fee = amount
+        .checked_mul(fee_bps as i128)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::ChargeOverflow))
+        / BPS_DENOMINATOR as i128;
+    if fee == 0 {
+        return 0;
+    }
+    let balance = read_developer_balance(e, &treasury)
+        .checked_add(fee)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::InvalidAmount));
+    write_developer_balance(e, &treasury, balance);
+    e.events().publish(
+        (symbol_short!("fee"), symbol_short!("protocol")),
+        ProtocolFeeLog {
+            run_id,
+            developer: developer.clone(),
+            treasury,
+            amount: fee,
+        },
+    );
+    fee
+}
+
+/// Credits `amount` across the agent's revenue split, or entirely to `developer` when
+/// none is configured. Rounding dust goes to the first payee. Each share of a split is
+/// logged, since the run's settlement only names `developer`.
+fn distribute_revenue(e: &Env, run_id: u64, agent_id: u32, developer: &Address, amount: i128) {
+    let registry = AgentRegistryClient::new(e, &require_registry(e));
+    let splits = registry.revenue_split_of(&agent_id);
+    if splits.is_empty() {
+        credit_developer(e, developer, agent_id, amount);
+        return;
+    }
 
+    let credit_share = |payee: Address, share: i128| {
+        credit_developer(e, &payee, agent_id, share);
         e.events().publish(
-            (symbol_short!("run"), symbol_short!("finalized")),
-            RunFinalizedLog {
+            (symbol_short!("revenue"), symbol_short!("share")),
+            RevenueShareLog {
                 run_id,
-                runner,
-                actual_charge,
-                refund,
-                usage: usage.clone(),
-                output_hash: output_hash_clone,
-                finalized_at: e.ledger().timestamp(),
+                agent_id,
+                payee,
+                amount: share,
             },
         );
-
-        RunReceipt {
-            run_id,
-            actual_charge,
-            refund,
-            developer,
-        }
+    };
+    let mut remaining = amount;
+    for (payee, bps) in splits.iter().skip(1) {
+        let share = amount
+            .checked_mul(bps as i128)
+            .unwrap_or_else(|| panic_with_error!(e, VaultError::ChargeOverflow))
+            / BPS_DENOMINATOR as i128;
+        credit_share(payee, share);
+        remaining -= share;
     }
+    let (first, _) = splits.get_unchecked(0);
+    credit_share(first, remaining);
+}
 
-    pub fn cancel_run(e: Env, user: Address, run_id: u64) {
-        user.require_auth();
-        let mut record = read_run_or_panic(&e, run_id);
-        if record.user != user {
-            panic_with_error!(&e, VaultError::Unauthorized);
-        }
-        match record.lifecycle {
-            RunLifecycle::Open => {}
-            _ => panic_with_error!(&e, VaultError::RunNotOpen),
-        }
+fn publish_withdraw(e: &Env, user: Address, amount: i128) {
+    e.events().publish(
+        (symbol_short!("withdraw"), symbol_short!("user")),
+        WithdrawLog { user, amount },
+    );
+}
 
-        let user_balance = read_balance(&e, &user);
-        let new_balance = user_balance
-            .checked_add(record.escrowed)
-            .unwrap_or_else(|| panic_with_error!(&e, VaultError::InvalidAmount));
-        write_balance(&e, &user, new_balance);
+fn publish_developer_claim(e: &Env, developer: Address, destination: Address, amount: i128) {
+    e.events().publish(
+        (symbol_short!("developer"), symbol_short!("claimed")),
+        DeveloperClaimLog {
+            developer,
+            destination,
+            amount,
+        },
+    );
+}
 
-        release_reserved(&e, &user, record.max_charge);
+/// Credits the developer's claimable balance and the lifetime earnings counter of the
+/// agent that earned `amount`.
+fn credit_developer(e: &Env, developer: &Address, agent_id: u32, amount: i128) {
+    let dev_balance = read_developer_balance(e, developer);
+    let new_dev_balance = dev_balance
+        .checked_add(amount)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::InvalidAmount));
+    write_developer_balance(e, developer, new_dev_balance);
 
-        record.escrowed = 0;
-        record.lifecycle = RunLifecycle::Cancelled;
+    let earnings = read_agent_earnings(e, developer, agent_id)
+        .checked_add(amount)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::InvalidAmount));
+    write_persistent(
+        e,
+        &DataKey::DeveloperAgentEarnings(developer.clone(), agent_id),
+        &earnings,
+    );
+}
 
-        e.storage().instance().set(&DataKey::Run(run_id), &record);
+/// Loads an open run and checks that `runner` may report `usage` for it on top of what
+/// has already been streamed.
+fn read_settleable_run(
+    e: &Env,
+    run_id: u64,
+    runner: &Address,
+    usage: &UsageBreakdown,
+) -> (RunRecord, SettlementInfo) {
+    if !validate_non_negative_usage(usage) {
+        panic_with_error!(e, VaultError::InvalidAmount);
     }
 
-    pub fn balance_of(e: Env, user: Address) -> i128 {
-        read_balance(&e, &user)
+    let record = read_run_or_panic(e, run_id);
+    match record.lifecycle {
+        RunLifecycle::Open => {}
+        _ => panic_with_error!(e, VaultError::RunNotOpen),
+    }
+    if is_run_expired(e, &record) {
+        panic_with_error!(e, VaultError::RunExpired);
     }
 
-    pub fn developer_balance(e: Env, developer: Address) -> i128 {
-        read_developer_balance(&e, &developer)
+    if exceeds_budget(&streamed_total(e, &record, usage), &record.budgets) {
+        panic_with_error!(e, VaultError::UsageExceedsBudget);
     }
 
-    pub fn claim_developer(e: Env, developer: Address, amount: i128) {
-        developer.require_auth();
-        if amount <= 0 {
-            panic_with_error!(&e, VaultError::InvalidAmount);
-        }
-        let balance = read_developer_balance(&e, &developer);
-        if balance < amount {
-            panic_with_error!(&e, VaultError::InsufficientBalance);
-        }
-        write_developer_balance(&e, &developer, balance - amount);
+    let registry_addr = require_registry(e);
+    let registry = AgentRegistryClient::new(e, &registry_addr);
+    let info = fetch_settlement_info(e, &registry, &record);
+
+    if info.paused {
+        panic_with_error!(e, VaultError::AgentPaused);
     }
 
-    pub fn get_run(e: Env, run_id: u64) -> RunRecord {
-        read_run_or_panic(&e, run_id)
+    if !registry.is_runner(&record.agent_id, runner)
+        || !runner_holds_grant(e, &record.user, runner, record.agent_id, || {
+            info.developer.clone()
+        })
+    {
+        panic_with_error!(e, VaultError::UnauthorizedRunner);
     }
+    (record, info)
 }
 
-fn ensure_runner_authorized(e: &Env, user: &Address, runner: &Address, agent_id: u32) -> bool {
-    let grants = read_runner_grants(e, user);
-    let grants = prune_expired_grants(e, grants);
-    let mut authorized = false;
+/// Charges `usage` against an escrowed run, crediting the developer and refunding the
+/// rest to the user.
+fn settle_run(
+    e: &Env,
+    run_id: u64,
+    mut record: RunRecord,
+    info: SettlementInfo,
+    runner: Address,
+    usage: UsageBreakdown,
+    output_hash: BytesN<32>,
+    failure_code: Option<u32>,
+) -> RunReceipt {
+    let pricing = run_rate_snapshot(e, &record, &info);
+    let developer = info.payout;
 
-    for grant in grants.iter() {
-        if grant.runner == runner.clone() && grant.agent_id == agent_id {
-            authorized = true;
-            break;
-        }
-    }
+    let mut free_usage = read_free_usage(e, &record.user, record.agent_id);
+    let billable = deduct_free_units(
+        e,
+        &usage,
+        &pricing.free_daily_units,
+        &mut free_usage.consumed,
+    );
+    // Price the whole run once, so the streamed charges round no differently.
+    let billable = record
+        .streamed_billable
+        .checked_add(&billable)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::InvalidAmount));
+    let mut charges = charge_or_panic(
+        e,
+        compute_charge_breakdown(
+            &pricing.rates,
+            pricing.base_fee,
+            &billable,
+            pricing.unit_scale,
+        ),
+    );
 
-    if authorized {
-        let registry_addr = require_registry(e);
-        let registry = AgentRegistryClient::new(e, &registry_addr);
-        if !registry.is_runner(&agent_id, runner) {
-            let (filtered, _) = remove_runner_grant(e, grants, runner, agent_id);
-            write_runner_grants(e, user, &filtered);
-            return false;
-        }
-        write_runner_grants(e, user, &grants);
-        true
-    } else {
-        write_runner_grants(e, user, &grants);
-        false
+    let metered_charge = charges.total();
+    if metered_charge > record.max_charge {
+        panic_with_error!(e, VaultError::UsageExceedsBudget);
     }
-}
+    let actual_charge = metered_charge
+        .max(pricing.min_charge)
+        .min(record.max_charge);
+    charges.minimum = actual_charge - metered_charge;
+    let final_charge = actual_charge - record.streamed_charge;
+    // Failed runs get the discount off what is still to be charged; streamed charges
+    // were already paid out.
+    let discount = match failure_code {
+        Some(_) => {
+            final_charge
+                .checked_mul(i128::from(read_failure_discount_bps(e)))
+                .unwrap_or_else(|| panic_with_error!(e, VaultError::ChargeOverflow))
+                / i128::from(BPS_DENOMINATOR)
+        }
+        None => 0,
+    };
+    let final_charge = final_charge - discount;
+    let actual_charge = actual_charge - discount;
+    charges.discount = discount;
+    // Streamed and final payments together are what the breakdown adds up to, and
+    // only rounding is left unattributed.
+    debug_assert_eq!(record.streamed_charge + final_charge, charges.total());
+    debug_assert!(
+        charges.adjustment >= 0 && charges.adjustment <= 4 + i128::from(billable.extra.len())
+    );
 
-fn require_registry(e: &Env) -> Address {
-    match e
-        .storage()
-        .instance()
-        .get::<_, Address>(&DataKey::AgentRegistry)
-    {
-        Some(addr) => addr,
-        None => panic_with_error!(e, VaultError::NotInitialized),
+    if !pricing.free_daily_units.is_empty() {
+        write_free_usage(e, &record.user, record.agent_id, &free_usage);
     }
-}
 
-fn read_balance(e: &Env, user: &Address) -> i128 {
-    e.storage()
-        .instance()
-        .get::<_, i128>(&DataKey::UserBalance(user.clone()))
-        .unwrap_or(0)
-}
+    record_settled_outflow(e, final_charge);
 
-fn write_balance(e: &Env, user: &Address, amount: i128) {
-    e.storage()
-        .instance()
-        .set(&DataKey::UserBalance(user.clone()), &amount);
-}
+    let refund = record.max_charge - actual_charge;
 
-fn read_developer_balance(e: &Env, developer: &Address) -> i128 {
-    e.storage()
-        .instance()
-        .get::<_, i128>(&DataKey::DeveloperBalance(developer.clone()))
-        .unwrap_or(0)
-}
+    credit_settlement(e, run_id, &record, &developer, final_charge);
+    if record.priority_fee > 0 {
+        credit_runner(e, &runner, record.priority_fee);
+    }
 
-fn write_developer_balance(e: &Env, developer: &Address, amount: i128) {
-    e.storage()
-        .instance()
-        .set(&DataKey::DeveloperBalance(developer.clone()), &amount);
-}
+    // refund whoever funded the escrow
+    let payer = run_payer(&record);
+    let mut account = Account::load(e, &payer);
+    account.credit(e, refund);
+    account.release_reserved(e, record.reserved);
+    account.persist(e);
+    release_rolling_spend(e, &payer, &record, refund);
+    restore_allowance(e, &record, refund);
 
-fn read_policy(e: &Env, user: &Address) -> UserPolicy {
-    e.storage()
-        .instance()
-        .get::<_, UserPolicy>(&DataKey::UserPolicy(user.clone()))
-        .unwrap_or_default()
-}
+    let usage = streamed_total(e, &record, &usage);
+    track_user_activity(
+        e,
+        &payer,
+        -1,
+        -record.escrowed,
+        final_charge + record.priority_fee,
+    );
+    record.escrowed = 0;
+    let settlement = RunSettlement {
+        usage: usage.clone(),
+        actual_charge,
+        refund,
+        output_hash: output_hash.clone(),
+        developer: developer.clone(),
+        goodwill_refunded: 0,
+        tipped: 0,
+        charges: charges.clone(),
+    };
+    record.lifecycle = match failure_code {
+        Some(failure_code) => RunLifecycle::Failed(FailedSettlement {
+            settlement,
+            failure_code,
+            discount,
+        }),
+        None => RunLifecycle::Finalized(settlement),
+    };
 
-fn write_policy(e: &Env, user: &Address, policy: &UserPolicy) {
-    e.storage()
-        .instance()
-        .set(&DataKey::UserPolicy(user.clone()), policy);
-}
+    write_run(e, run_id, &record);
+    record_run_finalized(e, record.agent_id, actual_charge);
+    add_user_agent_spend(e, &record.user, record.agent_id, actual_charge);
+    index_run_output(e, &output_hash, run_id);
+    index_run(e, RunIndex::Runner(runner.clone()), run_id);
 
-fn read_runner_grants(e: &Env, user: &Address) -> Vec<RunnerGrant> {
-    e.storage()
-        .instance()
-        .get::<_, Vec<RunnerGrant>>(&DataKey::RunnerGrants(user.clone()))
-        .unwrap_or_else(|| Vec::new(e))
-}
+    let finalized_at = e.ledger().timestamp();
+    e.events().publish(
+        (
+            symbol_short!("run"),
+            symbol_short!("finalized"),
+            record.agent_id,
+        ),
+        RunFinalizedLog {
+            run_id,
+            agent_id: record.agent_id,
+            user: record.user.clone(),
+            sponsor: record.sponsor.clone(),
+            developer: developer.clone(),
+            runner,
+            input_hash: record.input_hash.clone(),
+            actual_charge,
+            refund,
+            priority_fee: record.priority_fee,
+            usage: usage.clone(),
+            charges,
+            output_hash: output_hash.clone(),
+            failure_code,
+            finalized_at,
+        },
+    );
 
-fn write_runner_grants(e: &Env, user: &Address, grants: &Vec<RunnerGrant>) {
-    if grants.len() == 0 {
-        e.storage()
-            .instance()
-            .remove(&DataKey::RunnerGrants(user.clone()));
-    } else {
-        e.storage()
-            .instance()
-            .set(&DataKey::RunnerGrants(user.clone()), grants);
+    RunReceipt {
+        run_id,
+        agent_id: record.agent_id,
+        rate_version: record.rate_version,
+        usage,
+        actual_charge,
+        refund,
+        priority_fee: record.priority_fee,
+        output_hash,
+        developer,
+        failure_code,
+        finalized_at,
     }
 }
 
-fn prune_expired_grants(e: &Env, grants: Vec<RunnerGrant>) -> Vec<RunnerGrant> {
-    if grants.len() == 0 {
-        return grants;
-    }
-    let now = e.ledger().timestamp();
-    let mut filtered = Vec::new(e);
-    for grant in grants.iter() {
-        match grant.expires_at {
-            Some(expiry) if expiry <= now => {}
-            _ => filtered.push_back(grant),
-        }
+/// The max charge of a run with `budgets` and the token it is priced in.
+fn quote_max_charge(
+    e: &Env,
+    agent_id: u32,
+    rate_version: u32,
+    budgets: &UsageBreakdown,
+) -> (i128, Address) {
+    if !validate_non_negative_usage(budgets) {
+        panic_with_error!(e, VaultError::InvalidAmount);
     }
-    filtered
+    let registry_addr = require_registry(e);
+    let registry = AgentRegistryClient::new(e, &registry_addr);
+    let rate_version = resolve_rate_version(e, &registry, agent_id, rate_version);
+    let rate_card = load_openable_rate_card(e, &registry, agent_id, rate_version);
+    (max_charge_for(e, &rate_card, budgets), rate_card.token)
 }
 
-fn remove_runner_grant(
+/// Maps `rate_version` 0 to the agent's latest effective version at execution time.
+fn resolve_rate_version(
     e: &Env,
-    grants: Vec<RunnerGrant>,
-    runner: &Address,
+    registry: &AgentRegistryClient,
     agent_id: u32,
-) -> (Vec<RunnerGrant>, bool) {
-    if grants.len() == 0 {
-        return (grants, false);
-    }
-    let mut filtered = Vec::new(e);
-    let mut removed = false;
-    for grant in grants.iter() {
-        if grant.runner == runner.clone() && grant.agent_id == agent_id {
-            removed = true;
-            continue;
+    rate_version: u32,
+) -> u32 {
+    if rate_version == 0 {
+        match registry.try_latest_rate_version(&agent_id) {
+            Ok(Ok(version)) => version,
+            _ => panic_with_error!(e, VaultError::AgentNotFound),
         }
-        filtered.push_back(grant);
+    } else {
+        rate_version
     }
-    (filtered, removed)
 }
 
-fn release_reserved(e: &Env, user: &Address, amount: i128) {
-    let mut policy = read_policy(e, user);
-    let today = current_day(e);
-    policy.ensure_day(today);
-    if policy.reserved_today >= amount {
-        policy.reserved_today -= amount;
-    } else {
-        policy.reserved_today = 0;
+/// The most a run with `budgets` can be charged under `rate_card`, rejecting budgets that
+/// report unpriced meters or cannot cover the card's minimum charge.
+fn max_charge_for(e: &Env, rate_card: &RateCard, budgets: &UsageBreakdown) -> i128 {
+    if has_unpriced_meter(&rate_card.rates, budgets) {
+        panic_with_error!(e, VaultError::UnknownMeter);
+    }
+    let max_charge = charge_or_panic(
+        e,
+        compute_charge(
+            &rate_card.rates,
+            rate_card.base_fee,
+            budgets,
+            rate_card.unit_scale,
+        ),
+    );
+    if max_charge < rate_card.min_charge {
+        panic_with_error!(e, VaultError::BudgetBelowMinCharge);
+    }
+    max_charge
+}
+
+fn charge_or_panic<T>(e: &Env, charge: Result<T, ChargeError>) -> T {
+    match charge {
+        Ok(amount) => amount,
+        Err(ChargeError::Overflow) => panic_with_error!(e, VaultError::ChargeOverflow),
+        Err(ChargeError::NegativeInput) => panic_with_error!(e, VaultError::InvalidAmount),
+        Err(ChargeError::UnpricedMeter) => panic_with_error!(e, VaultError::UnknownMeter),
+        Err(ChargeError::ZeroUnitScale) => panic_with_error!(e, VaultError::InvalidAmount),
     }
-    write_policy(e, user, &policy);
 }
 
 fn next_run_id(e: &Env) -> u64 {
@@ -542,12 +4453,28 @@ fn next_run_id(e: &Env) -> u64 {
 }
 
 fn read_run_or_panic(e: &Env, run_id: u64) -> RunRecord {
-    match e
-        .storage()
-        .instance()
-        .get::<_, RunRecord>(&DataKey::Run(run_id))
-    {
+    match read_run(e, run_id) {
         Some(record) => record,
         None => panic_with_error!(e, VaultError::RunNotFound),
     }
 }
+
+/// The run, upgrading one still in instance storage in the schema version 1 layout.
+fn read_run(e: &Env, run_id: u64) -> Option<RunRecord> {
+    let key = DataKey::Run(run_id);
+    if let Some(stored) = e.storage().persistent().get::<_, Val>(&key) {
+        if !has_field(e, &stored, "pricing") {
+            panic_with_error!(e, SchemaError::UnknownLayout);
+        }
+        return Some(decode_stored::<RunRecord>(e, &stored));
+    }
+    let stored = e.storage().instance().get::<_, Val>(&key)?;
+    if !has_layout(e, &stored, &RunRecordV1::FIELDS) {
+        panic_with_error!(e, SchemaError::UnknownLayout);
+    }
+    let record = decode_stored::<RunRecordV1>(e, &stored);
+    let agent_id = record.agent_id;
+    Some(record.upgrade(e, || {
+        AgentRegistryClient::new(e, &require_registry(e)).developer_of(&agent_id)
+    }))
+}
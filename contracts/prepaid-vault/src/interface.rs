@@ -0,0 +1,428 @@
+use soroban_sdk::{contractclient, Address, Bytes, BytesN, Env, Map, String, Symbol, Vec};
+
+use crate::types::{
+    AgentAllowance, AgentStats, CircuitBreaker, ConfigUpdate, DeveloperGrant, InitConfig,
+    LifecycleKind, OpenRunResult, PauseLevel, PendingWithdrawal, PolicyInput, PriceOracleConfig,
+    QuoteResult, RunPage, RunReceipt, RunRecord, RunnerCap, RunnerGrant, SessionKey, Subscription,
+    UsageBreakdown, UsageMeterRates, UserPolicy, UserStats, VaultConfig, VersionedKey, Voucher,
+};
+
+/// Client-only interface for invoking the PrepaidVault contract.
+#[allow(dead_code)]
+#[contractclient(name = "PrepaidVaultClient")]
+pub trait PrepaidVaultInterface {
+    fn init(env: Env, admin: Address, registry: Address);
+
+    fn init_with_config(env: Env, config: InitConfig);
+
+    fn update_config(env: Env, update: ConfigUpdate);
+
+    fn get_config(env: Env) -> VaultConfig;
+
+    fn schema_version(env: Env) -> u32;
+
+    fn migrate(env: Env, from_version: u32, batch: Vec<VersionedKey>) -> u32;
+
+    fn finish_migration(env: Env, from_version: u32) -> u32;
+
+    fn migrated_entries(env: Env) -> u32;
+
+    fn pause_new_activity(env: Env);
+
+    fn pause_all(env: Env);
+
+    fn pause_vault(env: Env);
+
+    fn unpause_vault(env: Env);
+
+    fn is_vault_paused(env: Env) -> bool;
+
+    fn pause_level(env: Env) -> PauseLevel;
+
+    fn set_circuit_breaker_ceiling(env: Env, ceiling: i128);
+
+    fn override_circuit_breaker(env: Env);
+
+    fn circuit_breaker(env: Env) -> CircuitBreaker;
+
+    fn set_price_oracle(env: Env, config: Option<PriceOracleConfig>);
+
+    fn price_oracle(env: Env) -> Option<PriceOracleConfig>;
+
+    fn set_referral_bps(env: Env, bps: u32);
+
+    fn referral_bps(env: Env) -> u32;
+
+    fn add_fee_exempt(env: Env, developer: Address);
+
+    fn remove_fee_exempt(env: Env, developer: Address);
+
+    fn is_fee_exempt(env: Env, developer: Address) -> bool;
+
+    fn admin(env: Env) -> Address;
+
+    fn deposit(env: Env, user: Address, amount: i128);
+
+    fn deposit_for(env: Env, payer: Address, beneficiary: Address, amount: i128);
+
+    fn approve_deposits(env: Env, user: Address, spender: Address, amount: i128);
+
+    fn deposit_allowance(env: Env, user: Address, spender: Address) -> i128;
+
+    fn deposit_from_allowance(env: Env, spender: Address, user: Address, amount: i128);
+
+    fn withdraw(env: Env, user: Address, amount: i128);
+
+    fn withdraw_all(env: Env, user: Address) -> i128;
+
+    fn set_withdrawal_delay(env: Env, user: Address, delay_secs: u64);
+
+    fn withdrawal_delay_of(env: Env, user: Address) -> u64;
+
+    fn request_withdrawal(env: Env, user: Address, amount: i128) -> PendingWithdrawal;
+
+    fn execute_withdrawal(env: Env, user: Address) -> i128;
+
+    fn cancel_withdrawal(env: Env, user: Address);
+
+    fn pending_withdrawal(env: Env, user: Address) -> Option<PendingWithdrawal>;
+
+    fn set_migration_target(env: Env, target: Option<Address>);
+
+    fn migration_target(env: Env) -> Option<Address>;
+
+    fn set_migration_source(env: Env, source: Option<Address>);
+
+    fn migration_source(env: Env) -> Option<Address>;
+
+    fn migrate_balance(env: Env, user: Address) -> i128;
+
+    fn receive_migration(env: Env, user: Address, amount: i128);
+
+    fn set_policy_operator(env: Env, user: Address, operator: Option<Address>);
+
+    fn policy_operator_of(env: Env, user: Address) -> Option<Address>;
+
+    fn set_guardian(env: Env, user: Address, guardian: Option<Address>);
+
+    fn guardian_of(env: Env, user: Address) -> Option<Address>;
+
+    fn guardian_pause(env: Env, user: Address);
+
+    fn set_policy(env: Env, user: Address, caller: Address, policy: PolicyInput);
+
+    fn get_policy(env: Env, user: Address) -> UserPolicy;
+
+    fn rolling_spend(env: Env, user: Address) -> i128;
+
+    fn trust_agent(env: Env, user: Address, agent_id: u32);
+
+    fn untrust_agent(env: Env, user: Address, agent_id: u32);
+
+    fn is_trusted(env: Env, user: Address, agent_id: u32) -> bool;
+
+    fn list_trusted(env: Env, user: Address) -> Vec<u32>;
+
+    fn daily_headroom(env: Env, user: Address) -> i128;
+
+    fn get_user_stats(env: Env, user: Address) -> UserStats;
+
+    fn per_run_cap_of(env: Env, user: Address) -> i128;
+
+    fn set_balance_token(env: Env, user: Address, token: Address);
+
+    fn balance_token_of(env: Env, user: Address) -> Option<Address>;
+
+    fn grant_runner(
+        env: Env,
+        user: Address,
+        runner: Address,
+        agent_id: u32,
+        expires_at: Option<u64>,
+    );
+
+    fn revoke_runner(env: Env, user: Address, caller: Address, runner: Address, agent_id: u32);
+
+    fn grant_runner_for_developer(
+        env: Env,
+        user: Address,
+        runner: Address,
+        developer: Address,
+        expires_at: Option<u64>,
+    );
+
+    fn revoke_runner_for_developer(
+        env: Env,
+        user: Address,
+        caller: Address,
+        runner: Address,
+        developer: Address,
+    );
+
+    fn list_developer_grants(env: Env, user: Address) -> Vec<DeveloperGrant>;
+
+    fn revoke_all_runners(env: Env, user: Address, caller: Address);
+
+    fn extend_runner_grant(
+        env: Env,
+        user: Address,
+        runner: Address,
+        agent_id: u32,
+        new_expires_at: Option<u64>,
+    );
+
+    fn list_runner_grants(env: Env, user: Address) -> Vec<RunnerGrant>;
+
+    fn sweep_grants(env: Env, user: Address) -> u32;
+
+    fn sweep_invalid_grants(env: Env, user: Address);
+
+    fn set_runner_cap(env: Env, user: Address, runner: Address, cap: i128);
+
+    fn clear_runner_cap(env: Env, user: Address, runner: Address);
+
+    fn list_runner_caps(env: Env, user: Address) -> Map<Address, RunnerCap>;
+
+    fn authorize_session(
+        env: Env,
+        user: Address,
+        session_key: Address,
+        expires_at: u64,
+        max_total_spend: i128,
+    );
+
+    fn revoke_session(env: Env, user: Address, session_key: Address);
+
+    fn get_session(env: Env, user: Address, session_key: Address) -> Option<SessionKey>;
+
+    fn set_agent_allowance(
+        env: Env,
+        user: Address,
+        caller: Address,
+        agent_id: u32,
+        amount: i128,
+        expires_at: Option<u64>,
+    );
+
+    fn get_agent_allowance(env: Env, user: Address, agent_id: u32) -> Option<AgentAllowance>;
+
+    fn free_units_remaining(env: Env, user: Address, agent_id: u32) -> Map<Symbol, i128>;
+
+    fn is_runner_authorized(env: Env, user: Address, runner: Address, agent_id: u32) -> bool;
+
+    fn save_budget_preset(env: Env, user: Address, name: Symbol, budgets: UsageBreakdown);
+
+    fn delete_budget_preset(env: Env, user: Address, name: Symbol);
+
+    fn list_budget_presets(env: Env, user: Address) -> Map<Symbol, UsageBreakdown>;
+
+    fn open_run_with_preset(
+        env: Env,
+        user: Address,
+        caller: Address,
+        agent_id: u32,
+        rate_version: u32,
+        preset: Symbol,
+        input_hash: BytesN<32>,
+        memo: Option<String>,
+    ) -> OpenRunResult;
+
+    fn open_run(
+        env: Env,
+        user: Address,
+        caller: Address,
+        agent_id: u32,
+        rate_version: u32,
+        budgets: UsageBreakdown,
+        input_hash: BytesN<32>,
+        memo: Option<String>,
+    ) -> OpenRunResult;
+
+    fn open_run_with_ttl(
+        env: Env,
+        user: Address,
+        caller: Address,
+        agent_id: u32,
+        rate_version: u32,
+        budgets: UsageBreakdown,
+        input_hash: BytesN<32>,
+        ttl_secs: Option<u64>,
+        memo: Option<String>,
+    ) -> OpenRunResult;
+
+    fn open_run_with_priority_fee(
+        env: Env,
+        user: Address,
+        caller: Address,
+        agent_id: u32,
+        rate_version: u32,
+        budgets: UsageBreakdown,
+        input_hash: BytesN<32>,
+        priority_fee: i128,
+        memo: Option<String>,
+    ) -> OpenRunResult;
+
+    fn compute_charge_view(env: Env, rates: UsageMeterRates, usage: UsageBreakdown) -> i128;
+
+    fn compute_charge_for_agent(
+        env: Env,
+        agent_id: u32,
+        rate_version: u32,
+        usage: UsageBreakdown,
+    ) -> i128;
+
+    fn quote_run(env: Env, agent_id: u32, rate_version: u32, budgets: UsageBreakdown) -> i128;
+
+    fn quote_run_for(
+        env: Env,
+        user: Address,
+        agent_id: u32,
+        rate_version: u32,
+        budgets: UsageBreakdown,
+    ) -> QuoteResult;
+
+    fn runs_by_output_hash(env: Env, output_hash: BytesN<32>, offset: u32, limit: u32) -> Vec<u64>;
+
+    fn open_sponsored_run(
+        env: Env,
+        sponsor: Address,
+        user: Address,
+        caller: Address,
+        agent_id: u32,
+        rate_version: u32,
+        budgets: UsageBreakdown,
+        input_hash: BytesN<32>,
+        memo: Option<String>,
+    ) -> OpenRunResult;
+
+    fn finalize_run(
+        env: Env,
+        run_id: u64,
+        runner: Address,
+        rate_version: u32,
+        usage: UsageBreakdown,
+        output_hash: BytesN<32>,
+    ) -> RunReceipt;
+
+    fn finalize_failed(
+        env: Env,
+        run_id: u64,
+        runner: Address,
+        usage: UsageBreakdown,
+        failure_code: u32,
+    ) -> RunReceipt;
+
+    fn charge_run(env: Env, run_id: u64, runner: Address, usage_delta: UsageBreakdown) -> i128;
+
+    fn propose_settlement(
+        env: Env,
+        run_id: u64,
+        runner: Address,
+        usage: UsageBreakdown,
+        output_hash: BytesN<32>,
+    );
+
+    fn accept_settlement(env: Env, run_id: u64, user: Address) -> RunReceipt;
+
+    fn reject_settlement(env: Env, run_id: u64, user: Address);
+
+    fn settle_dispute(env: Env, run_id: u64, usage: UsageBreakdown) -> RunReceipt;
+
+    fn void_dispute(env: Env, run_id: u64);
+
+    fn cancel_run(env: Env, user: Address, run_id: u64);
+
+    fn expire_run(env: Env, run_id: u64);
+
+    fn force_close_run(env: Env, user: Address, run_id: u64);
+
+    fn open_subscription(env: Env, user: Address, agent_id: u32, rate_version: u32)
+        -> Subscription;
+
+    fn release_subscription(env: Env, user: Address, agent_id: u32) -> i128;
+
+    fn has_active_subscription(env: Env, user: Address, agent_id: u32) -> bool;
+
+    fn get_subscription(env: Env, user: Address, agent_id: u32) -> Option<Subscription>;
+
+    fn agent_stats(env: Env, agent_id: u32) -> AgentStats;
+
+    fn spend_on_agent(env: Env, user: Address, agent_id: u32) -> i128;
+
+    fn list_agent_spend(env: Env, user: Address, agent_ids: Vec<u32>) -> Vec<i128>;
+
+    fn balance_of(env: Env, user: Address) -> i128;
+
+    fn developer_balance(env: Env, developer: Address) -> i128;
+
+    fn earnings_for_agent(env: Env, developer: Address, agent_id: u32) -> i128;
+
+    fn earnings_breakdown(env: Env, developer: Address, agent_ids: Vec<u32>) -> Vec<i128>;
+
+    fn claim_developer(env: Env, developer: Address, amount: i128);
+
+    fn claim_developer_to(env: Env, developer: Address, destination: Address, amount: i128);
+
+    fn claim_developer_all(env: Env, developer: Address) -> i128;
+
+    fn tip_run(env: Env, user: Address, run_id: u64, amount: i128);
+
+    fn refund_user(env: Env, developer: Address, run_id: u64, amount: i128);
+
+    fn create_voucher(
+        env: Env,
+        funder: Address,
+        code_hash: BytesN<32>,
+        amount: i128,
+        max_redemptions: u32,
+        expires_at: u64,
+    );
+
+    fn redeem_voucher(env: Env, user: Address, preimage: Bytes) -> i128;
+
+    fn reclaim_voucher(env: Env, code_hash: BytesN<32>) -> i128;
+
+    fn get_voucher(env: Env, code_hash: BytesN<32>) -> Option<Voucher>;
+
+    fn set_referrer(env: Env, user: Address, referrer: Address);
+
+    fn referrer_of(env: Env, user: Address) -> Option<Address>;
+
+    fn referrer_balance(env: Env, referrer: Address) -> i128;
+
+    fn claim_referrer(env: Env, referrer: Address, amount: i128);
+
+    fn runner_balance(env: Env, runner: Address) -> i128;
+
+    fn claim_runner(env: Env, runner: Address, amount: i128);
+
+    fn list_runs_for_user(
+        env: Env,
+        user: Address,
+        lifecycle_filter: Option<LifecycleKind>,
+        cursor: u32,
+        limit: u32,
+    ) -> RunPage;
+
+    fn list_runs_for_agent(
+        env: Env,
+        agent_id: u32,
+        lifecycle_filter: Option<LifecycleKind>,
+        cursor: u32,
+        limit: u32,
+    ) -> RunPage;
+
+    fn list_runs_for_runner(
+        env: Env,
+        runner: Address,
+        lifecycle_filter: Option<LifecycleKind>,
+        cursor: u32,
+        limit: u32,
+    ) -> RunPage;
+
+    fn bump_user_ttl(env: Env, user: Address);
+
+    fn bump_run_ttl(env: Env, run_id: u64);
+
+    fn get_run(env: Env, run_id: u64) -> RunRecord;
+}
@@ -1,16 +1,48 @@
 #![no_std]
 #![allow(clippy::too_many_arguments)]
 
+#[cfg(feature = "contract")]
 mod contract;
+
+#[cfg(feature = "contract")]
+mod oracle;
+
+#[cfg(feature = "contract")]
 mod storage;
+
+#[cfg(feature = "interface")]
+mod interface;
 mod types;
+
+#[cfg(feature = "contract")]
 mod utils;
 
+#[cfg(feature = "contract")]
 pub use contract::PrepaidVault;
+
+#[cfg(all(feature = "contract", not(feature = "interface")))]
+pub use contract::PrepaidVaultClient;
+
+#[cfg(feature = "interface")]
+pub use interface::PrepaidVaultClient;
 pub use types::{
-    PolicyInput, RunFinalizedLog, RunLifecycle, RunOpenedLog, RunReceipt, RunSettlement,
-    RunnerGrant, RunnerGrantLog, RunnerRevokeLog, UsageBreakdown, UserPolicy, VaultError,
+    AgentAllowance, AgentStats, AllowanceSetLog, ChargeBreakdown, CircuitBreaker, ConfigUpdate,
+    DepositApprovalLog, DepositForLog, DeveloperClaimLog, DeveloperGrant, DeveloperGrantLog,
+    DeveloperRevokeLog, DisputeError, FailedSettlement, GoodwillRefundLog, GuardianPauseLog,
+    InitConfig, LifecycleKind, MigrationLog, OpenRunResult, PauseLevel, PendingWithdrawal,
+    PolicyError, PolicyInput, PriceOracleConfig, PriceOracleError, ProposedSettlement,
+    ProtocolFeeLog, QuoteResult, RateSnapshot, ReferralCreditLog, RevenueShareLog, RunChargedLog,
+    RunClosedLog, RunDisputedLog, RunFinalizedLog, RunLifecycle, RunOpenedLog, RunPage, RunPricing,
+    RunReceipt, RunRecord, RunSettlement, RunTipLog, RunnerCap, RunnerClaimLog, RunnerGrant,
+    RunnerGrantLog, RunnerRevokeLog, SchemaError, SessionKey, SessionLog, SettlementProposedLog,
+    SponsorError, Subscription, SubscriptionOpenedLog, SubscriptionReleasedLog, UsageBreakdown,
+    UsageMeterRates, UserPolicy, UserStats, VaultConfig, VaultError, VaultPauseLog, VersionedKey,
+    Voucher, VoucherCreatedLog, VoucherError, VoucherReclaimedLog, VoucherRedeemedLog, WithdrawLog,
+    WithdrawalError, MIN_FORCE_CLOSE_SECS,
 };
 
+#[cfg(all(feature = "contract", any(test, feature = "testutils")))]
+pub mod testutils;
+
 #[cfg(test)]
 mod test;
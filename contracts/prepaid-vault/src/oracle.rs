@@ -0,0 +1,10 @@
+use soroban_sdk::{contractclient, Address, Env};
+
+/// Price feed the vault consults to enforce USD-denominated policy caps.
+#[allow(dead_code)]
+#[contractclient(name = "PriceOracleClient")]
+pub trait PriceOracle {
+    /// USD price of one whole `token` scaled by `10^decimals`, and the ledger timestamp
+    /// it was last updated at, as `(price, decimals, updated_at)`.
+    fn price(env: Env, token: Address) -> (i128, u32, u64);
+}
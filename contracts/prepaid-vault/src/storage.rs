@@ -1,29 +1,318 @@
-use soroban_sdk::{contracttype, Address};
+use soroban_sdk::{contracttype, Address, BytesN, Env, Map, Symbol, Vec};
 
-use crate::types::{RunLifecycle, UsageBreakdown};
+use crate::types::{
+    ChargeBreakdown, RunLifecycle, RunPricing, RunRecord, RunSettlement, UsageBreakdown, UserPolicy,
+};
 
 #[derive(Clone)]
 #[contracttype]
 pub enum DataKey {
+    Admin,
+    Paused,
+    CircuitBreaker,
     AgentRegistry,
     UserBalance(Address),
     DeveloperBalance(Address),
+    DeveloperAgentEarnings(Address, u32),
     UserPolicy(Address),
     Run(u64),
     NextRunId,
     RunnerGrants(Address),
+    DeveloperGrants(Address),
+    Subscription(Address, u32),
+    AgentAllowance(Address, u32),
+    FreeUsage(Address, u32),
+    BalanceToken(Address),
+    AgentStats(u32),
+    UserAgentSpend(Address, u32),
+    TrustedAgents(Address),
+    BudgetPresets(Address),
+    ReferralBps,
+    Referrer(Address),
+    ReferrerBalance(Address),
+    FeeExempt(Address),
+    UserActivity(Address),
+    Session(Address, Address),
+    RunnerCaps(Address),
+    WithdrawalDelay(Address),
+    PendingWithdrawal(Address),
+    PolicyOperator(Address),
+    Guardian(Address),
+    MigrationTarget,
+    MigrationSource,
+    PriceOracle,
+    ConfigVersion,
+    VaultToken,
+    FeeBps,
+    Treasury,
+    SchemaVersion,
+    AgentEventTopics,
+    RunByOutput(BytesN<32>, u32),
+    RunByOutputCount(BytesN<32>),
+    Voucher(BytesN<32>),
+    VoucherRedeemed(BytesN<32>, Address),
+    RollingSpend(Address),
+    RunnerBalance(Address),
+    PauseLevel,
+    FailureDiscountBps,
+    MigratedEntries,
+    DepositAllowance(Address, Address),
+    IndexedRun(RunIndex, u32),
+    IndexedRunCount(RunIndex),
 }
 
+/// The run listings kept for `list_runs_for_*`, one persistent entry per run.
 #[derive(Clone)]
 #[contracttype]
-pub struct RunRecord {
+pub enum RunIndex {
+    /// Runs opened for the user.
+    User(Address),
+    /// Runs opened against the agent.
+    Agent(u32),
+    /// Runs the runner settled.
+    Runner(Address),
+}
+
+/// Free units a user has consumed against an agent's daily free tier, reset each day.
+#[derive(Clone)]
+#[contracttype]
+pub struct FreeUsage {
+    pub day: u64,
+    pub consumed: Map<Symbol, i128>,
+}
+
+/// Days covered by a policy's `rolling_cap`.
+pub const ROLLING_WINDOW_DAYS: u32 = 30;
+
+/// What a user spent against their caps over the trailing `ROLLING_WINDOW_DAYS` UTC
+/// days, one bucket per day. Day `d` lives in bucket `d % ROLLING_WINDOW_DAYS`, so the
+/// ring never grows; buckets of days that fell out of the window are cleared on the
+/// next write.
+#[derive(Clone)]
+#[contracttype]
+pub struct RollingSpend {
+    /// Newest day the buckets have been advanced to.
+    pub day: u64,
+    pub buckets: Vec<i128>,
+}
+
+impl RollingSpend {
+    pub fn new(e: &Env) -> Self {
+        let mut buckets = Vec::new(e);
+        for _ in 0..ROLLING_WINDOW_DAYS {
+            buckets.push_back(0);
+        }
+        Self { day: 0, buckets }
+    }
+
+    /// Moves the window up to `today`, clearing the buckets of the days it passed.
+    pub fn advance(&mut self, today: u64) {
+        if today <= self.day {
+            return;
+        }
+        let stale = (today - self.day).min(u64::from(ROLLING_WINDOW_DAYS));
+        for offset in 1..=stale {
+            self.buckets.set(Self::slot(self.day + offset), 0);
+        }
+        self.day = today;
+    }
+
+    pub fn total(&self) -> i128 {
+        self.buckets.iter().sum()
+    }
+
+    /// Adds `amount` to the newest day's bucket.
+    pub fn add(&mut self, amount: i128) {
+        let slot = Self::slot(self.day);
+        let spent = self.buckets.get_unchecked(slot);
+        self.buckets.set(slot, spent.saturating_add(amount));
+    }
+
+    /// Takes `amount` back off `day`'s bucket, if that day is still in the window.
+    pub fn release(&mut self, day: u64, amount: i128) {
+        if day > self.day || self.day - day >= u64::from(ROLLING_WINDOW_DAYS) {
+            return;
+        }
+        let slot = Self::slot(day);
+        let spent = self.buckets.get_unchecked(slot);
+        self.buckets.set(slot, (spent - amount).max(0));
+    }
+
+    fn slot(day: u64) -> u32 {
+        (day % u64::from(ROLLING_WINDOW_DAYS)) as u32
+    }
+}
+
+/// Running totals kept per user so aggregate views need not walk their runs.
+#[derive(Clone)]
+#[contracttype]
+pub struct UserActivity {
+    /// Runs still holding escrow, including proposed and disputed ones.
+    pub open_runs: u32,
+    pub escrowed: i128,
+    pub spent_day: u64,
+    pub spent_today: i128,
+}
+
+/// A user's withdrawal timelock. A shorter delay only takes over at `next_effective_at`,
+/// so a leaked key cannot lift the lock faster than the lock itself allows.
+#[derive(Clone)]
+#[contracttype]
+pub struct WithdrawalDelay {
+    pub delay_secs: u64,
+    pub next_delay_secs: Option<u64>,
+    pub next_effective_at: u64,
+}
+
+/// `UsageBreakdown` as written under schema version 1, before custom meters.
+#[derive(Clone)]
+#[contracttype]
+pub struct UsageBreakdownV1 {
+    pub llm_in: i128,
+    pub llm_out: i128,
+    pub http_calls: i128,
+    pub runtime_ms: i128,
+}
+
+impl UsageBreakdownV1 {
+    pub fn upgrade(self, e: &Env) -> UsageBreakdown {
+        UsageBreakdown {
+            llm_in: self.llm_in,
+            llm_out: self.llm_out,
+            http_calls: self.http_calls,
+            runtime_ms: self.runtime_ms,
+            extra: Map::new(e),
+        }
+    }
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct RunSettlementV1 {
+    pub usage: UsageBreakdownV1,
+    pub actual_charge: i128,
+    pub refund: i128,
+    pub output_hash: BytesN<32>,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub enum RunLifecycleV1 {
+    Open,
+    Finalized(RunSettlementV1),
+    Cancelled,
+}
+
+/// `RunRecord` as written under schema version 1, in instance storage.
+#[derive(Clone)]
+#[contracttype]
+pub struct RunRecordV1 {
     pub user: Address,
     pub opened_by: Address,
     pub agent_id: u32,
     pub rate_version: u32,
-    pub budgets: UsageBreakdown,
+    pub budgets: UsageBreakdownV1,
     pub max_charge: i128,
     pub escrowed: i128,
     pub opened_at: u64,
-    pub lifecycle: RunLifecycle,
+    pub lifecycle: RunLifecycleV1,
+}
+
+impl RunRecordV1 {
+    pub const FIELDS: [&'static str; 9] = [
+        "user",
+        "opened_by",
+        "agent_id",
+        "rate_version",
+        "budgets",
+        "max_charge",
+        "escrowed",
+        "opened_at",
+        "lifecycle",
+    ];
+
+    /// The current layout. Open runs hold their whole `max_charge` against the daily
+    /// cap, as version 1 reserved it; a settled run credited `developer`, who the
+    /// registry names for the agent, and its charge is reported as adjustment since its
+    /// meters are unknown.
+    pub fn upgrade(self, e: &Env, developer: impl FnOnce() -> Address) -> RunRecord {
+        let reserved = match self.lifecycle {
+            RunLifecycleV1::Open => self.max_charge,
+            _ => 0,
+        };
+        let lifecycle = match self.lifecycle {
+            RunLifecycleV1::Open => RunLifecycle::Open,
+            RunLifecycleV1::Cancelled => RunLifecycle::Cancelled,
+            RunLifecycleV1::Finalized(settlement) => RunLifecycle::Finalized(RunSettlement {
+                usage: settlement.usage.upgrade(e),
+                actual_charge: settlement.actual_charge,
+                refund: settlement.refund,
+                output_hash: settlement.output_hash,
+                developer: developer(),
+                goodwill_refunded: 0,
+                tipped: 0,
+                charges: ChargeBreakdown {
+                    adjustment: settlement.actual_charge,
+                    ..ChargeBreakdown::default()
+                },
+            }),
+        };
+        RunRecord {
+            user: self.user,
+            opened_by: self.opened_by,
+            agent_id: self.agent_id,
+            rate_version: self.rate_version,
+            budgets: self.budgets.upgrade(e),
+            max_charge: self.max_charge,
+            escrowed: self.escrowed,
+            reserved,
+            allowance_drawn: 0,
+            opened_at: self.opened_at,
+            lifecycle,
+            streamed_usage: UsageBreakdown::zero(e),
+            streamed_charge: 0,
+            streamed_billable: UsageBreakdown::zero(e),
+            require_user_ack: false,
+            expires_at: None,
+            input_hash: BytesN::from_array(e, &[0; 32]),
+            memo: None,
+            sponsor: None,
+            priority_fee: 0,
+            pricing: RunPricing::Registry,
+        }
+    }
+}
+
+/// `UserPolicy` as written under schema version 1.
+#[derive(Clone)]
+#[contracttype]
+pub struct UserPolicyV1 {
+    pub per_run_cap: i128,
+    pub daily_cap: i128,
+    pub paused: bool,
+    pub reserved_today: i128,
+    pub reserved_day: u64,
+}
+
+impl UserPolicyV1 {
+    pub const FIELDS: [&'static str; 5] = [
+        "per_run_cap",
+        "daily_cap",
+        "paused",
+        "reserved_today",
+        "reserved_day",
+    ];
+
+    /// The current layout. Version 1 caps applied per UTC day, which is the default cap
+    /// window, so `reserved_day` carries over as the window index.
+    pub fn upgrade(self) -> UserPolicy {
+        UserPolicy {
+            per_run_cap: self.per_run_cap,
+            daily_cap: self.daily_cap,
+            paused: self.paused,
+            reserved_today: self.reserved_today,
+            reserved_day: self.reserved_day,
+            ..UserPolicy::default()
+        }
+    }
 }
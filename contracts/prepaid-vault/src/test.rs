@@ -1,67 +1,31 @@
 extern crate std;
 
-use agent_registry::{AgentRegistry, AgentRegistryClient, RateCardInput, UsageMeterRates};
+use agent_registry::{
+    AgentRegistryClient, RateCardInput, SubscriptionOffer, SubscriptionPlan, UsageMeterRates,
+};
 use soroban_sdk::{
-    testutils::{Address as _, MockAuth, MockAuthInvoke},
-    Address, BytesN, Env, IntoVal, Val, Vec,
+    contract, contractimpl, symbol_short,
+    testutils::{storage::Persistent as _, Address as _, Events, Ledger, MockAuth, MockAuthInvoke},
+    vec, Address, Bytes, BytesN, Env, IntoVal, Map, String, Val, Vec,
 };
 
 use crate::{
     contract::{PrepaidVault, PrepaidVaultClient},
-    utils, PolicyInput, RunLifecycle, UsageBreakdown,
+    storage::{
+        DataKey, RunLifecycleV1, RunRecordV1, RunSettlementV1, UsageBreakdownV1, UserPolicyV1,
+    },
+    testutils::{
+        default_policy, hash, run_full_lifecycle, sample_rate_card, sample_rates, setup_agent,
+        setup_clients,
+    },
+    utils, ChargeBreakdown, ConfigUpdate, DeveloperClaimLog, DisputeError, GuardianPauseLog,
+    InitConfig, LifecycleKind, MigrationLog, PauseLevel, PolicyError, PolicyInput,
+    PriceOracleConfig, PriceOracleError, RevenueShareLog, RunClosedLog, RunFinalizedLog,
+    RunLifecycle, RunOpenedLog, RunPage, RunPricing, RunRecord, RunnerRevokeLog, SchemaError,
+    SponsorError, UsageBreakdown, VaultError, VaultPauseLog, VersionedKey, VoucherError,
+    VoucherReclaimedLog, WithdrawLog, WithdrawalError, MIN_FORCE_CLOSE_SECS,
 };
 
-fn setup_clients<'a>(
-    e: &'a Env,
-) -> (
-    AgentRegistryClient<'a>,
-    PrepaidVaultClient<'a>,
-    Address,
-    Address,
-) {
-    let registry_addr = e.register(AgentRegistry, ());
-    let vault_addr = e.register(PrepaidVault, ());
-    let registry_client = AgentRegistryClient::new(e, &registry_addr);
-    let vault_client = PrepaidVaultClient::new(e, &vault_addr);
-    (registry_client, vault_client, registry_addr, vault_addr)
-}
-
-fn hash(env: &Env, byte: u8) -> BytesN<32> {
-    BytesN::from_array(env, &[byte; 32])
-}
-
-fn sample_rates() -> UsageMeterRates {
-    UsageMeterRates {
-        llm_in: 10_000,
-        llm_out: 20_000,
-        http_calls: 10_000_000,
-        runtime_ms: 1,
-    }
-}
-
-fn default_policy() -> PolicyInput {
-    PolicyInput {
-        per_run_cap: 50_000_000,
-        daily_cap: 100_000_000,
-        paused: false,
-    }
-}
-
-fn setup_agent(
-    e: &Env,
-    registry: &AgentRegistryClient<'_>,
-    developer: &Address,
-    runner: &Address,
-) -> u32 {
-    let mut runners = Vec::new(e);
-    runners.push_back(runner.clone());
-    let rate = RateCardInput {
-        rates: sample_rates(),
-        manifest_hash: hash(e, 1),
-    };
-    registry.register_agent(developer, &None, &runners, &rate)
-}
-
 fn set_caller<T>(client: &PrepaidVaultClient, caller: &Address, fn_name: &'static str, args: T)
 where
     T: IntoVal<Env, Vec<Val>>,
@@ -100,6 +64,22 @@ fn set_registry_caller<T>(
     }]);
 }
 
+#[contract]
+struct MockPriceOracle;
+
+#[contractimpl]
+impl MockPriceOracle {
+    pub fn set_price(e: Env, price: i128, decimals: u32, updated_at: u64) {
+        e.storage()
+            .instance()
+            .set(&symbol_short!("price"), &(price, decimals, updated_at));
+    }
+
+    pub fn price(e: Env, _token: Address) -> (i128, u32, u64) {
+        e.storage().instance().get(&symbol_short!("price")).unwrap()
+    }
+}
+
 #[test]
 fn finalize_refunds_unused_amount() {
     let e = Env::default();
@@ -109,14 +89,19 @@ fn finalize_refunds_unused_amount() {
     let runner = Address::generate(&e);
     let user = Address::generate(&e);
 
-    vault.init(&registry_addr);
+    vault.init(&Address::generate(&e), &registry_addr);
     let agent_id = setup_agent(&e, &registry, &developer, &runner);
 
     let deposit_amount: i128 = 20_000_000;
     set_caller(&vault, &user, "deposit", (&user, &deposit_amount));
     vault.deposit(&user, &deposit_amount);
-    set_caller(&vault, &user, "set_policy", (&user, &default_policy()));
-    vault.set_policy(&user, &default_policy());
+    set_caller(
+        &vault,
+        &user,
+        "set_policy",
+        (&user, &user, &default_policy()),
+    );
+    vault.set_policy(&user, &user, &default_policy());
     set_caller(
         &vault,
         &user,
@@ -130,6 +115,7 @@ fn finalize_refunds_unused_amount() {
         llm_out: 50,
         http_calls: 1,
         runtime_ms: 1000,
+        extra: Map::new(&e),
     };
 
     let rate_version = 1u32;
@@ -137,19 +123,41 @@ fn finalize_refunds_unused_amount() {
         &vault,
         &user,
         "open_run",
-        (&user, &user, &agent_id, &rate_version, &budgets),
+        (
+            &user,
+            &user,
+            &agent_id,
+            &rate_version,
+            &budgets,
+            &hash(&e, 1),
+            &None::<String>,
+        ),
+    );
+    let opened = vault.open_run(
+        &user,
+        &user,
+        &agent_id,
+        &rate_version,
+        &budgets,
+        &hash(&e, 1),
+        &None,
     );
-    let run_id = vault.open_run(&user, &user, &agent_id, &rate_version, &budgets);
+    let run_id = opened.run_id;
+    assert_eq!(opened.max_charge, 12_001_000);
+    assert_eq!(opened.escrowed, opened.max_charge);
+    assert_eq!(opened.rate_version, rate_version);
+    assert_eq!(opened.opened_at, e.ledger().timestamp());
 
     let usage = UsageBreakdown {
         llm_in: 80,
         llm_out: 40,
         http_calls: 1,
         runtime_ms: 500,
+        extra: Map::new(&e),
     };
 
-    let expected_max = utils::compute_charge(&sample_rates(), &budgets).unwrap();
-    let expected_actual = utils::compute_charge(&sample_rates(), &usage).unwrap();
+    let expected_max = opened.max_charge;
+    let expected_actual = utils::compute_charge(&sample_rates(&e), 0, &usage, 1).unwrap();
     let expected_refund = expected_max - expected_actual;
 
     set_caller(
@@ -162,6 +170,9 @@ fn finalize_refunds_unused_amount() {
 
     assert_eq!(receipt.actual_charge, expected_actual);
     assert_eq!(receipt.refund, expected_refund);
+    assert_eq!(receipt.agent_id, agent_id);
+    assert_eq!(receipt.rate_version, rate_version);
+    assert_eq!(receipt.finalized_at, e.ledger().timestamp());
     assert_eq!(vault.balance_of(&user), deposit_amount - expected_actual);
     assert_eq!(vault.developer_balance(&developer), expected_actual);
 
@@ -170,6 +181,10 @@ fn finalize_refunds_unused_amount() {
         RunLifecycle::Finalized(settlement) => {
             assert_eq!(settlement.actual_charge, expected_actual);
             assert_eq!(settlement.refund, expected_refund);
+            assert_eq!(receipt.usage.llm_in, settlement.usage.llm_in);
+            assert_eq!(receipt.usage.runtime_ms, settlement.usage.runtime_ms);
+            assert_eq!(receipt.usage.extra, settlement.usage.extra);
+            assert_eq!(receipt.output_hash, settlement.output_hash);
         }
         _ => panic!("run should be finalized"),
     }
@@ -185,14 +200,19 @@ fn usage_over_budget_panics() {
     let runner = Address::generate(&e);
     let user = Address::generate(&e);
 
-    vault.init(&registry_addr);
+    vault.init(&Address::generate(&e), &registry_addr);
     let agent_id = setup_agent(&e, &registry, &developer, &runner);
 
     let deposit_amount = 20_000_000;
     set_caller(&vault, &user, "deposit", (&user, &deposit_amount));
     vault.deposit(&user, &deposit_amount);
-    set_caller(&vault, &user, "set_policy", (&user, &default_policy()));
-    vault.set_policy(&user, &default_policy());
+    set_caller(
+        &vault,
+        &user,
+        "set_policy",
+        (&user, &user, &default_policy()),
+    );
+    vault.set_policy(&user, &user, &default_policy());
     set_caller(
         &vault,
         &user,
@@ -206,20 +226,40 @@ fn usage_over_budget_panics() {
         llm_out: 50,
         http_calls: 1,
         runtime_ms: 1000,
+        extra: Map::new(&e),
     };
     set_caller(
         &vault,
         &user,
         "open_run",
-        (&user, &user, &agent_id, &1u32, &budgets),
+        (
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None::<String>,
+        ),
     );
-    let run_id = vault.open_run(&user, &user, &agent_id, &1u32, &budgets);
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
 
     let usage = UsageBreakdown {
         llm_in: 120,
         llm_out: 40,
         http_calls: 1,
         runtime_ms: 400,
+        extra: Map::new(&e),
     };
 
     set_caller(
@@ -241,13 +281,18 @@ fn mismatched_rate_version_rejected() {
     let runner = Address::generate(&e);
     let user = Address::generate(&e);
 
-    vault.init(&registry_addr);
+    vault.init(&Address::generate(&e), &registry_addr);
     let agent_id = setup_agent(&e, &registry, &developer, &runner);
     let deposit_amount: i128 = 20_000_000;
     set_caller(&vault, &user, "deposit", (&user, &deposit_amount));
     vault.deposit(&user, &deposit_amount);
-    set_caller(&vault, &user, "set_policy", (&user, &default_policy()));
-    vault.set_policy(&user, &default_policy());
+    set_caller(
+        &vault,
+        &user,
+        "set_policy",
+        (&user, &user, &default_policy()),
+    );
+    vault.set_policy(&user, &user, &default_policy());
     set_caller(
         &vault,
         &user,
@@ -261,22 +306,42 @@ fn mismatched_rate_version_rejected() {
         llm_out: 50,
         http_calls: 1,
         runtime_ms: 1000,
+        extra: Map::new(&e),
     };
     set_caller(
         &vault,
         &user,
         "open_run",
-        (&user, &user, &agent_id, &1u32, &budgets),
+        (
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None::<String>,
+        ),
     );
-    let run_id = vault.open_run(&user, &user, &agent_id, &1u32, &budgets);
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
 
     // publish new rate card version
     let new_rate = RateCardInput {
         rates: UsageMeterRates {
             llm_in: 12_000,
-            ..sample_rates()
+            ..sample_rates(&e)
         },
         manifest_hash: hash(&e, 3),
+        ..sample_rate_card(&e)
     };
     set_registry_caller(
         &registry,
@@ -291,6 +356,7 @@ fn mismatched_rate_version_rejected() {
         llm_out: 20,
         http_calls: 1,
         runtime_ms: 200,
+        extra: Map::new(&e),
     };
 
     set_caller(
@@ -302,6 +368,58 @@ fn mismatched_rate_version_rejected() {
     vault.finalize_run(&run_id, &runner, &2u32, &usage, &hash(&e, 4));
 }
 
+#[test]
+fn runs_are_indexed_by_output_hash() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.set_policy(&user, &user, &default_policy());
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 10,
+        llm_out: 10,
+        http_calls: 1,
+        runtime_ms: 100,
+        extra: Map::new(&e),
+    };
+
+    let mut run_ids = Vec::new(&e);
+    for output in [2u8, 2, 0] {
+        let run_id = vault
+            .open_run(
+                &user,
+                &runner,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None,
+            )
+            .run_id;
+        vault.finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, output));
+        run_ids.push_back(run_id);
+    }
+
+    assert_eq!(
+        vault.runs_by_output_hash(&hash(&e, 2), &0, &10),
+        vec![&e, run_ids.get(0).unwrap(), run_ids.get(1).unwrap()]
+    );
+    assert_eq!(
+        vault.runs_by_output_hash(&hash(&e, 2), &1, &10),
+        vec![&e, run_ids.get(1).unwrap()]
+    );
+    assert!(vault.runs_by_output_hash(&hash(&e, 2), &2, &10).is_empty());
+    assert!(vault.runs_by_output_hash(&hash(&e, 0), &0, &10).is_empty());
+    assert!(vault.runs_by_output_hash(&hash(&e, 3), &0, &10).is_empty());
+}
+
 #[test]
 fn cancel_run_refunds_full_amount() {
     let e = Env::default();
@@ -311,19 +429,25 @@ fn cancel_run_refunds_full_amount() {
     let runner = Address::generate(&e);
     let user = Address::generate(&e);
 
-    vault.init(&registry_addr);
+    vault.init(&Address::generate(&e), &registry_addr);
     let agent_id = setup_agent(&e, &registry, &developer, &runner);
     let deposit_amount = 15_000_000;
     set_caller(&vault, &user, "deposit", (&user, &deposit_amount));
     vault.deposit(&user, &deposit_amount);
-    set_caller(&vault, &user, "set_policy", (&user, &default_policy()));
-    vault.set_policy(&user, &default_policy());
+    set_caller(
+        &vault,
+        &user,
+        "set_policy",
+        (&user, &user, &default_policy()),
+    );
+    vault.set_policy(&user, &user, &default_policy());
 
     let budgets = UsageBreakdown {
         llm_in: 50,
         llm_out: 20,
         http_calls: 1,
         runtime_ms: 200,
+        extra: Map::new(&e),
     };
 
     let rate_version = 1u32;
@@ -331,9 +455,27 @@ fn cancel_run_refunds_full_amount() {
         &vault,
         &user,
         "open_run",
-        (&user, &user, &agent_id, &rate_version, &budgets),
+        (
+            &user,
+            &user,
+            &agent_id,
+            &rate_version,
+            &budgets,
+            &hash(&e, 1),
+            &None::<String>,
+        ),
     );
-    let run_id = vault.open_run(&user, &user, &agent_id, &rate_version, &budgets);
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &rate_version,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
     // Cancel should refund entire escrowed amount.
     set_caller(&vault, &user, "cancel_run", (&user, &run_id));
     vault.cancel_run(&user, &run_id);
@@ -346,6 +488,278 @@ fn cancel_run_refunds_full_amount() {
     }
 }
 
+#[test]
+fn run_events_carry_agent_topic_once_enabled() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().with_mut(|li| li.timestamp = 1_000);
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.set_policy(&user, &user, &default_policy());
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let closed = |run_id: u64| RunClosedLog {
+        run_id,
+        agent_id,
+        user: user.clone(),
+        sponsor: None,
+        refunded: 12_001_000,
+        priority_fee: 0,
+        closed_at: e.ledger().timestamp(),
+    };
+
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    vault.cancel_run(&user, &run_id);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault.address.clone(),
+                (symbol_short!("run"), symbol_short!("cancelled")).into_val(&e),
+                closed(run_id).into_val(&e),
+            ),
+        ]
+    );
+
+    assert!(!vault.get_config().agent_event_topics);
+    vault.update_config(&ConfigUpdate {
+        admin: None,
+        registry: None,
+        token: None,
+        fee_bps: None,
+        treasury: None,
+        agent_event_topics: Some(true),
+        failure_discount_bps: None,
+    });
+    assert!(vault.get_config().agent_event_topics);
+
+    let run_id = vault
+        .open_run_with_ttl(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &Some(60),
+            &None,
+        )
+        .run_id;
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault.address.clone(),
+                (symbol_short!("run"), symbol_short!("opened"), agent_id).into_val(&e),
+                RunOpenedLog {
+                    run_id,
+                    user: user.clone(),
+                    opened_by: user.clone(),
+                    agent_id,
+                    rate_version: 1,
+                    max_charge: 12_001_000,
+                    budgets: budgets.clone(),
+                    opened_at: 1_000,
+                    input_hash: hash(&e, 1),
+                    memo: None,
+                    sponsor: None,
+                    priority_fee: 0,
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+
+    e.ledger().with_mut(|li| li.timestamp = 1_060);
+    vault.expire_run(&run_id);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault.address.clone(),
+                (symbol_short!("run"), symbol_short!("expired"), agent_id).into_val(&e),
+                closed(run_id).into_val(&e),
+            ),
+        ]
+    );
+    assert_eq!(vault.balance_of(&user), 50_000_000);
+}
+
+#[test]
+fn sponsored_runs_draw_from_and_refund_the_sponsor() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+    let sponsor = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&sponsor, &50_000_000);
+    vault.set_policy(
+        &sponsor,
+        &sponsor,
+        &PolicyInput {
+            daily_cap: 20_000_000,
+            ..default_policy()
+        },
+    );
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+
+    assert_eq!(
+        vault
+            .try_open_sponsored_run(
+                &sponsor,
+                &user,
+                &runner,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::UnauthorizedRunner as u32
+        )))
+    );
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    assert_eq!(
+        vault
+            .try_open_sponsored_run(
+                &user,
+                &user,
+                &runner,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            SponsorError::SponsorIsUser as u32
+        )))
+    );
+
+    let run_id = vault
+        .open_sponsored_run(
+            &sponsor,
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 3),
+            &Some(String::from_str(&e, "sponsored")),
+        )
+        .run_id;
+    let run = vault.get_run(&run_id);
+    assert_eq!(run.user, user);
+    assert_eq!(run.sponsor, Some(sponsor.clone()));
+    assert_eq!(run.input_hash, hash(&e, 3));
+    assert_eq!(run.memo, Some(String::from_str(&e, "sponsored")));
+    assert_eq!(vault.balance_of(&sponsor), 50_000_000 - 12_001_000);
+    assert_eq!(vault.balance_of(&user), 0);
+
+    let usage = UsageBreakdown {
+        llm_in: 80,
+        llm_out: 40,
+        http_calls: 1,
+        runtime_ms: 500,
+        extra: Map::new(&e),
+    };
+    vault.finalize_run(&run_id, &runner, &1u32, &usage, &hash(&e, 2));
+    assert_eq!(vault.balance_of(&sponsor), 50_000_000 - 11_600_500);
+    assert_eq!(vault.balance_of(&user), 0);
+    assert_eq!(vault.developer_balance(&developer), 11_600_500);
+
+    let run_id = vault
+        .open_sponsored_run(
+            &sponsor,
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    assert_eq!(
+        vault
+            .try_open_sponsored_run(
+                &sponsor,
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::DailyCapExceeded as u32
+        )))
+    );
+    vault.cancel_run(&user, &run_id);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault.address.clone(),
+                (symbol_short!("run"), symbol_short!("cancelled")).into_val(&e),
+                RunClosedLog {
+                    run_id,
+                    agent_id,
+                    user: user.clone(),
+                    sponsor: Some(sponsor.clone()),
+                    refunded: 12_001_000,
+                    priority_fee: 0,
+                    closed_at: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    assert_eq!(vault.balance_of(&sponsor), 50_000_000 - 11_600_500);
+    assert_eq!(vault.balance_of(&user), 0);
+}
+
 #[test]
 fn runner_can_open_and_finalize_with_grant() {
     let e = Env::default();
@@ -355,14 +769,19 @@ fn runner_can_open_and_finalize_with_grant() {
     let runner = Address::generate(&e);
     let user = Address::generate(&e);
 
-    vault.init(&registry_addr);
+    vault.init(&Address::generate(&e), &registry_addr);
     let agent_id = setup_agent(&e, &registry, &developer, &runner);
 
     let deposit_amount = 25_000_000;
     set_caller(&vault, &user, "deposit", (&user, &deposit_amount));
     vault.deposit(&user, &deposit_amount);
-    set_caller(&vault, &user, "set_policy", (&user, &default_policy()));
-    vault.set_policy(&user, &default_policy());
+    set_caller(
+        &vault,
+        &user,
+        "set_policy",
+        (&user, &user, &default_policy()),
+    );
+    vault.set_policy(&user, &user, &default_policy());
     set_caller(
         &vault,
         &user,
@@ -379,15 +798,34 @@ fn runner_can_open_and_finalize_with_grant() {
         llm_out: 80,
         http_calls: 2,
         runtime_ms: 1500,
+        extra: Map::new(&e),
     };
 
     set_caller(
         &vault,
         &runner,
         "open_run",
-        (&user, &runner, &agent_id, &1u32, &budgets),
+        (
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None::<String>,
+        ),
     );
-    let run_id = vault.open_run(&user, &runner, &agent_id, &1u32, &budgets);
+    let run_id = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
     let run = vault.get_run(&run_id);
     assert_eq!(run.user, user.clone());
     assert_eq!(run.opened_by, runner.clone());
@@ -397,6 +835,7 @@ fn runner_can_open_and_finalize_with_grant() {
         llm_out: 60,
         http_calls: 1,
         runtime_ms: 1000,
+        extra: Map::new(&e),
     };
 
     set_caller(
@@ -412,6 +851,11 @@ fn runner_can_open_and_finalize_with_grant() {
     match run.lifecycle {
         RunLifecycle::Finalized(settlement) => {
             assert_eq!(settlement.usage.llm_in, usage.llm_in);
+            assert_eq!(receipt.usage.llm_in, settlement.usage.llm_in);
+            assert_eq!(receipt.usage.runtime_ms, settlement.usage.runtime_ms);
+            assert_eq!(receipt.usage.extra, settlement.usage.extra);
+            assert_eq!(receipt.actual_charge, settlement.actual_charge);
+            assert_eq!(receipt.output_hash, settlement.output_hash);
         }
         _ => panic!("run should be finalized"),
     }
@@ -427,14 +871,19 @@ fn revoked_runner_cannot_open() {
     let runner = Address::generate(&e);
     let user = Address::generate(&e);
 
-    vault.init(&registry_addr);
+    vault.init(&Address::generate(&e), &registry_addr);
     let agent_id = setup_agent(&e, &registry, &developer, &runner);
 
     let deposit_amount: i128 = 15_000_000;
     set_caller(&vault, &user, "deposit", (&user, &deposit_amount));
     vault.deposit(&user, &deposit_amount);
-    set_caller(&vault, &user, "set_policy", (&user, &default_policy()));
-    vault.set_policy(&user, &default_policy());
+    set_caller(
+        &vault,
+        &user,
+        "set_policy",
+        (&user, &user, &default_policy()),
+    );
+    vault.set_policy(&user, &user, &default_policy());
     set_caller(
         &vault,
         &user,
@@ -443,21 +892,6020 @@ fn revoked_runner_cannot_open() {
     );
     vault.grant_runner(&user, &runner, &agent_id, &Option::<u64>::None);
 
-    set_caller(&vault, &user, "revoke_runner", (&user, &runner, &agent_id));
-    vault.revoke_runner(&user, &runner, &agent_id);
+    set_caller(
+        &vault,
+        &user,
+        "revoke_runner",
+        (&user, &user, &runner, &agent_id),
+    );
+    vault.revoke_runner(&user, &user, &runner, &agent_id);
 
     let budgets = UsageBreakdown {
         llm_in: 10,
         llm_out: 10,
         http_calls: 1,
         runtime_ms: 100,
+        extra: Map::new(&e),
     };
 
     set_caller(
         &vault,
         &runner,
         "open_run",
-        (&user, &runner, &agent_id, &1u32, &budgets),
+        (
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None::<String>,
+        ),
+    );
+    vault.open_run(
+        &user,
+        &runner,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+}
+
+#[test]
+fn grant_runner_rejects_self_grants_and_past_expiry() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().with_mut(|li| li.timestamp = 1_000);
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+
+    assert_eq!(
+        vault.try_grant_runner(&user, &user, &agent_id, &None).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::SelfGrantNotAllowed as u32
+        )))
+    );
+    for expires_at in [999u64, 1_000] {
+        assert_eq!(
+            vault
+                .try_grant_runner(&user, &runner, &agent_id, &Some(expires_at))
+                .err(),
+            Some(Ok(soroban_sdk::Error::from_contract_error(
+                VaultError::GrantAlreadyExpired as u32
+            )))
+        );
+    }
+
+    vault.grant_runner(&user, &runner, &agent_id, &Some(1_001));
+    assert_eq!(vault.list_runner_grants(&user).len(), 1);
+}
+
+#[test]
+fn missing_registry_and_unknown_agents_use_vault_error_codes() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+    assert_eq!(VaultError::AgentRegistryNotSet as u32, 9);
+    assert_eq!(VaultError::AgentNotFound as u32, 10);
+
+    assert_eq!(
+        vault.try_free_units_remaining(&user, &1u32).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::AgentRegistryNotSet as u32
+        )))
+    );
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &20_000_000);
+    vault.set_policy(&user, &user, &default_policy());
+
+    let unknown = 99u32;
+    assert_eq!(
+        vault
+            .try_grant_runner(&user, &runner, &unknown, &None)
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::AgentNotFound as u32
+        )))
+    );
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    for rate_version in [0u32, 1] {
+        assert_eq!(
+            vault
+                .try_open_run(
+                    &user,
+                    &user,
+                    &unknown,
+                    &rate_version,
+                    &budgets,
+                    &hash(&e, 1),
+                    &None,
+                )
+                .err(),
+            Some(Ok(soroban_sdk::Error::from_contract_error(
+                VaultError::AgentNotFound as u32
+            )))
+        );
+    }
+}
+
+#[test]
+fn extend_runner_grant_moves_expiry_in_place() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().with_mut(|li| li.timestamp = 1_000);
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.grant_runner(&user, &runner, &agent_id, &Some(2_000));
+
+    e.ledger().with_mut(|li| li.timestamp = 1_500);
+    assert_eq!(
+        vault
+            .try_extend_runner_grant(&user, &runner, &agent_id, &Some(1_500))
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::GrantAlreadyExpired as u32
+        )))
+    );
+    vault.extend_runner_grant(&user, &runner, &agent_id, &Some(5_000));
+    let grant = vault.list_runner_grants(&user).get(0).unwrap();
+    assert_eq!(grant.issued_at, 1_000);
+    assert_eq!(grant.expires_at, Some(5_000));
+
+    vault.extend_runner_grant(&user, &runner, &agent_id, &None);
+    assert_eq!(
+        vault.list_runner_grants(&user).get(0).unwrap().expires_at,
+        None
+    );
+
+    let other = Address::generate(&e);
+    assert_eq!(
+        vault
+            .try_extend_runner_grant(&user, &other, &agent_id, &None)
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RunnerGrantNotFound as u32
+        )))
+    );
+
+    let expiring = Address::generate(&e);
+    registry.add_runner(&agent_id, &expiring);
+    vault.grant_runner(&user, &expiring, &agent_id, &Some(1_600));
+    e.ledger().with_mut(|li| li.timestamp = 1_600);
+    assert_eq!(
+        vault
+            .try_extend_runner_grant(&user, &expiring, &agent_id, &Some(9_000))
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RunnerGrantNotFound as u32
+        )))
+    );
+}
+
+#[test]
+fn revoke_all_runners_clears_every_grant() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let second = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    vault.revoke_all_runners(&user, &user);
+
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    registry.add_runner(&agent_id, &second);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    vault.grant_runner(&user, &second, &agent_id, &None);
+
+    vault.revoke_all_runners(&user, &user);
+    let revoked = |runner: &Address| {
+        (
+            vault.address.clone(),
+            (symbol_short!("runner"), symbol_short!("revoked")).into_val(&e),
+            RunnerRevokeLog {
+                user: user.clone(),
+                runner: runner.clone(),
+                agent_id,
+                revoked_at: e.ledger().timestamp(),
+            }
+            .into_val(&e),
+        )
+    };
+    assert_eq!(
+        e.events().all(),
+        vec![&e, revoked(&runner), revoked(&second)]
+    );
+    assert_eq!(vault.list_runner_grants(&user).len(), 0);
+
+    vault.deposit(&user, &20_000_000);
+    let budgets = UsageBreakdown {
+        llm_in: 10,
+        llm_out: 10,
+        http_calls: 1,
+        runtime_ms: 100,
+        extra: Map::new(&e),
+    };
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &runner,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::UnauthorizedRunner as u32
+        )))
+    );
+}
+
+#[test]
+fn sweep_drops_grants_for_runners_removed_from_registry() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let second = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    registry.add_runner(&agent_id, &second);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    vault.grant_runner(&user, &second, &agent_id, &None);
+
+    registry.remove_runner(&agent_id, &runner);
+    vault.sweep_invalid_grants(&user);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault.address.clone(),
+                (symbol_short!("runner"), symbol_short!("revoked")).into_val(&e),
+                RunnerRevokeLog {
+                    user: user.clone(),
+                    runner: runner.clone(),
+                    agent_id,
+                    revoked_at: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
     );
-    vault.open_run(&user, &runner, &agent_id, &1u32, &budgets);
+
+    let grants = vault.list_runner_grants(&user);
+    assert_eq!(grants.len(), 1);
+    assert_eq!(grants.get(0).unwrap().runner, second);
+
+    registry.add_runner(&agent_id, &runner);
+    let grants = vault.list_runner_grants(&user);
+    assert_eq!(grants.len(), 1);
+    assert_eq!(grants.get(0).unwrap().runner, second);
+}
+
+#[test]
+fn sweep_grants_removes_expired_grants_and_listing_does_not_write() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().with_mut(|li| li.timestamp = 1_000);
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let second = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    registry.add_runner(&agent_id, &second);
+
+    assert_eq!(vault.sweep_grants(&user), 0);
+    assert_eq!(e.events().all(), vec![&e]);
+
+    vault.grant_runner(&user, &runner, &agent_id, &Some(2_000));
+    vault.grant_runner(&user, &second, &agent_id, &None);
+    e.ledger().with_mut(|li| li.timestamp = 2_000);
+
+    let grants = vault.list_runner_grants(&user);
+    assert_eq!(grants.len(), 1);
+    assert_eq!(grants.get(0).unwrap().runner, second);
+    assert_eq!(e.events().all(), vec![&e]);
+
+    assert_eq!(vault.sweep_grants(&user), 1);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault.address.clone(),
+                (symbol_short!("runner"), symbol_short!("revoked")).into_val(&e),
+                RunnerRevokeLog {
+                    user: user.clone(),
+                    runner: runner.clone(),
+                    agent_id,
+                    revoked_at: 2_000,
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    assert_eq!(vault.sweep_grants(&user), 0);
+    assert_eq!(vault.list_runner_grants(&user).len(), 1);
+
+    registry.remove_runner(&agent_id, &second);
+    assert!(vault.list_runner_grants(&user).is_empty());
+    assert_eq!(e.events().all(), vec![&e]);
+    let stored = e.as_contract(&vault.address, || {
+        e.storage()
+            .instance()
+            .get::<_, Vec<crate::RunnerGrant>>(&DataKey::RunnerGrants(user.clone()))
+            .unwrap()
+    });
+    assert_eq!(stored.len(), 1);
+}
+
+#[test]
+fn expired_agent_grant_is_not_covered_by_the_developer_grant() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().with_mut(|li| li.timestamp = 1_000);
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let first = setup_agent(&e, &registry, &developer, &runner);
+    let second = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &first, &Some(2_000));
+    vault.grant_runner_for_developer(&user, &runner, &developer, &None);
+    assert!(vault.is_runner_authorized(&user, &runner, &first));
+
+    e.ledger().with_mut(|li| li.timestamp = 2_000);
+    assert!(!vault.is_runner_authorized(&user, &runner, &first));
+    assert!(vault.is_runner_authorized(&user, &runner, &second));
+
+    let budgets = UsageBreakdown {
+        llm_in: 10,
+        llm_out: 10,
+        http_calls: 1,
+        runtime_ms: 100,
+        extra: Map::new(&e),
+    };
+    assert_eq!(
+        vault
+            .try_open_run(&user, &runner, &first, &1u32, &budgets, &hash(&e, 1), &None)
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::UnauthorizedRunner as u32
+        )))
+    );
+    vault.open_run(
+        &user,
+        &runner,
+        &second,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+}
+
+#[test]
+fn developer_grant_covers_every_agent_of_that_developer() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().with_mut(|li| li.timestamp = 1_000);
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let first = setup_agent(&e, &registry, &developer, &runner);
+    let other_agent = setup_agent(&e, &registry, &Address::generate(&e), &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner_for_developer(&user, &runner, &developer, &None);
+    let second = setup_agent(&e, &registry, &developer, &runner);
+
+    let budgets = UsageBreakdown {
+        llm_in: 10,
+        llm_out: 10,
+        http_calls: 1,
+        runtime_ms: 100,
+        extra: Map::new(&e),
+    };
+    vault.open_run(&user, &runner, &first, &1u32, &budgets, &hash(&e, 1), &None);
+    vault.open_run(
+        &user,
+        &runner,
+        &second,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &runner,
+                &other_agent,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::UnauthorizedRunner as u32
+        )))
+    );
+
+    let grants = vault.list_developer_grants(&user);
+    assert_eq!(grants.len(), 1);
+    assert_eq!(grants.get(0).unwrap().developer, developer);
+
+    vault.revoke_runner_for_developer(&user, &user, &runner, &developer);
+    assert_eq!(vault.list_developer_grants(&user).len(), 0);
+    assert_eq!(
+        vault
+            .try_open_run(&user, &runner, &first, &1u32, &budgets, &hash(&e, 1), &None)
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::UnauthorizedRunner as u32
+        )))
+    );
+    assert_eq!(
+        vault
+            .try_revoke_runner_for_developer(&user, &user, &runner, &developer)
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RunnerGrantNotFound as u32
+        )))
+    );
+
+    vault.grant_runner_for_developer(&user, &runner, &developer, &Some(2_000));
+    vault.revoke_all_runners(&user, &user);
+    assert_eq!(vault.list_developer_grants(&user).len(), 0);
+}
+
+fn setup_subscription_agent(
+    e: &Env,
+    registry: &AgentRegistryClient<'_>,
+    developer: &Address,
+    runner: &Address,
+    plan: SubscriptionPlan,
+) -> u32 {
+    let mut runners = Vec::new(e);
+    runners.push_back(runner.clone());
+    let rate = RateCardInput {
+        subscription: SubscriptionOffer::Offered(plan),
+        ..sample_rate_card(e)
+    };
+    registry.register_agent(developer, &None, &runners, &rate)
+}
+
+#[test]
+fn subscription_charges_upfront_and_releases_after_period() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let plan = SubscriptionPlan {
+        price: 5_000_000,
+        period_secs: 7 * 86_400,
+    };
+    let agent_id = setup_subscription_agent(&e, &registry, &developer, &runner, plan);
+
+    e.ledger().with_mut(|li| li.timestamp = 1_000);
+    vault.deposit(&user, &20_000_000);
+    vault.set_policy(&user, &user, &default_policy());
+
+    assert!(!vault.has_active_subscription(&user, &agent_id));
+    let subscription = vault.open_subscription(&user, &agent_id, &1u32);
+    assert_eq!(subscription.expires_at, 1_000 + 7 * 86_400);
+    assert!(vault.has_active_subscription(&user, &agent_id));
+    assert_eq!(vault.balance_of(&user), 15_000_000);
+    assert_eq!(vault.developer_balance(&developer), 0);
+
+    assert!(vault.try_release_subscription(&user, &agent_id).is_err());
+    assert!(vault
+        .try_open_subscription(&user, &agent_id, &1u32)
+        .is_err());
+
+    e.ledger().with_mut(|li| li.timestamp = 1_000 + 7 * 86_400);
+    assert!(!vault.has_active_subscription(&user, &agent_id));
+    assert_eq!(vault.release_subscription(&user, &agent_id), 5_000_000);
+    assert_eq!(vault.developer_balance(&developer), 5_000_000);
+    assert!(vault.get_subscription(&user, &agent_id).unwrap().released);
+
+    // Renewal is an explicit second purchase.
+    vault.open_subscription(&user, &agent_id, &1u32);
+    assert!(vault.has_active_subscription(&user, &agent_id));
+    assert_eq!(vault.balance_of(&user), 10_000_000);
+}
+
+#[test]
+fn released_subscriptions_hand_back_their_cap_reservation() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let plan = SubscriptionPlan {
+        price: 5_000_000,
+        period_secs: 3_600,
+    };
+    let agent_id = setup_subscription_agent(&e, &registry, &developer, &runner, plan);
+
+    e.ledger().with_mut(|li| li.timestamp = 1_000);
+    vault.deposit(&user, &20_000_000);
+    vault.set_policy(&user, &user, &default_policy());
+    assert_eq!(
+        vault.open_subscription(&user, &agent_id, &1u32).reserved,
+        5_000_000
+    );
+    assert_eq!(vault.get_policy(&user).reserved_today, 5_000_000);
+
+    e.ledger().with_mut(|li| li.timestamp = 4_600);
+    vault.release_subscription(&user, &agent_id);
+    assert_eq!(vault.get_policy(&user).reserved_today, 0);
+
+    vault.open_subscription(&user, &agent_id, &1u32);
+    e.ledger().with_mut(|li| li.timestamp = 8_200);
+    // Renewing releases the lapsed period first.
+    vault.open_subscription(&user, &agent_id, &1u32);
+    assert_eq!(vault.get_policy(&user).reserved_today, 5_000_000);
+    assert_eq!(vault.developer_balance(&developer), 10_000_000);
+}
+
+#[test]
+#[should_panic(expected = "Error(Contract, #18)")]
+fn subscription_requires_plan_on_rate_card() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &20_000_000);
+    vault.open_subscription(&user, &agent_id, &1u32);
+}
+
+#[test]
+fn runner_opens_draw_from_allowance_and_refunds_restore_it() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.set_policy(&user, &user, &default_policy());
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    vault.set_agent_allowance(&user, &user, &agent_id, &20_000_000, &None);
+
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let opened = vault.open_run(
+        &user,
+        &runner,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+    let (run_id, max_charge) = (opened.run_id, opened.max_charge);
+    assert_eq!(opened.escrowed, max_charge);
+    let allowance = vault.get_agent_allowance(&user, &agent_id).unwrap();
+    assert_eq!(allowance.amount, 20_000_000 - max_charge);
+
+    let usage = UsageBreakdown {
+        llm_in: 80,
+        llm_out: 40,
+        http_calls: 1,
+        runtime_ms: 500,
+        extra: Map::new(&e),
+    };
+    let receipt = vault.finalize_run(&run_id, &runner, &1u32, &usage, &hash(&e, 9));
+    let allowance = vault.get_agent_allowance(&user, &agent_id).unwrap();
+    assert_eq!(allowance.amount, 20_000_000 - receipt.actual_charge);
+
+    // A second run larger than the remaining envelope is rejected.
+    vault.set_agent_allowance(&user, &user, &agent_id, &(max_charge - 1), &None);
+    let result = vault.try_open_run(
+        &user,
+        &runner,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+    assert_eq!(
+        result.err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::AllowanceExceeded as u32
+        )))
+    );
+
+    // User-initiated runs are not limited by the envelope.
+    vault.open_run(
+        &user,
+        &user,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+}
+
+#[test]
+#[should_panic(expected = "Error(Contract, #22)")]
+fn expired_allowance_blocks_runner_open() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &25_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    vault.set_agent_allowance(&user, &user, &agent_id, &10_000_000, &Some(500));
+
+    e.ledger().with_mut(|li| li.timestamp = 500);
+    let budgets = UsageBreakdown {
+        llm_in: 10,
+        llm_out: 10,
+        http_calls: 1,
+        runtime_ms: 100,
+        extra: Map::new(&e),
+    };
+    vault.open_run(
+        &user,
+        &runner,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+}
+
+#[test]
+fn deposit_for_funds_beneficiary_without_their_auth() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let payer = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+
+    let amount: i128 = 20_000_000;
+    set_caller(&vault, &payer, "deposit_for", (&payer, &user, &amount));
+    vault.deposit_for(&payer, &user, &amount);
+    assert_eq!(vault.balance_of(&user), amount);
+    assert_eq!(vault.balance_of(&payer), 0);
+
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    set_caller(
+        &vault,
+        &user,
+        "open_run",
+        (
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None::<String>,
+        ),
+    );
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    assert_eq!(vault.get_run(&run_id).user, user);
+}
+
+#[test]
+#[should_panic(expected = "Error(Contract, #4)")]
+fn deposit_for_rejects_non_positive_amount() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (_, vault, registry_addr, _) = setup_clients(&e);
+    let payer = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    vault.deposit_for(&payer, &user, &0);
+}
+
+#[test]
+fn payout_address_receives_only_future_settlements() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let payout = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let first = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    let first_receipt = vault.finalize_run(&first, &runner, &1u32, &budgets, &hash(&e, 1));
+    assert_eq!(first_receipt.developer, developer);
+
+    registry.set_payout_address(&agent_id, &payout);
+
+    let second = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    let second_receipt = vault.finalize_run(&second, &runner, &1u32, &budgets, &hash(&e, 2));
+    assert_eq!(second_receipt.developer, payout);
+
+    assert_eq!(
+        vault.developer_balance(&developer),
+        first_receipt.actual_charge
+    );
+    assert_eq!(
+        vault.developer_balance(&payout),
+        second_receipt.actual_charge
+    );
+}
+
+#[test]
+fn inactive_agent_rejects_new_runs_but_settles_open_ones() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+
+    registry.set_agent_active(&agent_id, &false);
+    assert!(!registry.is_active(&agent_id));
+
+    let result = vault.try_open_run(
+        &user,
+        &user,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+    assert_eq!(
+        result.err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::AgentInactive as u32
+        )))
+    );
+
+    let receipt = vault.finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 3));
+    assert_eq!(vault.developer_balance(&developer), receipt.actual_charge);
+}
+
+#[test]
+fn paused_agent_blocks_open_and_finalize_until_unpaused() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+
+    registry.pause_agent(&agent_id);
+    let paused = Some(Ok(soroban_sdk::Error::from_contract_error(
+        VaultError::AgentPaused as u32,
+    )));
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        paused
+    );
+    assert_eq!(
+        vault
+            .try_finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 4))
+            .err(),
+        paused
+    );
+
+    // The pause must not wipe the user's grants.
+    assert!(vault.is_runner_authorized(&user, &runner, &agent_id));
+
+    registry.unpause_agent(&agent_id);
+    vault.finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 4));
+}
+
+#[test]
+fn delisted_agent_stops_accepting_runs() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let admin = Address::generate(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    registry.init(&admin);
+    vault.init(&admin, &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+
+    registry.delist_agent(&agent_id, &hash(&e, 5));
+
+    let budgets = UsageBreakdown {
+        llm_in: 10,
+        llm_out: 10,
+        http_calls: 1,
+        runtime_ms: 100,
+        extra: Map::new(&e),
+    };
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::AgentInactive as u32
+        )))
+    );
+    assert!(!vault.is_runner_authorized(&user, &runner, &agent_id));
+}
+
+#[test]
+fn deprecated_rate_version_rejected_at_open_but_settles() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+
+    let new_rate = RateCardInput {
+        manifest_hash: hash(&e, 2),
+        ..sample_rate_card(&e)
+    };
+    registry.publish_rate_card(&agent_id, &new_rate);
+    registry.set_min_rate_version(&agent_id, &2);
+
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::InvalidRateVersion as u32
+        )))
+    );
+    vault.open_run(
+        &user,
+        &user,
+        &agent_id,
+        &2u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+
+    let receipt = vault.finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 3));
+    assert_eq!(receipt.refund, 0);
+}
+
+#[test]
+fn future_rate_card_cannot_be_opened_until_effective() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+
+    e.ledger().with_mut(|li| li.timestamp = 1_000);
+    let scheduled = RateCardInput {
+        rates: UsageMeterRates {
+            llm_in: 12_000,
+            ..sample_rates(&e)
+        },
+        manifest_hash: hash(&e, 2),
+        effective_from: Some(2_000),
+        ..sample_rate_card(&e)
+    };
+    registry.publish_rate_card(&agent_id, &scheduled);
+    assert_eq!(registry.latest_rate_version(&agent_id), 1);
+
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &user,
+                &agent_id,
+                &2u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::InvalidRateVersion as u32
+        )))
+    );
+
+    e.ledger().with_mut(|li| li.timestamp = 2_000);
+    assert_eq!(registry.latest_rate_version(&agent_id), 2);
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &2u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    assert_eq!(vault.get_run(&run_id).rate_version, 2);
+}
+
+#[test]
+fn expired_rate_card_rejected_at_open_but_settles() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let mut runners = Vec::new(&e);
+    runners.push_back(runner.clone());
+    let promo = RateCardInput {
+        expires_at: Some(1_000),
+        ..sample_rate_card(&e)
+    };
+    let agent_id = registry.register_agent(&developer, &None, &runners, &promo);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+
+    e.ledger().with_mut(|li| li.timestamp = 1_000);
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RateCardExpired as u32
+        )))
+    );
+
+    vault.finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 2));
+}
+
+#[test]
+fn custom_meters_are_priced_and_budgeted_key_by_key() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+    let gpu = soroban_sdk::Symbol::new(&e, "gpu_secs");
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let mut rates = sample_rates(&e);
+    rates.extra.set(gpu.clone(), 50_000);
+    let mut runners = Vec::new(&e);
+    runners.push_back(runner.clone());
+    let rate = RateCardInput {
+        rates: rates.clone(),
+        ..sample_rate_card(&e)
+    };
+    let agent_id = registry.register_agent(&developer, &None, &runners, &rate);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+
+    let mut budgets = UsageBreakdown {
+        llm_in: 10,
+        llm_out: 10,
+        http_calls: 0,
+        runtime_ms: 100,
+        extra: Map::new(&e),
+    };
+    budgets.extra.set(gpu.clone(), 20);
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    assert_eq!(
+        vault.get_run(&run_id).max_charge,
+        10 * 10_000 + 10 * 20_000 + 100 + 20 * 50_000
+    );
+
+    let mut over = budgets.clone();
+    over.extra.set(gpu.clone(), 21);
+    assert_eq!(
+        vault
+            .try_finalize_run(&run_id, &runner, &1u32, &over, &hash(&e, 2))
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::UsageExceedsBudget as u32
+        )))
+    );
+
+    let mut usage = budgets.clone();
+    usage.extra.set(gpu.clone(), 5);
+    let receipt = vault.finalize_run(&run_id, &runner, &1u32, &usage, &hash(&e, 2));
+    assert_eq!(
+        receipt.actual_charge,
+        utils::compute_charge(&rates, 0, &usage, 1).unwrap()
+    );
+
+    let mut unpriced = budgets.clone();
+    unpriced
+        .extra
+        .set(soroban_sdk::Symbol::new(&e, "vector_reads"), 1);
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &unpriced,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::UnknownMeter as u32
+        )))
+    );
+}
+
+#[test]
+fn free_daily_units_are_deducted_and_reset_each_day() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+    let http_calls = soroban_sdk::Symbol::new(&e, "http_calls");
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let mut runners = Vec::new(&e);
+    runners.push_back(runner.clone());
+    let mut rate = sample_rate_card(&e);
+    rate.free_daily_units.set(http_calls.clone(), 2);
+    let agent_id = registry.register_agent(&developer, &None, &runners, &rate);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+
+    let usage = UsageBreakdown {
+        llm_in: 0,
+        llm_out: 0,
+        http_calls: 1,
+        runtime_ms: 0,
+        extra: Map::new(&e),
+    };
+    assert_eq!(
+        vault
+            .free_units_remaining(&user, &agent_id)
+            .get(http_calls.clone()),
+        Some(2)
+    );
+
+    let mut charges = std::vec::Vec::new();
+    for _ in 0..3 {
+        let run_id = vault
+            .open_run(&user, &user, &agent_id, &1u32, &usage, &hash(&e, 1), &None)
+            .run_id;
+        assert_eq!(vault.get_run(&run_id).max_charge, 10_000_000);
+        let receipt = vault.finalize_run(&run_id, &runner, &1u32, &usage, &hash(&e, 2));
+        charges.push(receipt.actual_charge);
+    }
+    assert_eq!(charges, std::vec![0, 0, 10_000_000]);
+    assert_eq!(
+        vault
+            .free_units_remaining(&user, &agent_id)
+            .get(http_calls.clone()),
+        Some(0)
+    );
+    assert_eq!(vault.balance_of(&user), 40_000_000);
+
+    e.ledger().with_mut(|li| li.timestamp += 86_400);
+    assert_eq!(
+        vault
+            .free_units_remaining(&user, &agent_id)
+            .get(http_calls.clone()),
+        Some(2)
+    );
+    let run_id = vault
+        .open_run(&user, &user, &agent_id, &1u32, &usage, &hash(&e, 1), &None)
+        .run_id;
+    let receipt = vault.finalize_run(&run_id, &runner, &1u32, &usage, &hash(&e, 2));
+    assert_eq!(receipt.actual_charge, 0);
+
+    // A run keeps the free tier of the card it opened at, even once a newer card drops it.
+    let run_id = vault
+        .open_run(&user, &user, &agent_id, &1u32, &usage, &hash(&e, 1), &None)
+        .run_id;
+    registry.publish_rate_card(&agent_id, &sample_rate_card(&e));
+    assert_eq!(vault.free_units_remaining(&user, &agent_id).len(), 0);
+    let receipt = vault.finalize_run(&run_id, &runner, &1u32, &usage, &hash(&e, 2));
+    assert_eq!(receipt.actual_charge, 0);
+}
+
+#[test]
+fn min_charge_floors_settlement_and_gates_budgets() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let mut runners = Vec::new(&e);
+    runners.push_back(runner.clone());
+    let rate = RateCardInput {
+        min_charge: 5_000_000,
+        ..sample_rate_card(&e)
+    };
+    let agent_id = registry.register_agent(&developer, &None, &runners, &rate);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+
+    let tiny = UsageBreakdown {
+        llm_in: 1,
+        llm_out: 0,
+        http_calls: 0,
+        runtime_ms: 0,
+        extra: Map::new(&e),
+    };
+    assert_eq!(
+        vault
+            .try_open_run(&user, &user, &agent_id, &1u32, &tiny, &hash(&e, 1), &None)
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::BudgetBelowMinCharge as u32
+        )))
+    );
+
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    let receipt = vault.finalize_run(&run_id, &runner, &1u32, &tiny, &hash(&e, 2));
+    assert_eq!(receipt.actual_charge, 5_000_000);
+    assert_eq!(receipt.refund, 12_001_000 - 5_000_000);
+    assert_eq!(vault.developer_balance(&developer), 5_000_000);
+    match vault.get_run(&run_id).lifecycle {
+        RunLifecycle::Finalized(settlement) => assert_eq!(
+            settlement.charges,
+            ChargeBreakdown {
+                llm_in: 10_000,
+                minimum: 4_990_000,
+                ..ChargeBreakdown::default()
+            }
+        ),
+        _ => panic!("run should be finalized"),
+    }
+}
+
+#[test]
+fn overflowing_budget_charge_is_reported_as_overflow() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let mut runners = Vec::new(&e);
+    runners.push_back(runner.clone());
+    let rate = RateCardInput {
+        rates: UsageMeterRates {
+            llm_in: i128::MAX / 2,
+            ..sample_rates(&e)
+        },
+        base_fee: 2,
+        ..sample_rate_card(&e)
+    };
+    let agent_id = registry.register_agent(&developer, &None, &runners, &rate);
+    vault.deposit(&user, &50_000_000);
+
+    let budgets = UsageBreakdown {
+        llm_in: 2,
+        llm_out: 0,
+        http_calls: 0,
+        runtime_ms: 0,
+        extra: Map::new(&e),
+    };
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::ChargeOverflow as u32
+        )))
+    );
+}
+
+#[test]
+fn quote_run_prices_budgets_and_checks_policy_without_writes() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    assert_eq!(vault.quote_run(&agent_id, &1u32, &budgets), 12_001_000);
+
+    let quote = vault.quote_run_for(&user, &agent_id, &1u32, &budgets);
+    assert_eq!(quote.max_charge, 12_001_000);
+    assert!(quote.within_per_run_cap && quote.within_daily_cap);
+    assert!(!quote.sufficient_balance);
+
+    vault.deposit(&user, &20_000_000);
+    vault.set_policy(
+        &user,
+        &user,
+        &PolicyInput {
+            per_run_cap: 12_000_000,
+            daily_cap: 20_000_000,
+            paused: false,
+            require_user_ack: false,
+            default_run_ttl_secs: 0,
+            max_runs_per_hour: 0,
+            caps_in_usd: false,
+            only_verified_agents: false,
+            cap_window_secs: 86_400,
+            cap_window_offset_secs: 0,
+            rolling_cap: 0,
+            force_close_after_secs: 3_600,
+        },
+    );
+    let quote = vault.quote_run_for(&user, &agent_id, &1u32, &budgets);
+    assert!(!quote.within_per_run_cap);
+    assert!(quote.within_daily_cap);
+    assert!(quote.sufficient_balance);
+    assert_eq!(vault.daily_headroom(&user), 20_000_000);
+    assert_eq!(vault.balance_of(&user), 20_000_000);
+
+    vault.trust_agent(&user, &agent_id);
+    assert!(
+        vault
+            .quote_run_for(&user, &agent_id, &1u32, &budgets)
+            .within_per_run_cap
+    );
+}
+
+#[test]
+fn user_ack_policy_requires_co_signed_settlement() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.set_policy(
+        &user,
+        &user,
+        &PolicyInput {
+            require_user_ack: true,
+            ..default_policy()
+        },
+    );
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let usage = UsageBreakdown {
+        llm_in: 10,
+        ..budgets.clone()
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    assert_eq!(
+        vault
+            .try_finalize_run(&run_id, &runner, &1u32, &usage, &hash(&e, 2))
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::SettlementAckRequired as u32
+        )))
+    );
+    assert_eq!(
+        vault.try_accept_settlement(&run_id, &user).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::SettlementNotProposed as u32
+        )))
+    );
+
+    vault.propose_settlement(&run_id, &runner, &usage, &hash(&e, 2));
+    assert_eq!(vault.developer_balance(&developer), 0);
+    let receipt = vault.accept_settlement(&run_id, &user);
+    assert_eq!(receipt.actual_charge, 11_101_000);
+    assert_eq!(vault.developer_balance(&developer), 11_101_000);
+    assert_eq!(vault.balance_of(&user), 50_000_000 - 11_101_000);
+
+    let disputed = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    vault.propose_settlement(&disputed, &runner, &usage, &hash(&e, 3));
+    vault.reject_settlement(&disputed, &user);
+    match vault.get_run(&disputed).lifecycle {
+        RunLifecycle::Disputed(proposal) => assert_eq!(proposal.runner, runner),
+        _ => panic!("run should be disputed"),
+    }
+    assert_eq!(vault.developer_balance(&developer), 11_101_000);
+    assert_eq!(
+        vault.try_cancel_run(&user, &disputed).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RunNotOpen as u32
+        )))
+    );
+
+    // The admin ends a dispute by settling it for the usage they found...
+    let found = UsageBreakdown {
+        llm_in: 10,
+        llm_out: 10,
+        ..budgets.clone()
+    };
+    let receipt = vault.settle_dispute(&disputed, &found);
+    assert_eq!(receipt.actual_charge, 10_301_000);
+    assert_eq!(receipt.output_hash, hash(&e, 3));
+    assert_eq!(vault.developer_balance(&developer), 21_402_000);
+    assert_eq!(
+        vault.try_settle_dispute(&disputed, &found).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            DisputeError::RunNotDisputed as u32
+        )))
+    );
+
+    // ...or by refunding it.
+    let voided = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    vault.propose_settlement(&voided, &runner, &usage, &hash(&e, 4));
+    assert_eq!(
+        vault.try_void_dispute(&voided).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            DisputeError::RunNotDisputed as u32
+        )))
+    );
+    vault.reject_settlement(&voided, &user);
+    vault.void_dispute(&voided);
+    assert!(vault
+        .get_run(&voided)
+        .lifecycle
+        .is_kind(LifecycleKind::Cancelled));
+    assert_eq!(
+        vault.balance_of(&user),
+        50_000_000 - 11_101_000 - 10_301_000
+    );
+
+    // A proposal the user never answers is refunded once its deadline passes. Turning
+    // the policy off does not change how runs already open settle.
+    let unanswered = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    vault.set_policy(&user, &user, &default_policy());
+    assert_eq!(
+        vault
+            .try_finalize_run(&unanswered, &runner, &1u32, &usage, &hash(&e, 5))
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::SettlementAckRequired as u32
+        )))
+    );
+    vault.propose_settlement(&unanswered, &runner, &usage, &hash(&e, 5));
+    let respond_by = match vault.get_run(&unanswered).lifecycle {
+        RunLifecycle::Proposed(proposal) => proposal.respond_by,
+        _ => panic!("run should be proposed"),
+    };
+    assert_eq!(respond_by, e.ledger().timestamp() + 3 * 86_400);
+    e.ledger().with_mut(|li| li.timestamp = respond_by);
+    assert_eq!(
+        vault.try_expire_run(&unanswered).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RunNotExpired as u32
+        )))
+    );
+    e.ledger().with_mut(|li| li.timestamp = respond_by + 1);
+    vault.expire_run(&unanswered);
+    assert!(vault
+        .get_run(&unanswered)
+        .lifecycle
+        .is_kind(LifecycleKind::Expired));
+    assert_eq!(
+        vault.balance_of(&user),
+        50_000_000 - 11_101_000 - 10_301_000
+    );
+}
+
+#[test]
+fn rate_version_zero_opens_at_the_latest_card() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    assert_eq!(vault.quote_run(&agent_id, &0u32, &budgets), 12_001_000);
+
+    let cheaper = RateCardInput {
+        rates: UsageMeterRates {
+            http_calls: 1_000_000,
+            ..sample_rates(&e)
+        },
+        ..sample_rate_card(&e)
+    };
+    registry.publish_rate_card(&agent_id, &cheaper);
+
+    let opened = vault.open_run(
+        &user,
+        &user,
+        &agent_id,
+        &0u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+    assert_eq!(opened.rate_version, 2);
+    assert_eq!(opened.max_charge, 3_001_000);
+    assert_eq!(vault.get_run(&opened.run_id).rate_version, 2);
+
+    let pinned = vault.open_run(
+        &user,
+        &user,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+    assert_eq!(pinned.rate_version, 1);
+    assert_eq!(pinned.max_charge, 12_001_000);
+}
+
+#[test]
+fn budget_presets_open_runs_and_are_capped() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+
+    let small = soroban_sdk::Symbol::new(&e, "small");
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    vault.save_budget_preset(
+        &user,
+        &small,
+        &UsageBreakdown {
+            llm_in: 1,
+            ..budgets.clone()
+        },
+    );
+    vault.save_budget_preset(&user, &small, &budgets);
+    assert_eq!(vault.list_budget_presets(&user).len(), 1);
+
+    let opened =
+        vault.open_run_with_preset(&user, &user, &agent_id, &1u32, &small, &hash(&e, 1), &None);
+    assert_eq!(opened.max_charge, 12_001_000);
+    assert_eq!(vault.get_run(&opened.run_id).budgets.llm_in, 100);
+
+    for i in 1..16u32 {
+        let name = soroban_sdk::Symbol::new(&e, &std::format!("preset_{i}"));
+        vault.save_budget_preset(&user, &name, &budgets);
+    }
+    assert_eq!(
+        vault
+            .try_save_budget_preset(&user, &soroban_sdk::Symbol::new(&e, "extra"), &budgets)
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::TooManyPresets as u32
+        )))
+    );
+    vault.save_budget_preset(&user, &small, &budgets);
+
+    vault.delete_budget_preset(&user, &small);
+    assert_eq!(vault.list_budget_presets(&user).len(), 15);
+    assert_eq!(
+        vault
+            .try_open_run_with_preset(&user, &user, &agent_id, &1u32, &small, &hash(&e, 1), &None)
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::PresetNotFound as u32
+        )))
+    );
+}
+
+#[test]
+fn charge_run_streams_payments_and_finalize_settles_the_rest() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+
+    let delta = UsageBreakdown {
+        llm_in: 50,
+        llm_out: 0,
+        http_calls: 0,
+        runtime_ms: 0,
+        extra: Map::new(&e),
+    };
+    assert_eq!(vault.charge_run(&run_id, &runner, &delta), 500_000);
+    assert_eq!(vault.developer_balance(&developer), 500_000);
+    let record = vault.get_run(&run_id);
+    assert_eq!(record.streamed_charge, 500_000);
+    assert_eq!(record.escrowed, 12_001_000 - 500_000);
+
+    let over = UsageBreakdown {
+        llm_in: 51,
+        ..delta.clone()
+    };
+    assert_eq!(
+        vault.try_charge_run(&run_id, &runner, &over).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::UsageExceedsBudget as u32
+        )))
+    );
+
+    let remainder = UsageBreakdown {
+        llm_in: 30,
+        llm_out: 40,
+        http_calls: 1,
+        runtime_ms: 500,
+        extra: Map::new(&e),
+    };
+    let receipt = vault.finalize_run(&run_id, &runner, &1u32, &remainder, &hash(&e, 4));
+    assert_eq!(receipt.actual_charge, 500_000 + 11_100_500);
+    assert_eq!(receipt.refund, 12_001_000 - 11_600_500);
+    assert_eq!(receipt.usage.llm_in, 80);
+    assert_eq!(vault.developer_balance(&developer), 11_600_500);
+    assert_eq!(vault.balance_of(&user), 50_000_000 - 11_600_500);
+}
+
+#[test]
+fn streamed_chunks_round_like_the_whole_run() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let rate = RateCardInput {
+        unit_scale: 7,
+        ..sample_rate_card(&e)
+    };
+    let agent_id = registry.register_agent(&developer, &None, &vec![&e, runner.clone()], &rate);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+
+    // One llm_in unit costs 10_000 / 7 = 1_428.57; three cost 4_285.71. Each chunk pays
+    // the rounded-up price of everything streamed so far, less what was already paid.
+    let unit = UsageBreakdown {
+        llm_in: 1,
+        ..UsageBreakdown::zero(&e)
+    };
+    assert_eq!(vault.charge_run(&run_id, &runner, &unit), 1_429);
+    assert_eq!(vault.charge_run(&run_id, &runner, &unit), 1_429);
+    assert_eq!(vault.charge_run(&run_id, &runner, &unit), 1_428);
+    assert_eq!(vault.get_run(&run_id).streamed_charge, 4_286);
+
+    let receipt = vault.finalize_run(
+        &run_id,
+        &runner,
+        &1u32,
+        &UsageBreakdown::zero(&e),
+        &hash(&e, 2),
+    );
+    assert_eq!(receipt.actual_charge, 4_286);
+    assert_eq!(vault.developer_balance(&developer), 4_286);
+    // The breakdown covers the streamed usage, rounded once.
+    match vault.get_run(&run_id).lifecycle {
+        RunLifecycle::Finalized(settlement) => assert_eq!(
+            settlement.charges,
+            ChargeBreakdown {
+                llm_in: 4_285,
+                adjustment: 1,
+                ..ChargeBreakdown::default()
+            }
+        ),
+        _ => panic!("run should be finalized"),
+    }
+}
+
+#[test]
+fn policy_run_ttl_expires_runs_and_bounds_explicit_ttls() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().with_mut(|li| li.timestamp = 1_000);
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    vault.set_policy(
+        &user,
+        &user,
+        &PolicyInput {
+            default_run_ttl_secs: 7_200,
+            ..default_policy()
+        },
+    );
+
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    assert_eq!(vault.get_run(&run_id).expires_at, Some(8_200));
+    assert_eq!(
+        vault
+            .try_open_run_with_ttl(
+                &user,
+                &runner,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &Some(7_201),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RunTtlExceedsPolicy as u32
+        )))
+    );
+    let short = vault
+        .open_run_with_ttl(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &Some(60),
+            &None,
+        )
+        .run_id;
+    assert_eq!(vault.get_run(&short).expires_at, Some(1_060));
+    assert_eq!(
+        vault.try_expire_run(&run_id).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RunNotExpired as u32
+        )))
+    );
+
+    e.ledger().with_mut(|li| li.timestamp = 8_200);
+    assert_eq!(
+        vault
+            .try_finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 2))
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RunExpired as u32
+        )))
+    );
+    vault.expire_run(&run_id);
+    vault.expire_run(&short);
+    assert!(matches!(
+        vault.get_run(&run_id).lifecycle,
+        RunLifecycle::Expired
+    ));
+    assert_eq!(vault.balance_of(&user), 50_000_000);
+    assert_eq!(vault.daily_headroom(&user), 100_000_000);
+}
+
+#[test]
+fn developer_goodwill_refunds_are_capped_by_the_charge() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    let payout = Address::generate(&e);
+    registry.set_payout_address(&agent_id, &payout);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    assert_eq!(
+        vault.try_refund_user(&developer, &run_id, &1).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RunNotFinalized as u32
+        )))
+    );
+    vault.finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 2));
+
+    // The payout address only receives the charge; refunds are the developer's call.
+    for caller in [Address::generate(&e), payout.clone()] {
+        assert_eq!(
+            vault.try_refund_user(&caller, &run_id, &1).err(),
+            Some(Ok(soroban_sdk::Error::from_contract_error(
+                VaultError::Unauthorized as u32
+            )))
+        );
+    }
+    vault.refund_user(&developer, &run_id, &10_000_000);
+    assert_eq!(vault.developer_balance(&payout), 2_001_000);
+    assert_eq!(vault.balance_of(&user), 50_000_000 - 2_001_000);
+    assert_eq!(
+        vault.try_refund_user(&developer, &run_id, &2_001_001).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RefundExceedsCharge as u32
+        )))
+    );
+    vault.refund_user(&developer, &run_id, &2_001_000);
+    match vault.get_run(&run_id).lifecycle {
+        RunLifecycle::Finalized(settlement) => {
+            assert_eq!(settlement.goodwill_refunded, settlement.actual_charge)
+        }
+        _ => panic!("run should be finalized"),
+    }
+    assert_eq!(vault.balance_of(&user), 50_000_000);
+}
+
+#[test]
+fn tips_go_to_the_developer_outside_the_caps() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &20_000_000);
+    vault.set_policy(&user, &user, &default_policy());
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    assert_eq!(
+        vault.try_tip_run(&user, &run_id, &1).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RunNotFinalized as u32
+        )))
+    );
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    vault.finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 2));
+    let headroom = vault.daily_headroom(&user);
+
+    vault.tip_run(&user, &run_id, &5_000_000);
+    vault.tip_run(&user, &run_id, &1_000_000);
+    assert_eq!(vault.developer_balance(&developer), 12_001_000 + 6_000_000);
+    assert_eq!(vault.balance_of(&user), 20_000_000 - 12_001_000 - 6_000_000);
+    assert_eq!(vault.daily_headroom(&user), headroom);
+    match vault.get_run(&run_id).lifecycle {
+        RunLifecycle::Finalized(settlement) => assert_eq!(settlement.tipped, 6_000_000),
+        _ => panic!("run should be finalized"),
+    }
+    assert_eq!(
+        vault.try_tip_run(&user, &run_id, &2_000_000).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::InsufficientBalance as u32
+        )))
+    );
+}
+
+#[test]
+fn run_full_lifecycle_fixture_settles_a_run() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let usage = UsageBreakdown {
+        llm_in: 80,
+        llm_out: 40,
+        http_calls: 1,
+        runtime_ms: 500,
+        extra: Map::new(&e),
+    };
+
+    let receipt = run_full_lifecycle(&e, &vault, &user, &runner, agent_id, &budgets, &usage);
+    assert_eq!(receipt.actual_charge, 11_600_500);
+    assert_eq!(receipt.refund, 400_500);
+    assert_eq!(vault.balance_of(&user), 400_500);
+    assert_eq!(vault.developer_balance(&developer), 11_600_500);
+}
+
+#[test]
+fn open_run_stores_a_bounded_memo() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+
+    let memo = String::from_str(&e, "nightly report");
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &Some(memo.clone()),
+        )
+        .run_id;
+    let record = vault.get_run(&run_id);
+    assert_eq!(record.memo, Some(memo));
+    assert_eq!(record.input_hash, hash(&e, 1));
+
+    let too_long = String::from_bytes(&e, &[b'a'; 65]);
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &Some(too_long)
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::MemoTooLong as u32
+        )))
+    );
+}
+
+#[test]
+fn finalize_event_attributes_the_run() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, vault_addr) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    e.ledger().set_timestamp(42);
+    vault.finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 2));
+
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault_addr,
+                (symbol_short!("run"), symbol_short!("finalized"), agent_id).into_val(&e),
+                RunFinalizedLog {
+                    run_id,
+                    agent_id,
+                    user: user.clone(),
+                    sponsor: None,
+                    developer: developer.clone(),
+                    runner: runner.clone(),
+                    input_hash: hash(&e, 1),
+                    actual_charge: 12_001_000,
+                    refund: 0,
+                    priority_fee: 0,
+                    usage: budgets.clone(),
+                    charges: ChargeBreakdown {
+                        base_fee: 0,
+                        llm_in: 1_000_000,
+                        llm_out: 1_000_000,
+                        http_calls: 10_000_000,
+                        runtime_ms: 1_000,
+                        extra: 0,
+                        ..ChargeBreakdown::default()
+                    },
+                    output_hash: hash(&e, 2),
+                    failure_code: None,
+                    finalized_at: 42,
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+}
+
+#[test]
+fn earnings_breakdown_tracks_each_agent() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let first = setup_agent(&e, &registry, &developer, &runner);
+    let second = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &100_000_000);
+    vault.grant_runner(&user, &runner, &first, &None);
+    vault.grant_runner(&user, &runner, &second, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+
+    let run_id = vault
+        .open_run(&user, &runner, &first, &1u32, &budgets, &hash(&e, 1), &None)
+        .run_id;
+    vault.finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 2));
+    vault.tip_run(&user, &run_id, &500);
+
+    let run_id = vault
+        .open_run(
+            &user,
+            &runner,
+            &second,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    let usage = UsageBreakdown {
+        llm_in: 80,
+        llm_out: 40,
+        http_calls: 1,
+        runtime_ms: 500,
+        extra: Map::new(&e),
+    };
+    vault.finalize_run(&run_id, &runner, &1u32, &usage, &hash(&e, 3));
+    vault.claim_developer(&developer, &1_000);
+
+    assert_eq!(vault.earnings_for_agent(&developer, &first), 12_001_500);
+    assert_eq!(vault.earnings_for_agent(&developer, &second), 11_600_500);
+    let breakdown = vault.earnings_breakdown(&developer, &vec![&e, first, second, 99]);
+    assert_eq!(breakdown, vec![&e, 12_001_500, 11_600_500, 0]);
+    assert_eq!(
+        breakdown.iter().sum::<i128>(),
+        vault.developer_balance(&developer) + 1_000
+    );
+}
+
+#[test]
+fn withdraw_all_sweeps_the_balance() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (_, vault, registry_addr, vault_addr) = setup_clients(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    assert_eq!(vault.withdraw_all(&user), 0);
+
+    vault.deposit(&user, &5_000);
+    vault.withdraw(&user, &1_000);
+    assert_eq!(vault.withdraw_all(&user), 4_000);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault_addr,
+                (symbol_short!("withdraw"), symbol_short!("user")).into_val(&e),
+                WithdrawLog {
+                    user: user.clone(),
+                    amount: 4_000,
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    assert_eq!(vault.balance_of(&user), 0);
+
+    vault.pause_vault();
+    assert_eq!(
+        vault.try_withdraw_all(&user).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::VaultPaused as u32
+        )))
+    );
+}
+
+#[test]
+fn only_verified_agents_policy_rejects_unverified_agents() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    registry.init(&Address::generate(&e));
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.set_policy(
+        &user,
+        &user,
+        &PolicyInput {
+            only_verified_agents: true,
+            ..default_policy()
+        },
+    );
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            PolicyError::UnverifiedAgent as u32
+        )))
+    );
+
+    registry.set_verified(&agent_id, &true);
+    let opened = vault.open_run(
+        &user,
+        &user,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+    assert_eq!(opened.max_charge, 12_001_000);
+}
+
+#[test]
+fn usd_caps_convert_through_the_price_oracle_and_fail_closed() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().with_mut(|li| li.timestamp = 10_000);
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let oracle_addr = e.register(MockPriceOracle, ());
+    let oracle = MockPriceOracleClient::new(&e, &oracle_addr);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    // $0.10 per run and $0.20 per day; the budget below escrows 1.2001 tokens.
+    vault.set_policy(
+        &user,
+        &user,
+        &PolicyInput {
+            per_run_cap: 1_000_000,
+            daily_cap: 2_000_000,
+            caps_in_usd: true,
+            ..default_policy()
+        },
+    );
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let open = || {
+        vault.try_open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+    };
+    let oracle_error =
+        |error: PriceOracleError| Some(Ok(soroban_sdk::Error::from_contract_error(error as u32)));
+
+    assert_eq!(open().err(), oracle_error(PriceOracleError::OracleNotSet));
+    vault.set_price_oracle(&Some(PriceOracleConfig {
+        oracle: oracle_addr.clone(),
+        max_age_secs: 600,
+    }));
+    assert_eq!(
+        open().err(),
+        oracle_error(PriceOracleError::PriceUnavailable)
+    );
+
+    // $0.10 per token, so the run is worth $0.12001.
+    oracle.set_price(&1_000_000, &7, &9_500);
+    assert_eq!(
+        open().err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::PerRunCapExceeded as u32
+        )))
+    );
+    assert!(
+        !vault
+            .quote_run_for(&user, &agent_id, &1u32, &budgets)
+            .within_per_run_cap
+    );
+
+    vault.set_policy(
+        &user,
+        &user,
+        &PolicyInput {
+            per_run_cap: 2_000_000,
+            daily_cap: 2_000_000,
+            caps_in_usd: true,
+            ..default_policy()
+        },
+    );
+    let opened = open().unwrap().unwrap();
+    assert_eq!(opened.max_charge, 12_001_000);
+    assert_eq!(vault.get_policy(&user).reserved_today, 1_200_100);
+    assert_eq!(
+        open().err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::DailyCapExceeded as u32
+        )))
+    );
+
+    vault.cancel_run(&user, &opened.run_id);
+    assert_eq!(vault.get_policy(&user).reserved_today, 0);
+    e.ledger().with_mut(|li| li.timestamp = 10_200);
+    assert_eq!(open().err(), oracle_error(PriceOracleError::PriceStale));
+}
+
+#[test]
+fn switching_cap_units_converts_the_recorded_spend() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().with_mut(|li| li.timestamp = 10_000);
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let oracle_addr = e.register(MockPriceOracle, ());
+    let oracle = MockPriceOracleClient::new(&e, &oracle_addr);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    vault.deposit(&user, &50_000_000);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+
+    // Without caps a USD policy never needs a price.
+    vault.set_policy(
+        &user,
+        &user,
+        &PolicyInput {
+            per_run_cap: 0,
+            daily_cap: 0,
+            caps_in_usd: true,
+            ..default_policy()
+        },
+    );
+    let opened = vault.open_run(
+        &user,
+        &user,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+    vault.cancel_run(&user, &opened.run_id);
+
+    let token_caps = PolicyInput {
+        per_run_cap: 0,
+        daily_cap: 0,
+        rolling_cap: 40_000_000,
+        ..default_policy()
+    };
+    vault.set_policy(&user, &user, &token_caps);
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    let usd_caps = PolicyInput {
+        per_run_cap: 0,
+        daily_cap: 0,
+        rolling_cap: 4_000_000,
+        caps_in_usd: true,
+        ..default_policy()
+    };
+    assert_eq!(
+        vault.try_set_policy(&user, &user, &usd_caps).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RunNotFinalized as u32
+        )))
+    );
+    vault.finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 2));
+    assert_eq!(vault.rolling_spend(&user), 12_001_000);
+
+    assert_eq!(
+        vault.try_set_policy(&user, &user, &usd_caps).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::BalanceTokenNotSet as u32
+        )))
+    );
+    vault.set_balance_token(&user, &registry.settlement_token(&agent_id, &1));
+    vault.set_price_oracle(&Some(PriceOracleConfig {
+        oracle: oracle_addr,
+        max_age_secs: 600,
+    }));
+    // $0.10 per token.
+    oracle.set_price(&1_000_000, &7, &9_500);
+    vault.set_policy(&user, &user, &usd_caps);
+    assert_eq!(vault.rolling_spend(&user), 1_200_100);
+
+    vault.set_policy(&user, &user, &token_caps);
+    assert_eq!(vault.rolling_spend(&user), 12_001_000);
+}
+
+#[test]
+fn user_and_run_entries_stay_live_when_bumped() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, vault_addr) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.set_balance_token(&user, &registry.settlement_token(&agent_id, &1));
+    vault.deposit(&user, &20_000_000);
+    vault.set_policy(&user, &user, &default_policy());
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+
+    let ttl_of =
+        |key: DataKey| e.as_contract(&vault_addr, || e.storage().persistent().get_ttl(&key));
+    let full_ttl = ttl_of(DataKey::UserBalance(user.clone()));
+    assert_eq!(ttl_of(DataKey::Run(run_id)), full_ttl);
+
+    e.ledger()
+        .with_mut(|li| li.sequence_number += full_ttl - 1_000);
+    vault.bump_user_ttl(&user);
+    vault.bump_run_ttl(&run_id);
+    assert_eq!(ttl_of(DataKey::UserBalance(user.clone())), full_ttl);
+    assert_eq!(ttl_of(DataKey::UserPolicy(user.clone())), full_ttl);
+    assert_eq!(ttl_of(DataKey::BalanceToken(user.clone())), full_ttl);
+    assert_eq!(ttl_of(DataKey::Run(run_id)), full_ttl);
+
+    e.ledger()
+        .with_mut(|li| li.sequence_number += full_ttl - 1_000);
+    assert_eq!(vault.balance_of(&user), 20_000_000 - 12_001_000);
+    assert_eq!(vault.get_run(&run_id).max_charge, 12_001_000);
+
+    vault.bump_user_ttl(&Address::generate(&e));
+    assert_eq!(
+        vault.try_bump_run_ttl(&(run_id + 1)).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RunNotFound as u32
+        )))
+    );
+}
+
+#[test]
+fn migrate_balance_moves_funds_to_the_target_vault() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, vault_addr) = setup_clients(&e);
+    let target_addr = e.register(PrepaidVault, ());
+    let target = PrepaidVaultClient::new(&e, &target_addr);
+    let admin = Address::generate(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&admin, &registry_addr);
+    target.init(&admin, &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &20_000_000);
+    vault.set_policy(&user, &user, &default_policy());
+
+    assert_eq!(
+        vault.try_migrate_balance(&user).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::NotInitialized as u32
+        )))
+    );
+    vault.set_migration_target(&Some(target_addr.clone()));
+    target.set_migration_source(&Some(vault_addr.clone()));
+
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    assert_eq!(
+        vault.try_migrate_balance(&user).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RunNotFinalized as u32
+        )))
+    );
+    vault.cancel_run(&user, &run_id);
+
+    let locked = Some(Ok(soroban_sdk::Error::from_contract_error(
+        VaultError::WithdrawalLocked as u32,
+    )));
+    vault.set_withdrawal_delay(&user, &3_600);
+    assert_eq!(vault.try_migrate_balance(&user).err(), locked);
+    vault.request_withdrawal(&user, &1_000);
+    vault.set_withdrawal_delay(&user, &0);
+    e.ledger().with_mut(|li| li.timestamp += 3_600);
+    assert_eq!(vault.withdrawal_delay_of(&user), 0);
+    assert_eq!(vault.try_migrate_balance(&user).err(), locked);
+    vault.cancel_withdrawal(&user);
+
+    let guardian = Address::generate(&e);
+    vault.set_guardian(&user, &Some(guardian));
+    vault.guardian_pause(&user);
+    assert_eq!(
+        vault.try_migrate_balance(&user).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::PolicyPaused as u32
+        )))
+    );
+    vault.set_policy(&user, &user, &default_policy());
+
+    assert_eq!(vault.migrate_balance(&user), 20_000_000);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault_addr.clone(),
+                (symbol_short!("migrate"), symbol_short!("out")).into_val(&e),
+                MigrationLog {
+                    user: user.clone(),
+                    counterparty: target_addr.clone(),
+                    amount: 20_000_000,
+                }
+                .into_val(&e),
+            ),
+            (
+                target_addr.clone(),
+                (symbol_short!("migrate"), symbol_short!("in")).into_val(&e),
+                MigrationLog {
+                    user: user.clone(),
+                    counterparty: vault_addr.clone(),
+                    amount: 20_000_000,
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    assert_eq!(vault.balance_of(&user), 0);
+    assert_eq!(target.balance_of(&user), 20_000_000);
+    assert_eq!(vault.migrate_balance(&user), 0);
+
+    e.set_auths(&[]);
+    assert!(target.try_receive_migration(&user, &1_000).is_err());
+}
+
+#[test]
+fn guardian_pause_freezes_account_until_user_unpauses() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+    let guardian = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+
+    assert_eq!(
+        vault.try_guardian_pause(&user).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::Unauthorized as u32
+        )))
+    );
+    vault.set_guardian(&user, &Some(guardian.clone()));
+    vault.set_policy_operator(&user, &Some(guardian.clone()));
+    assert_eq!(vault.guardian_of(&user), Some(guardian.clone()));
+
+    set_caller(&vault, &guardian, "guardian_pause", (&user,));
+    vault.guardian_pause(&user);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault.address.clone(),
+                (symbol_short!("runner"), symbol_short!("revoked")).into_val(&e),
+                RunnerRevokeLog {
+                    user: user.clone(),
+                    runner: runner.clone(),
+                    agent_id,
+                    revoked_at: 0,
+                }
+                .into_val(&e),
+            ),
+            (
+                vault.address.clone(),
+                (symbol_short!("guardian"), symbol_short!("paused")).into_val(&e),
+                GuardianPauseLog {
+                    user: user.clone(),
+                    guardian: guardian.clone(),
+                    paused_at: 0,
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    assert!(vault.get_policy(&user).paused);
+    assert!(vault.list_runner_grants(&user).is_empty());
+
+    e.mock_all_auths();
+    assert_eq!(
+        vault
+            .try_set_policy(&user, &guardian, &default_policy())
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::Unauthorized as u32
+        )))
+    );
+    vault.set_policy(&user, &user, &default_policy());
+    assert!(!vault.get_policy(&user).paused);
+}
+
+#[test]
+fn policy_operator_manages_caps_but_cannot_move_funds() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+    let operator = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &5_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+
+    assert_eq!(
+        vault
+            .try_set_policy(&user, &operator, &default_policy())
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::Unauthorized as u32
+        )))
+    );
+    vault.set_policy_operator(&user, &Some(operator.clone()));
+    assert_eq!(vault.policy_operator_of(&user), Some(operator.clone()));
+
+    let paused = PolicyInput {
+        paused: true,
+        ..default_policy()
+    };
+    set_caller(&vault, &operator, "set_policy", (&user, &operator, &paused));
+    vault.set_policy(&user, &operator, &paused);
+    assert!(vault.get_policy(&user).paused);
+
+    set_caller(
+        &vault,
+        &operator,
+        "set_agent_allowance",
+        (&user, &operator, &agent_id, &1_000i128, &None::<u64>),
+    );
+    vault.set_agent_allowance(&user, &operator, &agent_id, &1_000, &None);
+    assert_eq!(
+        vault.get_agent_allowance(&user, &agent_id).unwrap().amount,
+        1_000
+    );
+
+    set_caller(&vault, &operator, "revoke_all_runners", (&user, &operator));
+    vault.revoke_all_runners(&user, &operator);
+    assert!(vault.list_runner_grants(&user).is_empty());
+
+    set_caller(&vault, &operator, "withdraw", (&user, &1_000i128));
+    assert!(vault.try_withdraw(&user, &1_000).is_err());
+    set_caller(
+        &vault,
+        &operator,
+        "grant_runner",
+        (&user, &runner, &agent_id, &None::<u64>),
+    );
+    assert!(vault
+        .try_grant_runner(&user, &runner, &agent_id, &None)
+        .is_err());
+    assert_eq!(vault.balance_of(&user), 5_000);
+
+    e.mock_all_auths();
+    vault.set_policy_operator(&user, &None);
+    assert!(vault
+        .try_set_policy(&user, &operator, &default_policy())
+        .is_err());
+}
+
+#[test]
+fn withdrawal_timelock_requires_request_then_execute() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (_, vault, registry_addr, _) = setup_clients(&e);
+    let user = Address::generate(&e);
+    let locked = Some(Ok(soroban_sdk::Error::from_contract_error(
+        VaultError::WithdrawalLocked as u32,
+    )));
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    vault.deposit(&user, &5_000);
+    assert_eq!(
+        vault.try_set_withdrawal_delay(&user, &u64::MAX).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            WithdrawalError::DelayTooLong as u32
+        )))
+    );
+    vault.set_withdrawal_delay(&user, &3_600);
+    assert_eq!(vault.try_withdraw(&user, &1_000).err(), locked);
+    assert_eq!(vault.try_withdraw_all(&user).err(), locked);
+
+    let now = e.ledger().timestamp();
+    let pending = vault.request_withdrawal(&user, &1_000);
+    assert_eq!(pending.available_at, now + 3_600);
+    assert_eq!(vault.balance_of(&user), 4_000);
+    assert_eq!(vault.try_execute_withdrawal(&user).err(), locked);
+
+    vault.cancel_withdrawal(&user);
+    assert!(vault.pending_withdrawal(&user).is_none());
+    assert_eq!(vault.balance_of(&user), 5_000);
+
+    vault.request_withdrawal(&user, &2_000);
+    e.ledger().set_timestamp(now + 3_600);
+    assert_eq!(vault.execute_withdrawal(&user), 2_000);
+    assert_eq!(vault.execute_withdrawal(&user), 0);
+    assert_eq!(vault.balance_of(&user), 3_000);
+
+    // Lowering the delay only applies once the current delay has elapsed.
+    vault.set_withdrawal_delay(&user, &0);
+    assert_eq!(vault.withdrawal_delay_of(&user), 3_600);
+    assert_eq!(vault.try_withdraw(&user, &1_000).err(), locked);
+    e.ledger().set_timestamp(now + 7_200);
+    assert_eq!(vault.withdrawal_delay_of(&user), 0);
+    vault.withdraw(&user, &1_000);
+    assert_eq!(vault.balance_of(&user), 2_000);
+}
+
+#[test]
+fn claim_developer_all_drains_the_balance() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, vault_addr) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    assert_eq!(vault.claim_developer_all(&developer), 0);
+
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    vault.finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 2));
+
+    assert_eq!(vault.claim_developer_all(&developer), 12_001_000);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault_addr,
+                (symbol_short!("developer"), symbol_short!("claimed")).into_val(&e),
+                DeveloperClaimLog {
+                    developer: developer.clone(),
+                    destination: developer.clone(),
+                    amount: 12_001_000,
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    assert_eq!(vault.developer_balance(&developer), 0);
+    assert_eq!(vault.claim_developer_all(&developer), 0);
+}
+
+#[test]
+fn claim_developer_to_records_the_destination() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, vault_addr) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let treasury = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    vault.finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 2));
+
+    assert_eq!(
+        vault
+            .try_claim_developer_to(&developer, &treasury, &12_001_001)
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::InsufficientBalance as u32
+        )))
+    );
+    vault.claim_developer_to(&developer, &treasury, &1_000);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault_addr,
+                (symbol_short!("developer"), symbol_short!("claimed")).into_val(&e),
+                DeveloperClaimLog {
+                    developer: developer.clone(),
+                    destination: treasury.clone(),
+                    amount: 1_000,
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    assert_eq!(vault.developer_balance(&developer), 12_000_000);
+    assert_eq!(vault.developer_balance(&treasury), 0);
+}
+
+#[test]
+fn deposit_from_allowance_draws_the_users_approval() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (_, vault, registry_addr, _) = setup_clients(&e);
+    let app = Address::generate(&e);
+    let user = Address::generate(&e);
+    vault.init(&Address::generate(&e), &registry_addr);
+
+    // Without an approval the app needs the user to co-sign.
+    set_caller(
+        &vault,
+        &app,
+        "deposit_from_allowance",
+        (&app, &user, 400i128),
+    );
+    assert!(vault.try_deposit_from_allowance(&app, &user, &400).is_err());
+    assert_eq!(vault.balance_of(&user), 0);
+    e.mock_all_auths();
+    vault.deposit_from_allowance(&app, &user, &100);
+    assert_eq!(vault.balance_of(&user), 100);
+
+    // Once approved, the app's signature alone is enough until the approval runs out.
+    vault.approve_deposits(&user, &app, &600);
+    assert_eq!(vault.deposit_allowance(&user, &app), 600);
+    set_caller(
+        &vault,
+        &app,
+        "deposit_from_allowance",
+        (&app, &user, 400i128),
+    );
+    vault.deposit_from_allowance(&app, &user, &400);
+    assert_eq!(vault.balance_of(&user), 500);
+    assert_eq!(vault.deposit_allowance(&user, &app), 200);
+    e.mock_all_auths();
+    assert_eq!(
+        vault.try_deposit_from_allowance(&app, &user, &300).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::InsufficientAllowance as u32
+        )))
+    );
+    assert_eq!(vault.balance_of(&user), 500);
+
+    // Revoking puts the app back to co-signed deposits.
+    vault.approve_deposits(&user, &app, &0);
+    assert_eq!(vault.deposit_allowance(&user, &app), 0);
+    vault.deposit_from_allowance(&app, &user, &300);
+    assert_eq!(vault.balance_of(&user), 800);
+    assert_eq!(
+        vault.try_approve_deposits(&user, &app, &-1).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::InvalidAmount as u32
+        )))
+    );
+}
+
+#[test]
+fn init_with_config_stores_a_versioned_config_once() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (_, vault, registry_addr, _) = setup_clients(&e);
+    let admin = Address::generate(&e);
+    let treasury = Address::generate(&e);
+
+    vault.init_with_config(&InitConfig {
+        admin: admin.clone(),
+        registry: registry_addr.clone(),
+        token: None,
+        fee_bps: 250,
+        treasury: Some(treasury.clone()),
+    });
+    let config = vault.get_config();
+    assert_eq!(config.admin, admin);
+    assert_eq!(config.registry, registry_addr);
+    assert_eq!(config.token, None);
+    assert_eq!(config.fee_bps, 250);
+    assert_eq!(config.treasury, Some(treasury.clone()));
+    assert_eq!(config.config_version, 1);
+
+    let already_initialized = Some(Ok(soroban_sdk::Error::from_contract_error(
+        VaultError::AlreadyInitialized as u32,
+    )));
+    assert_eq!(
+        vault.try_init(&admin, &registry_addr).err(),
+        already_initialized
+    );
+
+    let new_admin = Address::generate(&e);
+    let token = Address::generate(&e);
+    vault.update_config(&ConfigUpdate {
+        admin: Some(new_admin.clone()),
+        registry: None,
+        token: Some(token.clone()),
+        fee_bps: None,
+        treasury: None,
+        agent_event_topics: None,
+        failure_discount_bps: None,
+    });
+    assert_eq!(e.auths()[0].0, admin);
+    let config = vault.get_config();
+    assert_eq!(config.admin, new_admin);
+    assert_eq!(config.token, Some(token.clone()));
+    let user = Address::generate(&e);
+    assert_eq!(vault.balance_token_of(&user), Some(token));
+    assert_eq!(config.fee_bps, 250);
+    assert_eq!(config.treasury, Some(treasury));
+
+    assert_eq!(
+        vault
+            .try_update_config(&ConfigUpdate {
+                admin: None,
+                registry: None,
+                token: None,
+                fee_bps: Some(10_001),
+                treasury: None,
+                agent_event_topics: None,
+                failure_discount_bps: None,
+            })
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::InvalidAmount as u32
+        )))
+    );
+    assert_eq!(
+        vault
+            .try_init_with_config(&InitConfig {
+                admin,
+                registry: registry_addr,
+                token: None,
+                fee_bps: 0,
+                treasury: None,
+            })
+            .err(),
+        already_initialized
+    );
+}
+
+#[test]
+fn old_layout_runs_and_policies_upgrade_and_still_settle() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, vault_addr) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    assert_eq!(vault.schema_version(), 2);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+
+    // Store balances, a policy and an open run the way the unversioned vault did: in
+    // instance storage, in the schema 1 layouts.
+    let run_id = 7;
+    e.as_contract(&vault_addr, || {
+        let old_run = RunRecordV1 {
+            user: user.clone(),
+            opened_by: user.clone(),
+            agent_id,
+            rate_version: 1,
+            budgets: UsageBreakdownV1 {
+                llm_in: 100,
+                llm_out: 50,
+                http_calls: 1,
+                runtime_ms: 1000,
+            },
+            max_charge: 12_001_000,
+            escrowed: 12_001_000,
+            opened_at: 0,
+            lifecycle: RunLifecycleV1::Open,
+        };
+        let old_policy = UserPolicyV1 {
+            per_run_cap: 50_000_000,
+            daily_cap: 100_000_000,
+            paused: false,
+            reserved_today: 12_001_000,
+            reserved_day: 0,
+        };
+        let instance = e.storage().instance();
+        instance.set(&DataKey::Run(run_id), &old_run);
+        instance.set(&DataKey::UserPolicy(user.clone()), &old_policy);
+        instance.set(&DataKey::UserBalance(user.clone()), &7_998_999i128);
+        instance.set(&DataKey::DeveloperBalance(developer.clone()), &1_000i128);
+        instance.set(&DataKey::Run(99), &5u32);
+        instance.remove(&DataKey::SchemaVersion);
+    });
+    assert_eq!(vault.schema_version(), 1);
+
+    let upgraded = vault.get_run(&run_id);
+    assert_eq!(upgraded.input_hash, hash(&e, 0));
+    assert_eq!(upgraded.memo, None);
+    assert_eq!(upgraded.budgets.runtime_ms, 1000);
+    assert_eq!(upgraded.reserved, 12_001_000);
+    assert_eq!(vault.get_policy(&user).reserved_today, 12_001_000);
+    assert!(!vault.get_policy(&user).caps_in_usd);
+    assert_eq!(vault.balance_of(&user), 7_998_999);
+    assert_eq!(vault.developer_balance(&developer), 1_000);
+    let unknown_layout = Some(Ok(soroban_sdk::Error::from_contract_error(
+        SchemaError::UnknownLayout as u32,
+    )));
+    assert_eq!(vault.try_get_run(&99).err(), unknown_layout);
+
+    let version_mismatch = Some(Ok(soroban_sdk::Error::from_contract_error(
+        SchemaError::VersionMismatch as u32,
+    )));
+    assert_eq!(vault.try_migrate(&2, &vec![&e]).err(), version_mismatch);
+
+    // Batches may be migrated one after another until the operator finishes.
+    assert_eq!(vault.migrate(&1, &vec![&e, VersionedKey::Run(run_id)]), 1);
+    let batch = vec![
+        &e,
+        VersionedKey::Policy(user.clone()),
+        VersionedKey::Balance(user.clone()),
+        VersionedKey::DeveloperBalance(developer.clone()),
+        VersionedKey::Run(run_id + 1),
+    ];
+    assert_eq!(vault.migrate(&1, &batch), 3);
+    assert_eq!(vault.migrated_entries(), 4);
+    assert_eq!(vault.schema_version(), 1);
+    e.as_contract(&vault_addr, || {
+        let stored: RunRecord = e.storage().persistent().get(&DataKey::Run(run_id)).unwrap();
+        assert_eq!(stored.input_hash, hash(&e, 0));
+        assert!(!e.storage().instance().has(&DataKey::Run(run_id)));
+        assert!(!e
+            .storage()
+            .instance()
+            .has(&DataKey::UserBalance(user.clone())));
+        assert!(!e
+            .storage()
+            .instance()
+            .has(&DataKey::UserPolicy(user.clone())));
+        assert!(!e
+            .storage()
+            .instance()
+            .has(&DataKey::DeveloperBalance(developer.clone())));
+    });
+    assert_eq!(vault.balance_of(&user), 7_998_999);
+    assert_eq!(vault.developer_balance(&developer), 1_000);
+
+    assert_eq!(vault.finish_migration(&1), 4);
+    assert_eq!(vault.schema_version(), 2);
+    assert_eq!(vault.migrated_entries(), 0);
+    assert_eq!(vault.try_migrate(&1, &vec![&e]).err(), version_mismatch);
+
+    let usage = UsageBreakdown {
+        llm_in: 80,
+        llm_out: 40,
+        http_calls: 1,
+        runtime_ms: 500,
+        extra: Map::new(&e),
+    };
+    let receipt = vault.finalize_run(&run_id, &runner, &1u32, &usage, &hash(&e, 9));
+    assert_eq!(receipt.actual_charge, 11_600_500);
+    assert_eq!(vault.balance_of(&user), 7_998_999 + 400_500);
+    assert_eq!(vault.get_policy(&user).reserved_today, 0);
+    assert!(vault.developer_balance(&developer) > 1_000);
+}
+
+#[test]
+fn admin_manages_fee_exempt_developers() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    assert!(!vault.is_fee_exempt(&developer));
+    vault.add_fee_exempt(&developer);
+    assert!(vault.is_fee_exempt(&developer));
+
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    vault.finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 2));
+    assert_eq!(vault.developer_balance(&developer), 12_001_000);
+
+    vault.remove_fee_exempt(&developer);
+    assert!(!vault.is_fee_exempt(&developer));
+}
+
+#[test]
+fn settlements_follow_the_agent_revenue_split() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let partner = Address::generate(&e);
+    let designer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    registry.set_revenue_split(
+        &agent_id,
+        &vec![
+            &e,
+            (developer.clone(), 5_000u32),
+            (partner.clone(), 3_333u32),
+            (designer.clone(), 1_667u32),
+        ],
+    );
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let usage = UsageBreakdown {
+        llm_in: 80,
+        llm_out: 40,
+        http_calls: 1,
+        runtime_ms: 500,
+        extra: Map::new(&e),
+    };
+
+    let receipt = run_full_lifecycle(&e, &vault, &user, &runner, agent_id, &budgets, &usage);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault.address.clone(),
+                (symbol_short!("revenue"), symbol_short!("share")).into_val(&e),
+                RevenueShareLog {
+                    run_id: receipt.run_id,
+                    agent_id,
+                    payee: partner.clone(),
+                    amount: 3_866_446,
+                }
+                .into_val(&e),
+            ),
+            (
+                vault.address.clone(),
+                (symbol_short!("revenue"), symbol_short!("share")).into_val(&e),
+                RevenueShareLog {
+                    run_id: receipt.run_id,
+                    agent_id,
+                    payee: designer.clone(),
+                    amount: 1_933_803,
+                }
+                .into_val(&e),
+            ),
+            (
+                vault.address.clone(),
+                (symbol_short!("revenue"), symbol_short!("share")).into_val(&e),
+                RevenueShareLog {
+                    run_id: receipt.run_id,
+                    agent_id,
+                    payee: developer.clone(),
+                    amount: 5_800_251,
+                }
+                .into_val(&e),
+            ),
+            (
+                vault.address.clone(),
+                (symbol_short!("run"), symbol_short!("finalized"), agent_id).into_val(&e),
+                RunFinalizedLog {
+                    run_id: receipt.run_id,
+                    agent_id,
+                    user: user.clone(),
+                    sponsor: None,
+                    developer: developer.clone(),
+                    runner: runner.clone(),
+                    input_hash: hash(&e, 1),
+                    actual_charge: 11_600_500,
+                    refund: 400_500,
+                    priority_fee: 0,
+                    usage: usage.clone(),
+                    charges: ChargeBreakdown {
+                        base_fee: 0,
+                        llm_in: 800_000,
+                        llm_out: 800_000,
+                        http_calls: 10_000_000,
+                        runtime_ms: 500,
+                        extra: 0,
+                        ..ChargeBreakdown::default()
+                    },
+                    output_hash: hash(&e, 2),
+                    failure_code: None,
+                    finalized_at: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+
+    // 11_600_500 * 3_333 / 10_000 = 3_866_446.65 and * 1_667 / 10_000 = 1_933_803.35;
+    // the developer, listed first, keeps the rest including the dust.
+    assert_eq!(vault.developer_balance(&partner), 3_866_446);
+    assert_eq!(vault.developer_balance(&designer), 1_933_803);
+    assert_eq!(vault.developer_balance(&developer), 5_800_251);
+
+    registry.set_revenue_split(&agent_id, &Vec::new(&e));
+    let other_user = Address::generate(&e);
+    run_full_lifecycle(&e, &vault, &other_user, &runner, agent_id, &budgets, &usage);
+    assert_eq!(vault.developer_balance(&developer), 5_800_251 + 11_600_500);
+    assert_eq!(vault.developer_balance(&partner), 3_866_446);
+}
+
+#[test]
+fn settlements_pay_the_protocol_fee_to_the_treasury_unless_exempt() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let user = Address::generate(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let treasury = Address::generate(&e);
+
+    vault.init_with_config(&InitConfig {
+        admin: Address::generate(&e),
+        registry: registry_addr,
+        token: None,
+        fee_bps: 250,
+        treasury: Some(treasury.clone()),
+    });
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let usage = UsageBreakdown {
+        llm_in: 80,
+        llm_out: 40,
+        http_calls: 1,
+        runtime_ms: 500,
+        extra: Map::new(&e),
+    };
+
+    // 11_600_500 * 250 / 10_000 = 290_012.5, rounded down for the treasury.
+    run_full_lifecycle(&e, &vault, &user, &runner, agent_id, &budgets, &usage);
+    assert_eq!(vault.developer_balance(&treasury), 290_012);
+    assert_eq!(vault.developer_balance(&developer), 11_600_500 - 290_012);
+    vault.claim_developer(&treasury, &290_012);
+
+    vault.add_fee_exempt(&developer);
+    let other_user = Address::generate(&e);
+    run_full_lifecycle(&e, &vault, &other_user, &runner, agent_id, &budgets, &usage);
+    assert_eq!(vault.developer_balance(&treasury), 0);
+    assert_eq!(
+        vault.developer_balance(&developer),
+        2 * 11_600_500 - 290_012
+    );
+}
+
+#[test]
+fn user_stats_aggregate_balance_caps_and_escrow() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let stats = vault.get_user_stats(&user);
+    assert_eq!(stats.balance, 0);
+    assert_eq!(stats.per_run_cap, 0);
+    assert_eq!(stats.daily_cap, 0);
+    assert!(!stats.paused);
+    assert_eq!(stats.reserved_today, 0);
+    assert_eq!(stats.spent_today, 0);
+    assert_eq!(stats.open_runs, 0);
+    assert_eq!(stats.escrowed, 0);
+
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.set_policy(&user, &user, &default_policy());
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let first = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    let second = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    let partial = UsageBreakdown {
+        llm_in: 10,
+        llm_out: 0,
+        http_calls: 0,
+        runtime_ms: 0,
+        extra: Map::new(&e),
+    };
+    vault.charge_run(&second, &runner, &partial);
+
+    let stats = vault.get_user_stats(&user);
+    assert_eq!(stats.balance, 50_000_000 - 2 * 12_001_000);
+    assert_eq!(stats.per_run_cap, 50_000_000);
+    assert_eq!(stats.daily_cap, 100_000_000);
+    assert_eq!(stats.reserved_today, 2 * 12_001_000);
+    assert_eq!(stats.spent_today, 100_000);
+    assert_eq!(stats.open_runs, 2);
+    assert_eq!(stats.escrowed, 2 * 12_001_000 - 100_000);
+
+    let usage = UsageBreakdown {
+        llm_in: 80,
+        llm_out: 40,
+        http_calls: 1,
+        runtime_ms: 500,
+        extra: Map::new(&e),
+    };
+    vault.finalize_run(&first, &runner, &1u32, &usage, &hash(&e, 2));
+    vault.cancel_run(&user, &second);
+
+    let stats = vault.get_user_stats(&user);
+    assert_eq!(stats.balance, 50_000_000 - 11_600_500 - 100_000);
+    assert_eq!(stats.spent_today, 11_600_500 + 100_000);
+    assert_eq!(stats.open_runs, 0);
+    assert_eq!(stats.escrowed, 0);
+}
+
+#[test]
+fn run_listings_page_by_cursor_and_filter() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &100_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 10,
+        llm_out: 0,
+        http_calls: 0,
+        runtime_ms: 0,
+        extra: Map::new(&e),
+    };
+    let mut ids = std::vec::Vec::new();
+    for _ in 0..5 {
+        ids.push(
+            vault
+                .open_run(
+                    &user,
+                    &runner,
+                    &agent_id,
+                    &1u32,
+                    &budgets,
+                    &hash(&e, 1),
+                    &None,
+                )
+                .run_id,
+        );
+    }
+    vault.finalize_run(&ids[0], &runner, &1u32, &budgets, &hash(&e, 2));
+    vault.cancel_run(&user, &ids[2]);
+    vault.finalize_run(&ids[3], &runner, &1u32, &budgets, &hash(&e, 2));
+
+    let page = |runs: &[u64], next_cursor: Option<u32>| RunPage {
+        runs: Vec::from_slice(&e, runs),
+        next_cursor,
+    };
+    assert_eq!(
+        vault.list_runs_for_user(&user, &None, &1, &2),
+        page(&[ids[1], ids[2]], Some(3))
+    );
+    let open = Some(LifecycleKind::Open);
+    assert_eq!(
+        vault.list_runs_for_user(&user, &open, &0, &10),
+        page(&[ids[1], ids[4]], None)
+    );
+    // Cursors count positions in the whole listing, so a filtered page resumes right
+    // after the last run it examined.
+    let finalized = Some(LifecycleKind::Finalized);
+    assert_eq!(
+        vault.list_runs_for_user(&user, &finalized, &0, &1),
+        page(&[ids[0]], Some(1))
+    );
+    assert_eq!(
+        vault.list_runs_for_user(&user, &finalized, &1, &1),
+        page(&[ids[3]], Some(4))
+    );
+    assert_eq!(
+        vault.list_runs_for_user(&user, &finalized, &4, &1),
+        page(&[], None)
+    );
+    assert_eq!(
+        vault.list_runs_for_user(&user, &Some(LifecycleKind::Expired), &0, &10),
+        page(&[], None)
+    );
+
+    // The same listings exist per agent and per settling runner.
+    assert_eq!(
+        vault.list_runs_for_agent(&agent_id, &open, &0, &10),
+        page(&[ids[1], ids[4]], None)
+    );
+    assert_eq!(
+        vault.list_runs_for_runner(&runner, &None, &0, &10),
+        page(&[ids[0], ids[3]], None)
+    );
+    assert_eq!(
+        vault.list_runs_for_runner(&user, &None, &0, &10),
+        page(&[], None)
+    );
+
+    // A rarely matching filter examines one bounded window per call.
+    for _ in 0..60 {
+        vault.open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        );
+    }
+    let cancelled = Some(LifecycleKind::Cancelled);
+    assert_eq!(
+        vault.list_runs_for_user(&user, &cancelled, &3, &10),
+        page(&[], Some(43))
+    );
+    assert_eq!(
+        vault.list_runs_for_user(&user, &cancelled, &43, &10),
+        page(&[], None)
+    );
+    assert_eq!(
+        vault.list_runs_for_user(&user, &None, &60, &10).runs.len(),
+        5
+    );
+}
+
+#[test]
+fn runner_caps_limit_daily_escrow_per_runner() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &100_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    vault.set_runner_cap(&user, &runner, &25_000_000);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+
+    for _ in 0..2 {
+        let run_id = vault
+            .open_run(
+                &user,
+                &runner,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None,
+            )
+            .run_id;
+        vault.finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 2));
+    }
+    assert_eq!(
+        vault
+            .list_runner_caps(&user)
+            .get(runner.clone())
+            .unwrap()
+            .used_today,
+        24_002_000
+    );
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &runner,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RunnerCapExceeded as u32
+        )))
+    );
+    // The user's own runs are not limited by runner caps.
+    vault.open_run(
+        &user,
+        &user,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+
+    e.ledger().set_timestamp(e.ledger().timestamp() + 86_400);
+    assert_eq!(
+        vault
+            .list_runner_caps(&user)
+            .get(runner.clone())
+            .unwrap()
+            .used_today,
+        0
+    );
+    vault.open_run(
+        &user,
+        &runner,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+
+    vault.clear_runner_cap(&user, &runner);
+    assert!(vault.list_runner_caps(&user).is_empty());
+    vault.open_run(
+        &user,
+        &runner,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+}
+
+#[test]
+fn hourly_run_limit_counts_cancelled_runs() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &10_000_000);
+    vault.set_policy(
+        &user,
+        &user,
+        &PolicyInput {
+            max_runs_per_hour: 2,
+            ..default_policy()
+        },
+    );
+    let budgets = UsageBreakdown {
+        llm_in: 10,
+        llm_out: 0,
+        http_calls: 0,
+        runtime_ms: 0,
+        extra: Map::new(&e),
+    };
+
+    e.ledger().set_timestamp(7_200);
+    for _ in 0..2 {
+        let run_id = vault
+            .open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None,
+            )
+            .run_id;
+        vault.cancel_run(&user, &run_id);
+    }
+    assert_eq!(vault.get_policy(&user).runs_this_hour, 2);
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RateLimited as u32
+        )))
+    );
+
+    e.ledger().set_timestamp(10_800);
+    assert_eq!(vault.get_policy(&user).runs_this_hour, 0);
+    vault.open_run(
+        &user,
+        &user,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+}
+
+#[test]
+fn session_keys_open_runs_within_budget_and_expiry() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+    let session = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let now = e.ledger().timestamp();
+    vault.authorize_session(&user, &session, &(now + 600), &30_000_000);
+
+    let run_id = vault
+        .open_run(
+            &user,
+            &session,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    assert_eq!(vault.get_run(&run_id).opened_by, session);
+    assert_eq!(
+        vault.get_session(&user, &session).unwrap().spent,
+        12_001_000
+    );
+    vault.open_run(
+        &user,
+        &session,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &session,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::AllowanceExceeded as u32
+        )))
+    );
+
+    set_caller(&vault, &session, "withdraw", (&user, &1_000i128));
+    assert!(vault.try_withdraw(&user, &1_000).is_err());
+    e.mock_all_auths();
+
+    vault.authorize_session(&user, &session, &(now + 600), &30_000_000);
+    e.ledger().set_timestamp(now + 600);
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &session,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::AllowanceExpired as u32
+        )))
+    );
+
+    vault.revoke_session(&user, &session);
+    assert!(vault.get_session(&user, &session).is_none());
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &session,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::UnauthorizedRunner as u32
+        )))
+    );
+}
+
+#[test]
+fn referrers_earn_a_share_of_settlements() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+    let referrer = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.set_referral_bps(&333);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+
+    let run_id = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    vault.finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 2));
+    assert_eq!(vault.developer_balance(&developer), 12_001_000);
+
+    assert_eq!(
+        vault.try_set_referrer(&user, &user).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::InvalidReferrer as u32
+        )))
+    );
+    vault.set_referrer(&user, &referrer);
+    assert_eq!(
+        vault.try_set_referrer(&user, &Address::generate(&e)).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::ReferrerAlreadySet as u32
+        )))
+    );
+
+    let run_id = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    vault.finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 3));
+    // 12_001_000 * 333 / 10_000 = 399_633.3, rounded down for the referrer.
+    assert_eq!(vault.referrer_balance(&referrer), 399_633);
+    assert_eq!(
+        vault.developer_balance(&developer),
+        12_001_000 + 12_001_000 - 399_633
+    );
+    assert_eq!(vault.balance_of(&user), 50_000_000 - 2 * 12_001_000);
+
+    vault.claim_referrer(&referrer, &399_633);
+    assert_eq!(vault.referrer_balance(&referrer), 0);
+}
+
+#[test]
+fn base_fee_is_charged_on_finalize_and_refunded_on_cancel() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let mut runners = Vec::new(&e);
+    runners.push_back(runner.clone());
+    let rate = RateCardInput {
+        base_fee: 1_000_000,
+        ..sample_rate_card(&e)
+    };
+    let agent_id = registry.register_agent(&developer, &None, &runners, &rate);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    assert_eq!(vault.get_run(&run_id).max_charge, 12_001_000 + 1_000_000);
+    vault.cancel_run(&user, &run_id);
+    assert_eq!(vault.balance_of(&user), 50_000_000);
+
+    let idle = UsageBreakdown {
+        llm_in: 0,
+        llm_out: 0,
+        http_calls: 0,
+        runtime_ms: 0,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    let receipt = vault.finalize_run(&run_id, &runner, &1u32, &idle, &hash(&e, 2));
+    assert_eq!(receipt.actual_charge, 1_000_000);
+    assert_eq!(vault.balance_of(&user), 49_000_000);
+}
+
+#[test]
+fn open_run_requires_rate_card_in_balance_token() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+
+    let other_token = e
+        .register_stellar_asset_contract_v2(Address::generate(&e))
+        .address();
+    vault.set_balance_token(&user, &other_token);
+    assert_eq!(vault.balance_token_of(&user), Some(other_token));
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::TokenMismatch as u32
+        )))
+    );
+
+    let settlement_token = registry.settlement_token(&agent_id, &1);
+    assert_eq!(
+        vault.try_set_balance_token(&user, &settlement_token).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::TokenMismatch as u32
+        )))
+    );
+    vault.withdraw_all(&user);
+    vault.set_balance_token(&user, &settlement_token);
+    vault.deposit(&user, &50_000_000);
+    vault.open_run(
+        &user,
+        &user,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+}
+
+#[test]
+fn agent_stats_track_the_run_lifecycle() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+
+    let mut settled = 0;
+    for _ in 0..3 {
+        let run_id = vault
+            .open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None,
+            )
+            .run_id;
+        let receipt = vault.finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 2));
+        settled += receipt.actual_charge;
+    }
+    for _ in 0..2 {
+        let run_id = vault
+            .open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None,
+            )
+            .run_id;
+        vault.cancel_run(&user, &run_id);
+    }
+    vault.open_run(
+        &user,
+        &user,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+
+    let stats = vault.agent_stats(&agent_id);
+    assert_eq!(stats.runs_opened, 6);
+    assert_eq!(stats.runs_finalized, 3);
+    assert_eq!(stats.runs_cancelled, 2);
+    assert_eq!(stats.total_settled_volume, settled);
+    assert_eq!(vault.agent_stats(&99).runs_opened, 0);
+}
+
+#[test]
+fn user_spend_per_agent_counts_only_settled_charges() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    let other_agent = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let usage = UsageBreakdown {
+        llm_in: 40,
+        llm_out: 10,
+        http_calls: 0,
+        runtime_ms: 500,
+        extra: Map::new(&e),
+    };
+
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    let receipt = vault.finalize_run(&run_id, &runner, &1u32, &usage, &hash(&e, 2));
+    let cancelled = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    vault.cancel_run(&user, &cancelled);
+
+    assert_eq!(
+        vault.spend_on_agent(&user, &agent_id),
+        receipt.actual_charge
+    );
+    assert_eq!(
+        vault.list_agent_spend(&user, &Vec::from_array(&e, [other_agent, agent_id])),
+        Vec::from_array(&e, [0, receipt.actual_charge])
+    );
+}
+
+#[test]
+fn daily_headroom_reflects_reservations_and_day_rollover() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    assert_eq!(vault.daily_headroom(&user), i128::MAX);
+
+    vault.set_policy(&user, &user, &default_policy());
+    assert_eq!(vault.per_run_cap_of(&user), 50_000_000);
+    assert_eq!(vault.daily_headroom(&user), 100_000_000);
+
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    vault.open_run(
+        &user,
+        &user,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+    assert_eq!(vault.daily_headroom(&user), 100_000_000 - 12_001_000);
+
+    e.ledger().with_mut(|li| li.timestamp += 86_400);
+    assert_eq!(vault.daily_headroom(&user), 100_000_000);
+}
+
+#[test]
+fn get_policy_returns_defaults_then_stored_caps() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let fresh = vault.get_policy(&user);
+    assert_eq!(fresh.per_run_cap, 0);
+    assert_eq!(fresh.daily_cap, 0);
+    assert!(!fresh.paused);
+    assert_eq!(fresh.reserved_today, 0);
+
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.set_policy(&user, &user, &default_policy());
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    vault.open_run(
+        &user,
+        &user,
+        &agent_id,
+        &1u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+
+    let policy = vault.get_policy(&user);
+    assert_eq!(policy.per_run_cap, 50_000_000);
+    assert_eq!(policy.daily_cap, 100_000_000);
+    assert_eq!(policy.reserved_today, 12_001_000);
+}
+
+#[test]
+fn trusted_agents_bypass_caps_but_not_pause_or_balance() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.set_policy(
+        &user,
+        &user,
+        &PolicyInput {
+            per_run_cap: 1_000_000,
+            daily_cap: 1_000_000,
+            paused: false,
+            require_user_ack: false,
+            default_run_ttl_secs: 0,
+            max_runs_per_hour: 0,
+            caps_in_usd: false,
+            only_verified_agents: false,
+            cap_window_secs: 86_400,
+            cap_window_offset_secs: 0,
+            rolling_cap: 0,
+            force_close_after_secs: 3_600,
+        },
+    );
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::PerRunCapExceeded as u32
+        )))
+    );
+
+    vault.trust_agent(&user, &agent_id);
+    assert!(vault.is_trusted(&user, &agent_id));
+    assert_eq!(vault.list_trusted(&user), Vec::from_array(&e, [agent_id]));
+    let run_id = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    assert_eq!(vault.get_run(&run_id).reserved, 0);
+    assert_eq!(vault.get_policy(&user).reserved_today, 0);
+
+    let oversized = UsageBreakdown {
+        llm_in: 10_000,
+        ..budgets.clone()
+    };
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &oversized,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::InsufficientBalance as u32
+        )))
+    );
+
+    vault.set_policy(
+        &user,
+        &user,
+        &PolicyInput {
+            per_run_cap: 1_000_000,
+            daily_cap: 1_000_000,
+            paused: true,
+            require_user_ack: false,
+            default_run_ttl_secs: 0,
+            max_runs_per_hour: 0,
+            caps_in_usd: false,
+            only_verified_agents: false,
+            cap_window_secs: 86_400,
+            cap_window_offset_secs: 0,
+            rolling_cap: 0,
+            force_close_after_secs: 3_600,
+        },
+    );
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::PolicyPaused as u32
+        )))
+    );
+
+    vault.untrust_agent(&user, &agent_id);
+    assert!(!vault.is_trusted(&user, &agent_id));
+}
+
+#[test]
+fn vault_pause_blocks_new_activity_but_lets_runs_settle() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let admin = Address::generate(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&admin, &registry_addr);
+    assert_eq!(vault.admin(), admin);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let settling = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    let cancelling = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+
+    vault.pause_vault();
+    assert!(vault.is_vault_paused());
+    let paused = Some(Ok(soroban_sdk::Error::from_contract_error(
+        VaultError::VaultPaused as u32,
+    )));
+    assert_eq!(vault.try_deposit(&user, &1).err(), paused);
+    assert_eq!(vault.try_withdraw(&user, &1).err(), paused);
+    assert_eq!(
+        vault
+            .try_open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None
+            )
+            .err(),
+        paused
+    );
+
+    vault.finalize_run(&settling, &runner, &1u32, &budgets, &hash(&e, 2));
+    vault.cancel_run(&user, &cancelling);
+    assert_eq!(vault.try_claim_developer(&developer, &1).err(), paused);
+
+    vault.unpause_vault();
+    assert!(!vault.is_vault_paused());
+    vault.claim_developer(&developer, &12_001_000);
+}
+
+#[test]
+fn circuit_breaker_halts_settlement_until_override_or_next_day() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &50_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    vault.set_circuit_breaker_ceiling(&20_000_000);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+
+    let first = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    let second = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    let third = vault
+        .open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    vault.finalize_run(&first, &runner, &1u32, &budgets, &hash(&e, 2));
+    assert_eq!(vault.circuit_breaker().settled_today, 12_001_000);
+    assert_eq!(
+        vault
+            .try_finalize_run(&second, &runner, &1u32, &budgets, &hash(&e, 2))
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::CircuitBreakerTripped as u32
+        )))
+    );
+
+    vault.override_circuit_breaker();
+    vault.finalize_run(&second, &runner, &1u32, &budgets, &hash(&e, 2));
+
+    e.ledger().with_mut(|li| li.timestamp += 86_400);
+    let breaker = vault.circuit_breaker();
+    assert_eq!(breaker.settled_today, 0);
+    assert!(!breaker.overridden);
+    vault.finalize_run(&third, &runner, &1u32, &budgets, &hash(&e, 2));
+}
+
+#[test]
+fn vouchers_credit_each_user_once_and_refund_the_funder_after_expiry() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().with_mut(|li| li.timestamp = 1_000);
+    let (_, vault, registry_addr, _) = setup_clients(&e);
+    let funder = Address::generate(&e);
+    let first = Address::generate(&e);
+    let second = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    vault.deposit(&funder, &10_000_000);
+
+    let code = Bytes::from_slice(&e, b"HACK2026");
+    let code_hash: BytesN<32> = e.crypto().sha256(&code).into();
+    let voucher_error =
+        |error: VoucherError| Some(Ok(soroban_sdk::Error::from_contract_error(error as u32)));
+    assert_eq!(
+        vault
+            .try_create_voucher(&funder, &code_hash, &1_000_000, &0, &2_000)
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::InvalidAmount as u32
+        )))
+    );
+    assert_eq!(
+        vault
+            .try_create_voucher(&funder, &code_hash, &1_000_000, &2, &1_000)
+            .err(),
+        voucher_error(VoucherError::VoucherExpired)
+    );
+    vault.create_voucher(&funder, &code_hash, &1_000_000, &2, &2_000);
+    assert_eq!(vault.balance_of(&funder), 8_000_000);
+    assert_eq!(
+        vault
+            .try_create_voucher(&funder, &code_hash, &1_000_000, &2, &2_000)
+            .err(),
+        voucher_error(VoucherError::VoucherExists)
+    );
+
+    assert_eq!(vault.redeem_voucher(&first, &code), 1_000_000);
+    assert_eq!(vault.balance_of(&first), 1_000_000);
+    assert_eq!(
+        vault.try_redeem_voucher(&first, &code).err(),
+        voucher_error(VoucherError::AlreadyRedeemed)
+    );
+    assert_eq!(
+        vault
+            .try_redeem_voucher(&second, &Bytes::from_slice(&e, b"HACK2025"))
+            .err(),
+        voucher_error(VoucherError::VoucherNotFound)
+    );
+    assert_eq!(
+        vault.try_reclaim_voucher(&code_hash).err(),
+        voucher_error(VoucherError::VoucherNotExpired)
+    );
+
+    e.ledger().with_mut(|li| li.timestamp = 2_000);
+    assert_eq!(
+        vault.try_redeem_voucher(&second, &code).err(),
+        voucher_error(VoucherError::VoucherExpired)
+    );
+    assert_eq!(vault.reclaim_voucher(&code_hash), 1_000_000);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault.address.clone(),
+                (symbol_short!("voucher"), symbol_short!("reclaimed")).into_val(&e),
+                VoucherReclaimedLog {
+                    code_hash: code_hash.clone(),
+                    funder: funder.clone(),
+                    amount: 1_000_000,
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    assert_eq!(vault.balance_of(&funder), 9_000_000);
+    assert!(vault.get_voucher(&code_hash).is_none());
+
+    let single = Bytes::from_slice(&e, b"ONCE");
+    let single_hash: BytesN<32> = e.crypto().sha256(&single).into();
+    vault.create_voucher(&funder, &single_hash, &500_000, &1, &3_000);
+    vault.redeem_voucher(&second, &single);
+    assert_eq!(vault.get_voucher(&single_hash).unwrap().redeemed, 1);
+    assert_eq!(
+        vault.try_redeem_voucher(&first, &single).err(),
+        voucher_error(VoucherError::VoucherExhausted)
+    );
+
+    // A voucher only credits balances held in the token it was funded in.
+    let usdc = Address::generate(&e);
+    vault.set_balance_token(&funder, &usdc);
+    let priced = Bytes::from_slice(&e, b"USDC");
+    let priced_hash: BytesN<32> = e.crypto().sha256(&priced).into();
+    vault.create_voucher(&funder, &priced_hash, &100_000, &2, &3_000);
+    assert_eq!(
+        vault.get_voucher(&priced_hash).unwrap().token,
+        Some(usdc.clone())
+    );
+    let token_mismatch = Some(Ok(soroban_sdk::Error::from_contract_error(
+        VaultError::TokenMismatch as u32,
+    )));
+    assert_eq!(
+        vault.try_redeem_voucher(&first, &priced).err(),
+        token_mismatch
+    );
+    vault.set_balance_token(&second, &Address::generate(&e));
+    assert_eq!(
+        vault.try_redeem_voucher(&second, &priced).err(),
+        token_mismatch
+    );
+    vault.set_balance_token(&first, &usdc);
+    assert_eq!(vault.redeem_voucher(&first, &priced), 100_000);
+}
+
+#[test]
+fn cap_windows_follow_the_configured_length_and_offset() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().with_mut(|li| li.timestamp = 1_000);
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &100_000_000);
+    assert_eq!(vault.get_policy(&user).cap_window_secs, 86_400);
+
+    let invalid_amount = Some(Ok(soroban_sdk::Error::from_contract_error(
+        VaultError::InvalidAmount as u32,
+    )));
+    for (cap_window_secs, cap_window_offset_secs) in [(0u64, 0u64), (43_200, 43_200)] {
+        let policy = PolicyInput {
+            cap_window_secs,
+            cap_window_offset_secs,
+            ..default_policy()
+        };
+        assert_eq!(
+            vault.try_set_policy(&user, &user, &policy).err(),
+            invalid_amount
+        );
+    }
+
+    // Twelve-hour windows starting an hour before each multiple of 43_200.
+    let half_days = PolicyInput {
+        daily_cap: 20_000_000,
+        cap_window_secs: 43_200,
+        cap_window_offset_secs: 3_600,
+        ..default_policy()
+    };
+    vault.set_policy(&user, &user, &half_days);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let open = || {
+        vault.try_open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+    };
+    let cap_exceeded = Some(Ok(soroban_sdk::Error::from_contract_error(
+        VaultError::DailyCapExceeded as u32,
+    )));
+    assert!(open().is_ok());
+    assert_eq!(open().err(), cap_exceeded);
+
+    e.ledger().with_mut(|li| li.timestamp = 39_599);
+    assert_eq!(open().err(), cap_exceeded);
+    e.ledger().with_mut(|li| li.timestamp = 39_600);
+    assert!(open().is_ok());
+    let policy = vault.get_policy(&user);
+    assert_eq!(policy.reserved_day, 1);
+    assert_eq!(policy.reserved_today, 12_001_000);
+
+    // Switching back to UTC days mid-window starts the new window from zero.
+    vault.set_policy(
+        &user,
+        &user,
+        &PolicyInput {
+            daily_cap: 20_000_000,
+            ..default_policy()
+        },
+    );
+    let policy = vault.get_policy(&user);
+    assert_eq!(policy.reserved_day, 0);
+    assert_eq!(policy.reserved_today, 0);
+    assert!(open().is_ok());
+    assert_eq!(open().err(), cap_exceeded);
+    e.ledger().with_mut(|li| li.timestamp = 86_400);
+    assert!(open().is_ok());
+}
+
+#[test]
+fn rolling_cap_counts_spend_over_the_trailing_thirty_days() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().with_mut(|li| li.timestamp = 86_000);
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &100_000_000);
+    vault.set_policy(
+        &user,
+        &user,
+        &PolicyInput {
+            daily_cap: 20_000_000,
+            rolling_cap: 20_000_000,
+            ..default_policy()
+        },
+    );
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let open = || {
+        vault.try_open_run(
+            &user,
+            &user,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+    };
+    let rolling_exceeded = Some(Ok(soroban_sdk::Error::from_contract_error(
+        PolicyError::RollingCapExceeded as u32,
+    )));
+
+    let run_id = open().unwrap().unwrap().run_id;
+    let usage = UsageBreakdown {
+        llm_in: 80,
+        llm_out: 40,
+        http_calls: 1,
+        runtime_ms: 500,
+        extra: Map::new(&e),
+    };
+    vault.finalize_run(&run_id, &runner, &1u32, &usage, &hash(&e, 2));
+    assert_eq!(vault.rolling_spend(&user), 11_600_500);
+
+    // The daily window rolled over, but the spend from a few minutes ago still counts.
+    e.ledger().with_mut(|li| li.timestamp = 86_400);
+    assert_eq!(vault.get_policy(&user).reserved_today, 0);
+    assert_eq!(open().err(), rolling_exceeded);
+
+    // Day 0 leaves the window once day 30 starts.
+    e.ledger().with_mut(|li| li.timestamp = 30 * 86_400 - 1);
+    assert_eq!(vault.rolling_spend(&user), 11_600_500);
+    assert_eq!(open().err(), rolling_exceeded);
+    e.ledger().with_mut(|li| li.timestamp = 30 * 86_400);
+    assert_eq!(vault.rolling_spend(&user), 0);
+    let run_id = open().unwrap().unwrap().run_id;
+    assert_eq!(vault.rolling_spend(&user), 12_001_000);
+
+    // Cancelling hands the whole reservation back.
+    vault.cancel_run(&user, &run_id);
+    assert_eq!(vault.rolling_spend(&user), 0);
+
+    let negative = PolicyInput {
+        rolling_cap: -1,
+        ..default_policy()
+    };
+    assert_eq!(
+        vault.try_set_policy(&user, &user, &negative).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::InvalidAmount as u32
+        )))
+    );
+}
+
+#[test]
+fn priority_fee_goes_to_the_settling_runner() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, vault_addr) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &30_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let open = |priority_fee: i128| {
+        vault.try_open_run_with_priority_fee(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &priority_fee,
+            &None,
+        )
+    };
+    assert_eq!(
+        open(-1).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::InvalidAmount as u32
+        )))
+    );
+
+    let opened = open(500_000).unwrap().unwrap();
+    assert_eq!(opened.max_charge, 12_001_000);
+    assert_eq!(opened.escrowed, 12_501_000);
+    assert_eq!(vault.balance_of(&user), 17_499_000);
+
+    let usage = UsageBreakdown {
+        llm_in: 80,
+        llm_out: 40,
+        http_calls: 1,
+        runtime_ms: 500,
+        extra: Map::new(&e),
+    };
+    let receipt = vault.finalize_run(&opened.run_id, &runner, &1u32, &usage, &hash(&e, 2));
+    assert_eq!(receipt.actual_charge, 11_600_500);
+    assert_eq!(receipt.refund, 400_500);
+    assert_eq!(receipt.priority_fee, 500_000);
+    assert_eq!(vault.developer_balance(&developer), 11_600_500);
+    assert_eq!(vault.runner_balance(&runner), 500_000);
+    assert_eq!(vault.balance_of(&user), 17_899_500);
+
+    // A cancelled run hands the fee back with the rest of the escrow.
+    let opened = open(250_000).unwrap().unwrap();
+    vault.cancel_run(&user, &opened.run_id);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault_addr.clone(),
+                (symbol_short!("run"), symbol_short!("cancelled")).into_val(&e),
+                RunClosedLog {
+                    run_id: opened.run_id,
+                    agent_id,
+                    user: user.clone(),
+                    sponsor: None,
+                    refunded: 12_001_000,
+                    priority_fee: 250_000,
+                    closed_at: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    assert_eq!(vault.balance_of(&user), 17_899_500);
+    assert_eq!(vault.runner_balance(&runner), 500_000);
+
+    vault.claim_runner(&runner, &500_000);
+    assert_eq!(vault.runner_balance(&runner), 0);
+    assert_eq!(
+        vault.try_claim_runner(&runner, &1).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::InsufficientBalance as u32
+        )))
+    );
+}
+
+#[test]
+fn pause_levels_block_new_activity_and_withdrawals_separately() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, vault_addr) = setup_clients(&e);
+    let admin = Address::generate(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+    let other = Address::generate(&e);
+
+    vault.init(&admin, &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &100_000_000);
+    let budgets = UsageBreakdown {
+        llm_in: 10,
+        llm_out: 10,
+        http_calls: 1,
+        runtime_ms: 100,
+        extra: Map::new(&e),
+    };
+    vault.save_budget_preset(&user, &symbol_short!("small"), &budgets);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let open = || {
+        vault
+            .open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None,
+            )
+            .run_id
+    };
+    let runs = [open(), open(), open()];
+    assert_eq!(vault.pause_level(), PauseLevel::Unpaused);
+
+    let paused = Some(Ok(soroban_sdk::Error::from_contract_error(
+        VaultError::VaultPaused as u32,
+    )));
+    let assert_new_activity_blocked = || {
+        assert_eq!(vault.try_deposit(&user, &1).err(), paused);
+        assert_eq!(vault.try_deposit_for(&other, &user, &1).err(), paused);
+        assert_eq!(
+            vault.try_deposit_from_allowance(&other, &user, &1).err(),
+            paused
+        );
+        assert_eq!(vault.try_migrate_balance(&user).err(), paused);
+        assert_eq!(
+            vault
+                .try_open_run(
+                    &user,
+                    &user,
+                    &agent_id,
+                    &1u32,
+                    &budgets,
+                    &hash(&e, 1),
+                    &None
+                )
+                .err(),
+            paused
+        );
+        assert_eq!(
+            vault
+                .try_open_run_with_ttl(
+                    &user,
+                    &user,
+                    &agent_id,
+                    &1u32,
+                    &budgets,
+                    &hash(&e, 1),
+                    &Some(60),
+                    &None
+                )
+                .err(),
+            paused
+        );
+        assert_eq!(
+            vault
+                .try_open_run_with_priority_fee(
+                    &user,
+                    &user,
+                    &agent_id,
+                    &1u32,
+                    &budgets,
+                    &hash(&e, 1),
+                    &1,
+                    &None
+                )
+                .err(),
+            paused
+        );
+        assert_eq!(
+            vault
+                .try_open_run_with_preset(
+                    &user,
+                    &user,
+                    &agent_id,
+                    &1u32,
+                    &symbol_short!("small"),
+                    &hash(&e, 1),
+                    &None
+                )
+                .err(),
+            paused
+        );
+        assert_eq!(
+            vault
+                .try_open_sponsored_run(
+                    &user,
+                    &other,
+                    &other,
+                    &agent_id,
+                    &1u32,
+                    &budgets,
+                    &hash(&e, 1),
+                    &None
+                )
+                .err(),
+            paused
+        );
+        assert_eq!(
+            vault.try_open_subscription(&user, &agent_id, &1u32).err(),
+            paused
+        );
+        assert_eq!(vault.try_tip_run(&user, &runs[0], &1).err(), paused);
+        assert_eq!(
+            vault
+                .try_create_voucher(&user, &hash(&e, 3), &1, &1, &1_000)
+                .err(),
+            paused
+        );
+        assert_eq!(
+            vault
+                .try_redeem_voucher(&other, &Bytes::from_slice(&e, b"code"))
+                .err(),
+            paused
+        );
+        assert_eq!(vault.try_claim_developer(&developer, &1).err(), paused);
+        assert_eq!(
+            vault.try_claim_developer_to(&developer, &other, &1).err(),
+            paused
+        );
+        assert_eq!(vault.try_claim_developer_all(&developer).err(), paused);
+        assert_eq!(vault.try_claim_referrer(&other, &1).err(), paused);
+        assert_eq!(vault.try_claim_runner(&runner, &1).err(), paused);
+    };
+
+    vault.pause_new_activity();
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault_addr.clone(),
+                (symbol_short!("vault"), symbol_short!("paused")).into_val(&e),
+                VaultPauseLog {
+                    admin: admin.clone(),
+                    level: PauseLevel::NewActivity,
+                    timestamp: e.ledger().timestamp(),
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    assert!(vault.is_vault_paused());
+    assert_new_activity_blocked();
+    vault.withdraw(&user, &1);
+    vault.request_withdrawal(&user, &1);
+    assert_eq!(vault.execute_withdrawal(&user), 1);
+    vault.cancel_run(&user, &runs[0]);
+    vault.finalize_run(&runs[1], &runner, &1u32, &budgets, &hash(&e, 2));
+
+    vault.pause_all();
+    assert_eq!(vault.pause_level(), PauseLevel::All);
+    assert_new_activity_blocked();
+    assert_eq!(vault.try_withdraw(&user, &1).err(), paused);
+    assert_eq!(vault.try_withdraw_all(&user).err(), paused);
+    assert_eq!(vault.try_request_withdrawal(&user, &1).err(), paused);
+    assert_eq!(vault.try_execute_withdrawal(&user).err(), paused);
+    vault.cancel_run(&user, &runs[2]);
+
+    vault.unpause_vault();
+    assert_eq!(vault.pause_level(), PauseLevel::Unpaused);
+    assert!(vault.withdraw_all(&user) > 0);
+}
+
+#[test]
+fn user_force_closes_a_stuck_run_after_the_wait() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().with_mut(|li| li.timestamp = 1_000);
+    let (registry, vault, registry_addr, vault_addr) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &20_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    assert_eq!(
+        vault.get_policy(&user).force_close_after_secs,
+        MIN_FORCE_CLOSE_SECS
+    );
+    let too_short = PolicyInput {
+        force_close_after_secs: MIN_FORCE_CLOSE_SECS - 1,
+        ..default_policy()
+    };
+    assert_eq!(
+        vault.try_set_policy(&user, &user, &too_short).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::InvalidAmount as u32
+        )))
+    );
+    vault.set_policy(
+        &user,
+        &user,
+        &PolicyInput {
+            force_close_after_secs: 7_200,
+            ..default_policy()
+        },
+    );
+
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    assert_eq!(
+        vault.try_force_close_run(&runner, &run_id).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::Unauthorized as u32
+        )))
+    );
+    e.ledger().with_mut(|li| li.timestamp = 8_200);
+    assert_eq!(
+        vault.try_force_close_run(&user, &run_id).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RunNotExpired as u32
+        )))
+    );
+
+    e.ledger().with_mut(|li| li.timestamp = 8_201);
+    vault.force_close_run(&user, &run_id);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault_addr.clone(),
+                (symbol_short!("run"), symbol_short!("forced")).into_val(&e),
+                RunClosedLog {
+                    run_id,
+                    agent_id,
+                    user: user.clone(),
+                    sponsor: None,
+                    refunded: 12_001_000,
+                    priority_fee: 0,
+                    closed_at: 8_201,
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    assert_eq!(vault.balance_of(&user), 20_000_000);
+    assert!(vault
+        .get_run(&run_id)
+        .lifecycle
+        .is_kind(LifecycleKind::ForceClosed));
+    assert_eq!(
+        vault
+            .try_finalize_run(&run_id, &runner, &1u32, &budgets, &hash(&e, 2))
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::RunNotOpen as u32
+        )))
+    );
+}
+
+#[test]
+fn failed_runs_settle_at_a_discount_without_output() {
+    let e = Env::default();
+    e.mock_all_auths();
+    e.ledger().with_mut(|li| li.timestamp = 42);
+    let (registry, vault, registry_addr, vault_addr) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    let update = |failure_discount_bps: u32| ConfigUpdate {
+        admin: None,
+        registry: None,
+        token: None,
+        fee_bps: None,
+        treasury: None,
+        agent_event_topics: None,
+        failure_discount_bps: Some(failure_discount_bps),
+    };
+    assert_eq!(
+        vault.try_update_config(&update(10_001)).err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::InvalidAmount as u32
+        )))
+    );
+    vault.update_config(&update(2_500));
+    assert_eq!(vault.get_config().failure_discount_bps, 2_500);
+
+    vault.deposit(&user, &20_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let run_id = vault
+        .open_run(
+            &user,
+            &runner,
+            &agent_id,
+            &1u32,
+            &budgets,
+            &hash(&e, 1),
+            &None,
+        )
+        .run_id;
+    let usage = UsageBreakdown {
+        llm_in: 80,
+        llm_out: 40,
+        http_calls: 1,
+        runtime_ms: 500,
+        extra: Map::new(&e),
+    };
+    let receipt = vault.finalize_failed(&run_id, &runner, &usage, &7);
+    let zero_hash = BytesN::from_array(&e, &[0; 32]);
+    // 11_600_500 metered, less 25%.
+    assert_eq!(receipt.actual_charge, 8_700_375);
+    assert_eq!(receipt.refund, 3_300_625);
+    assert_eq!(receipt.failure_code, Some(7));
+    assert_eq!(receipt.output_hash, zero_hash);
+    assert_eq!(
+        e.events().all(),
+        vec![
+            &e,
+            (
+                vault_addr.clone(),
+                (symbol_short!("run"), symbol_short!("finalized"), agent_id).into_val(&e),
+                RunFinalizedLog {
+                    run_id,
+                    agent_id,
+                    user: user.clone(),
+                    sponsor: None,
+                    developer: developer.clone(),
+                    runner: runner.clone(),
+                    input_hash: hash(&e, 1),
+                    actual_charge: 8_700_375,
+                    refund: 3_300_625,
+                    priority_fee: 0,
+                    usage: usage.clone(),
+                    charges: ChargeBreakdown {
+                        base_fee: 0,
+                        llm_in: 800_000,
+                        llm_out: 800_000,
+                        http_calls: 10_000_000,
+                        runtime_ms: 500,
+                        extra: 0,
+                        discount: 2_900_125,
+                        ..ChargeBreakdown::default()
+                    },
+                    output_hash: zero_hash.clone(),
+                    failure_code: Some(7),
+                    finalized_at: 42,
+                }
+                .into_val(&e),
+            ),
+        ]
+    );
+    assert_eq!(vault.developer_balance(&developer), 8_700_375);
+    assert_eq!(vault.balance_of(&user), 11_299_625);
+
+    let record = vault.get_run(&run_id);
+    assert!(record.lifecycle.is_kind(LifecycleKind::Failed));
+    assert!(!record.lifecycle.is_kind(LifecycleKind::Finalized));
+    match record.lifecycle {
+        RunLifecycle::Failed(failed) => {
+            assert_eq!(failed.failure_code, 7);
+            assert_eq!(failed.discount, 2_900_125);
+            assert_eq!(failed.settlement.actual_charge, 8_700_375);
+        }
+        _ => panic!("run should be settled as failed"),
+    }
+    assert!(vault.runs_by_output_hash(&zero_hash, &0, &10).is_empty());
+
+    // Failed settlements can still be tipped and refunded like any other.
+    vault.tip_run(&user, &run_id, &100);
+    vault.refund_user(&developer, &run_id, &8_700_375);
+    assert_eq!(vault.balance_of(&user), 19_999_900);
+    assert_eq!(vault.developer_balance(&developer), 100);
+}
+
+#[test]
+fn settlement_charges_add_up_to_the_actual_charge() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, vault_addr) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let rate = RateCardInput {
+        unit_scale: 7,
+        ..sample_rate_card(&e)
+    };
+    let agent_id = registry.register_agent(&developer, &None, &vec![&e, runner.clone()], &rate);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let usage = UsageBreakdown {
+        llm_in: 80,
+        llm_out: 40,
+        http_calls: 1,
+        runtime_ms: 500,
+        extra: Map::new(&e),
+    };
+    let receipt = run_full_lifecycle(&e, &vault, &user, &runner, agent_id, &budgets, &usage);
+    // 11_600_500 metered over a scale of 7 rounds up to 1_657_215; the meters alone
+    // round down to 3 less.
+    assert_eq!(receipt.actual_charge, 1_657_215);
+    let current = vault.get_run(&receipt.run_id);
+    let settlement = match current.lifecycle {
+        RunLifecycle::Finalized(settlement) => settlement,
+        _ => panic!("run should be finalized"),
+    };
+    assert_eq!(
+        settlement.charges,
+        ChargeBreakdown {
+            base_fee: 0,
+            llm_in: 114_285,
+            llm_out: 114_285,
+            http_calls: 1_428_571,
+            runtime_ms: 71,
+            extra: 0,
+            adjustment: 3,
+            ..ChargeBreakdown::default()
+        }
+    );
+    assert_eq!(settlement.charges.total(), settlement.actual_charge);
+
+    // A settlement written before breakdowns existed reports its whole charge as
+    // adjustment, credited to the agent's developer.
+    e.as_contract(&vault_addr, || {
+        let old_run = RunRecordV1 {
+            user: current.user,
+            opened_by: current.opened_by,
+            agent_id: current.agent_id,
+            rate_version: current.rate_version,
+            budgets: UsageBreakdownV1 {
+                llm_in: 100,
+                llm_out: 50,
+                http_calls: 1,
+                runtime_ms: 1000,
+            },
+            max_charge: current.max_charge,
+            escrowed: 0,
+            opened_at: current.opened_at,
+            lifecycle: RunLifecycleV1::Finalized(RunSettlementV1 {
+                usage: UsageBreakdownV1 {
+                    llm_in: 80,
+                    llm_out: 40,
+                    http_calls: 1,
+                    runtime_ms: 500,
+                },
+                actual_charge: settlement.actual_charge,
+                refund: settlement.refund,
+                output_hash: settlement.output_hash,
+            }),
+        };
+        e.storage()
+            .persistent()
+            .remove(&DataKey::Run(receipt.run_id));
+        e.storage()
+            .instance()
+            .set(&DataKey::Run(receipt.run_id), &old_run);
+    });
+    match vault.get_run(&receipt.run_id).lifecycle {
+        RunLifecycle::Finalized(settlement) => {
+            assert_eq!(settlement.developer, developer);
+            assert_eq!(
+                settlement.charges,
+                ChargeBreakdown {
+                    adjustment: 1_657_215,
+                    ..ChargeBreakdown::default()
+                }
+            );
+        }
+        _ => panic!("run should be finalized"),
+    }
+}
+
+#[test]
+fn compute_charge_views_match_settlement_pricing() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    let usage = UsageBreakdown {
+        llm_in: 80,
+        llm_out: 40,
+        http_calls: 1,
+        runtime_ms: 500,
+        extra: Map::new(&e),
+    };
+    assert_eq!(
+        vault.compute_charge_view(&sample_rates(&e), &usage),
+        11_600_500
+    );
+    assert_eq!(
+        vault.compute_charge_for_agent(&agent_id, &0, &usage),
+        11_600_500
+    );
+    assert_eq!(
+        vault
+            .try_compute_charge_for_agent(&(agent_id + 1), &1, &usage)
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::AgentNotFound as u32
+        )))
+    );
+
+    let huge = UsageBreakdown {
+        llm_in: i128::MAX,
+        ..usage.clone()
+    };
+    assert_eq!(
+        vault
+            .try_compute_charge_view(&sample_rates(&e), &huge)
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::ChargeOverflow as u32
+        )))
+    );
+    assert_eq!(
+        vault
+            .try_compute_charge_for_agent(&agent_id, &1, &huge)
+            .err(),
+        Some(Ok(soroban_sdk::Error::from_contract_error(
+            VaultError::ChargeOverflow as u32
+        )))
+    );
+}
+
+#[test]
+fn runs_settle_at_the_rate_card_snapshotted_at_open() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &20_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let opened = vault.open_run(
+        &user,
+        &runner,
+        &agent_id,
+        &0u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+    let RunPricing::Snapshot(snapshot) = vault.get_run(&opened.run_id).pricing else {
+        panic!("run should snapshot its rate card");
+    };
+    assert_eq!(snapshot.rates.llm_in, sample_rates(&e).llm_in);
+    assert_eq!(snapshot.manifest_version, 1);
+
+    let repriced = RateCardInput {
+        rates: UsageMeterRates {
+            llm_in: 1,
+            llm_out: 1,
+            http_calls: 1,
+            runtime_ms: 1,
+            extra: Map::new(&e),
+        },
+        manifest_hash: hash(&e, 2),
+        base_fee: 5_000,
+        ..sample_rate_card(&e)
+    };
+    registry.publish_rate_card(&agent_id, &repriced);
+    assert_eq!(registry.latest_rate_version(&agent_id), 2);
+
+    let usage = UsageBreakdown {
+        llm_in: 80,
+        llm_out: 40,
+        http_calls: 1,
+        runtime_ms: 500,
+        extra: Map::new(&e),
+    };
+    let receipt = vault.finalize_run(
+        &opened.run_id,
+        &runner,
+        &opened.rate_version,
+        &usage,
+        &hash(&e, 2),
+    );
+    assert_eq!(receipt.rate_version, 1);
+    assert_eq!(receipt.actual_charge, 11_600_500);
+    assert_eq!(vault.developer_balance(&developer), 11_600_500);
+}
+
+#[test]
+fn finalize_reads_the_registry_in_one_combined_call() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &20_000_000);
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let opened = vault.open_run(
+        &user,
+        &runner,
+        &agent_id,
+        &0u32,
+        &budgets,
+        &hash(&e, 1),
+        &None,
+    );
+
+    let receipt = vault.finalize_run(
+        &opened.run_id,
+        &runner,
+        &opened.rate_version,
+        &budgets,
+        &hash(&e, 2),
+    );
+    // finalize_run measured 1_232_453 CPU instructions on this scenario while it still
+    // made four separate registry calls, and 1_203_102 with the combined call. Keeping
+    // balances in persistent storage adds about 104_000 to either, so the four-call cost
+    // is now about 1_336_628. The budget resets before every top-level call, so this is
+    // the cost of the finalize alone.
+    assert!(e.cost_estimate().budget().cpu_instruction_cost() < 1_336_628);
+    assert_eq!(receipt.actual_charge, 12_001_000);
+}
+
+#[test]
+fn account_state_round_trips_through_open_finalize_and_cancel() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let (registry, vault, registry_addr, _) = setup_clients(&e);
+    let developer = Address::generate(&e);
+    let runner = Address::generate(&e);
+    let user = Address::generate(&e);
+
+    vault.init(&Address::generate(&e), &registry_addr);
+    let agent_id = setup_agent(&e, &registry, &developer, &runner);
+    vault.deposit(&user, &30_000_000);
+    vault.set_policy(
+        &user,
+        &user,
+        &PolicyInput {
+            max_runs_per_hour: 5,
+            ..default_policy()
+        },
+    );
+    vault.grant_runner(&user, &runner, &agent_id, &None);
+    let budgets = UsageBreakdown {
+        llm_in: 100,
+        llm_out: 50,
+        http_calls: 1,
+        runtime_ms: 1000,
+        extra: Map::new(&e),
+    };
+    let open = || {
+        vault
+            .open_run(
+                &user,
+                &user,
+                &agent_id,
+                &1u32,
+                &budgets,
+                &hash(&e, 1),
+                &None,
+            )
+            .run_id
+    };
+
+    let capped_run = open();
+    assert_eq!(vault.balance_of(&user), 17_999_000);
+    assert_eq!(vault.get_policy(&user).reserved_today, 12_001_000);
+
+    // Trusted agents skip the caps but still count against the hourly limit.
+    vault.trust_agent(&user, &agent_id);
+    let trusted_run = open();
+    assert_eq!(vault.balance_of(&user), 5_998_000);
+    let policy = vault.get_policy(&user);
+    assert_eq!(policy.reserved_today, 12_001_000);
+    assert_eq!(policy.runs_this_hour, 2);
+
+    let usage = UsageBreakdown {
+        llm_in: 80,
+        llm_out: 40,
+        http_calls: 1,
+        runtime_ms: 500,
+        extra: Map::new(&e),
+    };
+    vault.finalize_run(&capped_run, &runner, &1u32, &usage, &hash(&e, 2));
+    assert_eq!(vault.balance_of(&user), 6_398_500);
+    assert_eq!(vault.get_policy(&user).reserved_today, 0);
+
+    vault.cancel_run(&user, &trusted_run);
+    assert_eq!(vault.balance_of(&user), 18_399_500);
+    assert_eq!(vault.get_policy(&user).reserved_today, 0);
 }
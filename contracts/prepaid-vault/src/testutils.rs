@@ -0,0 +1,115 @@
+//! Fixtures for integration tests against the vault and the agent registry.
+//!
+//! Helpers that submit transactions expect the caller to have set up auth, typically
+//! with `Env::mock_all_auths`.
+
+use agent_registry::{
+    AgentRegistry, AgentRegistryClient, RateCardInput, SubscriptionOffer, UsageMeterRates,
+};
+use soroban_sdk::{testutils::Address as _, Address, BytesN, Env, Map, Vec};
+
+use crate::{PolicyInput, PrepaidVault, PrepaidVaultClient, RunReceipt, UsageBreakdown};
+
+/// Registers a fresh registry and vault, returning their clients and addresses as
+/// `(registry, vault, registry_addr, vault_addr)`. The vault is not initialized.
+pub fn setup_clients<'a>(
+    e: &'a Env,
+) -> (
+    AgentRegistryClient<'a>,
+    PrepaidVaultClient<'a>,
+    Address,
+    Address,
+) {
+    let registry_addr = e.register(AgentRegistry, ());
+    let vault_addr = e.register(PrepaidVault, ());
+    let registry_client = AgentRegistryClient::new(e, &registry_addr);
+    let vault_client = PrepaidVaultClient::new(e, &vault_addr);
+    (registry_client, vault_client, registry_addr, vault_addr)
+}
+
+/// A 32-byte hash with every byte set to `byte`.
+pub fn hash(env: &Env, byte: u8) -> BytesN<32> {
+    BytesN::from_array(env, &[byte; 32])
+}
+
+pub fn sample_rates(e: &Env) -> UsageMeterRates {
+    UsageMeterRates {
+        llm_in: 10_000,
+        llm_out: 20_000,
+        http_calls: 10_000_000,
+        runtime_ms: 1,
+        extra: Map::new(e),
+    }
+}
+
+/// A rate card priced with `sample_rates`, effective immediately, in a fresh asset.
+pub fn sample_rate_card(e: &Env) -> RateCardInput {
+    RateCardInput {
+        rates: sample_rates(e),
+        unit_scale: 1,
+        manifest_hash: hash(e, 1),
+        subscription: SubscriptionOffer::NotOffered,
+        effective_from: None,
+        expires_at: None,
+        free_daily_units: Map::new(e),
+        min_charge: 0,
+        base_fee: 0,
+        token: e
+            .register_stellar_asset_contract_v2(Address::generate(e))
+            .address(),
+    }
+}
+
+pub fn default_policy() -> PolicyInput {
+    PolicyInput {
+        per_run_cap: 50_000_000,
+        daily_cap: 100_000_000,
+        paused: false,
+        require_user_ack: false,
+        default_run_ttl_secs: 0,
+        max_runs_per_hour: 0,
+        caps_in_usd: false,
+        only_verified_agents: false,
+        cap_window_secs: 86_400,
+        cap_window_offset_secs: 0,
+        rolling_cap: 0,
+        force_close_after_secs: 3_600,
+    }
+}
+
+/// Registers an agent owned by `developer` with `runner` as its only runner, priced
+/// with `sample_rate_card`.
+pub fn setup_agent(
+    e: &Env,
+    registry: &AgentRegistryClient<'_>,
+    developer: &Address,
+    runner: &Address,
+) -> u32 {
+    let mut runners = Vec::new(e);
+    runners.push_back(runner.clone());
+    let rate = sample_rate_card(e);
+    registry.register_agent(developer, &None, &runners, &rate)
+}
+
+/// Funds `user` with exactly the escrow `budgets` needs, grants `runner`, then opens
+/// and finalizes a run reporting `usage` at the agent's latest rate version.
+pub fn run_full_lifecycle(
+    e: &Env,
+    vault: &PrepaidVaultClient<'_>,
+    user: &Address,
+    runner: &Address,
+    agent_id: u32,
+    budgets: &UsageBreakdown,
+    usage: &UsageBreakdown,
+) -> RunReceipt {
+    vault.deposit(user, &vault.quote_run(&agent_id, &0, budgets));
+    vault.grant_runner(user, runner, &agent_id, &None);
+    let opened = vault.open_run(user, runner, &agent_id, &0, budgets, &hash(e, 1), &None);
+    vault.finalize_run(
+        &opened.run_id,
+        runner,
+        &opened.rate_version,
+        usage,
+        &hash(e, 2),
+    )
+}
@@ -1,51 +1,51 @@
-use agent_registry::UsageMeterRates;
-use soroban_sdk::{contracterror, contracttype, Address, BytesN};
+use soroban_sdk::{contracterror, contracttype, Address, BytesN, Map, String, Symbol, Vec};
 
-#[derive(Clone)]
-#[contracttype]
-pub struct UsageBreakdown {
-    pub llm_in: i128,
-    pub llm_out: i128,
-    pub http_calls: i128,
-    pub runtime_ms: i128,
-}
+pub use lumio_types::{ChargeBreakdown, UsageBreakdown, UsageMeterRates};
 
-impl From<UsageMeterRates> for UsageBreakdown {
-    fn from(value: UsageMeterRates) -> Self {
-        Self {
-            llm_in: value.llm_in,
-            llm_out: value.llm_out,
-            http_calls: value.http_calls,
-            runtime_ms: value.runtime_ms,
-        }
-    }
-}
+/// Default cap window length.
+const DAY_SECS: u64 = 86_400;
 
-impl From<UsageBreakdown> for UsageMeterRates {
-    fn from(value: UsageBreakdown) -> Self {
-        UsageMeterRates {
-            llm_in: value.llm_in,
-            llm_out: value.llm_out,
-            http_calls: value.http_calls,
-            runtime_ms: value.runtime_ms,
-        }
-    }
-}
-
-impl UsageBreakdown {
-    pub fn to_usage_meter_rates(&self) -> UsageMeterRates {
-        UsageMeterRates::from(self.clone())
-    }
-}
+/// Shortest wait before a user may `force_close_run`, so runners still working on a
+/// run are not cut off.
+pub const MIN_FORCE_CLOSE_SECS: u64 = 3_600;
 
 #[derive(Clone)]
 #[contracttype]
 pub struct UserPolicy {
     pub per_run_cap: i128,
+    /// Cap on what may be reserved per cap window, one day unless configured otherwise.
     pub daily_cap: i128,
     pub paused: bool,
     pub reserved_today: i128,
+    /// Index of the cap window `reserved_today` belongs to.
     pub reserved_day: u64,
+    /// Runs must be settled through `propose_settlement` and the user's
+    /// `accept_settlement` instead of a runner-only `finalize_run`. Runs keep the setting
+    /// they were opened under.
+    pub require_user_ack: bool,
+    /// Longest a run against this balance may stay open, in seconds. Zero means no limit.
+    pub default_run_ttl_secs: u64,
+    /// Most runs that may be opened in one clock hour, cancelled ones included. Zero
+    /// means no limit.
+    pub max_runs_per_hour: u32,
+    pub runs_this_hour: u32,
+    pub run_hour: u64,
+    /// The per-run and daily caps, and today's reservation, are in USD with
+    /// `USD_DECIMALS` decimals, converted through the price oracle when runs open.
+    pub caps_in_usd: bool,
+    /// Runs may only be opened against agents carrying the registry's verified badge.
+    pub only_verified_agents: bool,
+    /// Length of the window `daily_cap` applies to, in seconds.
+    pub cap_window_secs: u64,
+    /// Shifts window boundaries, e.g. to midnight in the user's timezone. Windows start
+    /// where `timestamp + cap_window_offset_secs` is a multiple of `cap_window_secs`.
+    pub cap_window_offset_secs: u64,
+    /// Cap on spend over the trailing `ROLLING_WINDOW_DAYS` UTC days, in the same unit
+    /// as the other caps. Zero leaves the rolling window off.
+    pub rolling_cap: i128,
+    /// How long after opening an unsettled run the user may `force_close_run` it. Never
+    /// below `MIN_FORCE_CLOSE_SECS`.
+    pub force_close_after_secs: u64,
 }
 
 impl Default for UserPolicy {
@@ -56,17 +56,59 @@ impl Default for UserPolicy {
             paused: false,
             reserved_today: 0,
             reserved_day: 0,
+            require_user_ack: false,
+            default_run_ttl_secs: 0,
+            max_runs_per_hour: 0,
+            runs_this_hour: 0,
+            run_hour: 0,
+            caps_in_usd: false,
+            only_verified_agents: false,
+            cap_window_secs: DAY_SECS,
+            cap_window_offset_secs: 0,
+            rolling_cap: 0,
+            force_close_after_secs: MIN_FORCE_CLOSE_SECS,
         }
     }
 }
 
 impl UserPolicy {
-    pub fn ensure_day(&mut self, current_day: u64) {
-        if self.reserved_day != current_day {
-            self.reserved_day = current_day;
+    /// Index of the cap window containing `timestamp`.
+    pub fn cap_window(&self, timestamp: u64) -> u64 {
+        timestamp.saturating_add(self.cap_window_offset_secs) / self.cap_window_secs.max(1)
+    }
+
+    /// Whether any per-run, daily or rolling cap is set.
+    pub fn has_caps(&self) -> bool {
+        self.per_run_cap > 0 || self.daily_cap > 0 || self.rolling_cap > 0
+    }
+
+    /// Starts a fresh reservation once `now` falls in a later cap window.
+    pub fn ensure_window(&mut self, now: u64) {
+        let window = self.cap_window(now);
+        if self.reserved_day != window {
+            self.reserved_day = window;
             self.reserved_today = 0;
         }
     }
+
+    pub fn ensure_hour(&mut self, current_hour: u64) {
+        if self.run_hour != current_hour {
+            self.run_hour = current_hour;
+            self.runs_this_hour = 0;
+        }
+    }
+}
+
+/// A settlement reported by the runner as failed. `settlement.actual_charge` is
+/// already net of `discount`.
+#[derive(Clone)]
+#[contracttype]
+pub struct FailedSettlement {
+    pub settlement: RunSettlement,
+    /// Runner-defined reason the run failed.
+    pub failure_code: u32,
+    /// Taken off the metered charge under the vault's `failure_discount_bps`.
+    pub discount: i128,
 }
 
 #[derive(Clone)]
@@ -76,6 +118,27 @@ pub struct RunSettlement {
     pub actual_charge: i128,
     pub refund: i128,
     pub output_hash: BytesN<32>,
+    /// Address whose developer balance was credited with the charge.
+    pub developer: Address,
+    /// Total the developer has handed back through `refund_user`.
+    pub goodwill_refunded: i128,
+    /// Total the user has added on top of the charge through `tip_run`.
+    pub tipped: i128,
+    /// `actual_charge` split by meter, streamed usage included. Settlements from before
+    /// breakdowns existed report their whole charge as `adjustment`.
+    pub charges: ChargeBreakdown,
+}
+
+/// Usage a runner reported for a run whose user must co-sign the settlement.
+#[derive(Clone)]
+#[contracttype]
+pub struct ProposedSettlement {
+    pub runner: Address,
+    pub usage: UsageBreakdown,
+    pub output_hash: BytesN<32>,
+    pub proposed_at: u64,
+    /// Left unanswered past this time, the run can be refunded through `expire_run`.
+    pub respond_by: u64,
 }
 
 #[derive(Clone)]
@@ -87,6 +150,53 @@ pub struct RunnerGrant {
     pub expires_at: Option<u64>,
 }
 
+/// Authorizes a runner for every agent published by `developer`, including future ones.
+#[derive(Clone)]
+#[contracttype]
+pub struct DeveloperGrant {
+    pub runner: Address,
+    pub developer: Address,
+    pub issued_at: u64,
+    pub expires_at: Option<u64>,
+}
+
+/// Daily limit on the escrow one runner or session key may open against a user.
+#[derive(Clone)]
+#[contracttype]
+pub struct RunnerCap {
+    pub cap: i128,
+    pub used_today: i128,
+    pub day: u64,
+}
+
+impl RunnerCap {
+    pub fn ensure_day(&mut self, current_day: u64) {
+        if self.day != current_day {
+            self.day = current_day;
+            self.used_today = 0;
+        }
+    }
+}
+
+/// An ephemeral key allowed to open runs for a user, within a total escrow budget.
+#[derive(Clone)]
+#[contracttype]
+pub struct SessionKey {
+    pub expires_at: u64,
+    pub max_total_spend: i128,
+    /// Sum of `max_charge` over the runs this key has opened.
+    pub spent: i128,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct SessionLog {
+    pub user: Address,
+    pub session_key: Address,
+    pub expires_at: u64,
+    pub max_total_spend: i128,
+}
+
 #[derive(Clone)]
 #[contracttype]
 pub struct RunnerGrantLog {
@@ -97,6 +207,16 @@ pub struct RunnerGrantLog {
     pub expires_at: Option<u64>,
 }
 
+#[derive(Clone)]
+#[contracttype]
+pub struct DeveloperGrantLog {
+    pub user: Address,
+    pub runner: Address,
+    pub developer: Address,
+    pub issued_at: u64,
+    pub expires_at: Option<u64>,
+}
+
 #[derive(Clone)]
 #[contracttype]
 pub struct RunnerRevokeLog {
@@ -106,6 +226,33 @@ pub struct RunnerRevokeLog {
     pub revoked_at: u64,
 }
 
+/// Emitted by both vaults when a balance moves between deployments. `counterparty` is
+/// the target on the sending side and the source on the receiving side.
+#[derive(Clone)]
+#[contracttype]
+pub struct MigrationLog {
+    pub user: Address,
+    pub counterparty: Address,
+    pub amount: i128,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct GuardianPauseLog {
+    pub user: Address,
+    pub guardian: Address,
+    pub paused_at: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct DeveloperRevokeLog {
+    pub user: Address,
+    pub runner: Address,
+    pub developer: Address,
+    pub revoked_at: u64,
+}
+
 #[derive(Clone)]
 #[contracttype]
 pub struct RunOpenedLog {
@@ -117,35 +264,460 @@ pub struct RunOpenedLog {
     pub max_charge: i128,
     pub budgets: UsageBreakdown,
     pub opened_at: u64,
+    pub input_hash: BytesN<32>,
+    pub memo: Option<String>,
+    pub sponsor: Option<Address>,
+    pub priority_fee: i128,
+}
+
+/// Published when an open run is cancelled by its user or expired, returning its escrow.
+#[derive(Clone)]
+#[contracttype]
+pub struct RunClosedLog {
+    pub run_id: u64,
+    pub agent_id: u32,
+    pub user: Address,
+    pub sponsor: Option<Address>,
+    /// Escrow returned for the charge, not counting the priority fee.
+    pub refunded: i128,
+    /// Priority fee returned along with it.
+    pub priority_fee: i128,
+    pub closed_at: u64,
 }
 
 #[derive(Clone)]
 #[contracttype]
 pub struct RunFinalizedLog {
     pub run_id: u64,
+    pub agent_id: u32,
+    pub user: Address,
+    pub sponsor: Option<Address>,
+    /// Payout address credited with the charge.
+    pub developer: Address,
     pub runner: Address,
+    pub input_hash: BytesN<32>,
     pub actual_charge: i128,
     pub refund: i128,
+    /// Paid to `runner` on top of `actual_charge`.
+    pub priority_fee: i128,
     pub usage: UsageBreakdown,
+    /// `actual_charge` split by meter.
+    pub charges: ChargeBreakdown,
     pub output_hash: BytesN<32>,
+    /// Set when the runner settled the run as failed through `finalize_failed`.
+    pub failure_code: Option<u32>,
     pub finalized_at: u64,
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone)]
 #[contracttype]
 pub enum RunLifecycle {
     Open,
     Finalized(RunSettlement),
     Cancelled,
+    /// Awaiting the user's `accept_settlement` or `reject_settlement`.
+    Proposed(ProposedSettlement),
+    /// The user rejected the proposed settlement; the escrow stays held until the admin
+    /// settles it through `settle_dispute` or refunds it through `void_dispute`.
+    Disputed(ProposedSettlement),
+    /// Passed its `expires_at` unsettled, or its proposal's `respond_by` unanswered; the
+    /// escrow was returned to the user.
+    Expired,
+    /// Closed unsettled by its user through `force_close_run`; the escrow was returned.
+    ForceClosed,
+    /// Settled through `finalize_failed` for the usage consumed before the run failed.
+    Failed(FailedSettlement),
+}
+
+/// Coarse run state used to filter listings. Proposed and disputed runs match none.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum LifecycleKind {
+    Open,
+    Finalized,
+    Cancelled,
+    Expired,
+    ForceClosed,
+    Failed,
+}
+
+impl RunLifecycle {
+    pub fn is_kind(&self, kind: LifecycleKind) -> bool {
+        matches!(
+            (self, kind),
+            (RunLifecycle::Open, LifecycleKind::Open)
+                | (RunLifecycle::Finalized(_), LifecycleKind::Finalized)
+                | (RunLifecycle::Cancelled, LifecycleKind::Cancelled)
+                | (RunLifecycle::Expired, LifecycleKind::Expired)
+                | (RunLifecycle::ForceClosed, LifecycleKind::ForceClosed)
+                | (RunLifecycle::Failed(_), LifecycleKind::Failed)
+        )
+    }
+
+    /// The settlement of a run settled either way, successful or failed.
+    pub fn settlement_mut(&mut self) -> Option<&mut RunSettlement> {
+        match self {
+            RunLifecycle::Finalized(settlement) => Some(settlement),
+            RunLifecycle::Failed(failed) => Some(&mut failed.settlement),
+            _ => None,
+        }
+    }
+}
+
+/// The pricing terms of a run's rate card, copied when the run opens so settling it does
+/// not depend on the registry still serving that card.
+#[derive(Clone)]
+#[contracttype]
+pub struct RateSnapshot {
+    pub rates: UsageMeterRates,
+    pub base_fee: i128,
+    pub unit_scale: u32,
+    pub min_charge: i128,
+    pub free_daily_units: Map<Symbol, i128>,
+    pub manifest_version: u32,
+}
+
+/// Where a run's settlement takes its rates from.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+#[contracttype]
+pub enum RunPricing {
+    /// Opened before runs snapshotted their rate card; priced from the registry's card.
+    Registry,
+    Snapshot(RateSnapshot),
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct RunRecord {
+    pub user: Address,
+    pub opened_by: Address,
+    pub agent_id: u32,
+    pub rate_version: u32,
+    pub budgets: UsageBreakdown,
+    pub max_charge: i128,
+    pub escrowed: i128,
+    /// Portion of `max_charge` reserved against the user's daily cap.
+    pub reserved: i128,
+    pub allowance_drawn: i128,
+    pub opened_at: u64,
+    pub lifecycle: RunLifecycle,
+    /// Usage already billed through `charge_run`; `finalize_run` reports the remainder.
+    pub streamed_usage: UsageBreakdown,
+    /// Amount already paid to the developer out of `max_charge` through `charge_run`.
+    pub streamed_charge: i128,
+    /// The part of `streamed_usage` the free tier did not cover. `streamed_charge` is
+    /// always its price, rounded once.
+    pub streamed_billable: UsageBreakdown,
+    /// The user's `require_user_ack` when the run opened, which decides how it settles.
+    pub require_user_ack: bool,
+    /// After this time the run can no longer be settled and `expire_run` refunds it.
+    pub expires_at: Option<u64>,
+    /// Hash of the job input, paired with the settlement's `output_hash` for audit.
+    pub input_hash: BytesN<32>,
+    /// User-supplied label; has no bearing on settlement.
+    pub memo: Option<String>,
+    /// Who funded the escrow when it is not `user`. Refunds and the caps the run counts
+    /// against are the sponsor's.
+    pub sponsor: Option<Address>,
+    /// Extra paid to the runner that settles the run. Held in `escrowed` on top of
+    /// `max_charge` and refunded if the run is cancelled or expires.
+    pub priority_fee: i128,
+    /// The rate card `rate_version` as it stood at open.
+    pub pricing: RunPricing,
+}
+
+/// What `open_run` escrowed, so callers need not re-derive the charge client-side.
+#[derive(Clone)]
+#[contracttype]
+pub struct OpenRunResult {
+    pub run_id: u64,
+    pub max_charge: i128,
+    pub escrowed: i128,
+    pub rate_version: u32,
+    pub opened_at: u64,
+}
+
+/// Everything a wallet shows for a user, in one call.
+#[derive(Clone)]
+#[contracttype]
+pub struct UserStats {
+    pub balance: i128,
+    pub per_run_cap: i128,
+    pub daily_cap: i128,
+    pub paused: bool,
+    pub reserved_today: i128,
+    /// Charges settled today, including streamed ones.
+    pub spent_today: i128,
+    pub open_runs: u32,
+    pub escrowed: i128,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct QuoteResult {
+    pub max_charge: i128,
+    pub within_per_run_cap: bool,
+    pub within_daily_cap: bool,
+    pub sufficient_balance: bool,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct RunChargedLog {
+    pub run_id: u64,
+    pub runner: Address,
+    pub charge: i128,
+    pub cumulative_charge: i128,
+    pub cumulative_usage: UsageBreakdown,
+    pub charged_at: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct GoodwillRefundLog {
+    pub run_id: u64,
+    pub developer: Address,
+    pub user: Address,
+    pub amount: i128,
+    pub total_refunded: i128,
+    pub refunded_at: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct RunTipLog {
+    pub run_id: u64,
+    pub user: Address,
+    pub developer: Address,
+    pub amount: i128,
+    pub total_tipped: i128,
+    pub tipped_at: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct ProtocolFeeLog {
+    pub run_id: u64,
+    pub developer: Address,
+    pub treasury: Address,
+    pub amount: i128,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct ReferralCreditLog {
+    pub run_id: u64,
+    pub user: Address,
+    pub referrer: Address,
+    pub amount: i128,
+}
+
+/// One payee's share of a settlement under the agent's revenue split.
+#[derive(Clone)]
+#[contracttype]
+pub struct RevenueShareLog {
+    pub run_id: u64,
+    pub agent_id: u32,
+    pub payee: Address,
+    pub amount: i128,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct SettlementProposedLog {
+    pub run_id: u64,
+    pub runner: Address,
+    pub usage: UsageBreakdown,
+    pub output_hash: BytesN<32>,
+    pub proposed_at: u64,
+    pub respond_by: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct RunDisputedLog {
+    pub run_id: u64,
+    pub user: Address,
+    pub disputed_at: u64,
+}
+
+/// One page of a run listing. `next_cursor` is where the following page starts, or
+/// `None` once the listing is exhausted.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct RunPage {
+    pub runs: Vec<u64>,
+    pub next_cursor: Option<u32>,
 }
 
 #[derive(Clone)]
 #[contracttype]
 pub struct RunReceipt {
     pub run_id: u64,
+    pub agent_id: u32,
+    pub rate_version: u32,
+    pub usage: UsageBreakdown,
     pub actual_charge: i128,
     pub refund: i128,
+    /// Credited to the settling runner, separately from the developer's charge.
+    pub priority_fee: i128,
+    pub output_hash: BytesN<32>,
+    pub developer: Address,
+    /// Set for runs settled through `finalize_failed`.
+    pub failure_code: Option<u32>,
+    pub finalized_at: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct DepositForLog {
+    pub payer: Address,
+    pub beneficiary: Address,
+    pub amount: i128,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct DepositApprovalLog {
+    pub user: Address,
+    pub spender: Address,
+    pub amount: i128,
+}
+
+/// Funds set aside by `request_withdrawal`, paid out by `execute_withdrawal`.
+#[derive(Clone)]
+#[contracttype]
+pub struct PendingWithdrawal {
+    pub amount: i128,
+    pub requested_at: u64,
+    pub available_at: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct WithdrawLog {
+    pub user: Address,
+    pub amount: i128,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct DeveloperClaimLog {
     pub developer: Address,
+    pub destination: Address,
+    pub amount: i128,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct RunnerClaimLog {
+    pub runner: Address,
+    pub amount: i128,
+}
+
+/// Spending envelope a user pre-approves for runner-initiated runs against one agent.
+#[derive(Clone)]
+#[contracttype]
+pub struct AgentAllowance {
+    pub amount: i128,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct AllowanceSetLog {
+    pub user: Address,
+    pub agent_id: u32,
+    pub amount: i128,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct Subscription {
+    pub agent_id: u32,
+    pub rate_version: u32,
+    pub price: i128,
+    pub started_at: u64,
+    pub expires_at: u64,
+    pub released: bool,
+    /// Taken from the user's daily cap at purchase and handed back on release.
+    pub reserved: i128,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct SubscriptionOpenedLog {
+    pub user: Address,
+    pub agent_id: u32,
+    pub rate_version: u32,
+    pub price: i128,
+    pub started_at: u64,
+    pub expires_at: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct SubscriptionReleasedLog {
+    pub user: Address,
+    pub agent_id: u32,
+    pub developer: Address,
+    pub amount: i128,
+    pub released_at: u64,
+}
+
+/// Lifetime run counters for one agent, maintained by the vault.
+#[derive(Clone, Default)]
+#[contracttype]
+pub struct AgentStats {
+    pub runs_opened: u64,
+    pub runs_finalized: u64,
+    pub runs_cancelled: u64,
+    pub total_settled_volume: i128,
+}
+
+/// How much of the vault an admin pause stops. Settling and cancelling open runs is
+/// never paused.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum PauseLevel {
+    Unpaused,
+    /// Blocks deposits, new runs, subscriptions, vouchers and claims. Users can still
+    /// withdraw, so an admin pause can never hold their balances.
+    NewActivity,
+    /// Additionally blocks withdrawals.
+    All,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct VaultPauseLog {
+    pub admin: Address,
+    pub level: PauseLevel,
+    pub timestamp: u64,
+}
+
+/// Protocol-wide ceiling on charges settled per day. A zero ceiling disables it.
+#[derive(Clone, Default)]
+#[contracttype]
+pub struct CircuitBreaker {
+    pub ceiling: i128,
+    pub settled_today: i128,
+    pub day: u64,
+    /// Set by the admin to let settlements through for the rest of `day`.
+    pub overridden: bool,
+}
+
+impl CircuitBreaker {
+    pub fn ensure_day(&mut self, current_day: u64) {
+        if self.day != current_day {
+            self.day = current_day;
+            self.settled_today = 0;
+            self.overridden = false;
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -154,6 +726,81 @@ pub struct PolicyInput {
     pub per_run_cap: i128,
     pub daily_cap: i128,
     pub paused: bool,
+    pub require_user_ack: bool,
+    pub default_run_ttl_secs: u64,
+    pub max_runs_per_hour: u32,
+    pub caps_in_usd: bool,
+    pub only_verified_agents: bool,
+    pub cap_window_secs: u64,
+    pub cap_window_offset_secs: u64,
+    pub rolling_cap: i128,
+    pub force_close_after_secs: u64,
+}
+
+/// Entries whose stored layout is versioned and can be rewritten by `migrate`.
+#[derive(Clone)]
+#[contracttype]
+pub enum VersionedKey {
+    Run(u64),
+    Policy(Address),
+    /// A user balance, kept in instance storage under schema version 1.
+    Balance(Address),
+    /// A developer balance, kept in instance storage under schema version 1.
+    DeveloperBalance(Address),
+}
+
+/// Deployment config passed to `init_with_config`. Settlements pay `fee_bps` of their
+/// charge to `treasury`; zero or no treasury leaves the fee off.
+#[derive(Clone)]
+#[contracttype]
+pub struct InitConfig {
+    pub admin: Address,
+    pub registry: Address,
+    /// Balance token of users who have not chosen their own.
+    pub token: Option<Address>,
+    pub fee_bps: u32,
+    pub treasury: Option<Address>,
+}
+
+/// Fields to change in `update_config`; `None` keeps the current value.
+#[derive(Clone)]
+#[contracttype]
+pub struct ConfigUpdate {
+    pub admin: Option<Address>,
+    pub registry: Option<Address>,
+    pub token: Option<Address>,
+    pub fee_bps: Option<u32>,
+    pub treasury: Option<Address>,
+    pub agent_event_topics: Option<bool>,
+    pub failure_discount_bps: Option<u32>,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct VaultConfig {
+    pub admin: Address,
+    pub registry: Address,
+    pub token: Option<Address>,
+    pub fee_bps: u32,
+    pub treasury: Option<Address>,
+    /// Layout version the config was last written with; 0 for vaults initialized
+    /// before configs were versioned.
+    pub config_version: u32,
+    /// Whether run opened, cancelled and expired events carry `agent_id` as a third
+    /// topic. Off by default so existing subscribers keep matching; `run/finalized`
+    /// always carries it.
+    pub agent_event_topics: bool,
+    /// Discount taken off the charge of runs settled through `finalize_failed`.
+    pub failure_discount_bps: u32,
+}
+
+/// Oracle used to convert charges into USD for USD-denominated caps. Prices older than
+/// `max_age_secs` are rejected.
+#[derive(Clone)]
+#[contracttype]
+pub struct PriceOracleConfig {
+    pub oracle: Address,
+    pub max_age_secs: u64,
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -177,4 +824,147 @@ pub enum VaultError {
     UnauthorizedRunner = 15,
     RunnerGrantExists = 16,
     RunnerGrantNotFound = 17,
+    SubscriptionNotOffered = 18,
+    SubscriptionActive = 19,
+    SubscriptionNotFound = 20,
+    AllowanceExceeded = 21,
+    AllowanceExpired = 22,
+    AgentInactive = 23,
+    AgentPaused = 24,
+    RateCardExpired = 25,
+    UnknownMeter = 26,
+    BudgetBelowMinCharge = 27,
+    TokenMismatch = 28,
+    VaultPaused = 29,
+    CircuitBreakerTripped = 30,
+    ChargeOverflow = 31,
+    GrantAlreadyExpired = 32,
+    SelfGrantNotAllowed = 33,
+    SettlementAckRequired = 34,
+    SettlementNotProposed = 35,
+    TooManyPresets = 36,
+    PresetNotFound = 37,
+    RunExpired = 38,
+    RunNotExpired = 39,
+    RunTtlExceedsPolicy = 40,
+    RefundExceedsCharge = 41,
+    RunNotFinalized = 42,
+    InvalidReferrer = 43,
+    ReferrerAlreadySet = 44,
+    BalanceTokenNotSet = 45,
+    InsufficientAllowance = 46,
+    MemoTooLong = 47,
+    RunnerCapExceeded = 48,
+    RateLimited = 49,
+    WithdrawalLocked = 50,
+}
+
+/// Failures converting charges to USD. `VaultError` has no room left for them, so they
+/// are numbered after it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracterror]
+#[repr(u32)]
+pub enum PriceOracleError {
+    OracleNotSet = 51,
+    PriceUnavailable = 52,
+    PriceStale = 53,
+}
+
+/// Runs a user's own policy refuses, numbered after `PriceOracleError`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracterror]
+#[repr(u32)]
+pub enum PolicyError {
+    UnverifiedAgent = 54,
+    RollingCapExceeded = 61,
+}
+
+/// Voucher failures, numbered after `PolicyError`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracterror]
+#[repr(u32)]
+pub enum VoucherError {
+    VoucherExists = 55,
+    VoucherNotFound = 56,
+    VoucherExpired = 57,
+    VoucherExhausted = 58,
+    AlreadyRedeemed = 59,
+    VoucherNotExpired = 60,
+}
+
+/// Withdrawal timelock failures, numbered after `VoucherError`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracterror]
+#[repr(u32)]
+pub enum WithdrawalError {
+    DelayTooLong = 62,
+}
+
+/// Sponsored run failures, numbered after `WithdrawalError`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracterror]
+#[repr(u32)]
+pub enum SponsorError {
+    SponsorIsUser = 63,
+}
+
+/// Storage schema failures, numbered after `SponsorError`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracterror]
+#[repr(u32)]
+pub enum SchemaError {
+    /// `migrate` was asked to upgrade from a version other than the stored one.
+    VersionMismatch = 64,
+    /// A stored entry matches no known layout.
+    UnknownLayout = 65,
+}
+
+/// Settlement dispute failures, numbered after `SchemaError`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracterror]
+#[repr(u32)]
+pub enum DisputeError {
+    RunNotDisputed = 66,
+}
+
+/// Promo credit escrowed from `funder`'s balance, paying `amount` to each of up to
+/// `max_redemptions` users who present the code.
+#[derive(Clone)]
+#[contracttype]
+pub struct Voucher {
+    pub funder: Address,
+    /// The funder's balance token; only users holding their balance in it can redeem.
+    pub token: Option<Address>,
+    pub amount: i128,
+    pub max_redemptions: u32,
+    pub redeemed: u32,
+    pub expires_at: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct VoucherCreatedLog {
+    pub code_hash: BytesN<32>,
+    pub funder: Address,
+    pub token: Option<Address>,
+    pub amount: i128,
+    pub max_redemptions: u32,
+    pub expires_at: u64,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct VoucherRedeemedLog {
+    pub code_hash: BytesN<32>,
+    pub user: Address,
+    pub amount: i128,
+    pub redemptions_left: u32,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct VoucherReclaimedLog {
+    pub code_hash: BytesN<32>,
+    pub funder: Address,
+    pub amount: i128,
 }
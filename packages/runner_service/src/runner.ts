@@ -34,6 +34,7 @@ const toContractUsage = (usage: UsageBudget): PrepaidVault.UsageBreakdown => ({
   llm_out: BigInt(toPositiveInt(usage.llmOut)),
   http_calls: BigInt(toPositiveInt(usage.httpCalls)),
   runtime_ms: BigInt(toPositiveInt(usage.runtimeMs)),
+  extra: new Map(),
 });
 
 const usageToHexHash = (payload: string): { hash: Buffer; hex: string } => {
@@ -220,12 +221,21 @@ export class RunnerService {
         })) ?? current;
 
       const budgets = toContractUsage(current.budgets);
+      const { hash: inputHash } = usageToHexHash(
+        JSON.stringify({
+          id: current.id,
+          workflowId: current.workflowId,
+          metadata: current.metadata ?? {},
+        }),
+      );
       const openTx = await this.vaultClient.open_run({
         user: current.user,
         caller: this.config.runnerPublicKey,
         agent_id: current.agentId,
         rate_version: rateVersion,
         budgets,
+        input_hash: inputHash,
+        memo: undefined,
       });
 
       const openHash = await this.signAndSend(
@@ -234,7 +244,7 @@ export class RunnerService {
       );
       transactionHashes.open = openHash;
 
-      const rawRunId = openTx.result;
+      const rawRunId = openTx.result?.run_id;
       onChainRunId =
         typeof rawRunId === "bigint" ? Number(rawRunId) : Number(rawRunId ?? 0);
       current =
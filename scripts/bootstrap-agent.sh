@@ -54,6 +54,8 @@ ACCOUNT_ADDRESS=$(stellar keys address "${ACCOUNT_NAME}")
 echo "Funding account ${ACCOUNT_ADDRESS} via friendbot..."
 curl -sf "http://localhost:8000/friendbot?addr=${ACCOUNT_ADDRESS}" >/dev/null || true
 
+SETTLEMENT_TOKEN=${SETTLEMENT_TOKEN:-$(stellar contract id asset --asset native --network development)}
+
 echo "Registering runner in agent registry..."
 stellar contract invoke \
   --network development \
@@ -63,7 +65,7 @@ stellar contract invoke \
   --developer "${ACCOUNT_ADDRESS}" \
   --metadata_uri null \
   --runners "[\"${VITE_RUNNER_PUBLIC_KEY}\"]" \
-  --initial_rate_card '{ "manifest_hash": "0000000000000000000000000000000000000000000000000000000000000000", "rates": { "http_calls": "100000000", "llm_in": "10000", "llm_out": "20000", "runtime_ms": "1" } }'
+  --initial_rate_card '{ "manifest_hash": "0000000000000000000000000000000000000000000000000000000000000000", "rates": { "http_calls": "100000000", "llm_in": "10000", "llm_out": "20000", "runtime_ms": "1", "extra": {} }, "unit_scale": 1, "subscription": "NotOffered", "effective_from": null, "expires_at": null, "free_daily_units": {}, "min_charge": "0", "base_fee": "0", "token": "'"${SETTLEMENT_TOKEN}"'" }'
 
 echo "Registering agent complete."
 
@@ -649,6 +649,7 @@ const Wallet = () => {
 
       const tx = await vaultClient.revoke_runner({
         user: address,
+        caller: address,
         runner: runnerAddress,
         agent_id: DEFAULT_AGENT_ID,
       });